@@ -7,5 +7,6 @@ check() {
 
 install() {
     inst /usr/lib/udev/prefixdevname
+    inst_symlink /usr/lib/systemd/system-generators/prefixdevname-generator
     inst_rules 71-prefixdevname.rules
 }
@@ -36,6 +36,9 @@ net_setup_link built-in (e.g. /etc/systemd/network/71-net-ifnames-prefix-net0.li
 %doc README.md
 %{_prefix}/lib/udev/%{name}
 %{_prefix}/lib/udev/rules.d/*.rules
+%{_prefix}/lib/systemd/system-generators/%{name}-generator
+%{_prefix}/lib/systemd/system/%{name}.socket
+%{_prefix}/lib/systemd/system/%{name}.service
 %dir %{_prefix}/lib/dracut/modules.d/71%{name}
 %{_prefix}/lib/dracut/modules.d/71%{name}/*
 %dir %{_prefix}/lib/dracut/modules.d/71%{name}-tools
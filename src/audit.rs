@@ -0,0 +1,220 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::util::var_available;
+
+pub static AUDIT_LOG: &str = "/var/lib/prefixdevname/audit.log";
+// Records of the generator and of the initrd, /var isn't available to them
+pub static AUDIT_QUEUE: &str = "/run/prefixdevname/audit.queue";
+
+/// Formats seconds since the epoch as RFC 3339 timestamp in UTC, e.g. 2024-02-29T13:05:00Z.
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Single naming decision, recorded so that it is possible to find out later why the interface
+/// got its name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub timestamp: u64,
+    pub name: String,
+    pub hwaddr: String,
+    pub link_match: String,
+    pub trigger: String,
+    pub devpath: String,
+}
+
+impl AuditRecord {
+    pub fn new(name: &str, hwaddr: &str, link_match: &str, trigger: &str, devpath: &str) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        AuditRecord {
+            timestamp,
+            name: name.to_string(),
+            hwaddr: hwaddr.to_string(),
+            link_match: link_match.to_string(),
+            trigger: trigger.to_string(),
+            devpath: devpath.to_string(),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        format!(
+            "timestamp={} name={} hwaddr={} match={} trigger={} devpath={}",
+            format_timestamp(self.timestamp),
+            self.name,
+            self.hwaddr,
+            self.link_match,
+            self.trigger,
+            self.devpath
+        )
+    }
+
+    pub fn append_to<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        writeln!(&mut log, "{}", self.to_line())?;
+
+        Ok(())
+    }
+
+    /// Appends the record to the log once /var is available, until then the records are queued
+    /// in /run and moved to the log along with the first record written later.
+    pub fn record<P: AsRef<Path>>(&self, log: P) -> Result<(), Box<dyn Error>> {
+        self.record_with(log.as_ref(), Path::new(AUDIT_QUEUE), var_available())
+    }
+
+    fn record_with(&self, log: &Path, queue: &Path, var: bool) -> Result<(), Box<dyn Error>> {
+        if !var {
+            return self.append_to(queue);
+        }
+
+        flush_audit_queue(queue, log)?;
+        self.append_to(log)
+    }
+}
+
+fn flush_audit_queue(queue: &Path, log: &Path) -> Result<(), Box<dyn Error>> {
+    // Concurrent udev events must not move the queued records twice
+    let taken = queue.with_extension(format!("{}", std::process::id()));
+
+    match fs::rename(queue, &taken) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(From::from(e)),
+    }
+
+    let moved = fs::read_to_string(&taken).and_then(|queued| {
+        if let Some(dir) = log.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log)?
+            .write_all(queued.as_bytes())
+    });
+
+    match moved {
+        Ok(()) => fs::remove_file(&taken)?,
+        Err(e) => {
+            let _ = fs::rename(&taken, queue);
+            return Err(From::from(e));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp_epoch() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn timestamp_leap_day() {
+        assert_eq!(format_timestamp(1709211900), "2024-02-29T13:05:00Z");
+    }
+
+    #[test]
+    fn audit_record_line() {
+        let mut record = AuditRecord::new(
+            "net1",
+            "52:54:00:1C:08:B7",
+            "MACAddress",
+            "add",
+            "/devices/pci0000:00/0000:00:03.0/net/eth0",
+        );
+        record.timestamp = 0;
+
+        assert_eq!(
+            record.to_line(),
+            "timestamp=1970-01-01T00:00:00Z name=net1 hwaddr=52:54:00:1C:08:B7 match=MACAddress trigger=add devpath=/devices/pci0000:00/0000:00:03.0/net/eth0"
+        );
+    }
+
+    #[test]
+    fn audit_record_append() {
+        let path = std::env::temp_dir().join(format!("prefixdevname-audit-{}", std::process::id()));
+        let record = AuditRecord::new("net1", "52:54:00:1C:08:B7", "MACAddress", "add", "/x");
+
+        record.append_to(&path).unwrap();
+        record.append_to(&path).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(content.lines().count(), 2);
+    }
+
+    #[test]
+    fn audit_record_queued() {
+        let dir =
+            std::env::temp_dir().join(format!("prefixdevname-audit-queue-{}", std::process::id()));
+        let log = dir.join("var/audit.log");
+        let queue = dir.join("run/audit.queue");
+        let record =
+            |name: &str| AuditRecord::new(name, "52:54:00:1C:08:B7", "MACAddress", "add", "/x");
+
+        record("net0").record_with(&log, &queue, false).unwrap();
+        record("net1").record_with(&log, &queue, false).unwrap();
+        assert!(!log.exists());
+
+        record("net2").record_with(&log, &queue, true).unwrap();
+        let names: Vec<_> = fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|l| l.split(' ').nth(1).unwrap().to_string())
+            .collect();
+        let run_left = fs::read_dir(dir.join("run")).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(names, vec!["name=net0", "name=net1", "name=net2"]);
+        assert_eq!(run_left, 0);
+    }
+}
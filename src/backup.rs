@@ -0,0 +1,198 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::io;
+#[cfg(not(feature = "minimal"))]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(not(feature = "minimal"))]
+use crate::util::temp_path_for;
+
+pub static BACKUP_DIR: &str = "/var/lib/prefixdevname/backups";
+
+/// Copy of the link file saved before it was overwritten or deleted. Backups are named
+/// <seconds since the epoch>-<file name>, hence they sort by time.
+#[cfg(not(feature = "minimal"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    pub name: String,
+    pub file_name: String,
+    pub timestamp: u64,
+}
+
+#[cfg(not(feature = "minimal"))]
+impl Backup {
+    pub fn from_name(name: &str) -> Option<Backup> {
+        let (timestamp, file_name) = name.split_once('-')?;
+
+        // Suffix distinguishing several backups taken within one second isn't part of the name
+        let file_name = match file_name.rsplit_once('.') {
+            Some((f, n)) if n.chars().all(|c| c.is_ascii_digit()) => f,
+            _ => file_name,
+        };
+
+        Some(Backup {
+            name: name.to_string(),
+            file_name: file_name.to_string(),
+            timestamp: timestamp.parse().ok()?,
+        })
+    }
+}
+
+/// Saves copy of the file in the backup directory, nothing is done when the file doesn't exist.
+pub fn backup_file<P: AsRef<Path>>(dir: P, path: &Path) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let content = match fs::read(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(From::from(e)),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or("Path doesn't name a file")?
+        .to_string_lossy();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    fs::create_dir_all(dir.as_ref())?;
+
+    // The same file may be backed up more than once within a second
+    for attempt in 0.. {
+        let name = match attempt {
+            0 => format!("{}-{}", timestamp, file_name),
+            n => format!("{}-{}.{}", timestamp, file_name, n),
+        };
+        let backup = dir.as_ref().join(name);
+
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&backup)
+        {
+            Ok(mut f) => {
+                io::Write::write_all(&mut f, &content)?;
+                return Ok(Some(backup));
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(From::from(e)),
+        }
+    }
+
+    unreachable!()
+}
+
+/// Backs up the link file before it is overwritten or deleted. Failure doesn't stop the operation,
+/// /var may not be available yet early during the boot.
+pub fn backup_link_file(path: &Path) {
+    match backup_file(BACKUP_DIR, path) {
+        Ok(Some(b)) => debug!("Saved backup of {} to {}", path.display(), b.display()),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to back up {}: {}", path.display(), e),
+    }
+}
+
+/// Backups ordered from the oldest.
+#[cfg(not(feature = "minimal"))]
+pub fn list_backups<P: AsRef<Path>>(dir: P) -> Result<Vec<Backup>, Box<dyn Error>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(From::from(e)),
+    };
+    let mut backups = Vec::new();
+
+    for entry in entries {
+        if let Some(b) = Backup::from_name(&entry?.file_name().to_string_lossy()) {
+            backups.push(b);
+        }
+    }
+
+    backups.sort_by(|a, b| (a.timestamp, &a.name).cmp(&(b.timestamp, &b.name)));
+    Ok(backups)
+}
+
+/// Puts the backup back in place of the original file. The current file is backed up first, so
+/// that the restore can be undone too. Returns path of the restored file.
+#[cfg(not(feature = "minimal"))]
+pub fn restore_backup<P: AsRef<Path>, T: AsRef<Path>>(
+    dir: P,
+    name: &str,
+    target_dir: T,
+    mode: u32,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let backup = Backup::from_name(name)
+        .filter(|b| !b.name.contains('/'))
+        .ok_or_else(|| format!("Invalid backup name \"{}\"", name))?;
+    let content = fs::read(dir.as_ref().join(&backup.name))?;
+    let path = target_dir.as_ref().join(&backup.file_name);
+    let tmp_path = temp_path_for(&path);
+
+    backup_file(&dir, &path)?;
+    let restored = fs::write(&tmp_path, content)
+        .and_then(|_| fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode)))
+        .and_then(|_| fs::rename(&tmp_path, &path));
+
+    if let Err(e) = restored {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(From::from(e));
+    }
+
+    Ok(path)
+}
+
+#[cfg(all(test, not(feature = "minimal")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backups() {
+        let root =
+            std::env::temp_dir().join(format!("prefixdevname-backup-{}", std::process::id()));
+        let dir = root.join("backups");
+        let target = root.join("network");
+        let link = target.join("71-net-ifnames-prefix-net0.link");
+
+        fs::create_dir_all(&target).unwrap();
+        assert_eq!(backup_file(&dir, &link).unwrap(), None);
+        assert!(list_backups(&dir).unwrap().is_empty());
+
+        fs::write(&link, "first").unwrap();
+        let first = backup_file(&dir, &link).unwrap().unwrap();
+        fs::write(&link, "second").unwrap();
+        backup_file(&dir, &link).unwrap().unwrap();
+
+        let backups = list_backups(&dir).unwrap();
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[0].file_name, "71-net-ifnames-prefix-net0.link");
+        assert_eq!(
+            backups[0].name,
+            first.file_name().unwrap().to_string_lossy()
+        );
+
+        fs::write(&link, "third").unwrap();
+        assert_eq!(
+            restore_backup(&dir, &backups[0].name, &target, 0o644).unwrap(),
+            link
+        );
+        assert_eq!(fs::read_to_string(&link).unwrap(), "first");
+        // Only the restored file, no temporary file is left behind
+        assert_eq!(fs::read_dir(&target).unwrap().count(), 1);
+        assert_eq!(
+            fs::metadata(&link).unwrap().permissions().mode() & 0o777,
+            0o644
+        );
+        assert_eq!(
+            Backup::from_name("1709211900-net0.link.2").map(|b| b.file_name),
+            Some("net0.link".to_string())
+        );
+        assert_eq!(list_backups(&dir).unwrap().len(), 3);
+
+        assert!(restore_backup(&dir, "garbage", &target, 0o644).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
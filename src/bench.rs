@@ -0,0 +1,158 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{max_index_for_prefix, PrefixedLink};
+use crate::simulate::SimulatedDevice;
+
+// MAC addresses are derived from the index, 3 bytes are left after the QEMU OUI
+const MAX_FAKE_DEVICES: u64 = 1 << 24;
+
+pub fn fake_hwaddr(i: u64) -> String {
+    format!(
+        "52:54:00:{:02X}:{:02X}:{:02X}",
+        (i >> 16) & 0xff,
+        (i >> 8) & 0xff,
+        i & 0xff
+    )
+}
+
+fn fake_device(name: String, i: u64) -> SimulatedDevice {
+    SimulatedDevice {
+        name,
+        hwaddr: fake_hwaddr(i),
+        path: Some(format!(
+            "pci-0000:{:02x}:{:02x}.{}",
+            i / 256,
+            (i % 256) / 8,
+            i % 8
+        )),
+        driver: Some("virtio_net".to_string()),
+        ..Default::default()
+    }
+}
+
+/// State of a machine with `count` NICs already named using the prefix, each with its link file,
+/// and `new` NICs that aren't named yet.
+pub fn fake_state(
+    prefix: &str,
+    count: u64,
+    new: u64,
+) -> Result<(Vec<PrefixedLink>, Vec<SimulatedDevice>), Box<dyn Error>> {
+    if count > max_index_for_prefix(prefix) + 1 {
+        return Err(From::from(format!(
+            "Prefix \"{}\" leaves room for at most {} names",
+            prefix,
+            max_index_for_prefix(prefix) + 1
+        )));
+    }
+    if count + new > MAX_FAKE_DEVICES {
+        return Err(From::from(format!(
+            "At most {} devices can be generated",
+            MAX_FAKE_DEVICES
+        )));
+    }
+
+    let mut links = Vec::new();
+    let mut devices = Vec::new();
+
+    for i in 0..count {
+        let name = format!("{}{}", prefix, i);
+
+        links.push(PrefixedLink::new_with_hwaddr(&name, &fake_hwaddr(i))?);
+        devices.push(fake_device(name, i));
+    }
+
+    for i in count..count + new {
+        devices.push(fake_device(format!("eth{}", i - count), i));
+    }
+
+    Ok((links, devices))
+}
+
+/// Device list in the format read by the simulate command.
+pub fn device_list_content(devices: &[SimulatedDevice]) -> String {
+    let mut content = String::new();
+
+    for d in devices {
+        content.push_str(&format!("- name: {}\n  address: {}\n", d.name, d.hwaddr));
+        if let Some(p) = &d.path {
+            content.push_str(&format!("  path: {}\n", p));
+        }
+        if let Some(driver) = &d.driver {
+            content.push_str(&format!("  driver: {}\n", driver));
+        }
+    }
+
+    content
+}
+
+pub fn write_link_files(
+    dir: &Path,
+    links: &[PrefixedLink],
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+
+    links
+        .iter()
+        .map(|l| {
+            let file_name = l.link_file_path();
+            let path = dir.join(file_name.file_name().ok_or("Invalid link file path")?);
+
+            fs::write(&path, l.link_file_content(&[]))?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// Writes the link files to DIR/network and the device list to DIR/devices.yaml.
+pub fn write_fake_state(
+    dir: &Path,
+    prefix: &str,
+    count: u64,
+    new: u64,
+) -> Result<(PathBuf, PathBuf), Box<dyn Error>> {
+    let (links, devices) = fake_state(prefix, count, new)?;
+    let links_dir = dir.join("network");
+    let devices_file = dir.join("devices.yaml");
+
+    write_link_files(&links_dir, &links)?;
+    fs::write(&devices_file, device_list_content(&devices))?;
+
+    Ok((links_dir, devices_file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_link_files;
+    use crate::simulate::load_devices;
+
+    #[test]
+    fn bench_fake_state() {
+        let dir = std::env::temp_dir().join(format!("prefixdevname-bench-{}", std::process::id()));
+        let (links_dir, devices_file) = write_fake_state(&dir, "net", 300, 2).unwrap();
+
+        let mut paths: Vec<PathBuf> = fs::read_dir(&links_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        paths.sort();
+        let devices = load_devices(&devices_file).unwrap();
+        let parsed = parse_link_files(&paths);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(paths.len(), 300);
+        assert!(parsed.iter().all(|c| c.is_ok()));
+        assert_eq!(devices.len(), 302);
+        assert_eq!(devices[299].name, "net299");
+        assert_eq!(devices[299].hwaddr, "52:54:00:00:01:2B");
+        assert_eq!(devices[301].name, "eth1");
+        assert_eq!(devices[301].path.as_deref(), Some("pci-0000:01:05.5"));
+
+        assert!(fake_state("netprefixabc", 1001, 0).is_err());
+        assert!(fake_state("net", MAX_FAKE_DEVICES, 1).is_err());
+    }
+}
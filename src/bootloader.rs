@@ -0,0 +1,173 @@
+// SPDX-License-Identifier:  MIT
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Boot Loader Specification entries, as installed by kernel-install and managed by grubby.
+pub static BLS_ENTRIES_DIR: &str = "/boot/loader/entries";
+/// GRUB environment block, holds the default entry and variables referenced by the entries.
+pub static GRUBENV_PATH: &str = "/boot/grub2/grubenv";
+
+/// Variables of the GRUB environment block, lines of padding are ignored.
+pub fn parse_grubenv(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+// Keys of BLS entries are separated from the values by whitespace
+fn entry_value<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+    content
+        .lines()
+        .filter_map(|l| l.trim().split_once(char::is_whitespace))
+        .filter(|(k, _)| *k == key)
+        .map(|(_, v)| v.trim())
+        .next_back()
+}
+
+/// Expands $name and ${name} references to GRUB variables, unknown variables expand to nothing
+/// as they do in GRUB.
+pub fn expand_grub_variables(options: &str, env: &HashMap<String, String>) -> String {
+    let mut expanded = String::new();
+    let mut chars = options.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut name = String::new();
+
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+
+        if braced {
+            chars.next_if_eq(&'}');
+        }
+
+        expanded.push_str(env.get(&name).map(|v| v.as_str()).unwrap_or_default());
+    }
+
+    expanded
+}
+
+/// Kernel command line of the default entry, i.e. the saved_entry of the GRUB environment, the
+/// entry of the given kernel version or the newest entry, in this order.
+pub fn default_entry_cmdline_in(
+    dir: &Path,
+    grubenv: &Path,
+    kernel_version: &str,
+) -> Result<(PathBuf, String), Box<dyn Error>> {
+    let env = fs::read_to_string(grubenv)
+        .map(|c| parse_grubenv(&c))
+        .unwrap_or_default();
+
+    let mut entries: Vec<(PathBuf, String)> = fs::read_dir(dir)
+        .map_err(|e| {
+            format!(
+                "Failed to read boot loader entries in {}: {}",
+                dir.display(),
+                e
+            )
+        })?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "conf"))
+        .filter_map(|p| fs::read_to_string(&p).ok().map(|c| (p, c)))
+        .collect();
+
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let saved = env.get("saved_entry").filter(|s| !s.is_empty());
+    let entry = entries
+        .iter()
+        .find(|(p, _)| saved.is_some_and(|s| p.file_stem().is_some_and(|n| n == s.as_str())))
+        .or_else(|| {
+            entries
+                .iter()
+                .find(|(_, c)| entry_value(c, "version") == Some(kernel_version))
+        })
+        .or_else(|| entries.last())
+        .ok_or_else(|| format!("No boot loader entries found in {}", dir.display()))?;
+
+    let options = entry_value(&entry.1, "options").unwrap_or_default();
+
+    Ok((entry.0.clone(), expand_grub_variables(options, &env)))
+}
+
+pub fn default_entry_cmdline() -> Result<(PathBuf, String), Box<dyn Error>> {
+    let kernel_version = fs::read_to_string("/proc/sys/kernel/osrelease")?;
+
+    default_entry_cmdline_in(
+        Path::new(BLS_ENTRIES_DIR),
+        Path::new(GRUBENV_PATH),
+        kernel_version.trim(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bootloader_expand_variables() {
+        let env = parse_grubenv(
+            "# GRUB Environment Block\nkernelopts=root=/dev/vda1 ro\ntuned_params=\n#####\n",
+        );
+
+        assert_eq!(
+            expand_grub_variables(
+                "$kernelopts ${tuned_params}net.ifnames.prefix=net $missing",
+                &env
+            ),
+            "root=/dev/vda1 ro net.ifnames.prefix=net "
+        );
+    }
+
+    #[test]
+    fn bootloader_default_entry() {
+        let root = std::env::temp_dir().join(format!("prefixdevname-bls-{}", std::process::id()));
+        let entries = root.join("entries");
+        let grubenv = root.join("grubenv");
+
+        fs::create_dir_all(&entries).unwrap();
+        fs::write(
+            entries.join("abc-5.14.0-1.conf"),
+            "title Old\nversion 5.14.0-1\noptions $kernelopts net.ifnames.prefix=old\n",
+        )
+        .unwrap();
+        fs::write(
+            entries.join("abc-5.14.0-2.conf"),
+            "title New\nversion 5.14.0-2\noptions root=/dev/vda1 net.ifnames.prefix=new\n",
+        )
+        .unwrap();
+
+        let cmdline = |version| {
+            default_entry_cmdline_in(&entries, &grubenv, version)
+                .unwrap()
+                .1
+        };
+
+        assert_eq!(cmdline("5.14.0-1"), " net.ifnames.prefix=old");
+        assert_eq!(cmdline("6.0"), "root=/dev/vda1 net.ifnames.prefix=new");
+
+        fs::write(
+            &grubenv,
+            "saved_entry=abc-5.14.0-1\nkernelopts=root=/dev/vda2\n",
+        )
+        .unwrap();
+        assert_eq!(cmdline("6.0"), "root=/dev/vda2 net.ifnames.prefix=old");
+
+        assert!(default_entry_cmdline_in(&root.join("missing"), &grubenv, "6.0").is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+// SPDX-License-Identifier:  MIT
+
+use crate::config::{LinkMatch, PrefixedLink};
+
+/// Network configuration version 2 for cloud-init pinning the assigned names to the MAC
+/// addresses, so that cloud-init on the first boot renames the NICs the same way we do.
+/// Links matched by path or original name can't be expressed by MAC address and are only listed
+/// in comments. Only the names are set, addressing is left to the rest of the configuration.
+pub fn network_config_v2(links: &[PrefixedLink]) -> String {
+    let mut config = String::from("network:\n  version: 2\n");
+    let mut ethernets = String::new();
+    let mut skipped = String::new();
+
+    for link in links {
+        match &link.link_match {
+            // Permanent address is the current one unless the NIC is enslaved, e.g. to a bond
+            LinkMatch::MACAddress | LinkMatch::PermanentMACAddress => {
+                ethernets.push_str(&format!(
+                    "    {}:\n      match:\n        macaddress: \"{}\"\n      set-name: {}\n",
+                    link.name,
+                    link.hwaddr.to_lowercase(),
+                    link.name
+                ));
+            }
+            LinkMatch::Path(_) | LinkMatch::OriginalName(_) | LinkMatch::Property(_) => {
+                skipped.push_str(&format!(
+                    "  # {} matches on {}={}, not expressible by MAC address\n",
+                    link.name,
+                    link.link_match.key(),
+                    link.match_value()
+                ));
+            }
+        }
+    }
+
+    config.push_str(&skipped);
+
+    if !ethernets.is_empty() {
+        config.push_str("  ethernets:\n");
+        config.push_str(&ethernets);
+    }
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cloud_init_v2() {
+        let links = vec![
+            PrefixedLink::new_with_hwaddr(&"net0", &"00:11:22:33:44:AA").unwrap(),
+            PrefixedLink::new_with_path(&"net1", &"pci-0000:00:03.0").unwrap(),
+            PrefixedLink::new_with_permanent_hwaddr(&"net2", &"00:11:22:33:44:bb").unwrap(),
+        ];
+
+        assert_eq!(
+            network_config_v2(&links),
+            "network:
+  version: 2
+  # net1 matches on Path=pci-0000:00:03.0, not expressible by MAC address
+  ethernets:
+    net0:
+      match:
+        macaddress: \"00:11:22:33:44:aa\"
+      set-name: net0
+    net2:
+      match:
+        macaddress: \"00:11:22:33:44:bb\"
+      set-name: net2
+"
+        );
+        assert_eq!(network_config_v2(&[]), "network:\n  version: 2\n");
+    }
+}
@@ -0,0 +1,329 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::util::hwaddr_normalize;
+
+pub static DMI_ID_DIR: &str = "/sys/class/dmi/id";
+pub static METADATA_ADDRESS: &str = "169.254.169.254:80";
+pub static NIC_INDEXES_CACHE: &str = "/run/prefixdevname/cloud-nic-indexes";
+
+// Chassis asset tag of all Azure virtual machines
+const AZURE_ASSET_TAG: &str = "7783-7084-3265-9085-8269-3286-77";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloudProvider {
+    Ec2,
+    Azure,
+    Gcp,
+}
+
+fn dmi_id(dir: &Path, attribute: &str) -> String {
+    fs::read_to_string(dir.join(attribute))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Provider is recognized by the DMI data of the virtual machine, no request is sent elsewhere.
+pub fn detect_provider<P: AsRef<Path>>(dmi_dir: P) -> Option<CloudProvider> {
+    let dir = dmi_dir.as_ref();
+
+    // Xen based instances report the vendor only in the BIOS version
+    if dmi_id(dir, "sys_vendor") == "Amazon EC2" || dmi_id(dir, "bios_version").contains("amazon") {
+        Some(CloudProvider::Ec2)
+    } else if dmi_id(dir, "chassis_asset_tag") == AZURE_ASSET_TAG {
+        Some(CloudProvider::Azure)
+    } else if dmi_id(dir, "product_name") == "Google Compute Engine" {
+        Some(CloudProvider::Gcp)
+    } else {
+        None
+    }
+}
+
+/// Minimal HTTP/1.0 request, the metadata services are link-local and don't need more. Returns
+/// body of the response, statuses other than 200 are errors.
+pub fn http_request(
+    address: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> Result<String, Box<dyn Error>> {
+    let socket_address: SocketAddr = address.parse()?;
+    let mut stream = TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT)?;
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, address);
+
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+
+    for (k, v) in headers {
+        request.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    request.push_str("Content-Length: 0\r\n\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("Malformed response of the metadata service")?;
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+
+    if status != "200" {
+        return Err(From::from(format!(
+            "Metadata service returned status {} for {}",
+            status, path
+        )));
+    }
+
+    Ok(body.to_string())
+}
+
+// Directory listings of EC2 and GCP, one entry per line, directories end with '/'
+fn listing(body: &str) -> Vec<String> {
+    body.lines()
+        .map(|l| l.trim().trim_end_matches('/').to_string())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+/// MAC addresses of the interfaces in the Azure instance metadata, the order of the interfaces is
+/// the order of the NICs of the virtual machine. Azure reports addresses without separators.
+pub fn parse_azure_interfaces(body: &str) -> Vec<String> {
+    body.split("\"macAddress\"")
+        .skip(1)
+        .filter_map(|s| {
+            let value = s
+                .trim_start()
+                .strip_prefix(':')?
+                .trim_start()
+                .strip_prefix('"')?;
+            let value = &value[..value.find('"')?];
+            let octets: Vec<&str> = (0..value.len())
+                .step_by(2)
+                .filter_map(|i| value.get(i..i + 2))
+                .collect();
+
+            hwaddr_normalize(&octets.join(":")).ok()
+        })
+        .collect()
+}
+
+fn ec2_nic_indexes(address: &str) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+    let macs_path = "/latest/meta-data/network/interfaces/macs/";
+
+    // IMDSv2, instances may also allow IMDSv1 requests without the token
+    let token = http_request(
+        address,
+        "PUT",
+        "/latest/api/token",
+        &[("X-aws-ec2-metadata-token-ttl-seconds", "60")],
+    )
+    .ok();
+    let headers: Vec<(&str, &str)> = token
+        .as_deref()
+        .map(|t| ("X-aws-ec2-metadata-token", t.trim()))
+        .into_iter()
+        .collect();
+    let mut indexes = Vec::new();
+
+    for mac in listing(&http_request(address, "GET", macs_path, &headers)?) {
+        let number = http_request(
+            address,
+            "GET",
+            &format!("{}{}/device-number", macs_path, mac),
+            &headers,
+        )?;
+
+        indexes.push((hwaddr_normalize(&mac)?, number.trim().parse()?));
+    }
+
+    Ok(indexes)
+}
+
+fn azure_nic_indexes(address: &str) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+    let body = http_request(
+        address,
+        "GET",
+        "/metadata/instance/network/interface?api-version=2021-02-01&format=json",
+        &[("Metadata", "true")],
+    )?;
+
+    Ok(parse_azure_interfaces(&body).into_iter().zip(0..).collect())
+}
+
+fn gcp_nic_indexes(address: &str) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+    let path = "/computeMetadata/v1/instance/network-interfaces/";
+    let headers = [("Metadata-Flavor", "Google")];
+    let mut indexes = Vec::new();
+
+    for index in listing(&http_request(address, "GET", path, &headers)?) {
+        let mac = http_request(address, "GET", &format!("{}{}/mac", path, index), &headers)?;
+
+        indexes.push((hwaddr_normalize(&mac.trim())?, index.parse()?));
+    }
+
+    Ok(indexes)
+}
+
+/// NIC index assigned by the provider (EC2 device-number, position of the interface on Azure and
+/// GCP) for each MAC address of the instance, the primary NIC has index 0.
+pub fn nic_indexes(
+    provider: CloudProvider,
+    address: &str,
+) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+    match provider {
+        CloudProvider::Ec2 => ec2_nic_indexes(address),
+        CloudProvider::Azure => azure_nic_indexes(address),
+        CloudProvider::Gcp => gcp_nic_indexes(address),
+    }
+}
+
+/// NIC indexes cached by write_nic_indexes(), None when there are none or they can't be read.
+pub fn read_nic_indexes<P: AsRef<Path>>(path: P) -> Option<Vec<(String, u64)>> {
+    let content = fs::read_to_string(path).ok()?;
+    let mut indexes = Vec::new();
+
+    for line in content.lines() {
+        let (hwaddr, index) = line.split_once(' ')?;
+        indexes.push((hwaddr.to_string(), index.parse().ok()?));
+    }
+
+    Some(indexes).filter(|i| !i.is_empty())
+}
+
+/// Caches the NIC indexes, one "MAC INDEX" line per NIC. The file is replaced atomically, events
+/// of other NICs may read it at the same time.
+pub fn write_nic_indexes<P: AsRef<Path>>(
+    path: P,
+    indexes: &[(String, u64)],
+) -> Result<(), Box<dyn Error>> {
+    let path = path.as_ref();
+    let mut tmp_name = path.as_os_str().to_os_string();
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let content: String = indexes
+        .iter()
+        .map(|(a, i)| format!("{} {}\n", a, i))
+        .collect();
+    fs::write(&tmp_name, content)?;
+    fs::rename(&tmp_name, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn cloud_detect_provider() {
+        let dir = std::env::temp_dir().join(format!("prefixdevname-dmi-{}", std::process::id()));
+
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(detect_provider(&dir), None);
+
+        fs::write(dir.join("product_name"), "Google Compute Engine\n").unwrap();
+        assert_eq!(detect_provider(&dir), Some(CloudProvider::Gcp));
+
+        fs::write(
+            dir.join("chassis_asset_tag"),
+            format!("{}\n", AZURE_ASSET_TAG),
+        )
+        .unwrap();
+        assert_eq!(detect_provider(&dir), Some(CloudProvider::Azure));
+
+        fs::write(dir.join("sys_vendor"), "Amazon EC2\n").unwrap();
+        assert_eq!(detect_provider(&dir), Some(CloudProvider::Ec2));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cloud_nic_indexes_cache() {
+        let dir =
+            std::env::temp_dir().join(format!("prefixdevname-nic-cache-{}", std::process::id()));
+        let path = dir.join("cloud-nic-indexes");
+        let indexes = vec![
+            ("42:01:0A:80:00:03".to_string(), 1),
+            ("42:01:0A:80:00:02".to_string(), 0),
+        ];
+
+        assert_eq!(read_nic_indexes(&path), None);
+        write_nic_indexes(&path, &indexes).unwrap();
+        assert_eq!(read_nic_indexes(&path), Some(indexes));
+
+        fs::write(&path, "42:01:0A:80:00:03 x\n").unwrap();
+        assert_eq!(read_nic_indexes(&path), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cloud_azure_interfaces() {
+        let body = r#"[{"ipv4": {}, "macAddress": "000D3A12ABCD"}, {"macAddress":"000d3a12abce"}]"#;
+
+        assert_eq!(
+            parse_azure_interfaces(body),
+            vec!["00:0D:3A:12:AB:CD", "00:0D:3A:12:AB:CE"]
+        );
+        assert!(parse_azure_interfaces("[]").is_empty());
+    }
+
+    // Serves the GCP metadata for two NICs, the secondary one listed first
+    #[test]
+    fn cloud_gcp_indexes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            for _ in 0..3 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 1024];
+                let n = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..n]).to_string();
+                let body = if !request.contains("Metadata-Flavor: Google") {
+                    None
+                } else if request
+                    .starts_with("GET /computeMetadata/v1/instance/network-interfaces/ ")
+                {
+                    Some("1/\n0/\n")
+                } else if request.contains("interfaces/0/mac") {
+                    Some("42:01:0a:80:00:02")
+                } else if request.contains("interfaces/1/mac") {
+                    Some("42:01:0a:80:00:03")
+                } else {
+                    None
+                };
+                let response = match body {
+                    Some(b) => format!("HTTP/1.0 200 OK\r\n\r\n{}", b),
+                    None => "HTTP/1.0 404 Not Found\r\n\r\n".to_string(),
+                };
+
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        assert_eq!(
+            nic_indexes(CloudProvider::Gcp, &address).unwrap(),
+            vec![
+                ("42:01:0A:80:00:03".to_string(), 1),
+                ("42:01:0A:80:00:02".to_string(), 0)
+            ]
+        );
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs::File;
+use std::io::prelude::*;
+
+#[cfg(not(feature = "minimal"))]
+use crate::config::IFNAME_MAX_LEN;
+use crate::patterns::prefix_argument_value;
+use crate::util::hwaddr_normalize;
+#[cfg(not(feature = "minimal"))]
+use crate::util::prefix_ok;
+
+/// Splits the kernel command line to arguments and their values. Values may be enclosed in double
+/// quotes, e.g. foo="bar baz".
+pub fn parse_cmdline(content: &str) -> Vec<(String, Option<String>)> {
+    let _span = tracing::debug_span!("cmdline").entered();
+    let mut args = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+
+    for c in content.chars().chain(std::iter::once(' ')) {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if word.is_empty() {
+                    continue;
+                }
+
+                let arg = match word.split_once('=') {
+                    Some((k, v)) => (k.to_string(), Some(v.to_string())),
+                    None => (word.clone(), None),
+                };
+                args.push(arg);
+                word.clear();
+            }
+            c => word.push(c),
+        }
+    }
+
+    args
+}
+
+/// Kernel arguments that make other tools rename network interfaces too. Combined behavior is
+/// undefined, hence these are reported as conflicting with net.ifnames.prefix.
+pub fn conflicting_arguments(content: &str) -> Vec<String> {
+    let mut ifnames = None;
+    let mut biosdevname = None;
+
+    // The last occurrence of the argument wins
+    for (k, v) in parse_cmdline(content) {
+        match k.as_str() {
+            "net.ifnames" => ifnames = v,
+            "biosdevname" => biosdevname = v,
+            _ => {}
+        }
+    }
+
+    let mut conflicts = Vec::new();
+
+    if let Some(v) = ifnames.filter(|v| v == "0") {
+        conflicts.push(format!("net.ifnames={}", v));
+    }
+
+    if let Some(v) = biosdevname.filter(|v| v == "1") {
+        conflicts.push(format!("biosdevname={}", v));
+    }
+
+    conflicts
+}
+
+/// Naming policy requested by net.ifnames.policy=, the last occurrence wins.
+pub fn naming_policy_argument(content: &str) -> Option<String> {
+    parse_cmdline(content)
+        .into_iter()
+        .filter(|(k, _)| k == "net.ifnames.policy")
+        .filter_map(|(_, v)| v)
+        .next_back()
+}
+
+/// Names pinned to MAC addresses by dracut's ifname=<name>:<MAC>, MAC addresses are normalized.
+/// Arguments with invalid MAC address are ignored, dracut refuses them too.
+pub fn ifname_arguments(content: &str) -> Vec<(String, String)> {
+    parse_cmdline(content)
+        .into_iter()
+        .filter(|(k, _)| k == "ifname")
+        .filter_map(|(_, v)| {
+            let (name, hwaddr) = v?
+                .split_once(':')
+                .map(|(n, a)| (n.to_string(), a.to_string()))?;
+            let hwaddr = hwaddr_normalize(&hwaddr).ok()?;
+
+            Some((name, hwaddr)).filter(|(n, _)| !n.is_empty())
+        })
+        .collect()
+}
+
+pub fn read_cmdline(path: &str) -> Result<String, Box<dyn Error>> {
+    let mut f = File::open(path)?;
+    let mut content = String::new();
+
+    f.read_to_string(&mut content)?;
+
+    Ok(content)
+}
+
+/// Prefix given by net.ifnames.prefix=, empty when there is none.
+pub fn prefix_argument(content: &str) -> Result<String, Box<dyn Error>> {
+    Ok(prefix_argument_value(content))
+}
+
+/// Outcome of the validation of net.ifnames.prefix= on a proposed kernel command line.
+#[cfg(not(feature = "minimal"))]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefixCheck {
+    /// Prefix the helper will read from the command line, empty when there is none
+    pub prefix: String,
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Validates the prefix before the system is rebooted with the command line. The prefix must
+/// consist of letters only, must not be used by other naming schemes and must leave room for the
+/// index within the interface name limit.
+#[cfg(not(feature = "minimal"))]
+pub fn check_prefix_argument(content: &str) -> PrefixCheck {
+    let values: Vec<String> = parse_cmdline(content)
+        .into_iter()
+        .filter(|(k, _)| k == "net.ifnames.prefix")
+        .map(|(_, v)| v.unwrap_or_default())
+        .collect();
+    let mut check = PrefixCheck {
+        prefix: prefix_argument(content).unwrap_or_default(),
+        ..Default::default()
+    };
+
+    let value = match values.first() {
+        Some(v) => v,
+        None => {
+            check
+                .errors
+                .push("No net.ifnames.prefix= argument, NICs won't be renamed".to_string());
+            return check;
+        }
+    };
+
+    if values.len() > 1 {
+        check.warnings.push(format!(
+            "net.ifnames.prefix= is given {} times, only the first one is used",
+            values.len()
+        ));
+    }
+
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphabetic()) {
+        check.errors.push(format!(
+            "Prefix \"{}\" must consist of letters only, \"{}\" would be used",
+            value, check.prefix
+        ));
+    }
+
+    if check.prefix.len() >= IFNAME_MAX_LEN {
+        check.errors.push(format!(
+            "Prefix \"{}\" leaves no room for the index, interface names are limited to {} characters",
+            check.prefix, IFNAME_MAX_LEN
+        ));
+    } else if !check.prefix.is_empty() && !prefix_ok(&check.prefix) {
+        check.errors.push(format!(
+            "Prefix \"{}\" is used by other naming schemes",
+            check.prefix
+        ));
+    }
+
+    for c in conflicting_arguments(content) {
+        check.warnings.push(format!(
+            "{} conflicts with net.ifnames.prefix=, other tools would rename NICs too",
+            c
+        ));
+    }
+
+    check
+}
+
+#[cfg(not(feature = "minimal"))]
+pub fn get_prefix_from_file(path: &str) -> Result<String, Box<dyn Error>> {
+    prefix_argument(&read_cmdline(path)?)
+}
+
+pub fn get_conflicting_arguments_from_file(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(conflicting_arguments(&read_cmdline(path)?))
+}
+
+pub fn get_ifname_arguments_from_file(path: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    Ok(ifname_arguments(&read_cmdline(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cmdline_parse() {
+        let args = parse_cmdline("ro quiet net.ifnames.prefix=net foo=\"bar baz\"\n");
+
+        assert_eq!(
+            args,
+            vec![
+                ("ro".to_string(), None),
+                ("quiet".to_string(), None),
+                ("net.ifnames.prefix".to_string(), Some("net".to_string())),
+                ("foo".to_string(), Some("bar baz".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn cmdline_no_conflicts() {
+        assert!(conflicting_arguments("ro net.ifnames.prefix=net net.ifnames=1").is_empty());
+        assert!(conflicting_arguments("biosdevname=0 net.ifnames.prefix=net").is_empty());
+    }
+
+    #[test]
+    fn cmdline_conflicts() {
+        assert_eq!(
+            conflicting_arguments("net.ifnames=0 net.ifnames.prefix=net biosdevname=1"),
+            vec!["net.ifnames=0".to_string(), "biosdevname=1".to_string()]
+        );
+    }
+
+    #[test]
+    fn cmdline_conflicts_last_wins() {
+        assert!(conflicting_arguments("net.ifnames=0 net.ifnames=1").is_empty());
+    }
+
+    #[test]
+    fn cmdline_naming_policy() {
+        assert_eq!(naming_policy_argument("ro net.ifnames.prefix=net"), None);
+        assert_eq!(
+            naming_policy_argument("net.ifnames.policy=smbios net.ifnames.policy=pci-order"),
+            Some("pci-order".to_string())
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn cmdline_check_prefix() {
+        assert_eq!(
+            check_prefix_argument("ro net.ifnames.prefix=net"),
+            PrefixCheck {
+                prefix: "net".to_string(),
+                ..Default::default()
+            }
+        );
+
+        let check = check_prefix_argument("ro quiet");
+        assert_eq!(check.prefix, "");
+        assert_eq!(check.errors.len(), 1);
+
+        let check = check_prefix_argument("net.ifnames.prefix=net_ net.ifnames.prefix=lan");
+        assert_eq!(check.prefix, "net");
+        assert_eq!(check.errors.len(), 1);
+        assert_eq!(check.warnings.len(), 1);
+
+        assert_eq!(
+            check_prefix_argument("net.ifnames.prefix=eth").errors.len(),
+            1
+        );
+        assert_eq!(
+            check_prefix_argument("net.ifnames.prefix=abcdefghijklmno")
+                .errors
+                .len(),
+            1
+        );
+        assert_eq!(
+            check_prefix_argument("net.ifnames.prefix=net net.ifnames=0").warnings,
+            vec!["net.ifnames=0 conflicts with net.ifnames.prefix=, other tools would rename NICs too"]
+        );
+    }
+
+    #[test]
+    fn cmdline_ifname() {
+        assert_eq!(
+            ifname_arguments(
+                "ro ifname=net5:00:11:22:33:44:aa ifname=bogus ifname=lan0:00-11-22-33-44-bb ifname=:00:11:22:33:44:cc"
+            ),
+            vec![
+                ("net5".to_string(), "00:11:22:33:44:AA".to_string()),
+                ("lan0".to_string(), "00:11:22:33:44:BB".to_string())
+            ]
+        );
+        assert!(ifname_arguments("ro ifname=net0:00:11:22").is_empty());
+    }
+}
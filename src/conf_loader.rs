@@ -0,0 +1,123 @@
+// SPDX-License-Identifier:  MIT
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ini::Ini;
+
+/// Configuration files in the order in which they are applied, later ones override earlier ones.
+/// Directories are given from the most important one (e.g. /etc before /usr/lib). The main file
+/// is taken from the first directory that has it, drop-ins (*.conf in <name>.d) from all of them
+/// follow ordered by their file name. Drop-in masks the one with the same file name in less
+/// important directories, the same way systemd treats its configuration.
+pub fn config_files<P: AsRef<Path>>(
+    dirs: &[P],
+    name: &str,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files: Vec<PathBuf> = dirs
+        .iter()
+        .map(|d| d.as_ref().join(name))
+        .find(|p| p.exists())
+        .into_iter()
+        .collect();
+    let mut dropins = BTreeMap::new();
+
+    for dir in dirs.iter().rev() {
+        let entries = match fs::read_dir(dir.as_ref().join(format!("{}.d", name))) {
+            Ok(e) => e,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(From::from(e)),
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+            let file_name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+
+            if file_name.ends_with(".conf") && !file_name.starts_with('.') {
+                dropins.insert(file_name, path);
+            }
+        }
+    }
+
+    files.extend(dropins.into_values());
+    Ok(files)
+}
+
+/// Merges the files into one, values from later files replace values of the same key.
+pub fn load_config_files(files: &[PathBuf]) -> Result<Ini, Box<dyn Error>> {
+    let mut merged = Ini::new();
+
+    for file in files {
+        let conf = Ini::load_from_file(file)
+            .map_err(|e| format!("Failed to parse {}: {}", file.display(), e))?;
+
+        for (section, properties) in conf.iter() {
+            for (k, v) in properties.iter() {
+                merged.with_section(section).set(k, v);
+            }
+        }
+    }
+
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_dropins() {
+        let root = std::env::temp_dir().join(format!("prefixdevname-conf-{}", std::process::id()));
+        let etc = root.join("etc");
+        let usr = root.join("usr");
+
+        fs::create_dir_all(etc.join("tool.conf.d")).unwrap();
+        fs::create_dir_all(usr.join("tool.conf.d")).unwrap();
+        fs::write(usr.join("tool.conf"), "[Naming]\nA=usr\nB=usr\n").unwrap();
+        fs::write(
+            usr.join("tool.conf.d/10-a.conf"),
+            "[Naming]\nB=usr-dropin\n",
+        )
+        .unwrap();
+        fs::write(usr.join("tool.conf.d/20-b.conf"), "[Naming]\nC=masked\n").unwrap();
+        fs::write(etc.join("tool.conf.d/20-b.conf"), "[Naming]\nC=etc\n").unwrap();
+        fs::write(etc.join("tool.conf.d/README"), "ignored").unwrap();
+
+        let files = config_files(&[&etc, &usr], "tool.conf").unwrap();
+        assert_eq!(
+            files,
+            vec![
+                usr.join("tool.conf"),
+                usr.join("tool.conf.d/10-a.conf"),
+                etc.join("tool.conf.d/20-b.conf")
+            ]
+        );
+
+        let conf = load_config_files(&files).unwrap();
+        let section = conf.section(Some("Naming")).unwrap();
+        assert_eq!(section.get("A"), Some("usr"));
+        assert_eq!(section.get("B"), Some("usr-dropin"));
+        assert_eq!(section.get("C"), Some("etc"));
+
+        // Main file in /etc replaces the one in /usr/lib
+        fs::write(etc.join("tool.conf"), "[Naming]\nA=etc\n").unwrap();
+        let files = config_files(&[&etc, &usr], "tool.conf").unwrap();
+        assert_eq!(files[0], etc.join("tool.conf"));
+        let conf = load_config_files(&files).unwrap();
+        assert_eq!(conf.get_from(Some("Naming"), "B"), Some("usr-dropin"));
+        assert_eq!(conf.get_from(Some("Naming"), "A"), Some("etc"));
+
+        assert!(config_files(&[root.join("missing")], "tool.conf")
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,116 +1,376 @@
 // SPDX-License-Identifier:  MIT
 
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
+use std::fmt;
 use std::fs;
 use std::io;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{BufRead, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::string::ToString;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use ini::Ini;
-use regex::Regex;
 
-use crate::hwaddr_from_event_device;
+use crate::audit::format_timestamp;
+use crate::backup::backup_link_file;
+#[cfg(not(feature = "minimal"))]
+use crate::drift::track_written;
+use crate::index_pool::{pool_for_device, IndexPool, Locality};
+use crate::legacy::{persistent_net_rule_names, RuleName, UDEV_RULES_DIRS};
+use crate::link_match::load_link_file;
+use crate::link_name::{parse_prefixed_name, LinkName};
+use crate::naming_policy::*;
+use crate::settings::{
+    OrphanPolicy, PrefixCollisionPolicy, Settings, DEFAULT_LINK_FILE_MODE, DEFAULT_LINK_TYPES,
+    DEFAULT_ORPHAN_REUSE_DELAY,
+};
+use crate::transaction::Transaction;
 use crate::util::*;
 
-static NET_SETUP_LINK_CONF_DIR: &str = "/etc/systemd/network/";
-static LINK_FILE_PREFIX: &str = "71-net-ifnames-prefix-";
+pub static NET_SETUP_LINK_CONF_DIR: &str = "/etc/systemd/network/";
+pub static LINK_FILE_PREFIX: &str = "71-net-ifnames-prefix-";
+
+/// First line of the link files generated by us, the files are recognized by it even when they
+/// were renamed or copied.
+pub static PROVENANCE_MARKER: &str = "# Generated by prefixdevname";
+static PROVENANCE_NOTICE: &str = "# Do not edit, this file is managed by prefixdevname";
+
+// Our link files recognized by the marker under other file names, by the name they assign
+static MARKED_LINK_FILES: Mutex<BTreeMap<String, PathBuf>> = Mutex::new(BTreeMap::new());
+
+// Comment added to the link file when its device is removed and RemovedDevicePolicy=mark
+static REMOVED_MARKER: &str = "# Device removed at ";
+
+// Number of names tried when the chosen name turns out to be taken by other naming source
+pub const MAX_ALLOCATION_ATTEMPTS: u32 = 5;
+
+// Interface names are limited by IFNAMSIZ (16) including the terminating NUL byte
+pub const IFNAME_MAX_LEN: usize = 15;
+
+/// Largest index that still makes a valid interface name when appended to the prefix.
+pub fn max_index_for_prefix(prefix: &str) -> u64 {
+    let digits = IFNAME_MAX_LEN.saturating_sub(prefix.len()) as u32;
+
+    10u64.saturating_pow(digits) - 1
+}
+
+#[derive(Debug)]
+pub struct IndexPoolExhausted {
+    pub prefix: String,
+    pub max_index: u64,
+}
+
+impl fmt::Display for IndexPoolExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "All names with prefix \"{}\" are taken, maximum index is {}",
+            self.prefix, self.max_index
+        )
+    }
+}
+
+impl Error for IndexPoolExhausted {}
+
+/// Property of the device used in the [Match] section of the link file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkMatch {
+    MACAddress,
+    PermanentMACAddress,
+    Path(String),
+    OriginalName(String),
+    // KEY=VALUE of the udev property
+    Property(String),
+}
+
+impl LinkMatch {
+    /// Name of the key used in the [Match] section
+    pub fn key(&self) -> &'static str {
+        match self {
+            LinkMatch::MACAddress => "MACAddress",
+            LinkMatch::PermanentMACAddress => "PermanentMACAddress",
+            LinkMatch::Path(_) => "Path",
+            LinkMatch::OriginalName(_) => "OriginalName",
+            LinkMatch::Property(_) => "Property",
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PrefixedLink {
-    pub name: String,
+    pub name: LinkName,
     pub index: u64,
     pub hwaddr: String,
+    pub link_match: LinkMatch,
+    // Driver= of the [Match] section, set when MatchDriver= is enabled
+    pub driver: Option<String>,
 }
 
 impl PrefixedLink {
+    // Links of the event device are built from the MAC address chosen by the policies
+    #[cfg(test)]
     pub fn new<T: ToString>(link_name: &T) -> Result<PrefixedLink, Box<dyn Error>> {
-        let name = link_name.to_string();
-        PrefixedLink::link_name_sane(&name)?;
-
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"([[:alpha:]]+)\d+").unwrap();
-        }
+        let name = LinkName::new(&link_name.to_string())?;
 
-        let prefix = match RE.captures(&name) {
-            Some(c) => c[1].to_string(),
-            None => "".to_string(),
+        let config = PrefixedLink {
+            index: name.index(),
+            name,
+            hwaddr: crate::hwaddr_from_event_device()?,
+            link_match: LinkMatch::MACAddress,
+            driver: None,
         };
 
-        let i = name.trim_start_matches(&prefix).parse::<u64>()?;
+        Ok(config)
+    }
+
+    pub fn new_with_hwaddr<T: ToString>(
+        link_name: &T,
+        hwaddr: &T,
+    ) -> Result<PrefixedLink, Box<dyn Error>> {
+        let addr = hwaddr_normalize(hwaddr)?;
+        let name = LinkName::new(&link_name.to_string())?;
 
         let config = PrefixedLink {
+            index: name.index(),
             name,
-            index: i,
-            hwaddr: hwaddr_from_event_device()?,
+            hwaddr: addr,
+            link_match: LinkMatch::MACAddress,
+            driver: None,
         };
 
         Ok(config)
     }
 
-    pub fn new_with_hwaddr<T: ToString>(
+    pub fn new_with_permanent_hwaddr<T: ToString>(
         link_name: &T,
         hwaddr: &T,
     ) -> Result<PrefixedLink, Box<dyn Error>> {
-        let addr = hwaddr_normalize(hwaddr)?;
-        let name = link_name.to_string();
-        PrefixedLink::link_name_sane(link_name)?;
+        let mut config = PrefixedLink::new_with_hwaddr(link_name, hwaddr)?;
 
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"([[:alpha:]]+)\d+").unwrap();
-        }
+        config.link_match = LinkMatch::PermanentMACAddress;
+        Ok(config)
+    }
 
-        let prefix = match RE.captures(&name) {
-            Some(c) => c[1].to_string(),
-            None => "".to_string(),
-        };
-        let i = name.trim_start_matches(&prefix).parse::<u64>()?;
+    /// Link matched by the persistent device path (ID_PATH) instead of the MAC address. Such links
+    /// don't carry any MAC address, hence hwaddr is left empty.
+    pub fn new_with_path<T: ToString>(
+        link_name: &T,
+        path: &T,
+    ) -> Result<PrefixedLink, Box<dyn Error>> {
+        let name = LinkName::new(&link_name.to_string())?;
+        let path = path.to_string();
+
+        if path.is_empty() {
+            return Err(From::from("Device path can't be empty string"));
+        }
 
         let config = PrefixedLink {
-            name: link_name.to_string(),
-            index: i,
-            hwaddr: addr,
+            index: name.index(),
+            name,
+            hwaddr: String::new(),
+            link_match: LinkMatch::Path(path),
+            driver: None,
         };
 
         Ok(config)
     }
 
-    pub fn link_name_sane<T: ToString>(link_name: &T) -> Result<(), Box<dyn Error>> {
-        let name = link_name.to_string();
+    /// Virtual devices created by the administrator (bonds, bridges, ...) don't have stable MAC
+    /// address, hence they are matched by the name given to them by the kernel or by the tool that
+    /// created them.
+    pub fn new_with_original_name<T: ToString>(
+        link_name: &T,
+        original_name: &T,
+    ) -> Result<PrefixedLink, Box<dyn Error>> {
+        let original_name = original_name.to_string();
 
-        if name.is_empty() {
-            return Err(From::from("Link name can't be empty string"));
+        if original_name.is_empty() {
+            return Err(From::from("Original device name can't be empty string"));
         }
 
-        if name.as_bytes().len() > 16 {
-            return Err(From::from("Link name too long"));
+        let mut config = PrefixedLink::new_with_path(&link_name.to_string(), &original_name)?;
+
+        config.link_match = LinkMatch::OriginalName(original_name);
+        Ok(config)
+    }
+
+    /// Match also on the driver, the MAC address alone may be reused by devices of other class,
+    /// e.g. USB gadgets.
+    pub fn with_driver(mut self, driver: Option<String>) -> PrefixedLink {
+        self.driver = driver.filter(|d| !d.is_empty());
+        self
+    }
+
+    /// Link matched by the value of the udev property, e.g. ID_SERIAL, for devices whose MAC
+    /// address and path are both unreliable. The property is given as KEY=VALUE.
+    pub fn new_with_property<T: ToString>(
+        link_name: &T,
+        property: &T,
+    ) -> Result<PrefixedLink, Box<dyn Error>> {
+        let property = property.to_string();
+
+        if parse_property_match(&property).is_none() {
+            return Err(From::from(format!(
+                "Invalid property match \"{}\", expected KEY=VALUE",
+                property
+            )));
         }
 
-        Ok(())
+        let mut config = PrefixedLink::new_with_path(&link_name.to_string(), &property)?;
+
+        config.link_match = LinkMatch::Property(property);
+        Ok(config)
+    }
+
+    pub fn match_value(&self) -> &str {
+        match &self.link_match {
+            LinkMatch::MACAddress | LinkMatch::PermanentMACAddress => &self.hwaddr,
+            LinkMatch::Path(p) | LinkMatch::OriginalName(p) | LinkMatch::Property(p) => p,
+        }
     }
 
     pub fn link_file_path(&self) -> PathBuf {
-        let mut path = PathBuf::from(NET_SETUP_LINK_CONF_DIR);
+        link_file_path_for_name(&self.name)
+    }
 
-        path.push(LINK_FILE_PREFIX.to_string() + &self.name + ".link");
-        path
+    pub fn link_file_path_in(&self, dir: &Path) -> PathBuf {
+        link_file_path_for_name_in(dir, &self.name)
     }
 
-    pub fn write_link_file(&self) -> Result<(), Box<dyn Error>> {
-        fs::create_dir_all(NET_SETUP_LINK_CONF_DIR)?;
+    /// Content of the link file, extra keys are appended to the [Link] section.
+    pub fn link_file_content(&self, extra_keys: &[(String, String)]) -> String {
+        let mut content = format!(
+            "[Match]\n{}={}\n",
+            self.link_match.key(),
+            self.match_value()
+        );
 
-        let path = self.link_file_path();
-        let mut link_file = fs::File::create(path)?;
+        if let Some(d) = &self.driver {
+            content.push_str(&format!("Driver={}\n", d));
+        }
 
-        write!(
-            &mut link_file,
-            "[Match]\nMACAddress={}\n\n[Link]\nName={}\n",
-            self.hwaddr, self.name
-        )?;
+        content.push_str(&format!("\n[Link]\nName={}\n", self.name));
 
-        Ok(())
+        for (k, v) in extra_keys {
+            content.push_str(&format!("{}={}\n", k, v));
+        }
+
+        content
+    }
+
+    /// Whether the link file content matches the same device as the link, either by the same
+    /// match or by the same MAC address.
+    pub fn same_device_as(&self, content: &str) -> bool {
+        let name = self.name.to_string();
+        let previous = match Ini::load_from_str(content)
+            .ok()
+            .and_then(|c| c.section(Some("Match")).cloned())
+            .and_then(|s| link_from_match_section(&name, &s).ok())
+        {
+            Some(l) => l,
+            None => return false,
+        };
+
+        (previous.link_match == self.link_match
+            && previous
+                .match_value()
+                .eq_ignore_ascii_case(self.match_value()))
+            || (!self.hwaddr.is_empty() && previous.hwaddr.eq_ignore_ascii_case(&self.hwaddr))
+    }
+
+    /// Content replacing the existing link file of the name. Keys and comments added by the
+    /// administrator are kept only when the file matches the same device, the file of other device
+    /// (orphaned index, replaced NIC) is written from scratch, its Driver= or [SR-IOV] keys would
+    /// keep the new device from matching.
+    pub fn rewritten_content(&self, existing: &str, extra_keys: &[(String, String)]) -> String {
+        if self.same_device_as(existing) {
+            merge_link_file(existing, self, extra_keys)
+        } else {
+            self.link_file_content(extra_keys)
+        }
+    }
+
+    /// Replaces the link file previously generated for the name, see rewritten_content(). Link
+    /// file is created when it doesn't exist.
+    pub fn rewrite_link_file(&self, options: &LinkFileOptions) -> Result<(), Box<dyn Error>> {
+        let path = self.link_file_path_in(&options.dir);
+        let content = match fs::read_to_string(&path) {
+            Ok(c) => self.rewritten_content(&c, &options.extra_keys),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.link_file_content(&options.extra_keys)
+            }
+            Err(e) => return Err(From::from(e)),
+        };
+        let content = with_provenance(&content);
+        let tmp_path = temp_path_for(&path);
+
+        fs::create_dir_all(&options.dir)?;
+
+        let written = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(options.mode)
+            .open(&tmp_path)
+            .map_err(From::from)
+            .and_then(|mut f| -> Result<(), Box<dyn Error>> {
+                f.write_all(content.as_bytes())?;
+                set_file_attributes(&f, options.mode, 0, 0)?;
+                backup_link_file(&path);
+                fs::rename(&tmp_path, &path)?;
+                Ok(())
+            });
+
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+
+        #[cfg(not(feature = "minimal"))]
+        if written.is_ok() && options.dir == Path::new(NET_SETUP_LINK_CONF_DIR) {
+            track_written(&path);
+        }
+
+        written
+    }
+
+    pub fn write_link_file_with(&self, options: &LinkFileOptions) -> Result<(), Box<dyn Error>> {
+        let _span = tracing::debug_span!("write", name = %self.name).entered();
+
+        fs::create_dir_all(&options.dir)?;
+
+        // Never overwrite link file of other link, the name may have been taken in the meantime.
+        // Complete file is linked into place, readers not holding the lock never see it partially
+        // written.
+        let path = self.link_file_path_in(&options.dir);
+        let tmp_path = temp_path_for(&path);
+        let written = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(options.mode)
+            .open(&tmp_path)
+            .map_err(From::from)
+            .and_then(|mut f| -> Result<(), Box<dyn Error>> {
+                f.write_all(
+                    with_provenance(&self.link_file_content(&options.extra_keys)).as_bytes(),
+                )?;
+                set_file_attributes(&f, options.mode, 0, 0)?;
+                fs::hard_link(&tmp_path, &path)?;
+                Ok(())
+            });
+
+        let _ = fs::remove_file(&tmp_path);
+
+        #[cfg(not(feature = "minimal"))]
+        if written.is_ok() && options.dir == Path::new(NET_SETUP_LINK_CONF_DIR) {
+            track_written(&path);
+        }
+
+        written
     }
 }
 
@@ -126,217 +386,2564 @@ impl PartialOrd for PrefixedLink {
     }
 }
 
-pub struct NetSetupLinkConfig {
-    config: HashMap<String, PrefixedLink>,
-    links: Vec<PrefixedLink>,
-    ifname_prefix: String,
-}
-
-impl NetSetupLinkConfig {
-    pub fn new_with_prefix<T: ToString>(prefix: &T) -> Self {
-        NetSetupLinkConfig {
-            config: HashMap::new(),
-            links: Vec::new(),
-            ifname_prefix: prefix.to_string(),
+/// First index starting at `start` that was claimed successfully, indexes for which `taken` is
+/// true aren't tried at all. Claim must be atomic (e.g. exclusive creation of the file), then
+/// concurrent callers never end up with the same index and each collision costs just one more
+/// attempt.
+pub fn claim_index<T, C>(
+    start: u64,
+    max_index: u64,
+    taken: T,
+    mut claim: C,
+) -> Result<Option<u64>, Box<dyn Error>>
+where
+    T: Fn(u64) -> bool,
+    C: FnMut(u64) -> Result<bool, Box<dyn Error>>,
+{
+    for index in start..=max_index {
+        if !taken(index) && claim(index)? {
+            return Ok(Some(index));
         }
     }
 
-    pub fn load(&mut self) -> Result<(), Box<dyn Error>> {
-        self.enumerate_links_from_udev()?;
-        self.enumerate_links_from_files()?;
+    Ok(None)
+}
 
-        // Most links have link file present and are currently known to udev.
-        // Hence enumeration from both sources created duplicate entries in the links vector.
-        self.links.sort();
-        self.links.dedup();
+// Spawning threads doesn't pay off for just a few link files
+const MIN_LINK_FILES_PER_THREAD: usize = 64;
+
+fn parse_link_files_with(paths: &[PathBuf], threads: usize) -> Vec<Result<Ini, Box<dyn Error>>> {
+    let parse = |chunk: &[PathBuf]| -> Vec<Result<Ini, String>> {
+        chunk
+            .iter()
+            .map(|p| load_link_file(p).map_err(|e| e.to_string()))
+            .collect()
+    };
+
+    let parsed = if threads <= 1 {
+        parse(paths)
+    } else {
+        let chunk_size = paths.len().div_ceil(threads);
+
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = paths
+                .chunks(chunk_size.max(1))
+                .map(|c| scope.spawn(move || parse(c)))
+                .collect();
+
+            // Results are merged in the order of the paths
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect()
+        })
+    };
+
+    parsed.into_iter().map(|r| r.map_err(From::from)).collect()
+}
 
-        debug!("Links: {:?}", self.links);
+/// Parses link files together with their drop-ins in parallel on hosts with many of them, results
+/// are in the order of paths.
+pub fn parse_link_files(paths: &[PathBuf]) -> Vec<Result<Ini, Box<dyn Error>>> {
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
 
-        Ok(())
-    }
+    parse_link_files_with(paths, cpus.min(paths.len() / MIN_LINK_FILES_PER_THREAD))
+}
 
-    pub fn for_hwaddr<T: ToString>(&self, mac: &T) -> Option<PrefixedLink> {
-        if let Some(c) = self.config.get(&mac.to_string()) {
-            return Some(c.clone());
+/// Path of the link file generated by us for the interface name. File recognized by the provenance
+/// marker is used when there is no file of the expected name.
+pub fn link_file_path_for_name(name: &LinkName) -> PathBuf {
+    link_file_path_for_name_in(Path::new(NET_SETUP_LINK_CONF_DIR), name)
+}
+
+pub fn link_file_path_for_name_in(dir: &Path, name: &LinkName) -> PathBuf {
+    let mut path = dir.to_path_buf();
+
+    path.push(LINK_FILE_PREFIX.to_string() + name.as_str() + ".link");
+
+    if !path.exists() {
+        if let Some(p) = MARKED_LINK_FILES.lock().unwrap().get(name.as_str()) {
+            return p.clone();
         }
-        None
     }
 
-    pub fn next_link_name(&self) -> Result<String, Box<dyn Error>> {
-        if self.links.is_empty() {
-            return Ok(format!("{}{}", self.ifname_prefix, "0"));
-        }
+    path
+}
 
-        let last = self
-            .links
-            .last()
-            .ok_or("Failed to obtain last vector element")?;
-        let last_index = last
-            .name
-            .trim_start_matches(&self.ifname_prefix)
-            .parse::<u64>()?;
+/// Header of the generated link files, written again with every change of the file.
+pub fn provenance_header(timestamp: u64) -> String {
+    format!(
+        "{} {} at {}\n{}\n",
+        PROVENANCE_MARKER,
+        env!("CARGO_PKG_VERSION"),
+        format_timestamp(timestamp),
+        PROVENANCE_NOTICE
+    )
+}
 
-        Ok(format!(
-            "{}{}",
-            self.ifname_prefix,
-            &(last_index + 1).to_string()
-        ))
+/// Content of the link file without the provenance header.
+pub fn strip_provenance(content: &str) -> &str {
+    let mut rest = content;
+
+    while let Some(line) = rest.lines().next() {
+        if !line.starts_with(PROVENANCE_MARKER) && line != PROVENANCE_NOTICE {
+            break;
+        }
+        rest = rest[line.len()..].strip_prefix('\n').unwrap_or("");
     }
 
-    fn match_ethernet_links(
-        udev_enumerate: &mut libudev::Enumerator,
-    ) -> Result<(), Box<dyn Error>> {
-        udev_enumerate.match_subsystem("net")?;
-        udev_enumerate.match_attribute("type", "1")?;
+    rest
+}
 
-        Ok(())
-    }
+/// Content with the provenance header of the current time.
+pub fn with_provenance(content: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
 
-    fn enumerate_links_from_udev(&mut self) -> Result<(), Box<dyn Error>> {
-        let udev = libudev::Context::new()?;
-        let mut enumerate = libudev::Enumerator::new(&udev)?;
-        let mut links = Vec::new();
+    provenance_header(now) + strip_provenance(content)
+}
 
-        NetSetupLinkConfig::match_ethernet_links(&mut enumerate)?;
+pub fn file_has_provenance_marker(path: &Path) -> bool {
+    let mut line = String::new();
 
-        for device in enumerate.scan_devices()? {
-            let name = device
-                .sysname()
-                .unwrap()
-                .to_str()
-                .ok_or("Failed to convert from ffi::OsStr to &str");
+    fs::File::open(path)
+        .and_then(|f| io::BufReader::new(f).read_line(&mut line))
+        .is_ok_and(|_| line.starts_with(PROVENANCE_MARKER))
+}
 
-            if !name?.to_string().starts_with(&self.ifname_prefix) {
-                continue;
-            }
+/// Name assigned by the link file and its [Match] section.
+fn link_file_sections(conf: &Ini) -> Result<(&str, &ini::Properties), Box<dyn Error>> {
+    let match_section = conf
+        .section(Some("Match".to_owned()))
+        .ok_or("Failed to parse link file, [Match] section not found")?;
+    let link_section = conf
+        .section(Some("Link".to_owned()))
+        .ok_or("Failed to parse link file, [Link] section not found")?;
 
-            // XXX: Move this to its own function and add more devtypes
-            match device.devtype() {
-                Some(t) => match t.to_str() {
-                    Some("vlan") | Some("bond") | Some("bridge") => continue,
-                    _ => {}
-                },
-                None => {}
-            }
+    let name = link_section
+        .get("Name")
+        .ok_or("Failed to parse link file, \"Name\" option not present in the [Link] section")?;
 
-            let hwaddr = device
-                .attribute_value("address")
-                .ok_or("Failed to read value of the 'address' sysfs attribute")?
-                .to_str()
-                .ok_or("Failed to convert from ffi::OsStr to &str");
-            links.push(PrefixedLink::new_with_hwaddr(&name?, &hwaddr?)?);
-        }
+    Ok((name, match_section))
+}
 
-        self.links = links;
+pub fn link_from_match_section(
+    name: &str,
+    match_section: &ini::Properties,
+) -> Result<PrefixedLink, Box<dyn Error>> {
+    let driver = match_section.get("Driver").map(|d| d.to_string());
+    let link = if let Some(hwaddr) = match_section.get("MACAddress") {
+        PrefixedLink::new_with_hwaddr(&name, &hwaddr)
+    } else if let Some(hwaddr) = match_section.get("PermanentMACAddress") {
+        PrefixedLink::new_with_permanent_hwaddr(&name, &hwaddr)
+    } else if let Some(path) = match_section.get("Path") {
+        PrefixedLink::new_with_path(&name, &path)
+    } else if let Some(original_name) = match_section.get("OriginalName") {
+        PrefixedLink::new_with_original_name(&name, &original_name)
+    } else if let Some(property) = match_section.get("Property") {
+        PrefixedLink::new_with_property(&name, &property)
+    } else {
+        Err(From::from("Failed to parse link file, none of \"MACAddress\", \"PermanentMACAddress\", \"Path\", \"OriginalName\" or \"Property\" options present in the [Match] section"))
+    };
+
+    link.map(|l| l.with_driver(driver))
+}
 
-        Ok(())
+/// Whether the file name is one of the link files generated by us.
+pub fn our_link_file_name(name: &str) -> bool {
+    name.ends_with(".link") && name.starts_with(LINK_FILE_PREFIX)
+}
+
+// Keys of the [Match] section we generate, exactly one of them is present in our link files
+const LINK_MATCH_KEYS: [&str; 5] = [
+    "MACAddress",
+    "PermanentMACAddress",
+    "Path",
+    "OriginalName",
+    "Property",
+];
+
+/// Splits KEY=VALUE of the Property= match we generate. Only single property without globs is
+/// generated, hence the value can't contain whitespace.
+pub fn parse_property_match(property: &str) -> Option<(&str, &str)> {
+    property
+        .split_once('=')
+        .filter(|(k, v)| !k.is_empty() && !v.is_empty() && !property.contains(char::is_whitespace))
+}
+
+fn link_file_key(line: &str) -> Option<&str> {
+    let line = line.trim();
+
+    if line.starts_with('#') || line.starts_with(';') {
+        return None;
     }
 
-    fn enumerate_links_from_files(&mut self) -> Result<(), Box<dyn Error>> {
-        let mut link_files = Vec::new();
+    line.split_once('=').map(|(k, _)| k.trim())
+}
 
-        let files = match fs::read_dir(NET_SETUP_LINK_CONF_DIR) {
-            Ok(d) => d,
-            Err(e) => match e.kind() {
-                io::ErrorKind::NotFound => return Ok(()),
-                _ => return Err(From::from(e)),
-            },
-        };
+/// Updates content of the existing link file for the link. Match and Name= are set to the values
+/// of the link, extra keys are added unless the administrator set them already. Comments, unknown
+/// sections and keys are kept as they are.
+pub fn merge_link_file(
+    content: &str,
+    link: &PrefixedLink,
+    extra_keys: &[(String, String)],
+) -> String {
+    let mut match_lines = vec![format!("{}={}", link.link_match.key(), link.match_value())];
+    if let Some(d) = &link.driver {
+        match_lines.push(format!("Driver={}", d));
+    }
+    let name_line = format!("Name={}", link.name);
+    let mut sections: Vec<(Option<String>, Vec<String>)> = vec![(None, Vec::new())];
 
-        for f in files {
-            let entry = match f {
-                Ok(e) => e,
-                Err(_) => continue,
-            };
+    for line in content.lines() {
+        let trimmed = line.trim();
 
-            let path = entry.path();
-            {
-                let name = path
-                    .file_name()
-                    .ok_or("Failed to obtain filename")?
-                    .to_str()
-                    .ok_or("Failed to convert OsStr to String")?;
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            sections.push((Some(trimmed.to_string()), vec![line.to_string()]));
+        } else if let Some((_, lines)) = sections.last_mut() {
+            lines.push(line.to_string());
+        }
+    }
 
-                if !name.starts_with(LINK_FILE_PREFIX) || !name.ends_with(".link") {
-                    continue;
+    // Only the first [Match] and [Link] sections are updated
+    let mut seen_match = false;
+    let mut seen_link = false;
+
+    for (name, lines) in sections.iter_mut() {
+        match name.as_deref() {
+            Some("[Match]") if !seen_match => {
+                seen_match = true;
+                // Driver= set by the administrator is kept unless we match on it ourselves
+                lines.retain(|l| {
+                    !link_file_key(l).is_some_and(|k| {
+                        LINK_MATCH_KEYS.contains(&k) || (k == "Driver" && link.driver.is_some())
+                    })
+                });
+                lines.splice(1..1, match_lines.iter().cloned());
+            }
+            Some("[Link]") if !seen_link => {
+                seen_link = true;
+
+                let mut added = Vec::new();
+                for (k, v) in extra_keys {
+                    if !lines.iter().any(|l| link_file_key(l) == Some(k)) {
+                        added.push(format!("{}={}", k, v));
+                    }
+                }
+
+                // Name= is updated in place, any duplicates are dropped
+                match lines.iter().position(|l| link_file_key(l) == Some("Name")) {
+                    Some(i) => {
+                        lines[i] = name_line.clone();
+                        *lines = lines
+                            .iter()
+                            .enumerate()
+                            .filter(|(j, l)| *j == i || link_file_key(l) != Some("Name"))
+                            .map(|(_, l)| l.clone())
+                            .collect();
+                    }
+                    None => added.insert(0, name_line.clone()),
                 }
+
+                // New keys go after the last key of the section, trailing empty lines stay last
+                let at = lines
+                    .iter()
+                    .rposition(|l| !l.trim().is_empty())
+                    .map_or(1, |i| i + 1);
+                lines.splice(at..at, added);
             }
+            _ => {}
+        }
+    }
 
-            link_files.push(path);
+    let mut merged: String = sections
+        .iter()
+        .flat_map(|(_, lines)| lines.iter())
+        .map(|l| format!("{}\n", l))
+        .collect();
+
+    if !seen_match {
+        merged.push_str("\n[Match]\n");
+        for l in &match_lines {
+            merged.push_str(&format!("{}\n", l));
         }
+    }
 
-        for l in &link_files {
-            let conf = Ini::load_from_file(l)?;
-            let match_section = conf
-                .section(Some("Match".to_owned()))
-                .ok_or("Failed to parse link file, [Match] section not found")?;
-            let link_section = conf
-                .section(Some("Link".to_owned()))
-                .ok_or("Failed to parse link file, [Link] section not found")?;
+    if !seen_link {
+        merged.push_str(&format!("\n[Link]\n{}\n", name_line));
+        for (k, v) in extra_keys {
+            merged.push_str(&format!("{}={}\n", k, v));
+        }
+    }
 
-            let mac = match_section.get("MACAddress").ok_or("Failed to parse link file, \"MACAddress\"' option not present in the [Link] section")?;
-            let name = link_section.get("Name").ok_or(
-                "Failed to parse link file, \"Name\" option not present in the [Link] section",
-            )?;
+    merged
+}
 
-            if !name.starts_with(&self.ifname_prefix) {
-                warn!("Unexpected link name");
-                continue;
-            }
+/// How generated link files are written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkFileOptions {
+    pub extra_keys: Vec<(String, String)>,
+    pub mode: u32,
+    pub dir: PathBuf,
+}
 
-            let hwaddr = mac;
+impl Default for LinkFileOptions {
+    fn default() -> Self {
+        LinkFileOptions {
+            extra_keys: Vec::new(),
+            mode: DEFAULT_LINK_FILE_MODE,
+            dir: PathBuf::from(NET_SETUP_LINK_CONF_DIR),
+        }
+    }
+}
 
-            self.config
-                .insert(hwaddr.to_string(), PrefixedLink::new(&name)?);
-            self.links
-                .push(PrefixedLink::new_with_hwaddr(&name, &hwaddr)?);
+impl LinkFileOptions {
+    pub fn from_settings(settings: &Settings) -> Self {
+        LinkFileOptions {
+            extra_keys: settings.link_keys.clone(),
+            mode: settings.link_file_mode,
+            ..Default::default()
         }
-        Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    enum UMockdevTestbed {}
-    #[link(name = "umockdev")]
-    extern "C" {
-        fn umockdev_testbed_new() -> *mut UMockdevTestbed;
-        fn umockdev_testbed_add_from_string(
-            testbed: *mut UMockdevTestbed,
-            device_description: *mut c_char,
-            err: *mut *mut u8,
-        );
+/// Mode is set explicitly, files created under restrictive umask would not be readable by
+/// unprivileged tools otherwise. Owner is changed only when it differs, which requires root.
+pub fn set_file_attributes(
+    file: &fs::File,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+) -> Result<(), Box<dyn Error>> {
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+
+    let metadata = file.metadata()?;
+    if metadata.uid() != uid || metadata.gid() != gid {
+        std::os::unix::fs::fchown(file, Some(uid), Some(gid))?;
     }
 
-    use std::env;
-    use std::ffi::CString;
-    use std::os::raw::c_char;
-    use std::path::Path;
+    Ok(())
+}
 
-    use super::*;
+/// Content of the link file with the comment recording the removal of its device, the marker
+/// left by earlier removal is replaced. Comments are ignored by udev, hence the link file still
+/// applies when the device comes back.
+pub fn mark_removed(content: &str, timestamp: &str) -> String {
+    let mut marked: String = content
+        .lines()
+        .filter(|l| !l.starts_with(REMOVED_MARKER))
+        .map(|l| format!("{}\n", l))
+        .collect();
+
+    marked.push_str(&format!("{}{}\n", REMOVED_MARKER, timestamp));
+    marked
+}
 
-    #[test]
-    fn prefixed_link_new() {
-        let config = PrefixedLink::new_with_hwaddr(&"net0", &"ff:ff:ff:ff:ff:ff");
-        assert!(config.is_ok());
+pub fn mark_link_file_removed(path: &Path, timestamp: &str) -> Result<(), Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    let metadata = fs::metadata(path)?;
+    let tmp_path = temp_path_for(path);
+
+    // Marked link file keeps the mode and the owner of the original one
+    let written = fs::File::create(&tmp_path).map_err(From::from).and_then(
+        |mut f| -> Result<(), Box<dyn Error>> {
+            f.write_all(mark_removed(&content, timestamp).as_bytes())?;
+            set_file_attributes(&f, metadata.mode() & 0o7777, metadata.uid(), metadata.gid())?;
+            fs::rename(&tmp_path, path)?;
+            Ok(())
+        },
+    );
+
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp_path);
     }
 
-    #[test]
-    fn prefixed_link_name_empty() {
-        let config = PrefixedLink::new_with_hwaddr(&"", &"ff:ff:ff:ff:ff:ff");
-        assert!(config.is_err());
+    #[cfg(not(feature = "minimal"))]
+    if written.is_ok() {
+        track_written(path);
     }
 
-    #[test]
-    fn prefixed_link_name_long() {
-        let config =
-            PrefixedLink::new_with_hwaddr(&"neeeeeeeeeeeeeeeeeeeeeeeeeet0", &"ff:ff:ff:ff:ff:ff");
-        assert!(config.is_err());
+    written
+}
+
+/// Network device present in the system, used to find link files without matching device.
+#[derive(Debug, Clone, Default)]
+pub struct PresentDevice {
+    pub name: String,
+    pub hwaddrs: Vec<String>,
+    pub permanent_hwaddr: Option<String>,
+    pub path: Option<String>,
+    pub properties: HashMap<String, String>,
+}
+
+impl PresentDevice {
+    pub fn from_device(device: &libudev::Device, with_permanent: bool) -> PresentDevice {
+        let name = device
+            .sysname()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mut hwaddrs: Vec<String> = device
+            .attribute_value("address")
+            .and_then(|a| a.to_str())
+            .and_then(|a| hwaddr_normalize(&a).ok())
+            .into_iter()
+            .collect();
+
+        if device_enslaved(device) {
+            hwaddrs.extend(hwaddr_enslaved(device).ok());
+        }
+
+        PresentDevice {
+            permanent_hwaddr: if with_permanent {
+                hwaddr_permanent(&name).ok()
+            } else {
+                None
+            },
+            path: device
+                .property_value("ID_PATH")
+                .map(|p| p.to_string_lossy().to_string()),
+            properties: device
+                .properties()
+                .map(|p| {
+                    (
+                        p.name().to_string_lossy().to_string(),
+                        p.value().to_string_lossy().to_string(),
+                    )
+                })
+                .collect(),
+            hwaddrs,
+            name,
+        }
     }
 
-    #[test]
-    fn prefixed_link_name_invalid() {
-        let config = PrefixedLink::new_with_hwaddr(&"1net0", &"ff:ff:ff:ff:ff:ff");
+    /// Device read from sysfs when udev isn't available, ID_PATH is unknown in such case.
+    pub fn from_net_device(device: &NetDevice, with_permanent: bool) -> PresentDevice {
+        let mut hwaddrs: Vec<String> = device
+            .hwaddr
+            .as_ref()
+            .and_then(|a| hwaddr_normalize(a).ok())
+            .into_iter()
+            .collect();
+
+        if net_device_enslaved(device) {
+            hwaddrs.extend(hwaddr_enslaved_ifname(&device.name).ok());
+        }
+
+        PresentDevice {
+            permanent_hwaddr: if with_permanent {
+                hwaddr_permanent(&device.name).ok()
+            } else {
+                None
+            },
+            path: None,
+            properties: HashMap::new(),
+            hwaddrs,
+            name: device.name.clone(),
+        }
+    }
+}
+
+/// Present device the [Match] section of the link file applies to.
+pub fn link_matched_device<'a>(
+    link: &PrefixedLink,
+    present: &'a [PresentDevice],
+) -> Option<&'a PresentDevice> {
+    present.iter().find(|d| match &link.link_match {
+        LinkMatch::MACAddress => d.hwaddrs.contains(&link.hwaddr),
+        LinkMatch::PermanentMACAddress => d.permanent_hwaddr.as_ref() == Some(&link.hwaddr),
+        LinkMatch::Path(p) => d.path.as_ref() == Some(p),
+        LinkMatch::OriginalName(n) => &d.name == n,
+        LinkMatch::Property(p) => property_matches(p, &d.properties),
+    })
+}
+
+/// Whether the device has the udev property of the Property= match.
+pub fn property_matches(property: &str, properties: &HashMap<String, String>) -> bool {
+    parse_property_match(property).is_some_and(|(k, v)| properties.get(k).is_some_and(|p| p == v))
+}
+
+/// Link is orphaned when no present device matches it and no present device uses its name.
+pub fn link_orphaned(link: &PrefixedLink, present: &[PresentDevice]) -> bool {
+    !present.iter().any(|d| d.name == link.name) && link_matched_device(link, present).is_none()
+}
+
+pub struct NetSetupLinkConfig {
+    config: HashMap<String, PrefixedLink>,
+    links: Vec<PrefixedLink>,
+    ifname_prefix: String,
+    max_index: u64,
+    link_types: Vec<u32>,
+    collision_policy: PrefixCollisionPolicy,
+    skipped_indexes: BTreeSet<u64>,
+    malformed: Vec<(PathBuf, String)>,
+    file_links: Vec<PrefixedLink>,
+    orphan_policy: OrphanPolicy,
+    orphan_reuse_delay: Duration,
+    orphans: Vec<PrefixedLink>,
+    strict: bool,
+    min_index: u64,
+    naming_policy: Box<dyn NamingPolicy>,
+    link_file_options: LinkFileOptions,
+    cmdline_links: Vec<PrefixedLink>,
+    index_pools: Vec<IndexPool>,
+    offline: bool,
+    sibling_prefixes: Vec<String>,
+}
+
+impl NetSetupLinkConfig {
+    pub fn new_with_prefix<T: ToString>(prefix: &T) -> Self {
+        let ifname_prefix = prefix.to_string();
+
+        NetSetupLinkConfig {
+            config: HashMap::new(),
+            links: Vec::new(),
+            max_index: max_index_for_prefix(&ifname_prefix),
+            ifname_prefix,
+            link_types: DEFAULT_LINK_TYPES.to_vec(),
+            collision_policy: PrefixCollisionPolicy::Warn,
+            skipped_indexes: BTreeSet::new(),
+            malformed: Vec::new(),
+            file_links: Vec::new(),
+            orphan_policy: OrphanPolicy::Occupied,
+            orphan_reuse_delay: DEFAULT_ORPHAN_REUSE_DELAY,
+            orphans: Vec::new(),
+            strict: false,
+            min_index: 0,
+            naming_policy: Box::new(Sequential),
+            link_file_options: LinkFileOptions::default(),
+            cmdline_links: Vec::new(),
+            index_pools: Vec::new(),
+            offline: false,
+            sibling_prefixes: Vec::new(),
+        }
+    }
+
+    /// Limits the index pool. Limit can't exceed the index derived from the maximum length of
+    /// the interface name.
+    pub fn set_max_index(&mut self, max_index: u64) {
+        let limit = max_index_for_prefix(&self.ifname_prefix);
+
+        if max_index > limit {
+            warn!(
+                "Maximum index {} is too large for prefix \"{}\", using {}",
+                max_index, self.ifname_prefix, limit
+            );
+        }
+
+        self.max_index = max_index.min(limit);
+    }
+
+    /// Indexes below the minimum are reserved (e.g. for devices labeled by the firmware), names
+    /// are allocated sequentially starting at the minimum.
+    pub fn set_min_index(&mut self, min_index: u64) {
+        self.min_index = min_index;
+    }
+
+    /// Offline config doesn't look at the interfaces of the host, e.g. when naming of other
+    /// machine is simulated.
+    #[cfg(any(test, not(feature = "minimal")))]
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    fn interface_present(&self, name: &str) -> bool {
+        !self.offline && interface_exists(name)
+    }
+
+    pub fn set_naming_policy(&mut self, policy: Box<dyn NamingPolicy>) {
+        self.set_min_index(policy.reserved_indexes());
+        self.naming_policy = policy;
+    }
+
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        if let Some(m) = settings.max_index {
+            self.set_max_index(m);
+        }
+
+        self.set_link_types(&settings.link_types);
+        self.collision_policy = settings.prefix_collision_policy;
+        self.orphan_policy = settings.orphan_policy;
+        self.orphan_reuse_delay = settings.orphan_reuse_delay;
+        self.strict = settings.strict;
+        self.link_file_options = LinkFileOptions::from_settings(settings);
+        self.index_pools = settings.index_pools.clone();
+        self.set_min_index(self.min_index);
+
+        let prefixes: Vec<String> = settings
+            .virtual_prefixes
+            .iter()
+            .map(|(_, p)| p.clone())
+            .chain(settings.vendor_prefixes.iter().map(|(_, p)| p.clone()))
+            .collect();
+        for p in &prefixes {
+            self.add_sibling_prefix(p);
+        }
+
+        // Only names following our naming scheme occupy indexes
+        self.cmdline_links = settings
+            .cmdline_ifnames
+            .iter()
+            .filter(|(n, _)| {
+                parse_prefixed_name(&self.ifname_prefix, n).is_ok_and(|p| p.takes_index())
+            })
+            .filter_map(|(n, a)| PrefixedLink::new_with_hwaddr(n, a).ok())
+            .collect();
+    }
+
+    /// Prefix of other index pool, e.g. of VendorPrefixes= or VirtualPrefixes=, whose link files
+    /// are in the same directory. Its names aren't unexpected.
+    pub fn add_sibling_prefix(&mut self, prefix: &str) {
+        if prefix != self.ifname_prefix && !self.sibling_prefixes.iter().any(|p| p == prefix) {
+            self.sibling_prefixes.push(prefix.to_string());
+        }
+    }
+
+    fn sibling_name(&self, name: &str) -> bool {
+        self.sibling_prefixes
+            .iter()
+            .any(|p| parse_prefixed_name(p, name).is_ok())
+    }
+
+    /// Names pinned by ifname= on the kernel command line are taken even before udev renames the
+    /// devices and before any link file exists.
+    fn seed_cmdline_links(&mut self) {
+        for link in &self.cmdline_links {
+            if self.links.iter().any(|l| l.name == link.name) {
+                continue;
+            }
+
+            self.config
+                .entry(link.hwaddr.clone())
+                .or_insert_with(|| link.clone());
+            self.links.push(link.clone());
+        }
+    }
+
+    /// Names with our prefix assigned by legacy persistent-net rules are taken, udev renames the
+    /// devices according to the rules regardless of our link files.
+    pub fn add_rule_names(&mut self, rule_names: &[RuleName]) -> Result<(), Box<dyn Error>> {
+        for r in rule_names {
+            let index = match parse_prefixed_name(&self.ifname_prefix, &r.name) {
+                Ok(p) if p.takes_index() => p.index,
+                _ => continue,
+            };
+
+            if index > self.max_index {
+                self.anomaly(format!(
+                    "Index of \"{}\" assigned by persistent-net rules exceeds the maximum index {}",
+                    r.name, self.max_index
+                ))?;
+                continue;
+            }
+
+            if self.links.iter().any(|l| l.name == r.name) {
+                continue;
+            }
+
+            debug!("Name {} is assigned by persistent-net rules", r.name);
+
+            match r
+                .hwaddr
+                .as_ref()
+                .and_then(|a| PrefixedLink::new_with_hwaddr(&r.name, a).ok())
+            {
+                Some(link) => {
+                    self.config
+                        .entry(link.hwaddr.clone())
+                        .or_insert_with(|| link.clone());
+                    self.links.push(link);
+                }
+                None => {
+                    self.skipped_indexes.insert(index);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn seed_rule_names(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.offline {
+            return Ok(());
+        }
+
+        self.add_rule_names(&persistent_net_rule_names(&UDEV_RULES_DIRS))
+    }
+
+    /// In strict mode anomalies in the configuration are fatal, otherwise they are only logged.
+    fn anomaly(&self, msg: String) -> Result<(), Box<dyn Error>> {
+        if self.strict {
+            return Err(From::from(msg));
+        }
+
+        warn!("{}", msg);
+        Ok(())
+    }
+
+    /// Interface types (ARPHRD_*) considered when enumerating links, Ethernet only by default.
+    pub fn set_link_types(&mut self, link_types: &[u32]) {
+        self.link_types = link_types.to_vec();
+    }
+
+    pub fn link_type_accepted(&self, device: &libudev::Device) -> bool {
+        self.net_link_type_accepted(device_link_type(device))
+    }
+
+    fn net_link_type_accepted(&self, link_type: Option<u32>) -> bool {
+        match link_type {
+            Some(t) => self.link_types.contains(&t),
+            None => false,
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.ifname_prefix
+    }
+
+    #[cfg(any(test, not(feature = "minimal")))]
+    pub fn max_index(&self) -> u64 {
+        self.max_index
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    pub fn min_index(&self) -> u64 {
+        self.min_index
+    }
+
+    #[cfg(any(test, not(feature = "minimal")))]
+    pub fn links(&self) -> &[PrefixedLink] {
+        &self.links
+    }
+
+    /// Links read from our link files by the last load().
+    #[cfg(not(feature = "minimal"))]
+    pub fn file_links(&self) -> &[PrefixedLink] {
+        &self.file_links
+    }
+
+    /// Records link that is not backed by the link file yet, so that the following call to
+    /// next_link_name() doesn't hand out its name again.
+    pub fn add_link(&mut self, link: PrefixedLink) {
+        if !link.hwaddr.is_empty() {
+            self.config.insert(link.hwaddr.clone(), link.clone());
+        }
+
+        self.links.push(link);
+        self.links.sort();
+    }
+
+    /// Tells whether the name belongs to our naming scheme. Names that start with the prefix, but
+    /// aren't followed just by the index, come from other naming scheme and are reported according
+    /// to the collision policy.
+    pub fn name_has_prefix(&self, name: &str) -> Result<bool, Box<dyn Error>> {
+        if !name.starts_with(&self.ifname_prefix) {
+            return Ok(false);
+        }
+
+        if parse_prefixed_name(&self.ifname_prefix, name).is_ok_and(|p| p.takes_index()) {
+            return Ok(true);
+        }
+
+        // Digits that don't fit in the index, e.g. in the name of hand-written link file
+        let digits = &name[self.ifname_prefix.len()..];
+        if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+            self.anomaly(format!("Index of \"{}\" is too large, ignoring it", name))?;
+            return Ok(false);
+        }
+
+        let msg = format!(
+            "Interface name \"{}\" collides with prefix \"{}\", but doesn't follow its naming scheme",
+            name, self.ifname_prefix
+        );
+
+        match self.collision_policy {
+            PrefixCollisionPolicy::Warn if !self.strict => {
+                warn!("{}, ignoring it", msg);
+                Ok(false)
+            }
+            _ => Err(From::from(msg)),
+        }
+    }
+
+    pub fn load(&mut self) -> Result<(), Box<dyn Error>> {
+        let _span = tracing::debug_span!("enumerate", prefix = %self.ifname_prefix).entered();
+
+        self.file_links.clear();
+        self.orphans.clear();
+
+        self.enumerate_links_from_udev()?;
+        self.enumerate_links_from_files()?;
+
+        // Finding orphans requires enumeration of all network devices, skip it unless needed
+        if self.orphan_policy != OrphanPolicy::Occupied {
+            self.orphans = self.find_orphaned_links()?;
+
+            let reuse = self.orphan_policy == OrphanPolicy::Reuse && self.orphan_reuse_allowed();
+
+            for o in &self.orphans {
+                match self.orphan_policy {
+                    OrphanPolicy::Reuse if reuse => {
+                        debug!("Index of orphaned link {} can be reused", o.name);
+                    }
+                    OrphanPolicy::Reuse => {
+                        debug!(
+                            "Index of orphaned link {} is kept until coldplug is over",
+                            o.name
+                        );
+                    }
+                    _ => warn!(
+                        "Link file {} doesn't match any present device, consider removing it",
+                        o.link_file_path().display()
+                    ),
+                }
+            }
+
+            if reuse {
+                let orphans = &self.orphans;
+                self.links.retain(|l| !orphans.contains(l));
+            }
+        }
+
+        self.seed_cmdline_links();
+        self.seed_rule_names()?;
+
+        // Most links have link file present and are currently known to udev.
+        // Hence enumeration from both sources created duplicate entries in the links vector.
+        // Links matched by path aren't equal to their udev counterparts, so dedup by name.
+        self.links.sort();
+        self.links.dedup_by(|a, b| a.name == b.name);
+        self.check_index_range()?;
+
+        debug!("Links: {:?}", self.links);
+
+        Ok(())
+    }
+
+    /// Links with index beyond the maximum keep their names, but next_index() doesn't continue
+    /// after them, otherwise the pool would appear exhausted.
+    fn check_index_range(&self) -> Result<(), Box<dyn Error>> {
+        for l in self.links.iter().filter(|l| l.index > self.max_index) {
+            self.anomaly(format!(
+                "Index of \"{}\" exceeds the maximum index {}, new names are allocated below it",
+                l.name, self.max_index
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads just the link files generated by us, that is enough to find out whether the device
+    /// was named already. Used when the next index comes from the index counter.
+    pub fn load_link_files(&mut self) -> Result<(), Box<dyn Error>> {
+        self.load_link_files_in(Path::new(NET_SETUP_LINK_CONF_DIR))
+    }
+
+    pub fn load_link_files_in(&mut self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let _span = tracing::debug_span!("enumerate", prefix = %self.ifname_prefix).entered();
+
+        self.links.clear();
+        self.file_links.clear();
+        self.orphans.clear();
+
+        self.enumerate_links_from_dir(dir)?;
+        self.seed_cmdline_links();
+        self.seed_rule_names()?;
+
+        self.links.sort();
+        self.links.dedup_by(|a, b| a.name == b.name);
+        self.check_index_range()?;
+
+        Ok(())
+    }
+
+    pub fn for_hwaddr<T: ToString>(&self, mac: &T) -> Option<PrefixedLink> {
+        if let Some(c) = self.config.get(&mac.to_string()) {
+            return Some(c.clone());
+        }
+        None
+    }
+
+    pub fn for_path<T: ToString>(&self, path: &T) -> Option<PrefixedLink> {
+        let link_match = LinkMatch::Path(path.to_string());
+
+        self.links
+            .iter()
+            .find(|l| l.link_match == link_match)
+            .cloned()
+    }
+
+    pub fn for_property<T: ToString>(&self, property: &T) -> Option<PrefixedLink> {
+        let link_match = LinkMatch::Property(property.to_string());
+
+        self.links
+            .iter()
+            .find(|l| l.link_match == link_match)
+            .cloned()
+    }
+
+    /// Link matching the device by one of its udev properties.
+    pub fn for_properties(&self, properties: &HashMap<String, String>) -> Option<PrefixedLink> {
+        self.links
+            .iter()
+            .find(|l| matches!(&l.link_match, LinkMatch::Property(p) if property_matches(p, properties)))
+            .cloned()
+    }
+
+    pub fn for_original_name<T: ToString>(&self, original_name: &T) -> Option<PrefixedLink> {
+        let link_match = LinkMatch::OriginalName(original_name.to_string());
+
+        self.links
+            .iter()
+            .find(|l| l.link_match == link_match)
+            .cloned()
+    }
+
+    /// Link matching the event device the same way the new link file would, i.e. the link the
+    /// device got when the event was handled before.
+    pub fn for_link_match(&self, link_match: &LinkMatch, hwaddr: &str) -> Option<PrefixedLink> {
+        match link_match {
+            LinkMatch::Path(p) => self.for_path(p),
+            LinkMatch::Property(p) => self.for_property(p),
+            LinkMatch::OriginalName(o) => self.for_original_name(o),
+            LinkMatch::MACAddress | LinkMatch::PermanentMACAddress => self.for_hwaddr(&hwaddr),
+        }
+    }
+
+    /// Orphaned link found by the last load(), orphans are only searched for when the orphan
+    /// policy isn't "occupied".
+    /// During coldplug NICs that haven't been probed yet look like orphans, their indexes are
+    /// reused only once the system is up for OrphanReuseDelaySec=.
+    fn orphan_reuse_allowed(&self) -> bool {
+        system_uptime().is_some_and(|u| u >= self.orphan_reuse_delay)
+    }
+
+    pub fn orphan_with_name(&self, name: &str) -> Option<&PrefixedLink> {
+        self.orphans.iter().find(|o| o.name == name)
+    }
+
+    /// Link files generated by us that don't match any device present in the system.
+    fn present_devices_from_udev(
+        with_permanent: bool,
+    ) -> Result<Vec<PresentDevice>, Box<dyn Error>> {
+        let udev = libudev::Context::new()?;
+        let mut enumerate = libudev::Enumerator::new(&udev)?;
+        let mut present = Vec::new();
+
+        enumerate.match_subsystem("net")?;
+
+        for device in enumerate.scan_devices()? {
+            present.push(PresentDevice::from_device(&device, with_permanent));
+        }
+
+        Ok(present)
+    }
+
+    /// Network devices present in the system, permanent MAC addresses are read only when some
+    /// link file matches on them.
+    pub fn present_devices(&self) -> Result<Vec<PresentDevice>, Box<dyn Error>> {
+        let with_permanent = self
+            .file_links
+            .iter()
+            .any(|l| l.link_match == LinkMatch::PermanentMACAddress);
+
+        match Self::present_devices_from_udev(with_permanent) {
+            Ok(p) => Ok(p),
+            Err(e) => {
+                debug!(
+                    "Failed to enumerate devices via udev ({}), reading sysfs",
+                    e
+                );
+                Ok(sysfs_net_devices()?
+                    .iter()
+                    .map(|d| PresentDevice::from_net_device(d, with_permanent))
+                    .collect())
+            }
+        }
+    }
+
+    pub fn find_orphaned_links(&self) -> Result<Vec<PrefixedLink>, Box<dyn Error>> {
+        if self.file_links.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let present = self.present_devices()?;
+
+        Ok(self
+            .file_links
+            .iter()
+            .filter(|l| link_orphaned(l, &present))
+            .cloned()
+            .collect())
+    }
+
+    /// Marks the name as taken even though no known link uses it, next_link_name() then allocates
+    /// names with higher index.
+    pub fn skip_link_name(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        let index = parse_prefixed_name(&self.ifname_prefix, name)?.index;
+
+        if index > self.max_index {
+            return Err(From::from(format!(
+                "Index of \"{}\" exceeds the maximum index {}",
+                name, self.max_index
+            )));
+        }
+
+        self.skipped_indexes.insert(index);
+        Ok(())
+    }
+
+    /// Allocates the next name and writes the link file built for it by `build`. Link files are
+    /// never overwritten, when the name turns out to be taken by other naming source in the
+    /// meantime, the state is loaded again and the next index is tried. Allocated link is
+    /// recorded, so that following allocations don't hand out its name again.
+    pub fn allocate_link<F>(&mut self, build: F) -> Result<PrefixedLink, Box<dyn Error>>
+    where
+        F: Fn(&str) -> Result<PrefixedLink, Box<dyn Error>>,
+    {
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+
+            let link = build(&self.next_link_name()?)?;
+
+            let orphan = self.orphan_with_name(&link.name).is_some();
+
+            // Other naming source may have taken the name since the state was loaded
+            let reason = if self.interface_present(&link.name) {
+                "interface with this name already exists".to_string()
+            } else {
+                let written = if orphan {
+                    info!(
+                        "Reusing index of orphaned link file {}",
+                        link.link_file_path().display()
+                    );
+                    link.rewrite_link_file(&self.link_file_options)
+                        .map_err(|e| {
+                            From::from(format!(
+                                "Failed to rewrite orphaned link file {}: {}",
+                                link.link_file_path().display(),
+                                e
+                            ))
+                        })
+                } else {
+                    link.write_link_file_with(&self.link_file_options)
+                };
+
+                match written {
+                    Ok(()) => {
+                        self.add_link(link.clone());
+                        return Ok(link);
+                    }
+                    Err(e) if error_already_exists(e.as_ref()) => {
+                        format!("{} already exists", link.link_file_path().display())
+                    }
+                    Err(e) => {
+                        return Err(From::from(format!(
+                            "Failed to write link file for {}: {}",
+                            link.name, e
+                        )))
+                    }
+                }
+            };
+
+            if attempts >= MAX_ALLOCATION_ATTEMPTS {
+                return Err(From::from(format!(
+                    "Failed to allocate name after {} attempts, {} is taken: {}",
+                    attempts, link.name, reason
+                )));
+            }
+
+            warn!(
+                "Name {} is taken ({}), trying next index",
+                link.name, reason
+            );
+
+            self.skip_link_name(&link.name)?;
+            self.load()?;
+        }
+    }
+
+    /// Next name not taken by any interface nor link file. Nothing is written, batch operations
+    /// stage link files of all reserved names and commit them at once.
+    pub fn reserve_link_name(&mut self) -> Result<String, Box<dyn Error>> {
+        loop {
+            let name = self.next_link_name()?;
+
+            // Link file of the orphan is rewritten, any other was written since the state was
+            // loaded
+            let reason = if self.interface_present(&name) {
+                "interface with this name already exists".to_string()
+            } else {
+                let path = link_file_path_for_name(&LinkName::new(&name)?);
+
+                if self.orphan_with_name(&name).is_none() && path.exists() {
+                    format!("{} already exists", path.display())
+                } else {
+                    return Ok(name);
+                }
+            };
+
+            warn!("Name {} is taken ({}), trying next index", name, reason);
+            self.skip_link_name(&name)?;
+        }
+    }
+
+    /// Stages the link file of the reserved link, orphaned link file with the same name is
+    /// rewritten the same way allocate_link() does.
+    pub fn stage_link(
+        &self,
+        transaction: &mut Transaction,
+        link: &PrefixedLink,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = link.link_file_path();
+        let file_name = path
+            .file_name()
+            .ok_or("Link file path doesn't name a file")?
+            .to_string_lossy();
+
+        if self.orphan_with_name(&link.name).is_some() {
+            info!("Reusing index of orphaned link file {}", path.display());
+            let content = fs::read_to_string(&path)?;
+            transaction.stage(
+                &file_name,
+                &with_provenance(
+                    &link.rewritten_content(&content, &self.link_file_options.extra_keys),
+                ),
+                self.link_file_options.mode,
+                true,
+            )
+        } else {
+            transaction.stage(
+                &file_name,
+                &with_provenance(&link.link_file_content(&self.link_file_options.extra_keys)),
+                self.link_file_options.mode,
+                false,
+            )
+        }
+    }
+
+    pub fn next_index(&self) -> Result<u64, Box<dyn Error>> {
+        let next_index = if self.orphan_policy == OrphanPolicy::Reuse {
+            // Lowest index that is free, orphaned links were already dropped from links
+            let taken: BTreeSet<u64> = self
+                .links
+                .iter()
+                .map(|l| l.index)
+                .chain(self.skipped_indexes.iter().copied())
+                .collect();
+
+            (self.min_index..=self.max_index).find(|i| !taken.contains(i))
+        } else {
+            let last_index = self
+                .links
+                .iter()
+                .map(|l| l.index)
+                .filter(|i| *i <= self.max_index)
+                .max();
+
+            match last_index.max(self.skipped_indexes.last().copied()) {
+                Some(i) => i.checked_add(1).map(|i| i.max(self.min_index)),
+                None => Some(self.min_index),
+            }
+        };
+
+        match next_index {
+            Some(i) if i <= self.max_index => Ok(i),
+            _ => Err(Box::new(IndexPoolExhausted {
+                prefix: self.ifname_prefix.clone(),
+                max_index: self.max_index,
+            })),
+        }
+    }
+
+    fn link_name_for_index(&self, index: u64) -> Result<LinkName, Box<dyn Error>> {
+        // Kernel would reject the name and rename would fail in udev with rather obscure error
+        let name = format!("{}{}", self.ifname_prefix, &index.to_string());
+        if name.len() > IFNAME_MAX_LEN {
+            return Err(From::from(format!(
+                "Name \"{}\" exceeds the interface name limit of {} characters, use shorter prefix",
+                name, IFNAME_MAX_LEN
+            )));
+        }
+
+        LinkName::with_prefix(&name, &self.ifname_prefix)
+    }
+
+    /// Number of names left for new NICs.
+    #[cfg(not(feature = "minimal"))]
+    pub fn free_names(&self) -> u64 {
+        self.next_index()
+            .map(|i| self.max_index - i + 1)
+            .unwrap_or(0)
+    }
+
+    pub fn next_link_name(&self) -> Result<String, Box<dyn Error>> {
+        self.link_name_for_index(self.next_index()?)
+            .map(String::from)
+    }
+
+    fn index_taken(&self, index: u64) -> bool {
+        if self.skipped_indexes.contains(&index) {
+            return true;
+        }
+
+        match self.link_name_for_index(index) {
+            Ok(n) => self.interface_present(&n) || self.links.iter().any(|l| l.name == n),
+            Err(_) => false,
+        }
+    }
+
+    /// Name with the given index, unless it is already taken.
+    pub fn free_link_name_at(&self, index: u64) -> Result<Option<String>, Box<dyn Error>> {
+        if index > self.max_index || self.index_taken(index) {
+            return Ok(None);
+        }
+
+        self.link_name_for_index(index).map(|n| Some(n.into()))
+    }
+
+    fn allocate_link_in<F>(
+        &mut self,
+        start: u64,
+        end: u64,
+        build: F,
+    ) -> Result<Option<PrefixedLink>, Box<dyn Error>>
+    where
+        F: Fn(&str) -> Result<PrefixedLink, Box<dyn Error>>,
+    {
+        let _span = tracing::debug_span!("allocate", start, end).entered();
+        let mut allocated = None;
+        let index = claim_index(
+            start,
+            end,
+            |i| self.index_taken(i),
+            |i| {
+                let link = build(&self.link_name_for_index(i)?)?;
+
+                match link.write_link_file_with(&self.link_file_options) {
+                    Ok(()) => {
+                        allocated = Some(link);
+                        Ok(true)
+                    }
+                    Err(e) if error_already_exists(e.as_ref()) => {
+                        debug!(
+                            "Name {} was taken concurrently, trying next index",
+                            link.name
+                        );
+                        Ok(false)
+                    }
+                    Err(e) => Err(From::from(format!(
+                        "Failed to write link file for {}: {}",
+                        link.name, e
+                    ))),
+                }
+            },
+        )?;
+
+        match (index, allocated) {
+            (Some(_), Some(link)) => {
+                self.add_link(link.clone());
+                Ok(Some(link))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Allocates the first free name starting at the index taken from the index counter, without
+    /// the full state of links. Creation of the link file is atomic, hence concurrent allocations
+    /// never hand out the same name, names of existing interfaces and link files are skipped.
+    pub fn allocate_link_from<F>(
+        &mut self,
+        start: u64,
+        build: F,
+    ) -> Result<PrefixedLink, Box<dyn Error>>
+    where
+        F: Fn(&str) -> Result<PrefixedLink, Box<dyn Error>>,
+    {
+        match self.allocate_link_in(start.max(self.min_index), self.max_index, build)? {
+            Some(link) => Ok(link),
+            None => Err(Box::new(IndexPoolExhausted {
+                prefix: self.ifname_prefix.clone(),
+                max_index: self.max_index,
+            })),
+        }
+    }
+
+    /// Name preferred for the device by the naming policy, None when the policy has no preference
+    /// or the name is taken.
+    pub fn preferred_link_name(
+        &self,
+        device: &PolicyDevice,
+    ) -> Result<Option<(String, PreferredIndex)>, Box<dyn Error>> {
+        let preferred = match self.naming_policy.preferred_index(device, self.max_index) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+
+        Ok(self
+            .free_link_name_at(preferred.index)?
+            .map(|n| (n, preferred)))
+    }
+
+    /// Indexes of the pool the device belongs to by its topology, limited by the index pool of
+    /// the prefix. None when no pool is configured for the device.
+    pub fn device_pool(&self, device: &PolicyDevice) -> Option<(u64, u64, Locality)> {
+        let pool = pool_for_device(&self.index_pools, device)?;
+        let last = pool.last.unwrap_or(self.max_index).min(self.max_index);
+
+        Some((pool.first.max(self.min_index), last, pool.locality))
+    }
+
+    /// First free name in the index pool of the device, nothing is written.
+    pub fn pooled_link_name(
+        &self,
+        device: &PolicyDevice,
+    ) -> Result<Option<(String, PreferredIndex)>, Box<dyn Error>> {
+        let (first, last, locality) = match self.device_pool(device) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+
+        match (first..=last).find(|i| !self.index_taken(*i)) {
+            Some(index) => Ok(Some((
+                self.link_name_for_index(index)?.into(),
+                PreferredIndex {
+                    index,
+                    reason: format!("index pool of {}", locality),
+                },
+            ))),
+            None => {
+                warn!(
+                    "Index pool of {} is exhausted, allocating next free name",
+                    locality
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Allocates the first free name in the index pool of the device. None is returned when no
+    /// pool is configured for the device or the pool is exhausted, callers then allocate the next
+    /// free name.
+    pub fn allocate_pooled_link<F>(
+        &mut self,
+        device: &PolicyDevice,
+        build: F,
+    ) -> Result<Option<(PrefixedLink, PreferredIndex)>, Box<dyn Error>>
+    where
+        F: Fn(&str) -> Result<PrefixedLink, Box<dyn Error>>,
+    {
+        let (first, last, locality) = match self.device_pool(device) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+
+        match self.allocate_link_in(first, last, build)? {
+            Some(link) => {
+                let pooled = PreferredIndex {
+                    index: link.index,
+                    reason: format!("index pool of {}", locality),
+                };
+                Ok(Some((link, pooled)))
+            }
+            None => {
+                warn!(
+                    "Index pool of {} is exhausted, allocating next free name",
+                    locality
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Allocates the name preferred for the device by the naming policy. None is returned when
+    /// the policy has no preference or the name is taken, callers then allocate the next free
+    /// name.
+    pub fn allocate_preferred_link<F>(
+        &mut self,
+        device: &PolicyDevice,
+        build: F,
+    ) -> Result<Option<(PrefixedLink, PreferredIndex)>, Box<dyn Error>>
+    where
+        F: Fn(&str) -> Result<PrefixedLink, Box<dyn Error>>,
+    {
+        let preferred = match self.naming_policy.preferred_index(device, self.max_index) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+
+        match self.allocate_link_at(preferred.index, build)? {
+            Some(link) => Ok(Some((link, preferred))),
+            None => {
+                warn!(
+                    "Name with index {} preferred by the naming policy ({}) is taken, allocating next free name",
+                    preferred.index, preferred.reason
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Allocates the name with exactly the given index, None if the name is taken.
+    pub fn allocate_link_at<F>(
+        &mut self,
+        index: u64,
+        build: F,
+    ) -> Result<Option<PrefixedLink>, Box<dyn Error>>
+    where
+        F: Fn(&str) -> Result<PrefixedLink, Box<dyn Error>>,
+    {
+        if index > self.max_index {
+            return Ok(None);
+        }
+
+        self.allocate_link_in(index, index, build)
+    }
+
+    // Several values of the same attribute can't be matched by udev, hence the type is checked
+    // by link_type_accepted() on the enumerated devices unless just one type is accepted
+    pub fn match_links(
+        &self,
+        udev_enumerate: &mut libudev::Enumerator,
+    ) -> Result<(), Box<dyn Error>> {
+        udev_enumerate.match_subsystem("net")?;
+
+        if let [t] = self.link_types[..] {
+            udev_enumerate.match_attribute("type", t.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    fn net_devices_from_udev(&self) -> Result<Vec<NetDevice>, Box<dyn Error>> {
+        let udev = libudev::Context::new()?;
+        let mut enumerate = libudev::Enumerator::new(&udev)?;
+
+        self.match_links(&mut enumerate)?;
+
+        Ok(enumerate
+            .scan_devices()?
+            .map(|d| NetDevice::from_udev(&d))
+            .collect())
+    }
+
+    /// Network devices are normally enumerated via udev. In minimal containers and very early
+    /// in boot udev may not be usable, hence we fall back to reading sysfs directly.
+    fn net_devices(&self) -> Result<Vec<NetDevice>, Box<dyn Error>> {
+        match self.net_devices_from_udev() {
+            Ok(d) => Ok(d),
+            Err(e) => {
+                debug!(
+                    "Failed to enumerate devices via udev ({}), reading sysfs",
+                    e
+                );
+                sysfs_net_devices()
+            }
+        }
+    }
+
+    fn enumerate_links_from_udev(&mut self) -> Result<(), Box<dyn Error>> {
+        let devices = self.net_devices()?;
+
+        self.enumerate_links_from_net_devices(devices)
+    }
+
+    fn enumerate_links_from_net_devices(
+        &mut self,
+        devices: Vec<NetDevice>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut links = Vec::new();
+
+        for device in devices {
+            if !self.net_link_type_accepted(device.link_type) {
+                continue;
+            }
+
+            let name = device.name.as_str();
+
+            if !self.name_has_prefix(name)? {
+                continue;
+            }
+
+            if net_device_stacked(&device) {
+                debug!("Ignoring stacked device {}", name);
+                continue;
+            }
+
+            let hwaddr = match device.hwaddr.clone() {
+                Some(a) => a,
+                None => {
+                    warn!(
+                        "Skipping {}, failed to read value of the 'address' sysfs attribute",
+                        name
+                    );
+                    continue;
+                }
+            };
+
+            let hwaddr = if net_device_enslaved(&device) {
+                match hwaddr_enslaved_ifname(name) {
+                    Ok(a) => a,
+                    Err(e) => {
+                        warn!(
+                            "Failed to determine original MAC address of enslaved device {}: {}",
+                            name, e
+                        );
+                        hwaddr
+                    }
+                }
+            } else {
+                hwaddr
+            };
+
+            // InfiniBand, CAN or WWAN devices don't have 6 octet MAC address, their link files match
+            // the device otherwise. The name still occupies its index.
+            match PrefixedLink::new_with_hwaddr(&name.to_string(), &hwaddr) {
+                Ok(link) => links.push(link),
+                Err(e) => {
+                    warn!("Skipping {}, no usable MAC address: {}", name, e);
+                    if let Err(e) = self.skip_link_name(name) {
+                        debug!("Failed to reserve index of {}: {}", name, e);
+                    }
+                }
+            }
+        }
+
+        self.links = links;
+
+        Ok(())
+    }
+
+    fn insert_hwaddr_link(&mut self, link: &PrefixedLink) -> Result<(), Box<dyn Error>> {
+        if let Some(other) = self.config.get(&link.hwaddr) {
+            if other.name != link.name {
+                self.anomaly(format!(
+                    "MAC address {} is assigned to both \"{}\" and \"{}\"",
+                    link.hwaddr, other.name, link.name
+                ))?;
+            }
+        }
+
+        self.config.insert(link.hwaddr.clone(), link.clone());
+        Ok(())
+    }
+
+    /// Link files skipped by the last load() because they couldn't be parsed, with the reason.
+    #[cfg(any(test, not(feature = "minimal")))]
+    pub fn malformed_link_files(&self) -> &[(PathBuf, String)] {
+        &self.malformed
+    }
+
+    // One corrupt file must not break naming of all NICs, it is skipped unless strict is set.
+    // The index in its file name stays taken, new link file couldn't be written there anyway.
+    fn malformed_link_file(
+        &mut self,
+        path: &Path,
+        error: Box<dyn Error>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.anomaly(format!(
+            "Skipping malformed link file {}: {}",
+            path.display(),
+            error
+        ))?;
+
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".link"))
+            .and_then(|n| n.strip_prefix(LINK_FILE_PREFIX));
+        if let Some(n) = name {
+            let _ = self.skip_link_name(n);
+        }
+
+        self.malformed.push((path.to_path_buf(), error.to_string()));
+        Ok(())
+    }
+
+    fn enumerate_links_from_files(&mut self) -> Result<(), Box<dyn Error>> {
+        self.enumerate_links_from_dir(Path::new(NET_SETUP_LINK_CONF_DIR))
+    }
+
+    fn enumerate_links_from_dir(&mut self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let mut link_files = Vec::new();
+        let mut marked = BTreeSet::new();
+
+        MARKED_LINK_FILES.lock().unwrap().clear();
+        self.malformed.clear();
+
+        let files = match fs::read_dir(dir) {
+            Ok(d) => d,
+            Err(e) => match e.kind() {
+                io::ErrorKind::NotFound => return Ok(()),
+                _ => return Err(From::from(e)),
+            },
+        };
+
+        for f in files {
+            let entry = match f {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+
+            let path = entry.path();
+            {
+                let name = match utf8_file_name(&path) {
+                    Some(n) => n,
+                    None => continue,
+                };
+
+                if !our_link_file_name(name) {
+                    // Renamed or copied by the administrator
+                    if !name.ends_with(".link") || !file_has_provenance_marker(&path) {
+                        continue;
+                    }
+                    marked.insert(path.clone());
+                }
+            }
+
+            link_files.push(path);
+        }
+
+        for (path, conf) in link_files.iter().zip(parse_link_files(&link_files)) {
+            // Link file may be removed while the state is read without the lock
+            let conf = match conf {
+                Ok(c) => c,
+                Err(_) if !path.exists() => continue,
+                Err(e) => {
+                    self.malformed_link_file(path, e)?;
+                    continue;
+                }
+            };
+            let (name, match_section) = match link_file_sections(&conf) {
+                Ok(s) => s,
+                Err(e) => {
+                    self.malformed_link_file(path, e)?;
+                    continue;
+                }
+            };
+
+            if !name.starts_with(&self.ifname_prefix) {
+                // Virtual devices and NICs of VendorPrefixes= have their own prefixes and index
+                // pools
+                if match_section.get("OriginalName").is_none() && !self.sibling_name(name) {
+                    self.anomaly(format!("Unexpected link name \"{}\"", name))?;
+                }
+                continue;
+            }
+
+            if !self.name_has_prefix(name)? {
+                continue;
+            }
+
+            let link = match link_from_match_section(name, match_section) {
+                Ok(l) => l,
+                Err(e) => {
+                    self.malformed_link_file(path, e)?;
+                    continue;
+                }
+            };
+
+            if matches!(
+                link.link_match,
+                LinkMatch::MACAddress | LinkMatch::PermanentMACAddress
+            ) {
+                self.insert_hwaddr_link(&link)?;
+            }
+
+            if marked.contains(path) {
+                MARKED_LINK_FILES
+                    .lock()
+                    .unwrap()
+                    .entry(link.name.to_string())
+                    .or_insert_with(|| path.clone());
+            }
+
+            self.file_links.push(link.clone());
+            self.links.push(link);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    enum UMockdevTestbed {}
+    #[link(name = "umockdev")]
+    extern "C" {
+        fn umockdev_testbed_new() -> *mut UMockdevTestbed;
+        fn umockdev_testbed_add_from_string(
+            testbed: *mut UMockdevTestbed,
+            device_description: *mut c_char,
+            err: *mut *mut u8,
+        );
+    }
+
+    use std::env;
+    use std::ffi::CString;
+    use std::os::raw::c_char;
+    use std::path::Path;
+
+    use super::*;
+    use crate::index_pool::parse_index_pools;
+    use crate::settings::parse_vendor_prefixes;
+
+    #[test]
+    fn max_index_derived_from_prefix() {
+        assert_eq!(max_index_for_prefix("net"), 999_999_999_999);
+        assert_eq!(max_index_for_prefix("neeeeeeeeeeet"), 99);
+    }
+
+    #[test]
+    fn next_link_name_exhausted() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        config.set_max_index(1);
+        config
+            .links
+            .push(PrefixedLink::new_with_hwaddr(&"net1", &"52:54:00:1c:08:b7").unwrap());
+
+        let err = config.next_link_name().unwrap_err();
+        assert!(err.is::<IndexPoolExhausted>());
+    }
+
+    #[test]
+    fn next_link_name_index_out_of_range() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        config.set_max_index(10);
+        config
+            .links
+            .push(PrefixedLink::new_with_hwaddr(&"net1", &"52:54:00:1c:08:b7").unwrap());
+        config
+            .links
+            .push(PrefixedLink::new_with_hwaddr(&"net999999999999", &"52:54:00:1c:08:b8").unwrap());
+
+        assert!(config.check_index_range().is_ok());
+        assert_eq!(config.next_link_name().unwrap(), "net2");
+        assert!(config.skip_link_name("net11").is_err());
+        assert!(config.skip_link_name("net184467440737095516150").is_err());
+        assert!(!config.name_has_prefix("net184467440737095516150").unwrap());
+
+        config.strict = true;
+        assert!(config.check_index_range().is_err());
+        assert!(config.name_has_prefix("net184467440737095516150").is_err());
+    }
+
+    #[test]
+    fn next_link_name_persistent_net_rules() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        config.set_max_index(10);
+        config
+            .links
+            .push(PrefixedLink::new_with_hwaddr(&"net0", &"52:54:00:1c:08:b7").unwrap());
+
+        let rule = |name: &str, hwaddr: Option<&str>| RuleName {
+            name: name.to_string(),
+            hwaddr: hwaddr.map(|a| a.to_string()),
+        };
+        config
+            .add_rule_names(&[
+                rule("net0", Some("52:54:00:1C:08:B9")),
+                rule("net1", Some("52:54:00:1C:08:B8")),
+                rule("net4", None),
+                rule("eth0", Some("52:54:00:1C:08:BA")),
+                rule("net11", None),
+            ])
+            .unwrap();
+
+        assert_eq!(config.next_link_name().unwrap(), "net5");
+        assert_eq!(
+            config.for_hwaddr(&"52:54:00:1C:08:B8").unwrap().name,
+            "net1"
+        );
+        assert!(config.for_hwaddr(&"52:54:00:1C:08:B9").is_none());
+        assert!(config.for_hwaddr(&"52:54:00:1C:08:BA").is_none());
+
+        config.strict = true;
+        assert!(config.add_rule_names(&[rule("net11", None)]).is_err());
+    }
+
+    #[test]
+    fn pooled_link_name_numa() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        config.index_pools = parse_index_pools("numa:0=0-1 numa:1=32-").unwrap();
+        config
+            .links
+            .push(PrefixedLink::new_with_hwaddr(&"net0", &"52:54:00:1c:08:b7").unwrap());
+        config
+            .links
+            .push(PrefixedLink::new_with_hwaddr(&"net1", &"52:54:00:1c:08:b8").unwrap());
+
+        let device = |numa_node| PolicyDevice {
+            hwaddr: "52:54:00:1c:08:b9".to_string(),
+            pci_address: None,
+            numa_node,
+        };
+        let pooled = |numa_node| {
+            config
+                .pooled_link_name(&device(numa_node))
+                .unwrap()
+                .map(|(n, _)| n)
+        };
+
+        assert_eq!(pooled(Some(1)), Some("net32".to_string()));
+        assert_eq!(pooled(Some(0)), None);
+        assert_eq!(pooled(None), None);
+    }
+
+    // Golden files of the generated link files, PREFIXDEVNAME_UPDATE_SNAPSHOTS=1 rewrites them.
+    // The provenance header carries the version and the time, hence it isn't part of them.
+    #[test]
+    fn link_file_snapshots() {
+        let dir = Path::new(file!())
+            .parent()
+            .unwrap()
+            .join("../test/snapshots");
+        let keys = |k: &[(&str, &str)]| -> Vec<(String, String)> {
+            k.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let hwaddr = "52:54:00:1c:08:b7";
+
+        let cases = [
+            (
+                "mac.link",
+                PrefixedLink::new_with_hwaddr(&"net0", &hwaddr).unwrap(),
+                Vec::new(),
+            ),
+            (
+                "permanent-mac.link",
+                PrefixedLink::new_with_permanent_hwaddr(&"net1", &hwaddr).unwrap(),
+                Vec::new(),
+            ),
+            (
+                "path.link",
+                PrefixedLink::new_with_path(&"net2", &"pci-0000:00:03.0").unwrap(),
+                Vec::new(),
+            ),
+            (
+                "original-name.link",
+                PrefixedLink::new_with_original_name(&"bd0", &"bond0").unwrap(),
+                Vec::new(),
+            ),
+            (
+                "altnames.link",
+                PrefixedLink::new_with_hwaddr(&"net3", &hwaddr).unwrap(),
+                keys(&[
+                    ("AlternativeNamesPolicy", "database onboard slot path"),
+                    ("AlternativeName", "uplink0"),
+                ]),
+            ),
+            (
+                "driver.link",
+                PrefixedLink::new_with_hwaddr(&"net5", &hwaddr)
+                    .unwrap()
+                    .with_driver(Some("virtio_net".to_string())),
+                Vec::new(),
+            ),
+            (
+                "property.link",
+                PrefixedLink::new_with_property(&"net6", &"ID_SERIAL=Intel_X710_0x1234").unwrap(),
+                Vec::new(),
+            ),
+            (
+                "extra-keys.link",
+                PrefixedLink::new_with_path(&"net4", &"pci-0000:3b:00.1").unwrap(),
+                keys(&[("MTUBytes", "9000"), ("WakeOnLan", "magic")]),
+            ),
+        ];
+
+        for (file, link, extra_keys) in cases {
+            let path = dir.join(file);
+            let rendered = link.link_file_content(&extra_keys);
+
+            if env::var_os("PREFIXDEVNAME_UPDATE_SNAPSHOTS").is_some() {
+                fs::write(&path, &rendered).unwrap();
+                continue;
+            }
+
+            let expected = fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+            assert_eq!(rendered, expected, "{} doesn't match", file);
+
+            // Rewriting the file must not change it
+            assert_eq!(merge_link_file(&expected, &link, &extra_keys), expected);
+        }
+    }
+
+    #[test]
+    fn next_link_name_first() {
+        let config = NetSetupLinkConfig::new_with_prefix(&"net");
+
+        assert_eq!(config.next_link_name().unwrap(), "net0");
+    }
+
+    #[test]
+    fn next_link_name_too_long() {
+        let config = NetSetupLinkConfig::new_with_prefix(&"neeeeeeeeeeeeet");
+        let err = config.next_link_name().unwrap_err();
+
+        assert!(!err.is::<IndexPoolExhausted>());
+        assert!(err.to_string().contains("use shorter prefix"));
+    }
+
+    #[test]
+    fn next_link_name_max_index_clamped() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"neeeeeeeeeeet");
+        config.set_max_index(1000);
+
+        assert_eq!(config.max_index(), 99);
+    }
+
+    #[test]
+    fn next_link_name_skipped() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+
+        config.skip_link_name("net0").unwrap();
+        assert_eq!(config.next_link_name().unwrap(), "net1");
+
+        config.add_link(PrefixedLink::new_with_path(&"net3", &"pci-0000:00:03.0").unwrap());
+        config.skip_link_name("net2").unwrap();
+        assert_eq!(config.next_link_name().unwrap(), "net4");
+
+        assert!(config.skip_link_name("eth0").is_err());
+    }
+
+    #[test]
+    fn non_ethernet_devices_occupy_indexes() {
+        let dir = std::env::temp_dir().join(format!("prefixdevname-ib-{}", std::process::id()));
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        config.set_link_types(&[1, 32, 280]);
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join(format!("{}net1.link", LINK_FILE_PREFIX)),
+            "[Match]\nPath=pci-0000:00:04.0\n\n[Link]\nName=net1\n",
+        )
+        .unwrap();
+
+        let devices = vec![
+            NetDevice {
+                name: "net0".to_string(),
+                hwaddr: Some("52:54:00:1c:08:b7".to_string()),
+                link_type: Some(1),
+                ..Default::default()
+            },
+            NetDevice {
+                name: "net1".to_string(),
+                hwaddr: Some(
+                    "80:00:02:08:fe:80:00:00:00:00:00:00:00:02:c9:03:00:0a:1b:2c".to_string(),
+                ),
+                link_type: Some(32),
+                ..Default::default()
+            },
+            NetDevice {
+                name: "net2".to_string(),
+                hwaddr: Some(String::new()),
+                link_type: Some(280),
+                ..Default::default()
+            },
+        ];
+
+        config.enumerate_links_from_net_devices(devices).unwrap();
+        config.enumerate_links_from_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.links.len(), 2);
+        assert!(config
+            .for_link_match(&LinkMatch::Path("pci-0000:00:04.0".to_string()), "")
+            .is_some_and(|l| l.name == "net1"));
+        assert_eq!(config.next_link_name().unwrap(), "net3");
+    }
+
+    #[test]
+    fn cmdline_links_occupy_indexes() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        let settings = Settings {
+            cmdline_ifnames: vec![
+                ("net0".to_string(), "00:11:22:33:44:AA".to_string()),
+                ("lan0".to_string(), "00:11:22:33:44:BB".to_string()),
+                ("net2".to_string(), "00:11:22:33:44:CC".to_string()),
+            ],
+            ..Default::default()
+        };
+
+        config.apply_settings(&settings);
+        config.seed_cmdline_links();
+        config.links.sort();
+
+        assert_eq!(config.links().len(), 2);
+        assert_eq!(
+            config.for_hwaddr(&"00:11:22:33:44:CC").unwrap().name,
+            "net2"
+        );
+        // Sequential naming continues after the highest pinned index
+        assert_eq!(config.next_link_name().unwrap(), "net3");
+    }
+
+    fn write_link_files(count: usize) -> (PathBuf, Vec<PathBuf>) {
+        let dir = std::env::temp_dir().join(format!(
+            "prefixdevname-link-files-{}-{}",
+            std::process::id(),
+            count
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        let paths = (0..count)
+            .map(|i| {
+                let path = dir.join(format!("{}net{}.link", LINK_FILE_PREFIX, i));
+                fs::write(
+                    &path,
+                    format!(
+                        "[Match]\nMACAddress=52:54:00:00:{:02X}:{:02X}\n\n[Link]\nName=net{}\n",
+                        i / 256,
+                        i % 256,
+                        i
+                    ),
+                )
+                .unwrap();
+                path
+            })
+            .collect();
+
+        (dir, paths)
+    }
+
+    #[test]
+    fn link_files_parse_parallel() {
+        let (dir, mut paths) = write_link_files(200);
+        paths.push(dir.join("missing.link"));
+
+        let serial = parse_link_files_with(&paths, 1);
+        let parallel = parse_link_files_with(&paths, 4);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(serial.len(), parallel.len());
+        for (i, (s, p)) in serial.iter().zip(&parallel).enumerate().take(200) {
+            let name = |c: &Ini| c.get_from(Some("Link"), "Name").unwrap().to_string();
+            assert_eq!(name(s.as_ref().unwrap()), format!("net{}", i));
+            assert_eq!(name(p.as_ref().unwrap()), format!("net{}", i));
+        }
+        assert!(parallel.last().unwrap().is_err());
+    }
+
+    #[test]
+    #[ignore = "Benchmark - run with cargo test --release -- --ignored link_files_parse_benchmark --nocapture"]
+    fn link_files_parse_benchmark() {
+        let (dir, paths) = write_link_files(5000);
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+        let start = std::time::Instant::now();
+        assert_eq!(parse_link_files_with(&paths, 1).len(), 5000);
+        let serial = start.elapsed();
+
+        let start = std::time::Instant::now();
+        assert_eq!(parse_link_files(&paths).len(), 5000);
+        let parallel = start.elapsed();
+        fs::remove_dir_all(&dir).unwrap();
+
+        println!(
+            "5000 link files: serial {:?}, parallel ({} threads) {:?}, speedup {:.1}x",
+            serial,
+            cpus,
+            parallel,
+            serial.as_secs_f64() / parallel.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn allocate_link_from_concurrent() {
+        let dir = std::env::temp_dir().join(format!("prefixdevname-claim-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Taken by a link file the allocations don't know about, costs one more attempt
+        fs::write(
+            dir.join(format!("{}net7.link", LINK_FILE_PREFIX)),
+            "[Match]\nMACAddress=52:54:00:00:00:ff\n\n[Link]\nName=net7\n",
+        )
+        .unwrap();
+
+        let start = std::sync::Barrier::new(100);
+        let allocated: Vec<(String, usize)> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..100)
+                .map(|t| {
+                    let (dir, start) = (&dir, &start);
+                    scope.spawn(move || {
+                        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+                        config.set_offline(true);
+                        config.link_file_options.dir = dir.clone();
+                        let attempts = std::sync::atomic::AtomicUsize::new(0);
+
+                        start.wait();
+                        let link = config
+                            .allocate_link_from(0, |n| {
+                                attempts.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                                PrefixedLink::new_with_hwaddr(
+                                    &n.to_string(),
+                                    &format!("52:54:00:00:00:{:02x}", t),
+                                )
+                            })
+                            .unwrap();
+
+                        (link.name.to_string(), attempts.into_inner())
+                    })
+                })
+                .collect();
+
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+
+        let names: BTreeSet<&str> = allocated.iter().map(|(n, _)| n.as_str()).collect();
+        let mut written: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        written.sort();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Every thread got its own name and no index was skipped except the taken one. Allocation
+        // of index i tried every index up to it, i failed attempts to create the file and no other
+        // cost, whatever the interleaving of the threads was.
+        let expected: BTreeSet<String> = (0..=100)
+            .filter(|i| *i != 7)
+            .map(|i| format!("net{}", i))
+            .collect();
+        assert_eq!(names, expected.iter().map(|n| n.as_str()).collect());
+        assert!(allocated
+            .iter()
+            .all(|(n, a)| n[3..].parse::<usize>().unwrap() + 1 == *a));
+        assert_eq!(allocated.iter().map(|(_, a)| a).sum::<usize>(), 5143);
+        // No temporary files are left behind
+        assert_eq!(written.len(), 101);
+        assert!(written.iter().all(|f| f.ends_with(".link")));
+        assert_eq!(claim_index(5, 6, |_| true, |_| Ok(true)).unwrap(), None);
+    }
+
+    #[test]
+    fn link_files_vendor_prefix_strict() {
+        let dir = std::env::temp_dir().join(format!(
+            "prefixdevname-vendor-prefix-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, hwaddr) in [("net0", "52:54:00:1C:08:B7"), ("fab0", "E4:1D:2D:12:34:56")] {
+            fs::write(
+                dir.join(format!("{}{}.link", LINK_FILE_PREFIX, name)),
+                format!("[Match]\nMACAddress={}\n\n[Link]\nName={}\n", hwaddr, name),
+            )
+            .unwrap();
+        }
+
+        let settings = Settings {
+            vendor_prefixes: parse_vendor_prefixes("0x15b3=fab").unwrap(),
+            strict: true,
+            ..Default::default()
+        };
+        let mut net = NetSetupLinkConfig::new_with_prefix(&"net");
+        net.apply_settings(&settings);
+        let mut fab = NetSetupLinkConfig::new_with_prefix(&"fab");
+        fab.apply_settings(&settings);
+        fab.add_sibling_prefix("net");
+        let mut unaware = NetSetupLinkConfig::new_with_prefix(&"net");
+        unaware.strict = true;
+
+        let net_loaded = net.enumerate_links_from_dir(&dir);
+        let fab_loaded = fab.enumerate_links_from_dir(&dir);
+        let unaware_loaded = unaware.enumerate_links_from_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(net_loaded.is_ok());
+        assert!(fab_loaded.is_ok());
+        assert_eq!(net.for_hwaddr(&"52:54:00:1C:08:B7").unwrap().name, "net0");
+        assert!(net.for_hwaddr(&"E4:1D:2D:12:34:56").is_none());
+        assert_eq!(fab.for_hwaddr(&"E4:1D:2D:12:34:56").unwrap().name, "fab0");
+        assert!(fab.for_hwaddr(&"52:54:00:1C:08:B7").is_none());
+        // Names of prefixes that aren't configured are still unexpected
+        assert!(unaware_loaded.is_err());
+    }
+
+    #[test]
+    fn config_replayed_events() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        config.set_offline(true);
+
+        // State after the first events were handled
+        let links = [
+            PrefixedLink::new_with_hwaddr(&"net0", &"52:54:00:1C:08:B7").unwrap(),
+            PrefixedLink::new_with_path(&"net1", &"pci-0000:00:04.0").unwrap(),
+            PrefixedLink::new_with_original_name(&"net2", &"usb0").unwrap(),
+            PrefixedLink::new_with_property(&"net3", &"ID_SERIAL=0x1234").unwrap(),
+            PrefixedLink::new_with_permanent_hwaddr(&"net4", &"A4:BB:6D:1C:08:B7").unwrap(),
+        ];
+        for l in &links {
+            config.add_link(l.clone());
+        }
+        let next_index = config.next_index().unwrap();
+
+        // Device renamed already is left alone before the config is even loaded
+        assert!(!rename_needed("net0", "net").unwrap());
+
+        // Replayed before the rename, the link the device got the first time is found
+        let replays = [
+            (LinkMatch::MACAddress, "52:54:00:1C:08:B7"),
+            (LinkMatch::Path("pci-0000:00:04.0".to_string()), ""),
+            (LinkMatch::OriginalName("usb0".to_string()), ""),
+            (LinkMatch::Property("ID_SERIAL=0x1234".to_string()), ""),
+            (LinkMatch::PermanentMACAddress, "A4:BB:6D:1C:08:B7"),
+        ];
+        for _ in 0..2 {
+            for ((link_match, hwaddr), link) in replays.iter().zip(&links) {
+                assert_eq!(
+                    config.for_link_match(link_match, hwaddr).as_ref(),
+                    Some(link)
+                );
+            }
+        }
+
+        assert!(config
+            .for_link_match(&LinkMatch::MACAddress, "52:54:00:1C:08:B8")
+            .is_none());
+        assert_eq!(config.links().len(), links.len());
+        assert_eq!(config.next_index().unwrap(), next_index);
+    }
+
+    #[test]
+    fn link_file_mark_removed() {
+        let content = "[Match]\nMACAddress=52:54:00:1C:08:B7\n\n[Link]\nName=net0\n";
+        let marked = mark_removed(content, "2024-02-29T13:05:00Z");
+
+        assert_eq!(
+            marked,
+            format!("{}# Device removed at 2024-02-29T13:05:00Z\n", content)
+        );
+        assert_eq!(
+            mark_removed(&marked, "2024-03-01T00:00:00Z"),
+            format!("{}# Device removed at 2024-03-01T00:00:00Z\n", content)
+        );
+    }
+
+    #[test]
+    fn link_file_mark_removed_keeps_mode() {
+        let path = std::env::temp_dir().join(format!(
+            "prefixdevname-mark-removed-{}.link",
+            std::process::id()
+        ));
+
+        fs::write(&path, "[Match]\nMACAddress=52:54:00:1C:08:B7\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        mark_link_file_removed(&path, "2024-02-29T13:05:00Z").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o7777, 0o640);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn orphaned_links() {
+        let present = vec![PresentDevice {
+            name: "eth0".to_string(),
+            hwaddrs: vec!["52:54:00:1C:08:B7".to_string()],
+            path: Some("pci-0000:00:03.0".to_string()),
+            ..Default::default()
+        }];
+
+        let link = PrefixedLink::new_with_hwaddr(&"net0", &"52:54:00:1c:08:b7").unwrap();
+        assert!(!link_orphaned(&link, &present));
+
+        let link = PrefixedLink::new_with_hwaddr(&"net1", &"52:54:00:1c:08:b8").unwrap();
+        assert!(link_orphaned(&link, &present));
+
+        let link = PrefixedLink::new_with_path(&"net2", &"pci-0000:00:03.0").unwrap();
+        assert!(!link_orphaned(&link, &present));
+
+        let link = PrefixedLink::new_with_permanent_hwaddr(&"net3", &"52:54:00:1c:08:b7").unwrap();
+        assert!(link_orphaned(&link, &present));
+
+        let link = PrefixedLink::new_with_hwaddr(&"eth0", &"52:54:00:1c:08:b8").unwrap();
+        assert!(!link_orphaned(&link, &present));
+    }
+
+    #[test]
+    fn next_link_name_reuse() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        config.orphan_policy = OrphanPolicy::Reuse;
+
+        config.add_link(PrefixedLink::new_with_path(&"net0", &"pci-0000:00:03.0").unwrap());
+        config.add_link(PrefixedLink::new_with_path(&"net2", &"pci-0000:00:04.0").unwrap());
+        assert_eq!(config.next_link_name().unwrap(), "net1");
+
+        config.skip_link_name("net1").unwrap();
+        assert_eq!(config.next_link_name().unwrap(), "net3");
+    }
+
+    #[test]
+    fn orphan_reuse_delay() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+
+        config.orphan_reuse_delay = Duration::ZERO;
+        assert!(config.orphan_reuse_allowed());
+
+        config.orphan_reuse_delay = Duration::MAX;
+        assert!(!config.orphan_reuse_allowed());
+    }
+
+    #[test]
+    fn malformed_link_file_skipped() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        let conf = Ini::load_from_str("[Match]\nDriver=ixgbe\n\n[Link]\nName=net0\n").unwrap();
+        let (name, match_section) = link_file_sections(&conf).unwrap();
+
+        assert_eq!(name, "net0");
+        assert!(link_from_match_section(name, match_section).is_err());
+        assert!(link_file_sections(&Ini::load_from_str("[Match]\n").unwrap()).is_err());
+
+        let path = link_file_path_for_name(&LinkName::new("net0").unwrap());
+        config
+            .malformed_link_file(&path, From::from("bad file"))
+            .unwrap();
+        assert_eq!(config.malformed_link_files().len(), 1);
+        assert_eq!(config.free_link_name_at(0).unwrap(), None);
+        assert_eq!(config.next_link_name().unwrap(), "net1");
+
+        config.strict = true;
+        assert!(config
+            .malformed_link_file(&path, From::from("bad file"))
+            .is_err());
+    }
+
+    #[test]
+    fn next_link_name_min_index() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        config.set_min_index(4);
+        assert_eq!(config.next_link_name().unwrap(), "net4");
+
+        config.add_link(PrefixedLink::new_with_path(&"net1", &"pci-0000:00:03.0").unwrap());
+        assert_eq!(config.next_link_name().unwrap(), "net4");
+        assert_eq!(config.free_link_name_at(1).unwrap(), None);
+        assert_eq!(
+            config.free_link_name_at(0).unwrap(),
+            Some("net0".to_string())
+        );
+
+        config.orphan_policy = OrphanPolicy::Reuse;
+        assert_eq!(config.next_link_name().unwrap(), "net4");
+    }
+
+    #[test]
+    fn name_has_prefix_collision() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+
+        assert!(config.name_has_prefix("net12").unwrap());
+        assert!(!config.name_has_prefix("eth0").unwrap());
+        assert!(!config.name_has_prefix("netmon0").unwrap());
+        assert!(!config.name_has_prefix("net").unwrap());
+
+        config.collision_policy = PrefixCollisionPolicy::Refuse;
+        assert!(config.name_has_prefix("net12").unwrap());
+        assert!(config.name_has_prefix("netmon0").is_err());
+    }
+
+    #[test]
+    fn strict_anomalies() {
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        let link = PrefixedLink::new_with_hwaddr(&"net0", &"00:11:22:33:44:55").unwrap();
+        let duplicate = PrefixedLink::new_with_hwaddr(&"net1", &"00:11:22:33:44:55").unwrap();
+
+        assert!(config.insert_hwaddr_link(&link).is_ok());
+        assert!(config.insert_hwaddr_link(&duplicate).is_ok());
+
+        config.strict = true;
+        assert!(config.insert_hwaddr_link(&link).is_err());
+        assert!(config.name_has_prefix("netmon0").is_err());
+        assert!(config.anomaly("Unexpected link name".to_string()).is_err());
+    }
+
+    #[test]
+    fn prefixed_link_file_content() {
+        let link = PrefixedLink::new_with_hwaddr(&"net0", &"00:11:22:33:44:55").unwrap();
+
+        assert_eq!(
+            link.link_file_content(&[]),
+            "[Match]\nMACAddress=00:11:22:33:44:55\n\n[Link]\nName=net0\n"
+        );
+        assert_eq!(
+            link.link_file_content(&[("MTUBytes".to_string(), "9000".to_string())]),
+            "[Match]\nMACAddress=00:11:22:33:44:55\n\n[Link]\nName=net0\nMTUBytes=9000\n"
+        );
+    }
+
+    #[test]
+    fn link_file_provenance() {
+        let header = provenance_header(1709211900);
+        let body = "[Match]\nMACAddress=00:11:22:33:44:55\n\n[Link]\nName=net0\n";
+
+        assert!(header.starts_with(&format!(
+            "{} {} at 2024-02-29T13:05:00Z\n",
+            PROVENANCE_MARKER,
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert_eq!(strip_provenance(&(header.clone() + body)), body);
+        assert_eq!(strip_provenance(body), body);
+        assert_eq!(strip_provenance(&with_provenance(body)), body);
+        assert_eq!(strip_provenance(&header), "");
+
+        let path = std::env::temp_dir().join(format!(
+            "prefixdevname-provenance-{}.link",
+            std::process::id()
+        ));
+        fs::write(&path, header + body).unwrap();
+        assert!(file_has_provenance_marker(&path));
+        fs::write(&path, body).unwrap();
+        assert!(!file_has_provenance_marker(&path));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn link_file_merge() {
+        let link = PrefixedLink::new_with_permanent_hwaddr(&"net3", &"00:11:22:33:44:66").unwrap();
+        let extra = [
+            ("MTUBytes".to_string(), "9000".to_string()),
+            ("WakeOnLan".to_string(), "magic".to_string()),
+        ];
+        let content = "# Edited by admin\n[Match]\nMACAddress=00:11:22:33:44:55\nDriver=ixgbe\n\n[Link]\nName=net1\nMTUBytes=1500\n\n[SR-IOV]\nVirtualFunction=0\n";
+
+        assert_eq!(
+            merge_link_file(content, &link, &extra),
+            "# Edited by admin\n[Match]\nPermanentMACAddress=00:11:22:33:44:66\nDriver=ixgbe\n\n[Link]\nName=net3\nMTUBytes=1500\nWakeOnLan=magic\n\n[SR-IOV]\nVirtualFunction=0\n"
+        );
+
+        // Sections missing in the existing file are added
+        assert_eq!(
+            merge_link_file("# comment\n", &link, &[]),
+            "# comment\n\n[Match]\nPermanentMACAddress=00:11:22:33:44:66\n\n[Link]\nName=net3\n"
+        );
+
+        // Merging the file generated for the link doesn't change it
+        let generated = link.link_file_content(&extra);
+        assert_eq!(merge_link_file(&generated, &link, &extra), generated);
+
+        // Driver= we match on replaces the one set by the administrator
+        let link = link.with_driver(Some("e1000e".to_string()));
+        assert_eq!(
+            merge_link_file(content, &link, &[]),
+            "# Edited by admin\n[Match]\nPermanentMACAddress=00:11:22:33:44:66\nDriver=e1000e\n\n[Link]\nName=net3\nMTUBytes=1500\n\n[SR-IOV]\nVirtualFunction=0\n"
+        );
+    }
+
+    #[test]
+    fn link_file_rewrite_other_device() {
+        let content = "[Match]\nMACAddress=00:11:22:33:44:55\nDriver=ixgbe\n\n[Link]\nName=net1\nMTUBytes=1500\n\n[SR-IOV]\nVirtualFunction=0\n";
+
+        // Orphaned index reused by other NIC, nothing of the previous device is kept
+        let link = PrefixedLink::new_with_hwaddr(&"net1", &"00:11:22:33:44:66").unwrap();
+        assert!(!link.same_device_as(content));
+        assert_eq!(
+            link.rewritten_content(content, &[]),
+            link.link_file_content(&[])
+        );
+
+        // Same device matched differently keeps the keys of the administrator
+        let link = PrefixedLink::new_with_permanent_hwaddr(&"net1", &"00:11:22:33:44:55").unwrap();
+        assert!(link.same_device_as(content));
+        assert_eq!(
+            link.rewritten_content(content, &[]),
+            merge_link_file(content, &link, &[])
+        );
+
+        let link = PrefixedLink::new_with_path(&"net1", &"pci-0000:00:04.0").unwrap();
+        assert!(!link.same_device_as(content));
+        assert!(!link.same_device_as("garbage"));
+    }
+
+    #[test]
+    fn prefixed_link_new() {
+        let config = PrefixedLink::new_with_hwaddr(&"net0", &"ff:ff:ff:ff:ff:ff");
+        assert!(config.is_ok());
+    }
+
+    #[test]
+    fn prefixed_link_name_empty() {
+        let config = PrefixedLink::new_with_hwaddr(&"", &"ff:ff:ff:ff:ff:ff");
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn prefixed_link_name_long() {
+        let config =
+            PrefixedLink::new_with_hwaddr(&"neeeeeeeeeeeeeeeeeeeeeeeeeet0", &"ff:ff:ff:ff:ff:ff");
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn prefixed_link_name_invalid() {
+        let config = PrefixedLink::new_with_hwaddr(&"1net0", &"ff:ff:ff:ff:ff:ff");
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn prefixed_link_new_with_permanent_hwaddr() {
+        let config =
+            PrefixedLink::new_with_permanent_hwaddr(&"net0", &"52:54:00:52:1f:93").unwrap();
+        assert_eq!(config.link_match, LinkMatch::PermanentMACAddress);
+        assert_eq!(config.hwaddr, "52:54:00:52:1F:93");
+    }
+
+    #[test]
+    fn prefixed_link_new_with_path() {
+        let config = PrefixedLink::new_with_path(&"net0", &"pci-0000:00:03.0").unwrap();
+        assert_eq!(
+            config.link_match,
+            LinkMatch::Path("pci-0000:00:03.0".to_string())
+        );
+        assert_eq!(config.index, 0);
+    }
+
+    #[test]
+    fn prefixed_link_new_with_original_name() {
+        let config = PrefixedLink::new_with_original_name(&"bd1", &"bond0").unwrap();
+        assert_eq!(
+            config.link_match,
+            LinkMatch::OriginalName("bond0".to_string())
+        );
+        assert_eq!(config.match_value(), "bond0");
+        assert_eq!(config.index, 1);
+
+        assert!(PrefixedLink::new_with_original_name(&"bd1", &"").is_err());
+    }
+
+    #[test]
+    fn prefixed_link_new_with_property() {
+        let link = PrefixedLink::new_with_property(&"net4", &"ID_SERIAL=0x1234").unwrap();
+        assert_eq!(
+            link.link_match,
+            LinkMatch::Property("ID_SERIAL=0x1234".to_string())
+        );
+        assert_eq!(link.match_value(), "ID_SERIAL=0x1234");
+
+        for property in ["", "ID_SERIAL", "=0x1234", "ID_SERIAL=", "A=1 B=2"] {
+            assert!(
+                PrefixedLink::new_with_property(&"net4", &property).is_err(),
+                "{}",
+                property
+            );
+        }
+
+        let conf = Ini::load_from_str("[Match]\nProperty=ID_SERIAL=0x1234\n\n[Link]\nName=net4\n")
+            .unwrap();
+        let (name, section) = link_file_sections(&conf).unwrap();
+        assert_eq!(link_from_match_section(name, section).unwrap(), link);
+
+        let properties = HashMap::from([("ID_SERIAL".to_string(), "0x1234".to_string())]);
+        assert!(property_matches("ID_SERIAL=0x1234", &properties));
+        assert!(!property_matches("ID_SERIAL=0x1235", &properties));
+        assert!(!property_matches("ID_MODEL=0x1234", &properties));
+    }
+
+    #[test]
+    fn prefixed_link_new_with_path_empty() {
+        let config = PrefixedLink::new_with_path(&"net0", &"");
         assert!(config.is_err());
     }
 
@@ -441,9 +3048,12 @@ mod tests {
         let c2 = PrefixedLink::new_with_hwaddr(&"net2", &"FF:FF:FF:FF:FF:BB").unwrap();
         let c3 = PrefixedLink::new_with_hwaddr(&"net3", &"FF:FF:FF:FF:FF:CC").unwrap();
 
-        c1.write_link_file().unwrap();
-        c2.write_link_file().unwrap();
-        c3.write_link_file().unwrap();
+        c1.write_link_file_with(&LinkFileOptions::default())
+            .unwrap();
+        c2.write_link_file_with(&LinkFileOptions::default())
+            .unwrap();
+        c3.write_link_file_with(&LinkFileOptions::default())
+            .unwrap();
 
         let mut net_setup_link_config = NetSetupLinkConfig::new_with_prefix(&"net");
         net_setup_link_config.load().unwrap();
@@ -0,0 +1,291 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::debounce::marker_path;
+
+pub static COUNTERS_FILE: &str = "/run/prefixdevname/counters";
+pub static CONFLICTS_DIR: &str = "/run/prefixdevname/conflicts";
+#[cfg(not(feature = "minimal"))]
+pub static PROMETHEUS_TEXTFILE: &str = "/var/lib/prometheus/node-exporter/prefixdevname.prom";
+
+/// Runtime statistics shared by all invocations, stored as simple key=value lines so that
+/// node monitoring agents can scrape them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub assignments: u64,
+    pub conflicts: u64,
+    pub lock_timeouts: u64,
+    pub enumerations: u64,
+    pub enumeration_ms_total: u64,
+    pub enumeration_ms_last: u64,
+}
+
+impl Counters {
+    pub fn parse(content: &str) -> Counters {
+        let mut counters = Counters::default();
+
+        for line in content.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => continue,
+            };
+
+            let value = match value.parse::<u64>() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+            match key {
+                "assignments" => counters.assignments = value,
+                "conflicts" => counters.conflicts = value,
+                "lock_timeouts" => counters.lock_timeouts = value,
+                "enumerations" => counters.enumerations = value,
+                "enumeration_ms_total" => counters.enumeration_ms_total = value,
+                "enumeration_ms_last" => counters.enumeration_ms_last = value,
+                _ => {}
+            }
+        }
+
+        counters
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Counters, Box<dyn Error>> {
+        match fs::read_to_string(path) {
+            Ok(c) => Ok(Counters::parse(&c)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Counters::default()),
+            Err(e) => Err(From::from(e)),
+        }
+    }
+
+    /// Read-modify-write of the counters file. Updates are serialized using flock() on a separate
+    /// lock file and the new content is renamed into place, so readers never see partial file.
+    pub fn update<P, F>(path: P, f: F) -> Result<(), Box<dyn Error>>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut Counters),
+    {
+        let path = path.as_ref();
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let lock_path = path.with_extension("lock");
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path)?;
+
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } < 0 {
+            return Err(From::from(io::Error::last_os_error()));
+        }
+
+        let mut counters = Counters::load(path)?;
+        f(&mut counters);
+
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp = fs::File::create(&tmp_path)?;
+        write!(&mut tmp, "{}", counters)?;
+        fs::rename(&tmp_path, path)?;
+
+        // Lock is released when the file is closed
+        Ok(())
+    }
+
+    /// Metrics in the format expected by the textfile collector of Prometheus node exporter.
+    pub fn to_prometheus(&self) -> String {
+        let metrics = [
+            (
+                "prefixdevname_assignments_total",
+                "counter",
+                "Number of interface names assigned",
+                self.assignments.to_string(),
+            ),
+            (
+                "prefixdevname_conflicts_total",
+                "counter",
+                "Number of devices left alone because other configuration names them",
+                self.conflicts.to_string(),
+            ),
+            (
+                "prefixdevname_lock_timeouts_total",
+                "counter",
+                "Number of timeouts while waiting for the allocation lock",
+                self.lock_timeouts.to_string(),
+            ),
+            (
+                "prefixdevname_enumerations_total",
+                "counter",
+                "Number of enumerations of existing links",
+                self.enumerations.to_string(),
+            ),
+            (
+                "prefixdevname_enumeration_duration_seconds_total",
+                "counter",
+                "Total time spent enumerating existing links",
+                format!("{:.3}", self.enumeration_ms_total as f64 / 1000.0),
+            ),
+            (
+                "prefixdevname_last_enumeration_duration_seconds",
+                "gauge",
+                "Time spent by the last enumeration of existing links",
+                format!("{:.3}", self.enumeration_ms_last as f64 / 1000.0),
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            out.push_str(&format!(
+                "# HELP {} {}.\n# TYPE {} {}\n{} {}\n",
+                name, help, name, kind, name, value
+            ));
+        }
+
+        out
+    }
+
+    /// Textfile collector may read the file at any time, hence the file is written to temporary
+    /// file in the same directory first and then renamed into place.
+    pub fn write_prometheus_textfile<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut tmp_name = path.as_os_str().to_os_string();
+        tmp_name.push(".tmp");
+
+        let mut tmp = fs::File::create(&tmp_name)?;
+        write!(&mut tmp, "{}", self.to_prometheus())?;
+        fs::rename(&tmp_name, path)?;
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Counters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "assignments={}", self.assignments)?;
+        writeln!(f, "conflicts={}", self.conflicts)?;
+        writeln!(f, "lock_timeouts={}", self.lock_timeouts)?;
+        writeln!(f, "enumerations={}", self.enumerations)?;
+        writeln!(f, "enumeration_ms_total={}", self.enumeration_ms_total)?;
+        writeln!(f, "enumeration_ms_last={}", self.enumeration_ms_last)
+    }
+}
+
+/// Updates the runtime counters, failure is never fatal for interface naming.
+pub fn bump_counters<F: FnOnce(&mut Counters)>(f: F) {
+    if let Err(e) = Counters::update(COUNTERS_FILE, f) {
+        debug!("Failed to update counters in {}: {}", COUNTERS_FILE, e);
+    }
+}
+
+/// Counts the device left alone because other configuration names it. Each device is counted once
+/// per boot, events replayed for it later find its marker in the directory and change nothing.
+pub fn count_conflict<P: AsRef<Path>, Q: AsRef<Path>>(
+    markers: P,
+    counters: Q,
+    hwaddr: &str,
+) -> Result<(), Box<dyn Error>> {
+    let markers = markers.as_ref();
+
+    fs::create_dir_all(markers)?;
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(marker_path(markers, hwaddr))
+    {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => return Err(From::from(e)),
+    }
+
+    Counters::update(counters, |c| c.conflicts += 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_roundtrip() {
+        let counters = Counters {
+            assignments: 3,
+            conflicts: 1,
+            lock_timeouts: 0,
+            enumerations: 4,
+            enumeration_ms_total: 120,
+            enumeration_ms_last: 25,
+        };
+
+        assert_eq!(Counters::parse(&counters.to_string()), counters);
+    }
+
+    #[test]
+    fn counters_parse_garbage() {
+        let counters = Counters::parse("assignments=x\nfoo\nconflicts=2\n");
+
+        assert_eq!(counters.assignments, 0);
+        assert_eq!(counters.conflicts, 2);
+    }
+
+    #[test]
+    fn counters_prometheus() {
+        let counters = Counters {
+            assignments: 3,
+            enumeration_ms_last: 25,
+            ..Default::default()
+        };
+        let metrics = counters.to_prometheus();
+
+        assert!(metrics.contains(
+            "# TYPE prefixdevname_assignments_total counter\nprefixdevname_assignments_total 3\n"
+        ));
+        assert!(metrics.contains("prefixdevname_last_enumeration_duration_seconds 0.025\n"));
+    }
+
+    #[test]
+    fn counters_update() {
+        let dir =
+            std::env::temp_dir().join(format!("prefixdevname-counters-{}", std::process::id()));
+        let path = dir.join("counters");
+
+        Counters::update(&path, |c| c.assignments += 1).unwrap();
+        Counters::update(&path, |c| c.assignments += 1).unwrap();
+
+        let counters = Counters::load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(counters.assignments, 2);
+    }
+
+    #[test]
+    fn counters_conflict_once() {
+        let dir =
+            std::env::temp_dir().join(format!("prefixdevname-conflicts-{}", std::process::id()));
+        let markers = dir.join("conflicts");
+        let path = dir.join("counters");
+
+        count_conflict(&markers, &path, "52:54:00:1C:08:B7").unwrap();
+        let first = fs::read(&path).unwrap();
+        count_conflict(&markers, &path, "52:54:00:1C:08:B7").unwrap();
+        let replayed = fs::read(&path).unwrap();
+        count_conflict(&markers, &path, "52:54:00:1C:08:B8").unwrap();
+
+        let counters = Counters::load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(first, replayed);
+        assert_eq!(counters.conflicts, 2);
+    }
+}
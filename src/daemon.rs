@@ -0,0 +1,576 @@
+// SPDX-License-Identifier:  MIT
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::config::*;
+use crate::healthcheck::{notify, notify_status, service_watchdog_timeout};
+use crate::link_match::LINK_FILE_SEARCH_PATH;
+use crate::resolver::Resolver;
+use crate::settings::*;
+
+pub static DAEMON_SOCKET: &str = "/run/prefixdevname/daemon.socket";
+
+// The first file descriptor passed by the service manager, see sd_listen_fds(3)
+const LISTEN_FDS_START: i32 = 3;
+
+// Clients that don't send the request or read the answer in time are dropped, the loop serves
+// them one by one
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// struct inotify_event without the name, see inotify(7)
+const INOTIFY_EVENT_SIZE: usize = 16;
+
+/// Long running mode that keeps the naming state in memory and answers queries on the unix
+/// socket. Link file directories and the configuration file are watched with inotify and the
+/// state is refreshed whenever they change.
+pub struct Daemon {
+    prefix: String,
+    // Command line options of the daemon, the prefix is resolved again on SIGHUP
+    options: Vec<(String, String)>,
+    config: NetSetupLinkConfig,
+    inotify: OwnedFd,
+    // Watches of the directories holding the configuration file, other files there are ignored
+    settings_watches: BTreeSet<i32>,
+    sighup: OwnedFd,
+    listener: UnixListener,
+    // Half of the watchdog timeout of the service, if any
+    watchdog_interval: Option<Duration>,
+}
+
+/// Watches the link file directories and the configuration. Directories holding the
+/// configuration file are watched rather than the file, so that it is noticed when it is created
+/// or replaced, their watch descriptors are returned.
+fn inotify_watch_all() -> Result<(OwnedFd, BTreeSet<i32>), Box<dyn Error>> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(From::from(io::Error::last_os_error()));
+    }
+
+    let inotify = unsafe { OwnedFd::from_raw_fd(fd) };
+    let dir_mask = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO;
+    let mut settings_watches = BTreeSet::new();
+
+    let settings_dirs = SETTINGS_DIRS.iter().flat_map(|d| {
+        [
+            (d.to_string(), true),
+            (format!("{}/{}.d", d, SETTINGS_NAME), false),
+        ]
+    });
+    let watches = LINK_FILE_SEARCH_PATH
+        .iter()
+        .map(|d| (d.to_string(), false))
+        .chain(settings_dirs);
+
+    for (path, settings_dir) in watches {
+        if !Path::new(&path).exists() {
+            continue;
+        }
+
+        let p = std::ffi::CString::new(path.as_str())?;
+        match unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), p.as_ptr(), dir_mask) } {
+            wd if wd < 0 => warn!("Failed to watch {}: {}", path, io::Error::last_os_error()),
+            wd if settings_dir => {
+                settings_watches.insert(wd);
+            }
+            _ => {}
+        }
+    }
+
+    Ok((inotify, settings_watches))
+}
+
+/// Whether any of the inotify events read into the buffer concerns the naming state. Events of
+/// the directories holding the configuration file count only for the file and its drop-in
+/// directory.
+fn inotify_events_relevant(buf: &[u8], settings_watches: &BTreeSet<i32>) -> bool {
+    let mut offset = 0;
+
+    while offset + INOTIFY_EVENT_SIZE <= buf.len() {
+        let field = |at: usize| buf[offset + at..offset + at + 4].try_into().unwrap();
+        let wd = i32::from_ne_bytes(field(0));
+        let len = u32::from_ne_bytes(field(12)) as usize;
+        let name_start = offset + INOTIFY_EVENT_SIZE;
+        let name = buf
+            .get(name_start..name_start + len)
+            .unwrap_or_default()
+            .split(|b| *b == 0)
+            .next()
+            .unwrap_or_default();
+
+        if !settings_watches.contains(&wd)
+            || name == SETTINGS_NAME.as_bytes()
+            || name == format!("{}.d", SETTINGS_NAME).as_bytes()
+        {
+            return true;
+        }
+
+        offset = name_start + len;
+    }
+
+    false
+}
+
+/// Number of sockets passed by the service manager, they apply to the process only if LISTEN_PID
+/// names it.
+pub fn listen_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> usize {
+    if pid.and_then(|p| p.parse::<u32>().ok()) != Some(own_pid) {
+        return 0;
+    }
+
+    fds.and_then(|n| n.parse().ok()).unwrap_or(0)
+}
+
+/// Listening socket passed by socket activation, the daemon is started on the first query then.
+fn activated_listener() -> Result<Option<UnixListener>, Box<dyn Error>> {
+    let n = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+
+    // Not inherited by the processes we may run
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+
+    match n {
+        0 => Ok(None),
+        1 => {
+            if unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(From::from(io::Error::last_os_error()));
+            }
+
+            Ok(Some(unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) }))
+        }
+        n => Err(From::from(format!(
+            "Expected one socket from the service manager, got {}",
+            n
+        ))),
+    }
+}
+
+// SIGHUP is blocked and delivered via signalfd, hence it is handled in the loop between requests
+fn sighup_fd() -> Result<OwnedFd, Box<dyn Error>> {
+    let fd = unsafe {
+        let mut mask: libc::sigset_t = std::mem::zeroed();
+
+        libc::sigemptyset(&mut mask);
+        libc::sigaddset(&mut mask, libc::SIGHUP);
+        if libc::sigprocmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut()) < 0 {
+            return Err(From::from(io::Error::last_os_error()));
+        }
+
+        libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+    };
+
+    if fd < 0 {
+        return Err(From::from(io::Error::last_os_error()));
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Describes what a reload changed, for the log.
+pub fn reload_summary(
+    old_prefix: &str,
+    old_links: &[PrefixedLink],
+    new_prefix: &str,
+    new_links: &[PrefixedLink],
+) -> String {
+    let names = |links: &[PrefixedLink]| -> BTreeSet<String> {
+        links.iter().map(|l| l.name.to_string()).collect()
+    };
+    let (old_names, new_names) = (names(old_links), names(new_links));
+    let mut changes = Vec::new();
+
+    if old_prefix != new_prefix {
+        changes.push(format!(
+            "prefix changed from \"{}\" to \"{}\"",
+            old_prefix, new_prefix
+        ));
+    }
+
+    let added: Vec<&str> = new_names
+        .difference(&old_names)
+        .map(|n| n.as_str())
+        .collect();
+    if !added.is_empty() {
+        changes.push(format!("added {}", added.join(", ")));
+    }
+
+    let removed: Vec<&str> = old_names
+        .difference(&new_names)
+        .map(|n| n.as_str())
+        .collect();
+    if !removed.is_empty() {
+        changes.push(format!("removed {}", removed.join(", ")));
+    }
+
+    if changes.is_empty() {
+        "no changes of links".to_string()
+    } else {
+        changes.join("; ")
+    }
+}
+
+fn bind_listener() -> Result<UnixListener, Box<dyn Error>> {
+    let socket = Path::new(DAEMON_SOCKET);
+
+    if let Some(dir) = socket.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // Socket left behind by previous instance
+    let _ = fs::remove_file(socket);
+
+    Ok(UnixListener::bind(socket)?)
+}
+
+impl Daemon {
+    pub fn new(prefix: &str, options: &[(String, String)]) -> Result<Daemon, Box<dyn Error>> {
+        let listener = match activated_listener()? {
+            Some(l) => {
+                info!("Using socket passed by the service manager");
+                l
+            }
+            None => bind_listener()?,
+        };
+
+        let prefix = prefix.to_string();
+        let (inotify, settings_watches) = inotify_watch_all()?;
+        let mut daemon = Daemon {
+            config: NetSetupLinkConfig::new_with_prefix(&prefix),
+            prefix,
+            options: options.to_vec(),
+            inotify,
+            settings_watches,
+            sighup: sighup_fd()?,
+            listener,
+            watchdog_interval: service_watchdog_timeout().map(|t| t / 2),
+        };
+
+        daemon.reload()?;
+        Ok(daemon)
+    }
+
+    pub fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        // Overrides given on the command line apply to every reload, the same as at startup
+        let settings = Resolver::load(&self.options)?.settings()?;
+        let mut config = NetSetupLinkConfig::new_with_prefix(&self.prefix);
+
+        config.apply_settings(&settings);
+
+        // Files might have been replaced and old watches are gone with them. Watches are added
+        // before the state is read, so that changes made while it is read trigger another reload.
+        (self.inotify, self.settings_watches) = inotify_watch_all()?;
+
+        config.load()?;
+        self.config = config;
+
+        info!(
+            "Loaded naming state, {} links with prefix \"{}\"",
+            self.config.links().len(),
+            self.prefix
+        );
+        notify_status(&format!(
+            "Serving {} links with prefix \"{}\"",
+            self.config.links().len(),
+            self.prefix
+        ));
+
+        Ok(())
+    }
+
+    /// Resolves the prefix again and reloads the settings and the link files. The old state is
+    /// kept when any of it fails.
+    pub fn reload_all(&mut self) -> Result<(), Box<dyn Error>> {
+        let prefix = Resolver::load(&self.options)?.prefix();
+
+        if prefix.is_empty() {
+            return Err(From::from("No prefix specified"));
+        }
+
+        let (old_prefix, old_links) = (self.prefix.clone(), self.config.links().to_vec());
+
+        self.prefix = prefix;
+        if let Err(e) = self.reload() {
+            self.prefix = old_prefix;
+            return Err(e);
+        }
+
+        info!(
+            "Configuration reloaded, {}",
+            reload_summary(&old_prefix, &old_links, &self.prefix, self.config.links())
+        );
+
+        Ok(())
+    }
+
+    fn drain_sighup(&self) -> bool {
+        let mut buf = [0u8; 128];
+        let mut received = false;
+
+        while unsafe {
+            libc::read(
+                self.sighup.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        } > 0
+        {
+            received = true;
+        }
+
+        received
+    }
+
+    fn drain_inotify(&self) -> bool {
+        let mut buf = [0u8; 4096];
+        let mut changed = false;
+
+        loop {
+            let n = unsafe {
+                libc::read(
+                    self.inotify.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+
+            if n <= 0 {
+                break;
+            }
+
+            changed |= inotify_events_relevant(&buf[..n as usize], &self.settings_watches);
+        }
+
+        changed
+    }
+
+    pub fn query(&self, request: &str) -> String {
+        let mut words = request.split_whitespace();
+
+        match (words.next(), words.next()) {
+            (Some("list"), None) => self
+                .config
+                .links()
+                .iter()
+                .map(|l| format!("{} {}={}\n", l.name, l.link_match.key(), l.match_value()))
+                .collect(),
+            (Some("lookup"), Some(hwaddr)) => match self.config.for_hwaddr(&hwaddr) {
+                Some(l) => format!("{}\n", l.name),
+                None => "\n".to_string(),
+            },
+            (Some("next"), None) => match self.config.next_link_name() {
+                Ok(n) => format!("{}\n", n),
+                Err(e) => format!("error: {}\n", e),
+            },
+            _ => format!("error: unknown request \"{}\"\n", request.trim()),
+        }
+    }
+
+    fn handle_client(&self, stream: UnixStream) -> Result<(), Box<dyn Error>> {
+        let mut request = String::new();
+
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+        BufReader::new(&stream).read_line(&mut request)?;
+        (&stream).write_all(self.query(&request).as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Serves the requests until a fatal error. The service manager is told when the daemon is
+    /// ready and the watchdog is pinged from the loop, hence the daemon stuck anywhere in the loop
+    /// is restarted.
+    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+        let timeout = self
+            .watchdog_interval
+            .map(|i| i.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int)
+            .unwrap_or(-1);
+        let mut last_ping = Instant::now();
+
+        notify("READY=1");
+
+        loop {
+            let mut fds = [
+                libc::pollfd {
+                    fd: self.inotify.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: self.listener.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: self.sighup.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+
+            if let Some(interval) = self.watchdog_interval {
+                if last_ping.elapsed() >= interval {
+                    notify("WATCHDOG=1");
+                    last_ping = Instant::now();
+                }
+            }
+
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(From::from(e));
+            }
+
+            if fds[0].revents & libc::POLLIN != 0 && self.drain_inotify() {
+                debug!("Link files or configuration changed, reloading");
+                notify("RELOADING=1");
+                if let Err(e) = self.reload() {
+                    error!("Failed to reload naming state: {}", e);
+                    notify_status(&format!("Failed to reload naming state: {}", e));
+                }
+                notify("READY=1");
+            }
+
+            if fds[2].revents & libc::POLLIN != 0 && self.drain_sighup() {
+                info!("Received SIGHUP, reloading configuration");
+                notify("RELOADING=1");
+                if let Err(e) = self.reload_all() {
+                    error!(
+                        "Failed to reload configuration, keeping the old state: {}",
+                        e
+                    );
+                    notify_status(&format!("Failed to reload configuration: {}", e));
+                }
+                notify("READY=1");
+            }
+
+            if fds[1].revents & libc::POLLIN != 0 {
+                match self.listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = self.handle_client(stream) {
+                            warn!("Failed to handle request: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Failed to accept connection: {}", e),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daemon_query() {
+        let socket =
+            std::env::temp_dir().join(format!("prefixdevname-daemon-{}", std::process::id()));
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        let daemon = Daemon {
+            prefix: "net".to_string(),
+            options: Vec::new(),
+            config: NetSetupLinkConfig::new_with_prefix(&"net"),
+            inotify: unsafe { OwnedFd::from_raw_fd(fd) },
+            settings_watches: BTreeSet::new(),
+            sighup: unsafe { OwnedFd::from_raw_fd(libc::inotify_init1(libc::IN_CLOEXEC)) },
+            listener: UnixListener::bind(&socket).unwrap(),
+            watchdog_interval: None,
+        };
+        fs::remove_file(&socket).unwrap();
+
+        assert_eq!(daemon.query("list\n"), "");
+        assert_eq!(daemon.query("next\n"), "net0\n");
+        assert_eq!(daemon.query("lookup 52:54:00:1c:08:b7\n"), "\n");
+        assert_eq!(
+            daemon.query("foo bar\n"),
+            "error: unknown request \"foo bar\"\n"
+        );
+    }
+
+    #[test]
+    fn daemon_inotify_events() {
+        let event = |wd: i32, name: &str| {
+            // Names are padded with NULs, see inotify(7)
+            let len = if name.is_empty() { 0 } else { 32 };
+            let mut e = Vec::new();
+            e.extend_from_slice(&wd.to_ne_bytes());
+            e.extend_from_slice(&libc::IN_CLOSE_WRITE.to_ne_bytes());
+            e.extend_from_slice(&0u32.to_ne_bytes());
+            e.extend_from_slice(&(len as u32).to_ne_bytes());
+            e.extend_from_slice(name.as_bytes());
+            e.resize(INOTIFY_EVENT_SIZE + len, 0);
+            e
+        };
+        let settings_watches = BTreeSet::from([2]);
+
+        assert!(inotify_events_relevant(
+            &event(1, "foo.link"),
+            &settings_watches
+        ));
+        assert!(!inotify_events_relevant(
+            &event(2, "hosts"),
+            &settings_watches
+        ));
+        assert!(inotify_events_relevant(
+            &[event(2, "hosts"), event(2, SETTINGS_NAME)].concat(),
+            &settings_watches
+        ));
+        assert!(inotify_events_relevant(
+            &event(2, "prefixdevname.conf.d"),
+            &settings_watches
+        ));
+        assert!(!inotify_events_relevant(&[], &settings_watches));
+    }
+
+    #[test]
+    fn daemon_reload_summary() {
+        let links = |names: &[&str]| -> Vec<PrefixedLink> {
+            names
+                .iter()
+                .enumerate()
+                .map(|(i, n)| {
+                    PrefixedLink::new_with_hwaddr(&n.to_string(), &format!("52:54:00:1c:08:0{}", i))
+                        .unwrap()
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            reload_summary("net", &links(&["net0"]), "net", &links(&["net0"])),
+            "no changes of links"
+        );
+        assert_eq!(
+            reload_summary("net", &links(&["net0", "net1"]), "lan", &links(&["lan0"])),
+            "prefix changed from \"net\" to \"lan\"; added lan0; removed net0, net1"
+        );
+    }
+
+    #[test]
+    fn daemon_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("1"), 42), 1);
+        assert_eq!(listen_fds(Some("41"), Some("1"), 42), 0);
+        assert_eq!(listen_fds(None, Some("1"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("bogus"), 42), 0);
+    }
+}
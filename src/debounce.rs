@@ -0,0 +1,73 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub static DEBOUNCE_DIR: &str = "/run/prefixdevname/events";
+
+/// Hypervisors sometimes emit bursts of events for one NIC. The answer given to the first event
+/// is remembered in per-MAC marker file, so that repeated events within the short window don't
+/// have to load the whole naming state again.
+pub fn marker_path<P: AsRef<Path>>(dir: P, hwaddr: &str) -> PathBuf {
+    dir.as_ref().join(hwaddr.replace(':', "-"))
+}
+
+/// Answer recorded for the device within the window.
+pub fn cached_answer<P: AsRef<Path>>(dir: P, hwaddr: &str, window: Duration) -> Option<String> {
+    let path = marker_path(dir, hwaddr);
+    let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+
+    match SystemTime::now().duration_since(modified) {
+        Ok(age) if age < window => {}
+        // Clock went backwards, don't trust the marker
+        _ => return None,
+    }
+
+    fs::read_to_string(path).ok().map(|a| a.trim().to_string())
+}
+
+pub fn record_answer<P: AsRef<Path>>(
+    dir: P,
+    hwaddr: &str,
+    answer: &str,
+) -> Result<(), Box<dyn Error>> {
+    let dir = dir.as_ref();
+    let path = marker_path(dir, hwaddr);
+    let tmp_path = path.with_extension("tmp");
+
+    fs::create_dir_all(dir)?;
+    fs::write(&tmp_path, format!("{}\n", answer))?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debounce_marker() {
+        let dir =
+            std::env::temp_dir().join(format!("prefixdevname-debounce-{}", std::process::id()));
+        let hwaddr = "52:54:00:1C:08:B7";
+        let window = Duration::from_secs(60);
+
+        assert_eq!(cached_answer(&dir, hwaddr, window), None);
+
+        record_answer(&dir, hwaddr, "net3").unwrap();
+        assert_eq!(
+            cached_answer(&dir, hwaddr, window),
+            Some("net3".to_string())
+        );
+        assert_eq!(cached_answer(&dir, hwaddr, Duration::ZERO), None);
+        assert_eq!(cached_answer(&dir, "52:54:00:1C:08:B8", window), None);
+
+        record_answer(&dir, hwaddr, "").unwrap();
+        assert_eq!(cached_answer(&dir, hwaddr, window), Some(String::new()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+// SPDX-License-Identifier:  MIT
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+pub static DMI_ENTRIES_DIR: &str = "/sys/firmware/dmi/entries";
+
+const SMBIOS_TYPE_SYSTEM_SLOT: u8 = 9;
+const SMBIOS_TYPE_ONBOARD_DEVICE: u8 = 41;
+const ONBOARD_DEVICE_TYPE_ETHERNET: u8 = 0x05;
+
+/// Device labeled by the firmware, either onboard device (SMBIOS type 41) or device in the slot
+/// (SMBIOS type 9).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmbiosDevice {
+    Onboard {
+        label: String,
+        instance: u8,
+        pci_address: String,
+    },
+    Slot {
+        label: String,
+        slot_id: u16,
+        pci_address: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareLabel {
+    pub label: String,
+    pub pci_address: String,
+    pub index: u64,
+}
+
+fn smbios_string(raw: &[u8], number: u8) -> Option<String> {
+    if number == 0 {
+        return None;
+    }
+
+    let length = *raw.get(1)? as usize;
+
+    raw.get(length..)?
+        .split(|b| *b == 0)
+        .nth(number as usize - 1)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).trim().to_string())
+}
+
+// Bus and device/function 0xff are used by the firmware when the address doesn't apply, the
+// segment is either 0xffff or 0 then
+fn pci_address(segment: u16, bus: u8, devfn: u8) -> Option<String> {
+    if bus == 0xff && devfn == 0xff {
+        return None;
+    }
+
+    Some(format!(
+        "{:04x}:{:02x}:{:02x}.{:x}",
+        segment,
+        bus,
+        devfn >> 3,
+        devfn & 0x07
+    ))
+}
+
+/// Parses raw SMBIOS structure as exported by the kernel in /sys/firmware/dmi/entries/*/raw.
+/// Onboard devices other than Ethernet and structures without PCI address are ignored.
+pub fn parse_smbios_structure(raw: &[u8]) -> Option<SmbiosDevice> {
+    let word = |offset: usize| -> Option<u16> {
+        Some(u16::from_le_bytes([
+            *raw.get(offset)?,
+            *raw.get(offset + 1)?,
+        ]))
+    };
+    let length = *raw.get(1)? as usize;
+
+    if raw.len() < length {
+        return None;
+    }
+
+    match raw[0] {
+        SMBIOS_TYPE_ONBOARD_DEVICE if length >= 0x0b => {
+            if raw[5] & 0x7f != ONBOARD_DEVICE_TYPE_ETHERNET || raw[6] == 0 {
+                return None;
+            }
+
+            Some(SmbiosDevice::Onboard {
+                label: smbios_string(raw, raw[4])?,
+                instance: raw[6],
+                pci_address: pci_address(word(7)?, raw[9], raw[10])?,
+            })
+        }
+        // Bus address of the slot is present since SMBIOS 2.6
+        SMBIOS_TYPE_SYSTEM_SLOT if length >= 0x11 => Some(SmbiosDevice::Slot {
+            label: smbios_string(raw, raw[4])?,
+            slot_id: word(9)?,
+            pci_address: pci_address(word(0x0d)?, raw[0x0f], raw[0x10])?,
+        }),
+        _ => None,
+    }
+}
+
+/// Maps labeled devices to indexes deterministically. Onboard devices get index derived from
+/// their instance ("Onboard LAN 1" is index 0), devices in slots follow ordered by the slot
+/// number.
+pub fn assign_firmware_indexes(devices: &[SmbiosDevice]) -> Vec<FirmwareLabel> {
+    let mut onboard = BTreeMap::new();
+    let mut slots = BTreeMap::new();
+
+    for d in devices {
+        match d {
+            SmbiosDevice::Onboard {
+                label,
+                instance,
+                pci_address,
+            } => {
+                onboard
+                    .entry(*instance)
+                    .or_insert((label.clone(), pci_address.clone()));
+            }
+            SmbiosDevice::Slot {
+                label,
+                slot_id,
+                pci_address,
+            } => {
+                slots
+                    .entry(*slot_id)
+                    .or_insert((label.clone(), pci_address.clone()));
+            }
+        }
+    }
+
+    let first_slot_index = onboard.keys().last().map_or(0, |i| *i as u64);
+
+    onboard
+        .into_iter()
+        .map(|(instance, (label, pci_address))| FirmwareLabel {
+            label,
+            pci_address,
+            index: instance as u64 - 1,
+        })
+        .chain(
+            slots
+                .into_values()
+                .enumerate()
+                .map(|(i, (label, pci_address))| FirmwareLabel {
+                    label,
+                    pci_address,
+                    index: first_slot_index + i as u64,
+                }),
+        )
+        .collect()
+}
+
+/// Labeled devices described by the SMBIOS tables. Systems without the tables (e.g. most of
+/// the virtual machines and non-x86 machines) simply have no labels.
+pub fn firmware_labels<P: AsRef<Path>>(dir: P) -> Result<Vec<FirmwareLabel>, Box<dyn Error>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(From::from(e)),
+    };
+    let mut devices = Vec::new();
+
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        if !name.starts_with("9-") && !name.starts_with("41-") {
+            continue;
+        }
+
+        if let Some(d) = parse_smbios_structure(&fs::read(path.join("raw"))?) {
+            devices.push(d);
+        }
+    }
+
+    Ok(assign_firmware_indexes(&devices))
+}
+
+/// Indexes below the returned one belong to the labeled devices.
+pub fn firmware_reserved_indexes(labels: &[FirmwareLabel]) -> u64 {
+    labels.iter().map(|l| l.index + 1).max().unwrap_or(0)
+}
+
+/// PCI address of the network device, e.g. "0000:00:19.0".
+pub fn pci_address_for_syspath(syspath: &Path) -> Option<String> {
+    fs::read_link(syspath.join("device"))
+        .ok()?
+        .file_name()
+        .map(|a| a.to_string_lossy().to_string())
+}
+
+pub fn firmware_label_for_pci_address<'a>(
+    labels: &'a [FirmwareLabel],
+    pci_address: &str,
+) -> Option<&'a FirmwareLabel> {
+    labels.iter().find(|l| l.pci_address == pci_address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn onboard(label: &str, instance: u8, bus: u8, devfn: u8) -> Vec<u8> {
+        let mut raw = vec![
+            41, 0x0b, 0x00, 0x01, 1, 0x85, instance, 0x00, 0x00, bus, devfn,
+        ];
+        raw.extend_from_slice(label.as_bytes());
+        raw.extend_from_slice(&[0, 0]);
+        raw
+    }
+
+    fn slot(label: &str, slot_id: u16, bus: u8, devfn: u8) -> Vec<u8> {
+        let id = slot_id.to_le_bytes();
+        let mut raw = vec![
+            9, 0x11, 0x00, 0x02, 1, 0xa5, 0x0d, 0x04, 0x04, id[0], id[1], 0x0c, 0x01, 0x00, 0x00,
+            bus, devfn,
+        ];
+        raw.extend_from_slice(label.as_bytes());
+        raw.extend_from_slice(&[0, 0]);
+        raw
+    }
+
+    #[test]
+    fn smbios_structure() {
+        assert_eq!(
+            parse_smbios_structure(&onboard("Onboard LAN 1", 1, 0x00, 0xc8)),
+            Some(SmbiosDevice::Onboard {
+                label: "Onboard LAN 1".to_string(),
+                instance: 1,
+                pci_address: "0000:00:19.0".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_smbios_structure(&slot("PCIe Slot 2", 2, 0x3b, 0x01)),
+            Some(SmbiosDevice::Slot {
+                label: "PCIe Slot 2".to_string(),
+                slot_id: 2,
+                pci_address: "0000:3b:00.1".to_string(),
+            })
+        );
+
+        // Onboard video device and slot without bus address
+        let mut video = onboard("Onboard Video", 1, 0x00, 0x10);
+        video[5] = 0x83;
+        assert_eq!(parse_smbios_structure(&video), None);
+        assert_eq!(parse_smbios_structure(&slot("Slot 3", 3, 0xff, 0xff)), None);
+        assert_eq!(parse_smbios_structure(&[41, 0x0b, 0x00]), None);
+    }
+
+    #[test]
+    fn firmware_indexes() {
+        let devices: Vec<SmbiosDevice> = [
+            slot("PCIe Slot 4", 4, 0x5e, 0x00),
+            onboard("Onboard LAN 2", 2, 0x01, 0x01),
+            slot("PCIe Slot 1", 1, 0x3b, 0x00),
+            onboard("Onboard LAN 1", 1, 0x01, 0x00),
+        ]
+        .iter()
+        .filter_map(|r| parse_smbios_structure(r))
+        .collect();
+
+        let labels = assign_firmware_indexes(&devices);
+        let indexes: Vec<(&str, u64)> =
+            labels.iter().map(|l| (l.label.as_str(), l.index)).collect();
+
+        assert_eq!(
+            indexes,
+            vec![
+                ("Onboard LAN 1", 0),
+                ("Onboard LAN 2", 1),
+                ("PCIe Slot 1", 2),
+                ("PCIe Slot 4", 3)
+            ]
+        );
+        assert_eq!(firmware_reserved_indexes(&labels), 4);
+        assert_eq!(firmware_reserved_indexes(&[]), 0);
+        assert_eq!(
+            firmware_label_for_pci_address(&labels, "0000:01:00.1").map(|l| l.index),
+            Some(1)
+        );
+        assert!(firmware_label_for_pci_address(&labels, "0000:02:00.0").is_none());
+    }
+}
@@ -0,0 +1,273 @@
+// SPDX-License-Identifier:  MIT
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::util::var_available;
+
+pub static WRITTEN_DIR: &str = "/var/lib/prefixdevname/written";
+
+fn file_name_of(path: &Path) -> Result<String, Box<dyn Error>> {
+    Ok(path
+        .file_name()
+        .ok_or("Path doesn't name a file")?
+        .to_string_lossy()
+        .to_string())
+}
+
+/// Recorded content of the written files by file name, every file has its own record.
+fn read_records(dir: &Path) -> Result<BTreeMap<String, Vec<u8>>, Box<dyn Error>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(From::from(e)),
+    };
+    let mut records = BTreeMap::new();
+
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if name.ends_with(".link") && !name.starts_with('.') {
+            records.insert(name, fs::read(entry.path())?);
+        }
+    }
+
+    Ok(records)
+}
+
+/// Remembers the content of the file just written, the copy is kept to show what was changed
+/// later. Records of concurrent udev events are written without the lock, hence each one
+/// replaces only its own record.
+pub fn record_written<P: AsRef<Path>>(dir: P, path: &Path) -> Result<(), Box<dyn Error>> {
+    let dir = dir.as_ref();
+    let file_name = file_name_of(path)?;
+    let content = fs::read(path)?;
+    let tmp_path = dir.join(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+    fs::create_dir_all(dir)?;
+    fs::write(&tmp_path, &content)?;
+    fs::rename(&tmp_path, dir.join(&file_name))?;
+
+    Ok(())
+}
+
+/// Forgets the file deleted by us.
+pub fn forget_written<P: AsRef<Path>>(dir: P, path: &Path) -> Result<(), Box<dyn Error>> {
+    let file_name = file_name_of(path)?;
+
+    match fs::remove_file(dir.as_ref().join(&file_name)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(From::from(e)),
+        _ => Ok(()),
+    }
+}
+
+/// Records the written link file, failure is only logged. Nothing is recorded before /var is
+/// available, the file is then reported as untracked by the audit.
+pub fn track_written(path: &Path) {
+    if !var_available() {
+        debug!("/var is not available, not recording {}", path.display());
+        return;
+    }
+
+    if let Err(e) = record_written(WRITTEN_DIR, path) {
+        warn!("Failed to record content of {}: {}", path.display(), e);
+    }
+}
+
+pub fn untrack_written(path: &Path) {
+    if let Err(e) = forget_written(WRITTEN_DIR, path) {
+        warn!("Failed to forget content of {}: {}", path.display(), e);
+    }
+}
+
+/// Line diff between the recorded and the current content, removed lines are prefixed with "-",
+/// added lines with "+", unchanged lines with " ".
+pub fn line_diff(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // Longest common subsequence, link files are short
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push_str(&format!(" {}\n", old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push_str(&format!("-{}\n", old[i]));
+            i += 1;
+        } else {
+            diff.push_str(&format!("+{}\n", new[j]));
+            j += 1;
+        }
+    }
+
+    diff
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriftState {
+    Unchanged,
+    // Diff against the content we wrote
+    Modified(String),
+    Removed,
+    Untracked,
+}
+
+impl fmt::Display for DriftState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DriftState::Unchanged => write!(f, "unchanged"),
+            DriftState::Modified(_) => write!(f, "modified"),
+            DriftState::Removed => write!(f, "removed"),
+            DriftState::Untracked => write!(f, "untracked"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Drift {
+    pub file_name: String,
+    pub state: DriftState,
+}
+
+/// Compares our files in the target directory (those starting with file_prefix) with what we
+/// wrote. Files modified or removed outside of prefixdevname are reported, as well as files
+/// written before the content was recorded.
+pub fn audit_files<P: AsRef<Path>, T: AsRef<Path>>(
+    dir: P,
+    target_dir: T,
+    file_prefix: &str,
+) -> Result<Vec<Drift>, Box<dyn Error>> {
+    let records = read_records(dir.as_ref())?;
+    let mut file_names: BTreeSet<String> = records.keys().cloned().collect();
+
+    match fs::read_dir(target_dir.as_ref()) {
+        Ok(entries) => {
+            for entry in entries {
+                let name = entry?.file_name().to_string_lossy().to_string();
+
+                if name.starts_with(file_prefix) && name.ends_with(".link") {
+                    file_names.insert(name);
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(From::from(e)),
+    }
+
+    let mut drifts = Vec::new();
+
+    for file_name in file_names {
+        let current = match fs::read(target_dir.as_ref().join(&file_name)) {
+            Ok(c) => Some(c),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(From::from(e)),
+        };
+        let state = match (records.get(&file_name), current) {
+            (None, _) => DriftState::Untracked,
+            (Some(_), None) => DriftState::Removed,
+            (Some(r), Some(c)) if *r == c => DriftState::Unchanged,
+            (Some(r), Some(c)) => DriftState::Modified(line_diff(
+                &String::from_utf8_lossy(r),
+                &String::from_utf8_lossy(&c),
+            )),
+        };
+
+        drifts.push(Drift { file_name, state });
+    }
+
+    Ok(drifts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drift_diff() {
+        assert_eq!(
+            line_diff(
+                "[Match]\nA=1\n[Link]\nName=net0\n",
+                "[Match]\nA=2\n[Link]\nName=net0\nMTUBytes=9000\n"
+            ),
+            " [Match]\n-A=1\n+A=2\n [Link]\n Name=net0\n+MTUBytes=9000\n"
+        );
+        assert_eq!(line_diff("", ""), "");
+    }
+
+    #[test]
+    fn drift_audit() {
+        let root = std::env::temp_dir().join(format!("prefixdevname-drift-{}", std::process::id()));
+        let written = root.join("written");
+        let target = root.join("network");
+        let file = |n: &str| target.join(format!("71-net-ifnames-prefix-{}.link", n));
+
+        fs::create_dir_all(&target).unwrap();
+        for n in ["net0", "net1", "net2", "net3"] {
+            fs::write(file(n), format!("[Link]\nName={}\n", n)).unwrap();
+        }
+        for n in ["net0", "net1", "net2"] {
+            record_written(&written, &file(n)).unwrap();
+        }
+        fs::write(target.join("10-other.link"), "").unwrap();
+
+        fs::write(file("net1"), "[Link]\nName=net1\nMTUBytes=9000\n").unwrap();
+        fs::remove_file(file("net2")).unwrap();
+
+        let states: Vec<(String, DriftState)> =
+            audit_files(&written, &target, "71-net-ifnames-prefix-")
+                .unwrap()
+                .into_iter()
+                .map(|d| (d.file_name, d.state))
+                .collect();
+        assert_eq!(
+            states,
+            vec![
+                (
+                    "71-net-ifnames-prefix-net0.link".to_string(),
+                    DriftState::Unchanged
+                ),
+                (
+                    "71-net-ifnames-prefix-net1.link".to_string(),
+                    DriftState::Modified(" [Link]\n Name=net1\n+MTUBytes=9000\n".to_string())
+                ),
+                (
+                    "71-net-ifnames-prefix-net2.link".to_string(),
+                    DriftState::Removed
+                ),
+                (
+                    "71-net-ifnames-prefix-net3.link".to_string(),
+                    DriftState::Untracked
+                ),
+            ]
+        );
+
+        forget_written(&written, &file("net2")).unwrap();
+        assert_eq!(
+            audit_files(&written, &target, "71-net-ifnames-prefix-")
+                .unwrap()
+                .len(),
+            3
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
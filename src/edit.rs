@@ -0,0 +1,449 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::backup::BACKUP_DIR;
+use crate::config::{
+    link_file_path_for_name, with_provenance, LinkFileOptions, PrefixedLink,
+    NET_SETUP_LINK_CONF_DIR,
+};
+use crate::drift::{track_written, untrack_written};
+use crate::link_name::LinkName;
+use crate::output::Status;
+use crate::plan::{PlanSource, PlannedLink};
+use crate::transaction::{Transaction, TRANSACTION_RECORD};
+use crate::util::hwaddr_normalize;
+
+/// Device (or orphaned link file when kernel_name is None) as shown by the editor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditRow {
+    pub kernel_name: Option<String>,
+    pub hwaddr: String,
+    pub name: Option<String>,
+    pub source: PlanSource,
+}
+
+impl EditRow {
+    pub fn from_planned(link: &PlannedLink) -> EditRow {
+        EditRow {
+            kernel_name: Some(link.kernel_name.clone()),
+            hwaddr: link.hwaddr.clone(),
+            name: link.name.clone(),
+            source: link.source.clone(),
+        }
+    }
+
+    pub fn from_orphan(link: &PrefixedLink) -> EditRow {
+        EditRow {
+            kernel_name: None,
+            hwaddr: link.hwaddr.clone(),
+            name: Some(link.name.to_string()),
+            source: PlanSource::Orphaned(link.link_file_path()),
+        }
+    }
+
+    // Only our link files can be released or moved to another device
+    fn has_link_file(&self) -> bool {
+        matches!(
+            self.source,
+            PlanSource::LinkFile(_) | PlanSource::Orphaned(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditAction {
+    Assign { hwaddr: String, name: String },
+    Release { name: String },
+    Replace { name: String, hwaddr: String },
+}
+
+impl fmt::Display for EditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditAction::Assign { hwaddr, name } => write!(f, "assign {} to {}", name, hwaddr),
+            EditAction::Release { name } => write!(f, "release {}", name),
+            EditAction::Replace { name, hwaddr } => write!(f, "move {} to {}", name, hwaddr),
+        }
+    }
+}
+
+/// Changes of the assignments collected by the editor, nothing is written until they are applied.
+/// Every change is validated against the rows and the changes made before it.
+pub struct EditSession {
+    prefix: String,
+    rows: Vec<EditRow>,
+    actions: Vec<EditAction>,
+}
+
+impl EditSession {
+    pub fn new(prefix: &str, rows: Vec<EditRow>) -> EditSession {
+        EditSession {
+            prefix: prefix.to_string(),
+            rows,
+            actions: Vec::new(),
+        }
+    }
+
+    pub fn rows(&self) -> &[EditRow] {
+        &self.rows
+    }
+
+    pub fn pending(&self) -> &[EditAction] {
+        &self.actions
+    }
+
+    /// Name the device gets once the pending changes are applied.
+    pub fn planned_name(&self, hwaddr: &str) -> Option<String> {
+        let row = self.row_for_hwaddr(hwaddr)?;
+        let mut name = row.name.clone();
+
+        for action in &self.actions {
+            match action {
+                EditAction::Assign { hwaddr: a, name: n }
+                | EditAction::Replace { name: n, hwaddr: a }
+                    if a.eq_ignore_ascii_case(hwaddr) =>
+                {
+                    name = Some(n.clone())
+                }
+                EditAction::Release { name: n } | EditAction::Replace { name: n, .. }
+                    if name.as_ref() == Some(n) =>
+                {
+                    name = None
+                }
+                _ => {}
+            }
+        }
+
+        name
+    }
+
+    fn row_for_hwaddr(&self, hwaddr: &str) -> Option<&EditRow> {
+        self.rows
+            .iter()
+            .find(|r| r.hwaddr.eq_ignore_ascii_case(hwaddr))
+    }
+
+    fn row_for_name(&self, name: &str) -> Option<&EditRow> {
+        self.rows.iter().find(|r| r.name.as_deref() == Some(name))
+    }
+
+    fn name_taken(&self, name: &str) -> bool {
+        self.rows
+            .iter()
+            .any(|r| self.planned_name(&r.hwaddr).as_deref() == Some(name))
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        let index = name.strip_prefix(&self.prefix).unwrap_or_default();
+
+        if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+            return Err(From::from(format!(
+                "Name \"{}\" doesn't follow the naming scheme of prefix \"{}\"",
+                name, self.prefix
+            )));
+        }
+
+        if self.name_taken(name) {
+            return Err(From::from(format!("Name {} is already taken", name)));
+        }
+
+        Ok(())
+    }
+
+    // Device that is named by our link file can't get the second one
+    fn check_unnamed_device(&self, hwaddr: &str) -> Result<String, Box<dyn Error>> {
+        let hwaddr = hwaddr_normalize(&hwaddr)?;
+        let row = self
+            .row_for_hwaddr(&hwaddr)
+            .ok_or_else(|| format!("No device with MAC address {}", hwaddr))?;
+
+        if row.kernel_name.is_none() {
+            return Err(From::from(format!("Device {} isn't present", hwaddr)));
+        }
+
+        // Name comes either from our link file or from the pending change
+        let named = match self.planned_name(&hwaddr) {
+            Some(n) => row.name.as_ref() != Some(&n) || row.has_link_file(),
+            None => false,
+        };
+        if named {
+            return Err(From::from(format!(
+                "Device {} is already named, release its name first",
+                hwaddr
+            )));
+        }
+
+        Ok(hwaddr)
+    }
+
+    fn check_link_file(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        match self.row_for_name(name) {
+            Some(r)
+                if r.has_link_file() && self.planned_name(&r.hwaddr).as_deref() == Some(name) =>
+            {
+                Ok(())
+            }
+            _ => Err(From::from(format!(
+                "No link file was generated for {}",
+                name
+            ))),
+        }
+    }
+
+    /// Writes the link file naming the device.
+    pub fn assign(&mut self, hwaddr: &str, name: &str) -> Result<(), Box<dyn Error>> {
+        let hwaddr = self.check_unnamed_device(hwaddr)?;
+        self.check_name(name)?;
+
+        self.actions.push(EditAction::Assign {
+            hwaddr,
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Removes the link file, its index can be assigned again.
+    pub fn release(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.check_link_file(name)?;
+
+        self.actions.push(EditAction::Release {
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Moves the name to the device, e.g. after the NIC was swapped.
+    pub fn replace(&mut self, name: &str, hwaddr: &str) -> Result<(), Box<dyn Error>> {
+        let hwaddr = self.check_unnamed_device(hwaddr)?;
+
+        self.check_link_file(name)?;
+
+        self.actions.push(EditAction::Replace {
+            name: name.to_string(),
+            hwaddr,
+        });
+        Ok(())
+    }
+
+    pub fn undo(&mut self) -> Option<EditAction> {
+        self.actions.pop()
+    }
+
+    /// Rows that need attention, names provided by other configuration and names planned for
+    /// more than one device.
+    pub fn conflicts(&self) -> Vec<String> {
+        let mut conflicts: Vec<String> = self
+            .rows
+            .iter()
+            .filter(|r| r.source.status() == Status::Conflict)
+            .map(|r| format!("{}: {}", r.hwaddr, r.source))
+            .collect();
+        let mut names: Vec<String> = self
+            .rows
+            .iter()
+            .filter_map(|r| self.planned_name(&r.hwaddr))
+            .collect();
+
+        names.sort();
+        for pair in names.windows(2).filter(|p| p[0] == p[1]) {
+            conflicts.push(format!("{} is planned for more than one device", pair[0]));
+        }
+
+        conflicts
+    }
+
+    /// Validates the pending changes again against the rows loaded under the lock, the state
+    /// may have changed while the editor was open.
+    pub fn replay(&self, rows: Vec<EditRow>) -> Result<EditSession, Box<dyn Error>> {
+        let mut session = EditSession::new(&self.prefix, rows);
+
+        for action in &self.actions {
+            match action {
+                EditAction::Assign { hwaddr, name } => session.assign(hwaddr, name),
+                EditAction::Release { name } => session.release(name),
+                EditAction::Replace { name, hwaddr } => session.replace(name, hwaddr),
+            }
+            .map_err(|e| format!("Can't {}, naming state changed: {}", action, e))?;
+        }
+
+        Ok(session)
+    }
+}
+
+/// Writes the changes all at once in a transaction, none of them is applied when any fails. The
+/// lock must be held. Replaced and released link files are backed up first.
+pub fn apply_actions(
+    actions: &[EditAction],
+    options: &LinkFileOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut transaction = Transaction::new(NET_SETUP_LINK_CONF_DIR)?;
+    let mut released = Vec::new();
+
+    transaction.record_to(TRANSACTION_RECORD, BACKUP_DIR);
+
+    for action in actions {
+        let path = match action {
+            EditAction::Assign { name, .. }
+            | EditAction::Release { name }
+            | EditAction::Replace { name, .. } => link_file_path_for_name(&LinkName::new(name)?),
+        };
+        let file_name = path
+            .file_name()
+            .ok_or("Link file path doesn't name a file")?
+            .to_string_lossy();
+
+        match action {
+            EditAction::Assign { hwaddr, name } => {
+                let link = PrefixedLink::new_with_hwaddr(name, hwaddr)?;
+                transaction.stage(
+                    &file_name,
+                    &with_provenance(&link.link_file_content(&options.extra_keys)),
+                    options.mode,
+                    false,
+                )?
+            }
+            EditAction::Release { .. } => {
+                transaction.stage_removal(&file_name)?;
+                released.push(path.clone());
+            }
+            EditAction::Replace { name, hwaddr } => {
+                let link = PrefixedLink::new_with_hwaddr(name, hwaddr)?;
+                let content = match fs::read_to_string(&path) {
+                    Ok(c) => link.rewritten_content(&c, &options.extra_keys),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        link.link_file_content(&options.extra_keys)
+                    }
+                    Err(e) => return Err(From::from(e)),
+                };
+                transaction.stage(&file_name, &with_provenance(&content), options.mode, true)?
+            }
+        }
+    }
+
+    let written = transaction.commit()?;
+
+    written.iter().for_each(|p| track_written(p));
+    released.iter().for_each(|p| untrack_written(p));
+
+    for action in actions {
+        info!("Applied change: {}", action);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn row(
+        kernel_name: Option<&str>,
+        hwaddr: &str,
+        name: Option<&str>,
+        source: PlanSource,
+    ) -> EditRow {
+        EditRow {
+            kernel_name: kernel_name.map(|n| n.to_string()),
+            hwaddr: hwaddr.to_string(),
+            name: name.map(|n| n.to_string()),
+            source,
+        }
+    }
+
+    fn rows() -> Vec<EditRow> {
+        let link_file =
+            |n: &str| PlanSource::LinkFile(link_file_path_for_name(&LinkName::new(n).unwrap()));
+
+        vec![
+            row(
+                Some("net0"),
+                "00:11:22:33:44:00",
+                Some("net0"),
+                link_file("net0"),
+            ),
+            row(
+                Some("eth1"),
+                "00:11:22:33:44:01",
+                Some("net2"),
+                PlanSource::NewName,
+            ),
+            row(
+                None,
+                "00:11:22:33:44:02",
+                Some("net1"),
+                PlanSource::Orphaned(PathBuf::from("/x")),
+            ),
+            row(
+                Some("eth3"),
+                "00:11:22:33:44:03",
+                Some("eth3"),
+                PlanSource::Udev("x".to_string()),
+            ),
+        ]
+    }
+
+    #[test]
+    fn edit_session() {
+        let mut session = EditSession::new("net", rows());
+
+        assert!(session.assign("00:11:22:33:44:01", "net0").is_err());
+        assert!(session.assign("00:11:22:33:44:01", "lan5").is_err());
+        assert!(session.assign("00:11:22:33:44:00", "net5").is_err());
+        assert!(session.assign("00:11:22:33:44:02", "net5").is_err());
+        session.assign("00:11:22:33:44:01", "net5").unwrap();
+        assert_eq!(
+            session.planned_name("00:11:22:33:44:01").as_deref(),
+            Some("net5")
+        );
+
+        // The orphaned name moves to the new NIC
+        session.replace("net1", "00-11-22-33-44-03").unwrap();
+        assert_eq!(
+            session.planned_name("00:11:22:33:44:03").as_deref(),
+            Some("net1")
+        );
+        assert_eq!(session.planned_name("00:11:22:33:44:02"), None);
+
+        assert!(session.release("net2").is_err());
+        session.release("net0").unwrap();
+        assert!(session.release("net0").is_err());
+        session.assign("00:11:22:33:44:00", "net0").unwrap();
+
+        assert_eq!(
+            session
+                .pending()
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<String>>(),
+            vec![
+                "assign net5 to 00:11:22:33:44:01",
+                "move net1 to 00:11:22:33:44:03",
+                "release net0",
+                "assign net0 to 00:11:22:33:44:00"
+            ]
+        );
+
+        assert!(session.undo().is_some());
+        assert_eq!(session.planned_name("00:11:22:33:44:00"), None);
+        assert_eq!(session.conflicts().len(), 1);
+    }
+
+    #[test]
+    fn edit_replay() {
+        let mut session = EditSession::new("net", rows());
+        session.assign("00:11:22:33:44:01", "net5").unwrap();
+
+        assert_eq!(session.replay(rows()).unwrap().pending(), session.pending());
+
+        // Somebody else named the device in the meantime
+        let mut changed = rows();
+        changed[1].source =
+            PlanSource::LinkFile(link_file_path_for_name(&LinkName::new("net2").unwrap()));
+        assert!(session.replay(changed).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::util::json_string;
+use crate::watchdog::{
+    stage, STAGE_ENUMERATE, STAGE_LOCK, STAGE_NAMING, STAGE_READ_LINK_FILES, STAGE_SETTINGS,
+    STAGE_SETTLE, STAGE_WRITE,
+};
+use crate::EXIT_INDEX_EXHAUSTED;
+
+/// Selects the format of the error report the same way as --error-format, e.g. via ENV{} in the
+/// udev rule.
+pub static ERROR_FORMAT_ENV: &str = "PREFIXDEVNAME_ERROR_FORMAT";
+
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+static LAST_ERROR: Mutex<Option<RecordedError>> = Mutex::new(None);
+
+struct RecordedError {
+    message: String,
+    stage: &'static str,
+    path: Option<String>,
+    value: Option<String>,
+}
+
+/// Only "text" (human log lines only) and "json" (log lines and the JSON report) are known.
+pub fn set_error_format(format: &str) -> Result<(), Box<dyn Error>> {
+    match format {
+        "text" => JSON_ERRORS.store(false, Ordering::SeqCst),
+        "json" => JSON_ERRORS.store(true, Ordering::SeqCst),
+        f => return Err(From::from(format!("Unknown error format \"{}\"", f))),
+    }
+
+    Ok(())
+}
+
+/// Remembers the message together with the stage of the run and the offending path and value
+/// given by the caller, the last error logged before the process exits is the one reported.
+pub fn record_error(message: &str, path: Option<String>, value: Option<String>) {
+    *LAST_ERROR.lock().unwrap() = Some(RecordedError {
+        message: message.to_string(),
+        stage: stage(),
+        path,
+        value,
+    });
+}
+
+/// Failure of the run, for provisioning systems that react to it programmatically.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorReport {
+    pub code: &'static str,
+    pub exit_status: i32,
+    pub stage: String,
+    pub path: Option<String>,
+    pub value: Option<String>,
+    pub message: String,
+    pub hint: &'static str,
+}
+
+fn error_code(stage: &str, exit_status: i32) -> &'static str {
+    if exit_status == EXIT_INDEX_EXHAUSTED {
+        return "index-exhausted";
+    }
+
+    match stage {
+        s if s == STAGE_SETTINGS => "settings-failed",
+        s if s == STAGE_LOCK => "lock-timeout",
+        s if s == STAGE_SETTLE => "settle-failed",
+        s if s == STAGE_ENUMERATE || s == STAGE_READ_LINK_FILES => "enumeration-failed",
+        s if s == STAGE_NAMING => "naming-failed",
+        s if s == STAGE_WRITE => "write-failed",
+        _ => "failed",
+    }
+}
+
+fn remediation_hint(code: &str) -> &'static str {
+    match code {
+        "index-exhausted" => "Remove orphaned link files or raise MaxIndex=, see \"prefixdevname stats\"",
+        "settings-failed" => "Check the prefix, /etc/prefixdevname.conf and its drop-ins, see \"prefixdevname config-dump\"",
+        "lock-timeout" => "Another invocation holds the lock, see \"prefixdevname lock --status\"",
+        "settle-failed" => "udev didn't process all events in time, raise SettleTimeoutSec=",
+        "enumeration-failed" => "Check the link files in /etc/systemd/network, see \"prefixdevname verify\"",
+        "naming-failed" => "See \"prefixdevname plan\" for the names that would be assigned",
+        "write-failed" => "Check that /etc/systemd/network is writable and not full",
+        _ => "Run with RUST_LOG=debug for details",
+    }
+}
+
+impl ErrorReport {
+    pub fn new(
+        message: &str,
+        stage: &str,
+        exit_status: i32,
+        path: Option<String>,
+        value: Option<String>,
+    ) -> ErrorReport {
+        let code = error_code(stage, exit_status);
+
+        ErrorReport {
+            code,
+            exit_status,
+            stage: stage.to_string(),
+            path,
+            value,
+            message: message.to_string(),
+            hint: remediation_hint(code),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let optional = |v: &Option<String>| v.as_deref().map_or("null".to_string(), json_string);
+
+        format!(
+            "{{\"code\":{},\"exit_status\":{},\"stage\":{},\"path\":{},\"value\":{},\"message\":{},\"hint\":{}}}",
+            json_string(self.code),
+            self.exit_status,
+            json_string(&self.stage),
+            optional(&self.path),
+            optional(&self.value),
+            json_string(&self.message),
+            json_string(self.hint)
+        )
+    }
+}
+
+/// Writes the report of the last error to stderr, as a single line, when the run fails and the
+/// JSON error format is selected.
+pub fn emit_error_report(exit_status: i32) {
+    if exit_status == 0 || !JSON_ERRORS.load(Ordering::SeqCst) {
+        return;
+    }
+
+    if let Some(e) = LAST_ERROR.lock().unwrap().take() {
+        eprintln!(
+            "{}",
+            ErrorReport::new(&e.message, e.stage, exit_status, e.path, e.value).to_json()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_report_json() {
+        let report = ErrorReport::new(
+            "Failed to parse /etc/systemd/network/71-net-ifnames-prefix-net0.link: invalid MAC address \"zz\"",
+            STAGE_ENUMERATE,
+            1,
+            Some("/etc/systemd/network/71-net-ifnames-prefix-net0.link".to_string()),
+            Some("zz".to_string()),
+        );
+
+        assert_eq!(report.code, "enumeration-failed");
+        assert_eq!(
+            report.path.as_deref(),
+            Some("/etc/systemd/network/71-net-ifnames-prefix-net0.link")
+        );
+        assert_eq!(report.value.as_deref(), Some("zz"));
+        assert_eq!(
+            report.to_json(),
+            "{\"code\":\"enumeration-failed\",\"exit_status\":1,\"stage\":\"enumerating links\",\
+             \"path\":\"/etc/systemd/network/71-net-ifnames-prefix-net0.link\",\"value\":\"zz\",\
+             \"message\":\"Failed to parse /etc/systemd/network/71-net-ifnames-prefix-net0.link: invalid MAC address \\\"zz\\\"\",\
+             \"hint\":\"Check the link files in /etc/systemd/network, see \\\"prefixdevname verify\\\"\"}"
+        );
+
+        let report = ErrorReport::new("All indexes are taken", STAGE_NAMING, 3, None, None);
+        assert_eq!(report.code, "index-exhausted");
+        assert_eq!(report.path, None);
+        assert_eq!(report.value, None);
+        assert!(report.to_json().contains("\"path\":null,\"value\":null"));
+
+        assert!(set_error_format("yaml").is_err());
+    }
+}
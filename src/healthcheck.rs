@@ -0,0 +1,214 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use crate::config::{IndexPoolExhausted, NetSetupLinkConfig};
+use crate::resolver::Resolver;
+use crate::util::prefix_ok;
+
+// Exit codes are stable, units use them in ExecCondition= and SuccessExitStatus=
+pub const HEALTH_OK: i32 = 0;
+pub const HEALTH_NO_PREFIX: i32 = 1;
+pub const HEALTH_INCONSISTENT: i32 = 2;
+pub const HEALTH_INDEX_EXHAUSTED: i32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    pub code: i32,
+    pub status: String,
+}
+
+impl Health {
+    fn new<T: ToString>(code: i32, status: T) -> Health {
+        Health {
+            code,
+            status: status.to_string(),
+        }
+    }
+}
+
+/// Health of the loaded state, the pool must have a free index left for the next NIC.
+pub fn state_health(
+    prefix: &str,
+    assigned: usize,
+    next_link_name: Result<String, Box<dyn Error>>,
+) -> Health {
+    match next_link_name {
+        Ok(n) => Health::new(
+            HEALTH_OK,
+            format!(
+                "Prefix {}, {} names assigned, next name {}",
+                prefix, assigned, n
+            ),
+        ),
+        Err(e) if e.is::<IndexPoolExhausted>() => Health::new(HEALTH_INDEX_EXHAUSTED, e),
+        Err(e) => Health::new(HEALTH_INCONSISTENT, e),
+    }
+}
+
+/// Quick consistency check of the configuration and the link files, meant for ExecCondition= and
+/// ExecStartPre= of the units running the helper. Kernel command line without the prefix isn't
+/// an error, there is just nothing to do.
+pub fn healthcheck(options: &[(String, String)]) -> Health {
+    let resolver = match Resolver::load(options) {
+        Ok(r) => r,
+        Err(e) => {
+            return Health::new(
+                HEALTH_INCONSISTENT,
+                format!("Failed to load settings: {}", e),
+            )
+        }
+    };
+    let prefix = resolver.prefix();
+
+    if prefix.is_empty() {
+        return Health::new(HEALTH_NO_PREFIX, "No prefix specified");
+    }
+
+    if !prefix_ok(&prefix) {
+        return Health::new(
+            HEALTH_INCONSISTENT,
+            format!("Invalid prefix \"{}\"", prefix),
+        );
+    }
+
+    let settings = match resolver.settings() {
+        Ok(s) => s,
+        Err(e) => {
+            return Health::new(
+                HEALTH_INCONSISTENT,
+                format!("Failed to load settings: {}", e),
+            )
+        }
+    };
+
+    let mut config = NetSetupLinkConfig::new_with_prefix(&prefix);
+    config.apply_settings(&settings);
+
+    if let Err(e) = config.load() {
+        return Health::new(
+            HEALTH_INCONSISTENT,
+            format!("Failed to load current state of network links: {}", e),
+        );
+    }
+
+    state_health(&prefix, config.links().len(), config.next_link_name())
+}
+
+/// Sends the state to the service manager, see sd_notify(3). Socket names starting with '@' are
+/// in the abstract namespace.
+pub fn notify_to(socket: &str, state: &str) -> Result<(), Box<dyn Error>> {
+    let sock = UnixDatagram::unbound()?;
+
+    match socket.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            sock.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        None => {
+            sock.send_to(state.as_bytes(), socket)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reports the status text when running under the service manager, does nothing otherwise.
+pub fn notify_status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+/// Sends the state (e.g. READY=1 or WATCHDOG=1) when running under the service manager.
+pub fn notify(state: &str) {
+    if let Ok(socket) = std::env::var("NOTIFY_SOCKET") {
+        if let Err(e) = notify_to(&socket, state) {
+            debug!("Failed to notify service manager: {}", e);
+        }
+    }
+}
+
+/// Watchdog timeout requested by WatchdogSec= of the service, see sd_watchdog_enabled(3). It
+/// applies to the process only if WATCHDOG_PID is unset or names it.
+pub fn watchdog_timeout(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|p| p.parse::<u32>().ok() != Some(own_pid)) {
+        return None;
+    }
+
+    usec?
+        .parse::<u64>()
+        .ok()
+        .filter(|u| *u > 0)
+        .map(Duration::from_micros)
+}
+
+pub fn service_watchdog_timeout() -> Option<Duration> {
+    watchdog_timeout(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_state() {
+        assert_eq!(
+            state_health("net", 2, Ok("net2".to_string())),
+            Health::new(HEALTH_OK, "Prefix net, 2 names assigned, next name net2")
+        );
+        assert_eq!(
+            state_health(
+                "net",
+                2,
+                Err(Box::new(IndexPoolExhausted {
+                    prefix: "net".to_string(),
+                    max_index: 1
+                }))
+            )
+            .code,
+            HEALTH_INDEX_EXHAUSTED
+        );
+        assert_eq!(
+            state_health("net", 0, Err(From::from("Name too long"))).code,
+            HEALTH_INCONSISTENT
+        );
+    }
+
+    #[test]
+    fn health_notify() {
+        let path =
+            std::env::temp_dir().join(format!("prefixdevname-notify-{}", std::process::id()));
+        let listener = UnixDatagram::bind(&path).unwrap();
+        let mut buf = [0u8; 64];
+
+        notify_to(&path.to_string_lossy(), "STATUS=ok").unwrap();
+        let n = listener.recv(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&buf[..n], b"STATUS=ok");
+        assert!(notify_to("/nonexistent/socket", "STATUS=ok").is_err());
+    }
+
+    #[test]
+    fn health_watchdog_timeout() {
+        assert_eq!(
+            watchdog_timeout(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_timeout(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_timeout(Some("30000000"), Some("1"), 42), None);
+        assert_eq!(watchdog_timeout(Some("0"), None, 42), None);
+        assert_eq!(watchdog_timeout(Some("bogus"), None, 42), None);
+        assert_eq!(watchdog_timeout(None, None, 42), None);
+    }
+}
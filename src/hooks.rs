@@ -0,0 +1,250 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub static HOOKS_DIR: &str = "/etc/prefixdevname/hooks.d";
+pub static DEFERRED_HOOKS_FILE: &str = "/run/prefixdevname/hooks.deferred";
+
+/// Hook still running after the timeout is killed, the udev event must not wait for it.
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Assignment passed to the hooks in the environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookEvent {
+    pub name: String,
+    pub hwaddr: String,
+    pub link_file: PathBuf,
+    pub action: String,
+}
+
+/// Executable files in the directory ordered by their name. Hidden files and backups of editors
+/// are ignored.
+pub fn hook_scripts<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(From::from(e)),
+    };
+    let mut hooks = Vec::new();
+
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        if name.starts_with('.') || name.ends_with('~') {
+            continue;
+        }
+
+        match fs::metadata(&path) {
+            Ok(m) if m.is_file() && m.permissions().mode() & 0o111 != 0 => hooks.push(path),
+            _ => {}
+        }
+    }
+
+    hooks.sort();
+    Ok(hooks)
+}
+
+fn run_hook(hook: &Path, event: &HookEvent, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    // Standard output of the helper is the name for udev, hooks must not write there
+    let mut child = Command::new(hook)
+        .env("NAME", &event.name)
+        .env("MAC", &event.hwaddr)
+        .env("LINK_FILE", &event.link_file)
+        .env("ACTION", &event.action)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let deadline = Instant::now() + timeout;
+
+    let status = loop {
+        if let Some(s) = child.try_wait()? {
+            break s;
+        }
+
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(From::from(format!(
+                "hook killed after {}s",
+                timeout.as_secs_f64()
+            )));
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    if !status.success() {
+        return Err(From::from(format!("hook exited with {}", status)));
+    }
+
+    Ok(())
+}
+
+/// Runs the hooks one after another after the link file was written successfully. Hooks run
+/// synchronously, also within the udev event, hence each of them is killed after HOOK_TIMEOUT.
+/// Failure of the hook doesn't stop the others.
+pub fn run_hooks<P: AsRef<Path>>(dir: P, event: &HookEvent) {
+    let hooks = match hook_scripts(dir.as_ref()) {
+        Ok(h) => h,
+        Err(e) => {
+            warn!("Failed to list hooks in {}: {}", dir.as_ref().display(), e);
+            return;
+        }
+    };
+
+    for hook in hooks {
+        match run_hook(&hook, event, HOOK_TIMEOUT) {
+            Ok(()) => debug!("Hook {} finished", hook.display()),
+            Err(e) => warn!("Hook {} failed: {}", hook.display(), e),
+        }
+    }
+}
+
+/// Queues the assignment for the hooks. The generator runs before the system is up, the hooks
+/// are run by the next udev event instead.
+pub fn defer_hooks<P: AsRef<Path>>(file: P, event: &HookEvent) -> Result<(), Box<dyn Error>> {
+    let file = file.as_ref();
+
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut f = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)?;
+    writeln!(
+        f,
+        "{}\t{}\t{}\t{}",
+        event.name,
+        event.hwaddr,
+        event.link_file.display(),
+        event.action
+    )?;
+
+    Ok(())
+}
+
+/// Takes over the queued assignments. The queue is renamed first, concurrent udev events don't
+/// run the same hooks twice.
+pub fn take_deferred_hooks<P: AsRef<Path>>(file: P) -> Result<Vec<HookEvent>, Box<dyn Error>> {
+    let file = file.as_ref();
+    let taken = file.with_extension(format!("{}", std::process::id()));
+
+    match fs::rename(file, &taken) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(From::from(e)),
+    }
+
+    let content = fs::read_to_string(&taken);
+    let _ = fs::remove_file(&taken);
+
+    Ok(content?
+        .lines()
+        .filter_map(|l| match l.split('\t').collect::<Vec<_>>()[..] {
+            [name, hwaddr, link_file, action] => Some(HookEvent {
+                name: name.to_string(),
+                hwaddr: hwaddr.to_string(),
+                link_file: PathBuf::from(link_file),
+                action: action.to_string(),
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hooks_run() {
+        let dir = std::env::temp_dir().join(format!("prefixdevname-hooks-{}", std::process::id()));
+        let out = dir.join("out");
+        let hooks_dir = dir.join("hooks.d");
+        let script = |name: &str, content: &str, mode: u32| {
+            let path = hooks_dir.join(name);
+            fs::write(&path, content).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            path
+        };
+
+        fs::create_dir_all(&hooks_dir).unwrap();
+        let first = script(
+            "10-record",
+            &format!(
+                "#!/bin/sh\necho \"$ACTION $NAME $MAC $LINK_FILE\" >> {}\n",
+                out.display()
+            ),
+            0o755,
+        );
+        let second = script("20-fail", "#!/bin/sh\nexit 1\n", 0o755);
+        script("30-not-executable", "#!/bin/sh\n", 0o644);
+        script(".hidden", "#!/bin/sh\n", 0o755);
+
+        assert_eq!(hook_scripts(&hooks_dir).unwrap(), vec![first, second]);
+        assert!(hook_scripts(dir.join("missing")).unwrap().is_empty());
+
+        let event = HookEvent {
+            name: "net0".to_string(),
+            hwaddr: "00:11:22:33:44:55".to_string(),
+            link_file: PathBuf::from("/etc/systemd/network/71-net-ifnames-prefix-net0.link"),
+            action: "add".to_string(),
+        };
+        run_hooks(&hooks_dir, &event);
+
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "add net0 00:11:22:33:44:55 /etc/systemd/network/71-net-ifnames-prefix-net0.link\n"
+        );
+
+        let slow = script("40-slow", "#!/bin/sh\nexec sleep 10\n", 0o755);
+        let started = Instant::now();
+        assert!(run_hook(&slow, &event, Duration::from_millis(100)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hooks_deferred() {
+        let dir = std::env::temp_dir().join(format!(
+            "prefixdevname-hooks-deferred-{}",
+            std::process::id()
+        ));
+        let queue = dir.join("hooks.deferred");
+        let event = |name: &str| HookEvent {
+            name: name.to_string(),
+            hwaddr: "00:11:22:33:44:55".to_string(),
+            link_file: PathBuf::from(format!(
+                "/etc/systemd/network/71-net-ifnames-prefix-{}.link",
+                name
+            )),
+            action: "generator".to_string(),
+        };
+
+        assert!(take_deferred_hooks(&queue).unwrap().is_empty());
+
+        defer_hooks(&queue, &event("net0")).unwrap();
+        defer_hooks(&queue, &event("net1")).unwrap();
+
+        assert_eq!(
+            take_deferred_hooks(&queue).unwrap(),
+            vec![event("net0"), event("net1")]
+        );
+        assert!(take_deferred_hooks(&queue).unwrap().is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
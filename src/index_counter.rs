@@ -0,0 +1,64 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub static INDEX_COUNTER_DIR: &str = "/run/prefixdevname";
+
+/// Next index to try for the prefix. The counter lets concurrent invocations allocate names
+/// without taking the global lock and enumerating all links, it is only a hint though, link
+/// files are created exclusively and taken names are skipped. /run is empty after boot, hence
+/// the first invocation reconciles the counter with the full state of links.
+pub fn index_counter_path<P: AsRef<Path>>(dir: P, prefix: &str) -> PathBuf {
+    dir.as_ref().join(format!("{}.next-index", prefix))
+}
+
+pub fn read_index_counter<P: AsRef<Path>>(path: P) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Counter is replaced atomically, readers see either the old or the new value.
+pub fn store_index_counter<P: AsRef<Path>>(path: P, next: u64) -> Result<(), Box<dyn Error>> {
+    let path = path.as_ref();
+    let mut tmp_name = path.as_os_str().to_os_string();
+
+    // Each writer needs its own temporary file, there is no lock held
+    tmp_name.push(format!(".{}", std::process::id()));
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    fs::write(&tmp_name, format!("{}\n", next))?;
+    fs::rename(&tmp_name, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_counter() {
+        let dir = std::env::temp_dir().join(format!(
+            "prefixdevname-index-counter-{}",
+            std::process::id()
+        ));
+        let path = index_counter_path(&dir, "net");
+
+        assert_eq!(read_index_counter(&path), None);
+
+        store_index_counter(&path, 3).unwrap();
+        assert_eq!(read_index_counter(&path), Some(3));
+
+        store_index_counter(&path, 4).unwrap();
+        assert_eq!(read_index_counter(&path), Some(4));
+
+        fs::write(&path, "garbage").unwrap();
+        assert_eq!(read_index_counter(&path), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
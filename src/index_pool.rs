@@ -0,0 +1,208 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::naming_policy::PolicyDevice;
+
+/// Topology of the device that selects its index pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locality {
+    NumaNode(u32),
+    PciDomain(u32),
+}
+
+impl fmt::Display for Locality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Locality::NumaNode(n) => write!(f, "NUMA node {}", n),
+            Locality::PciDomain(d) => write!(f, "PCI domain {:04x}", d),
+        }
+    }
+}
+
+/// Range of indexes assigned to the devices of one NUMA node or PCI domain, the last index is
+/// limited only by MaxIndex= when not given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexPool {
+    pub locality: Locality,
+    pub first: u64,
+    pub last: Option<u64>,
+}
+
+impl IndexPool {
+    pub fn contains(&self, index: u64) -> bool {
+        index >= self.first && self.last.is_none_or(|l| index <= l)
+    }
+}
+
+fn parse_locality(value: &str) -> Option<Locality> {
+    match value.split_once(':')? {
+        ("numa", n) => n.parse().ok().map(Locality::NumaNode),
+        ("pci-domain", d) if !d.is_empty() && d.len() <= 4 => {
+            u32::from_str_radix(d, 16).ok().map(Locality::PciDomain)
+        }
+        _ => None,
+    }
+}
+
+fn parse_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (first, last) = value.split_once('-')?;
+    let first = first.parse().ok()?;
+    let last = match last {
+        "" => None,
+        l => Some(l.parse().ok().filter(|l| *l >= first)?),
+    };
+
+    Some((first, last))
+}
+
+/// Parses whitespace separated list of LOCALITY=FIRST-[LAST] entries, e.g.
+/// "numa:0=0-31 numa:1=32-". Pools of one kind only can be used, a device belongs to both its
+/// NUMA node and its PCI domain.
+pub fn parse_index_pools(value: &str) -> Result<Vec<IndexPool>, Box<dyn Error>> {
+    let mut pools: Vec<IndexPool> = Vec::new();
+
+    for entry in value.split_whitespace() {
+        let pool = entry
+            .split_once('=')
+            .and_then(|(l, r)| {
+                let (first, last) = parse_range(r)?;
+
+                Some(IndexPool {
+                    locality: parse_locality(l)?,
+                    first,
+                    last,
+                })
+            })
+            .ok_or_else(|| {
+                format!(
+                    "Invalid value \"{}\" of IndexPools=, expected numa:NODE=FIRST-[LAST] or pci-domain:DOMAIN=FIRST-[LAST]",
+                    entry
+                )
+            })?;
+
+        if let Some(p) = pools
+            .iter()
+            .find(|p| std::mem::discriminant(&p.locality) != std::mem::discriminant(&pool.locality))
+        {
+            return Err(From::from(format!(
+                "Pools of {} and {} can't be combined in IndexPools=",
+                p.locality, pool.locality
+            )));
+        }
+
+        if let Some(p) = pools.iter().find(|p| {
+            p.locality == pool.locality || p.contains(pool.first) || pool.contains(p.first)
+        }) {
+            return Err(From::from(format!(
+                "Pools of {} and {} overlap in IndexPools=",
+                p.locality, pool.locality
+            )));
+        }
+
+        pools.push(pool);
+    }
+
+    Ok(pools)
+}
+
+/// NUMA node of the device, the kernel reports -1 when the platform doesn't tell.
+pub fn numa_node_for_syspath(syspath: &Path) -> Option<u32> {
+    fs::read_to_string(syspath.join("device/numa_node"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Domain of the PCI address, e.g. 0001 of 0001:3b:00.0.
+pub fn pci_domain(pci_address: &str) -> Option<u32> {
+    let (domain, _) = pci_address.split_once(':')?;
+
+    u32::from_str_radix(domain, 16).ok()
+}
+
+pub fn pool_for_device<'a>(pools: &'a [IndexPool], device: &PolicyDevice) -> Option<&'a IndexPool> {
+    pools.iter().find(|p| match p.locality {
+        Locality::NumaNode(n) => device.numa_node == Some(n),
+        Locality::PciDomain(d) => device
+            .pci_address
+            .as_deref()
+            .and_then(pci_domain)
+            .is_some_and(|a| a == d),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_pool_parse() {
+        assert_eq!(
+            parse_index_pools("numa:0=0-31 numa:1=32-").unwrap(),
+            vec![
+                IndexPool {
+                    locality: Locality::NumaNode(0),
+                    first: 0,
+                    last: Some(31),
+                },
+                IndexPool {
+                    locality: Locality::NumaNode(1),
+                    first: 32,
+                    last: None,
+                },
+            ]
+        );
+        assert_eq!(
+            parse_index_pools("pci-domain:0001=16-23").unwrap()[0].locality,
+            Locality::PciDomain(1)
+        );
+        assert!(parse_index_pools("").unwrap().is_empty());
+
+        for value in [
+            "numa:0",
+            "numa:x=0-1",
+            "numa:0=5-1",
+            "numa:0=-5",
+            "socket:0=0-1",
+            "pci-domain:00001=0-1",
+            "numa:0=0-31 numa:1=16-",
+            "numa:0=32- numa:1=0-40",
+            "numa:0=0-1 numa:0=2-3",
+            "numa:0=0-1 pci-domain:0=2-3",
+        ] {
+            assert!(parse_index_pools(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn index_pool_for_device() {
+        let pools = parse_index_pools("pci-domain:0000=0-15 pci-domain:0001=16-").unwrap();
+        let device = |address: &str| PolicyDevice {
+            hwaddr: "00:11:22:33:44:55".to_string(),
+            pci_address: Some(address.to_string()),
+            numa_node: None,
+        };
+
+        assert_eq!(
+            pool_for_device(&pools, &device("0001:3b:00.0")).map(|p| p.first),
+            Some(16)
+        );
+        assert_eq!(
+            pool_for_device(&pools, &device("0000:00:03.0")).map(|p| p.first),
+            Some(0)
+        );
+        assert_eq!(pool_for_device(&pools, &device("0002:00:03.0")), None);
+        assert_eq!(pool_for_device(&pools, &device("virtio0")), None);
+
+        let pools = parse_index_pools("numa:1=32-").unwrap();
+        let mut d = device("0000:00:03.0");
+        assert_eq!(pool_for_device(&pools, &d), None);
+        d.numa_node = Some(1);
+        assert_eq!(pool_for_device(&pools, &d).map(|p| p.first), Some(32));
+    }
+}
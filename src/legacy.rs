@@ -0,0 +1,289 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::util::hwaddr_normalize;
+
+pub static IFCFG_DIR: &str = "/etc/sysconfig/network-scripts";
+/// Rule of initscripts renaming devices according to DEVICE= and HWADDR= of the ifcfg files.
+pub static NET_RULES_FILE: &str = "60-net.rules";
+/// Rules written by the persistent-net generator of old udev, e.g. 70-persistent-net.rules.
+pub static PERSISTENT_NET_RULES_SUFFIX: &str = "persistent-net.rules";
+// From the most important, the same file name in earlier directory masks the later ones
+pub static UDEV_RULES_DIRS: [&str; 4] = [
+    "/etc/udev/rules.d",
+    "/run/udev/rules.d",
+    "/usr/lib/udev/rules.d",
+    "/lib/udev/rules.d",
+];
+
+/// The rules file is in effect unless it is missing or masked by an empty file or a symlink to
+/// /dev/null.
+pub fn net_rules_active(dirs: &[&str]) -> bool {
+    for dir in dirs {
+        let path = Path::new(dir).join(NET_RULES_FILE);
+
+        match fs::metadata(&path) {
+            Ok(m) => return m.is_file() && m.len() > 0,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // Dangling symlink masks the file as well
+                if fs::symlink_metadata(&path).is_ok() {
+                    return false;
+                }
+            }
+            Err(_) => return false,
+        }
+    }
+
+    false
+}
+
+// Values of ifcfg files are shell assignments, optionally quoted
+fn ifcfg_value<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+    content
+        .lines()
+        .filter_map(|l| l.trim().split_once('='))
+        .filter(|(k, _)| k.trim() == key)
+        .map(|(_, v)| v.trim().trim_matches(|c| c == '"' || c == '\''))
+        .next_back()
+        .filter(|v| !v.is_empty())
+}
+
+/// Name given to the device with the MAC address by ifcfg files in the directory, i.e. the name
+/// the 60-net.rules assign. DEVICE= wins over the name in the file name, as in rename_device.
+pub fn ifcfg_name<P: AsRef<Path>>(dir: P, hwaddr: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let entries = match fs::read_dir(dir.as_ref()) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(From::from(e)),
+    };
+    let mut paths: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("ifcfg-") && !n.ends_with('~'))
+        })
+        .collect();
+
+    paths.sort();
+
+    for path in paths {
+        let content = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+
+        match ifcfg_value(&content, "HWADDR").map(|a| hwaddr_normalize(&a)) {
+            Some(Ok(a)) if a.eq_ignore_ascii_case(hwaddr) => {}
+            _ => continue,
+        }
+
+        let name = match ifcfg_value(&content, "DEVICE") {
+            Some(d) => d.to_string(),
+            None => {
+                path.file_name().unwrap_or_default().to_string_lossy()["ifcfg-".len()..].to_string()
+            }
+        };
+
+        return Ok(Some(name));
+    }
+
+    Ok(None)
+}
+
+/// Name assigned to the device by the legacy 60-net.rules, if they are in effect.
+pub fn legacy_rule_name(hwaddr: &str) -> Option<String> {
+    if !net_rules_active(&UDEV_RULES_DIRS) {
+        return None;
+    }
+
+    ifcfg_name(IFCFG_DIR, hwaddr).unwrap_or_else(|e| {
+        warn!("Failed to read ifcfg files in {}: {}", IFCFG_DIR, e);
+        None
+    })
+}
+
+/// Name assigned by the rule of persistent-net rules file, the MAC address is unknown when the
+/// rule matches the device differently, e.g. by KERNELS==.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleName {
+    pub name: String,
+    pub hwaddr: Option<String>,
+}
+
+/// Names assigned by the rules, e.g.
+/// SUBSYSTEM=="net", ACTION=="add", ATTR{address}=="52:54:00:1c:08:b7", NAME="net0"
+pub fn parse_persistent_net_rules(content: &str) -> Vec<RuleName> {
+    let mut names = Vec::new();
+
+    for line in content.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut name = None;
+        let mut hwaddr = None;
+
+        for token in line.split(',').map(|t| t.trim()) {
+            let unquote = |v: &str| v.trim().trim_matches('"').to_string();
+
+            if let Some(v) = token.strip_prefix("ATTR{address}==") {
+                hwaddr = hwaddr_normalize(&unquote(v)).ok();
+            } else if let Some(v) = token
+                .strip_prefix("NAME:=")
+                .or_else(|| token.strip_prefix("NAME="))
+                .filter(|v| !v.starts_with('='))
+            {
+                name = Some(unquote(v)).filter(|n| !n.is_empty());
+            }
+        }
+
+        if let Some(name) = name {
+            names.push(RuleName { name, hwaddr });
+        }
+    }
+
+    names
+}
+
+/// Names assigned by the persistent-net rules files in effect. The file in earlier directory
+/// masks the file with the same name in the later ones.
+pub fn persistent_net_rule_names(dirs: &[&str]) -> Vec<RuleName> {
+    let mut seen = std::collections::HashSet::new();
+    let mut names = Vec::new();
+
+    for dir in dirs {
+        let mut paths: Vec<_> = match fs::read_dir(dir) {
+            Ok(e) => e.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(_) => continue,
+        };
+        paths.sort();
+
+        for path in paths {
+            let file_name = path.file_name().unwrap_or_default().to_owned();
+
+            if !file_name
+                .to_string_lossy()
+                .ends_with(PERSISTENT_NET_RULES_SUFFIX)
+                || !seen.insert(file_name)
+            {
+                continue;
+            }
+
+            match fs::read_to_string(&path) {
+                Ok(c) => names.extend(parse_persistent_net_rules(&c)),
+                Err(e) => debug!("Failed to read {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_ifcfg_name() {
+        let dir = std::env::temp_dir().join(format!("prefixdevname-ifcfg-{}", std::process::id()));
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("ifcfg-eth0"),
+            "TYPE=Ethernet\nHWADDR=\"a4:bb:6d:1c:08:b7\"\nDEVICE=lan0\n",
+        )
+        .unwrap();
+        fs::write(dir.join("ifcfg-eth1"), "HWADDR=A4:BB:6D:1C:08:B8\n").unwrap();
+        fs::write(dir.join("ifcfg-lo"), "DEVICE=lo\n").unwrap();
+
+        assert_eq!(
+            ifcfg_name(&dir, "A4:BB:6D:1C:08:B7").unwrap().as_deref(),
+            Some("lan0")
+        );
+        assert_eq!(
+            ifcfg_name(&dir, "A4:BB:6D:1C:08:B8").unwrap().as_deref(),
+            Some("eth1")
+        );
+        assert_eq!(ifcfg_name(&dir, "A4:BB:6D:1C:08:B9").unwrap(), None);
+        assert_eq!(
+            ifcfg_name(dir.join("missing"), "A4:BB:6D:1C:08:B7").unwrap(),
+            None
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_net_rules() {
+        let root =
+            std::env::temp_dir().join(format!("prefixdevname-netrules-{}", std::process::id()));
+        let (etc, usr) = (root.join("etc"), root.join("usr"));
+        let dirs = [etc.to_str().unwrap(), usr.to_str().unwrap()];
+
+        fs::create_dir_all(&etc).unwrap();
+        fs::create_dir_all(&usr).unwrap();
+        assert!(!net_rules_active(&dirs));
+
+        fs::write(
+            usr.join(NET_RULES_FILE),
+            "IMPORT{program}=\"rename_device\"\n",
+        )
+        .unwrap();
+        assert!(net_rules_active(&dirs));
+
+        std::os::unix::fs::symlink("/dev/null", etc.join(NET_RULES_FILE)).unwrap();
+        assert!(!net_rules_active(&dirs));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn legacy_persistent_net_rules() {
+        let content = r#"# This file was automatically generated by the /lib/udev/write_net_rules
+# PCI device 0x8086:0x10d3 (e1000e)
+SUBSYSTEM=="net", ACTION=="add", DRIVERS=="?*", ATTR{address}=="52:54:00:1c:08:b7", ATTR{type}=="1", KERNEL=="eth*", NAME="net0"
+SUBSYSTEM=="net", ACTION=="add", KERNELS=="0000:00:04.0", NAME:="net3"
+SUBSYSTEM=="net", ACTION=="add", NAME=="eth0", RUN+="/bin/true"
+"#;
+
+        assert_eq!(
+            parse_persistent_net_rules(content),
+            vec![
+                RuleName {
+                    name: "net0".to_string(),
+                    hwaddr: Some("52:54:00:1C:08:B7".to_string()),
+                },
+                RuleName {
+                    name: "net3".to_string(),
+                    hwaddr: None,
+                },
+            ]
+        );
+
+        let root =
+            std::env::temp_dir().join(format!("prefixdevname-persistent-{}", std::process::id()));
+        let (etc, usr) = (root.join("etc"), root.join("usr"));
+        let dirs = [etc.to_str().unwrap(), usr.to_str().unwrap()];
+
+        fs::create_dir_all(&etc).unwrap();
+        fs::create_dir_all(&usr).unwrap();
+        fs::write(etc.join("70-persistent-net.rules"), content).unwrap();
+        fs::write(usr.join("70-persistent-net.rules"), "NAME=\"net9\"\n").unwrap();
+        fs::write(usr.join("75-persistent-net.rules"), "NAME=\"net7\"\n").unwrap();
+        fs::write(usr.join("80-net-setup-link.rules"), "NAME=\"net8\"\n").unwrap();
+
+        let names: Vec<String> = persistent_net_rule_names(&dirs)
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(names, vec!["net0", "net3", "net7"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,524 @@
+// SPDX-License-Identifier:  MIT
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ini::{Ini, Properties};
+use libudev::Device;
+
+use crate::config::{file_has_provenance_marker, our_link_file_name};
+use crate::util::*;
+
+// Directories searched by systemd-udevd for .link files, in order of precedence
+pub static LINK_FILE_SEARCH_PATH: [&str; 5] = [
+    "/etc/systemd/network",
+    "/run/systemd/network",
+    "/usr/local/lib/systemd/network",
+    "/usr/lib/systemd/network",
+    "/lib/systemd/network",
+];
+
+/// Properties of the network device that can be tested in the [Match] section of a .link file.
+#[derive(Debug, Clone, Default)]
+pub struct MatchedDevice {
+    pub hwaddr: String,
+    pub permanent_hwaddr: Option<String>,
+    pub path: Option<String>,
+    pub driver: Option<String>,
+    pub devtype: Option<String>,
+    pub original_name: String,
+    pub properties: HashMap<String, String>,
+}
+
+impl MatchedDevice {
+    /// Collects properties of the event device. Properties set by earlier udev rules and builtins
+    /// are passed to us in the environment.
+    pub fn from_event_device(hwaddr: &str) -> MatchedDevice {
+        let properties: HashMap<String, String> = env::vars().collect();
+        let ifname = event_device_name();
+
+        MatchedDevice {
+            hwaddr: hwaddr.to_string(),
+            permanent_hwaddr: hwaddr_permanent(&ifname).ok(),
+            path: properties.get("ID_PATH").cloned(),
+            driver: properties.get("ID_NET_DRIVER").cloned(),
+            devtype: properties.get("DEVTYPE").cloned(),
+            original_name: ifname,
+            properties,
+        }
+    }
+
+    /// Same as from_event_device(), but properties are taken from the udev database entry of the
+    /// device.
+    pub fn from_device(device: &Device, hwaddr: &str) -> MatchedDevice {
+        let properties: HashMap<String, String> = device
+            .properties()
+            .map(|p| {
+                (
+                    p.name().to_string_lossy().to_string(),
+                    p.value().to_string_lossy().to_string(),
+                )
+            })
+            .collect();
+        let ifname = device
+            .sysname()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        MatchedDevice {
+            hwaddr: hwaddr.to_string(),
+            permanent_hwaddr: hwaddr_permanent(&ifname).ok(),
+            path: properties.get("ID_PATH").cloned(),
+            driver: device
+                .driver()
+                .map(|d| d.to_string_lossy().to_string())
+                .or_else(|| properties.get("ID_NET_DRIVER").cloned()),
+            devtype: properties.get("DEVTYPE").cloned(),
+            original_name: ifname,
+            properties,
+        }
+    }
+}
+
+pub fn fnmatch(pattern: &str, s: &str) -> bool {
+    let (p, s) = match (CString::new(pattern), CString::new(s)) {
+        (Ok(p), Ok(s)) => (p, s),
+        _ => return false,
+    };
+
+    unsafe { libc::fnmatch(p.as_ptr(), s.as_ptr(), 0) == 0 }
+}
+
+/// Returns all values of the key as a single list. Like systemd, assigning the empty string resets
+/// the list.
+fn match_values(section: &Properties, key: &str) -> Option<Vec<String>> {
+    let mut values: Option<Vec<String>> = None;
+
+    for v in section.get_all(key) {
+        if v.trim().is_empty() {
+            values = Some(Vec::new());
+            continue;
+        }
+
+        values
+            .get_or_insert_with(Vec::new)
+            .extend(v.split_whitespace().map(|s| s.to_string()));
+    }
+
+    values.filter(|v| !v.is_empty())
+}
+
+/// Glob list with optional leading "!" that inverts the whole test.
+fn match_glob_list(patterns: &[String], value: Option<&str>) -> bool {
+    let (invert, patterns) = match patterns.first() {
+        Some(p) if p.starts_with('!') => {
+            let mut p = patterns.to_vec();
+            p[0] = p[0].trim_start_matches('!').to_string();
+            (true, p.into_iter().filter(|p| !p.is_empty()).collect())
+        }
+        _ => (false, patterns.to_vec()),
+    };
+
+    let matched = match value {
+        Some(v) => patterns.iter().any(|p| fnmatch(p, v)),
+        None => false,
+    };
+
+    matched != invert
+}
+
+fn match_hwaddr_list(addrs: &[String], hwaddr: Option<&str>) -> bool {
+    let hwaddr = match hwaddr.and_then(|a| hwaddr_normalize(&a).ok()) {
+        Some(a) => a,
+        None => return false,
+    };
+
+    addrs
+        .iter()
+        .filter_map(|a| hwaddr_normalize(a).ok())
+        .any(|a| a == hwaddr)
+}
+
+fn match_property_list(properties: &[String], device: &MatchedDevice) -> bool {
+    properties.iter().all(|p| {
+        let (key, pattern) = match p.split_once('=') {
+            Some((k, v)) => (k, v),
+            None => (p.as_str(), "*"),
+        };
+
+        match device.properties.get(key) {
+            Some(v) => fnmatch(pattern, v),
+            None => false,
+        }
+    })
+}
+
+/// Evaluates the [Match] section of a .link file against the device. Conditions that don't depend
+/// on the device (Host=, Virtualization=, KernelCommandLine=, ...) are assumed to be satisfied.
+pub fn match_section_matches(section: &Properties, device: &MatchedDevice) -> bool {
+    let mut conditions = 0;
+
+    if let Some(addrs) = match_values(section, "MACAddress") {
+        conditions += 1;
+        if !match_hwaddr_list(&addrs, Some(&device.hwaddr)) {
+            return false;
+        }
+    }
+
+    if let Some(addrs) = match_values(section, "PermanentMACAddress") {
+        conditions += 1;
+        if !match_hwaddr_list(&addrs, device.permanent_hwaddr.as_deref()) {
+            return false;
+        }
+    }
+
+    let globs = [
+        ("Path", device.path.as_deref()),
+        ("Driver", device.driver.as_deref()),
+        ("Type", device.devtype.as_deref().or(Some("ether"))),
+        ("OriginalName", Some(device.original_name.as_str())),
+    ];
+
+    for (key, value) in globs {
+        if let Some(patterns) = match_values(section, key) {
+            conditions += 1;
+            if !match_glob_list(&patterns, value) {
+                return false;
+            }
+        }
+    }
+
+    if let Some(properties) = match_values(section, "Property") {
+        conditions += 1;
+        if !match_property_list(&properties, device) {
+            return false;
+        }
+    }
+
+    // systemd ignores .link files with empty [Match] section
+    conditions > 0
+}
+
+fn link_files_in_search_path(dirs: &[&str]) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name();
+
+            if !name.to_string_lossy().ends_with(".link") {
+                continue;
+            }
+
+            // File in the directory with higher precedence masks files with the same name
+            if seen.insert(name) {
+                files.push(entry.path());
+            }
+        }
+    }
+
+    files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    files
+}
+
+/// Drop-ins of the link file, <file name>.d/*.conf in the given directories ordered by their file
+/// names. Drop-in in the directory with higher precedence masks drop-ins with the same name.
+pub fn link_file_drop_ins_in(dirs: &[&str], file_name: &str) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    let mut drop_ins = Vec::new();
+
+    for dir in dirs {
+        let entries = match fs::read_dir(Path::new(dir).join(format!("{}.d", file_name))) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name();
+
+            if name.to_string_lossy().ends_with(".conf") && seen.insert(name) {
+                drop_ins.push(entry.path());
+            }
+        }
+    }
+
+    drop_ins.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    drop_ins
+}
+
+pub fn link_file_drop_ins(file_name: &str) -> Vec<PathBuf> {
+    link_file_drop_ins_in(&LINK_FILE_SEARCH_PATH, file_name)
+}
+
+// Keys of the [Match] section taking lists, assignments in drop-ins extend the list
+static MATCH_LIST_KEYS: [&str; 8] = [
+    "MACAddress",
+    "PermanentMACAddress",
+    "Path",
+    "Driver",
+    "Type",
+    "Kind",
+    "OriginalName",
+    "Property",
+];
+
+/// Applies the drop-ins in order the way systemd does, the last assignment of a key wins, except
+/// for list-valued [Match] keys that are appended to, and empty value resets the key.
+pub fn merge_drop_ins(conf: &mut Ini, drop_ins: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    for path in drop_ins {
+        let drop_in = Ini::load_from_file(path)
+            .map_err(|e| format!("Failed to parse drop-in {}: {}", path.display(), e))?;
+
+        for (section, properties) in drop_in.iter() {
+            let section = match section {
+                Some(s) => s.to_string(),
+                None => continue,
+            };
+
+            for (k, v) in properties.iter() {
+                if v.is_empty() {
+                    if let Some(p) = conf.section_mut(Some(section.clone())) {
+                        p.remove(k);
+                    }
+                } else if section == "Match" && MATCH_LIST_KEYS.contains(&k) {
+                    match conf.section_mut(Some(section.clone())) {
+                        Some(p) => p.append(k, v),
+                        None => {
+                            conf.with_section(Some(section.clone())).set(k, v);
+                        }
+                    }
+                } else {
+                    conf.with_section(Some(section.clone())).set(k, v);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads the link file together with its drop-ins.
+pub fn load_link_file(path: &Path) -> Result<Ini, Box<dyn Error>> {
+    let mut conf = Ini::load_from_file(path)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    merge_drop_ins(&mut conf, &link_file_drop_ins(&file_name))?;
+    Ok(conf)
+}
+
+/// Finds .link file not generated by us that assigns name to the device, i.e. the device is
+/// already named by configuration provided by the administrator or other tool. Unparseable files
+/// are skipped, unless strict is set.
+pub fn link_file_claiming_device(
+    device: &MatchedDevice,
+    strict: bool,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    link_file_claiming_device_in(&LINK_FILE_SEARCH_PATH, device, strict)
+}
+
+pub fn link_file_claiming_device_in(
+    dirs: &[&str],
+    device: &MatchedDevice,
+    strict: bool,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    for path in link_files_in_search_path(dirs) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        if our_link_file_name(&name) {
+            continue;
+        }
+
+        // Empty files and symlinks to /dev/null are used to mask files in the search path
+        match fs::metadata(&path) {
+            Ok(m) if m.len() > 0 && m.is_file() => {}
+            _ => continue,
+        }
+
+        // Our link file under other file name
+        if file_has_provenance_marker(&path) {
+            continue;
+        }
+
+        let conf = match load_link_file(&path) {
+            Ok(c) => c,
+            Err(e) if strict => {
+                return Err(From::from(format!(
+                    "Failed to parse link file {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+            Err(e) => {
+                warn!("Failed to parse link file {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        let names_device = conf
+            .section(Some("Link"))
+            .and_then(|l| l.get("Name"))
+            .is_some_and(|n| !n.is_empty());
+
+        if !names_device {
+            continue;
+        }
+
+        if let Some(m) = conf.section(Some("Match")) {
+            if match_section_matches(m, device) {
+                return Ok(Some(path));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Name assigned by the [Link] section of the .link file.
+pub fn link_file_name(path: &Path) -> Option<String> {
+    load_link_file(path)
+        .ok()?
+        .section(Some("Link"))?
+        .get("Name")
+        .filter(|n| !n.is_empty())
+        .map(|n| n.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> MatchedDevice {
+        let mut properties = HashMap::new();
+        properties.insert("ID_VENDOR_ID".to_string(), "0x8086".to_string());
+
+        MatchedDevice {
+            hwaddr: "52:54:00:1C:08:B7".to_string(),
+            permanent_hwaddr: None,
+            path: Some("pci-0000:00:03.0".to_string()),
+            driver: Some("e1000".to_string()),
+            devtype: None,
+            original_name: "eth0".to_string(),
+            properties,
+        }
+    }
+
+    fn matches(conf: &str) -> bool {
+        let conf = Ini::load_from_str(conf).unwrap();
+        match_section_matches(conf.section(Some("Match")).unwrap(), &device())
+    }
+
+    #[test]
+    fn link_file_drop_in_merge() {
+        let root =
+            std::env::temp_dir().join(format!("prefixdevname-dropin-{}", std::process::id()));
+        let (etc, usr) = (root.join("etc"), root.join("usr"));
+        let file_name = "71-net-ifnames-prefix-net3.link";
+        let drop_in_dir = |d: &Path| d.join(format!("{}.d", file_name));
+
+        fs::create_dir_all(drop_in_dir(&etc)).unwrap();
+        fs::create_dir_all(drop_in_dir(&usr)).unwrap();
+        fs::write(
+            drop_in_dir(&usr).join("10-mtu.conf"),
+            "[Link]\nMTUBytes=1500\n",
+        )
+        .unwrap();
+        fs::write(
+            drop_in_dir(&usr).join("20-name.conf"),
+            "[Link]\nName=net9\n",
+        )
+        .unwrap();
+        fs::write(
+            drop_in_dir(&etc).join("20-name.conf"),
+            "[Link]\nName=net7\n",
+        )
+        .unwrap();
+        fs::write(
+            drop_in_dir(&etc).join("30-reset.conf"),
+            "[Match]\nDriver=\n[Link]\nMTUBytes=9000\n",
+        )
+        .unwrap();
+        fs::write(
+            drop_in_dir(&etc).join("40-mac.conf"),
+            "[Match]\nMACAddress=52:54:00:1c:08:b8\n",
+        )
+        .unwrap();
+        fs::write(drop_in_dir(&etc).join("README"), "").unwrap();
+
+        let dirs = [etc.to_str().unwrap(), usr.to_str().unwrap()];
+        let drop_ins = link_file_drop_ins_in(&dirs, file_name);
+        assert_eq!(
+            drop_ins,
+            vec![
+                drop_in_dir(&usr).join("10-mtu.conf"),
+                drop_in_dir(&etc).join("20-name.conf"),
+                drop_in_dir(&etc).join("30-reset.conf"),
+                drop_in_dir(&etc).join("40-mac.conf"),
+            ]
+        );
+
+        let mut conf = Ini::load_from_str(
+            "[Match]\nMACAddress=52:54:00:1c:08:b7\nDriver=e1000\n\n[Link]\nName=net3\n",
+        )
+        .unwrap();
+        merge_drop_ins(&mut conf, &drop_ins).unwrap();
+        assert_eq!(conf.get_from(Some("Link"), "Name"), Some("net7"));
+        assert_eq!(conf.get_from(Some("Link"), "MTUBytes"), Some("9000"));
+        assert_eq!(conf.get_from(Some("Match"), "Driver"), None);
+        assert_eq!(
+            conf.section(Some("Match"))
+                .unwrap()
+                .get_all("MACAddress")
+                .collect::<Vec<_>>(),
+            vec!["52:54:00:1c:08:b7", "52:54:00:1c:08:b8"]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn link_match_hwaddr() {
+        assert!(matches("[Match]\nMACAddress=52:54:00:1c:08:b7\n"));
+        assert!(matches(
+            "[Match]\nMACAddress=52:54:00:1c:08:b6 52:54:00:1c:08:b7\n"
+        ));
+        assert!(!matches("[Match]\nMACAddress=52:54:00:1c:08:b6\n"));
+    }
+
+    #[test]
+    fn link_match_permanent_hwaddr_unknown() {
+        assert!(!matches("[Match]\nPermanentMACAddress=52:54:00:1c:08:b7\n"));
+    }
+
+    #[test]
+    fn link_match_globs() {
+        assert!(matches("[Match]\nPath=pci-0000:00:*\nDriver=e1000*\n"));
+        assert!(!matches("[Match]\nPath=pci-0000:00:*\nDriver=ixgbe\n"));
+        assert!(matches("[Match]\nOriginalName=!wl*\n"));
+        assert!(!matches("[Match]\nOriginalName=!eth*\n"));
+        assert!(matches("[Match]\nType=ether\n"));
+    }
+
+    #[test]
+    fn link_match_property() {
+        assert!(matches("[Match]\nProperty=ID_VENDOR_ID=0x8086\n"));
+        assert!(!matches("[Match]\nProperty=ID_VENDOR_ID=0x15b3\n"));
+        assert!(!matches("[Match]\nProperty=ID_MODEL_ID\n"));
+    }
+
+    #[test]
+    fn link_match_empty() {
+        assert!(!matches("[Match]\n"));
+        assert!(!matches("[Match]\nMACAddress=\n"));
+    }
+}
@@ -0,0 +1,298 @@
+// SPDX-License-Identifier:  MIT
+
+use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use crate::config::IFNAME_MAX_LEN;
+use crate::patterns::name_prefix;
+
+/// How the index follows the prefix in the interface name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameSuffix {
+    /// net3, the scheme used for the names we allocate
+    Plain,
+    /// net003, index padded by zeros to the width
+    ZeroPadded { width: usize },
+    /// net0v3, virtual function of the device with the index
+    VirtualFunction { vf: u64 },
+}
+
+/// Interface name split to the prefix and the index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixedName {
+    pub prefix: String,
+    pub index: u64,
+    pub suffix: NameSuffix,
+}
+
+impl PrefixedName {
+    /// Names of our naming scheme occupy the index, VFs are named after their parent device
+    pub fn takes_index(&self) -> bool {
+        !matches!(self.suffix, NameSuffix::VirtualFunction { .. })
+    }
+}
+
+fn parse_number(digits: &str) -> Result<u64, Box<dyn Error>> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(From::from(format!("\"{}\" isn't a number", digits)));
+    }
+
+    Ok(digits.parse::<u64>()?)
+}
+
+/// Splits the name to the prefix and the index. Names must consist of the prefix followed by the
+/// index, which may be padded by zeros (net007) or followed by the index of the virtual function
+/// (net0v1).
+pub fn parse_prefixed_name(prefix: &str, name: &str) -> Result<PrefixedName, Box<dyn Error>> {
+    let rest = name
+        .strip_prefix(prefix)
+        .filter(|_| !prefix.is_empty())
+        .ok_or_else(|| format!("Name \"{}\" doesn't start with prefix \"{}\"", name, prefix))?;
+    let invalid = |e: Box<dyn Error>| -> Box<dyn Error> {
+        From::from(format!(
+            "Name \"{}\" isn't prefix \"{}\" followed by an index: {}",
+            name, prefix, e
+        ))
+    };
+
+    let (digits, vf_digits) = match rest.split_once('v') {
+        Some((d, v)) => (d, Some(v)),
+        None => (rest, None),
+    };
+    let index = parse_number(digits).map_err(invalid)?;
+
+    let suffix = match vf_digits {
+        Some(v) => {
+            let vf = parse_number(v).map_err(invalid)?;
+
+            // Both indexes of VF names are plain, otherwise the names would be ambiguous
+            if index.to_string() != digits || vf.to_string() != v {
+                return Err(invalid(From::from("indexes of VF names can't be padded")));
+            }
+
+            NameSuffix::VirtualFunction { vf }
+        }
+        None if digits.len() > 1 && digits.starts_with('0') => NameSuffix::ZeroPadded {
+            width: digits.len(),
+        },
+        None => NameSuffix::Plain,
+    };
+
+    Ok(PrefixedName {
+        prefix: prefix.to_string(),
+        index,
+        suffix,
+    })
+}
+
+/// Interface name of the form <prefix><index>. It can only be constructed by validation, hence
+/// names used for the link files and their paths are always valid interface names.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LinkName(String);
+
+impl LinkName {
+    pub fn new(name: &str) -> Result<LinkName, Box<dyn Error>> {
+        if name.is_empty() {
+            return Err(From::from("Link name can't be empty string"));
+        }
+
+        if name.len() > IFNAME_MAX_LEN {
+            return Err(From::from(format!(
+                "Link name \"{}\" too long, interface names are limited to {} characters",
+                name, IFNAME_MAX_LEN
+            )));
+        }
+
+        if !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(From::from(format!(
+                "Link name \"{}\" must consist of letters and digits only",
+                name
+            )));
+        }
+
+        let parsed = parse_prefixed_name(&name_prefix(name), name)?;
+
+        if !parsed.takes_index() {
+            return Err(From::from(format!(
+                "Link name \"{}\" is a name of virtual function",
+                name
+            )));
+        }
+
+        Ok(LinkName(name.to_string()))
+    }
+
+    /// Name which must also have the given prefix.
+    pub fn with_prefix(name: &str, prefix: &str) -> Result<LinkName, Box<dyn Error>> {
+        let link_name = LinkName::new(name)?;
+
+        if link_name.prefix() != prefix {
+            return Err(From::from(format!(
+                "Link name \"{}\" doesn't have prefix \"{}\"",
+                name, prefix
+            )));
+        }
+
+        Ok(link_name)
+    }
+
+    pub fn from_index(prefix: &str, index: u64) -> Result<LinkName, Box<dyn Error>> {
+        LinkName::with_prefix(&format!("{}{}", prefix, index), prefix)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.0.trim_end_matches(|c: char| c.is_ascii_digit())
+    }
+
+    pub fn index(&self) -> u64 {
+        // Validated when constructed
+        parse_prefixed_name(self.prefix(), &self.0)
+            .map(|p| p.index)
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for LinkName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for LinkName {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LinkName::new(s)
+    }
+}
+
+impl Deref for LinkName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for LinkName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for LinkName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<LinkName> for String {
+    fn from(name: LinkName) -> String {
+        name.0
+    }
+}
+
+impl PartialEq<str> for LinkName {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for LinkName {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<String> for LinkName {
+    fn eq(&self, other: &String) -> bool {
+        &self.0 == other
+    }
+}
+
+impl PartialEq<LinkName> for String {
+    fn eq(&self, other: &LinkName) -> bool {
+        *self == other.0
+    }
+}
+
+impl PartialEq<LinkName> for &str {
+    fn eq(&self, other: &LinkName) -> bool {
+        *self == other.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_name_valid() {
+        let name = LinkName::new("net12").unwrap();
+
+        assert_eq!(name, "net12");
+        assert_eq!(name.prefix(), "net");
+        assert_eq!(name.index(), 12);
+        assert_eq!(LinkName::from_index("lan", 0).unwrap(), "lan0");
+        assert!(LinkName::with_prefix("net0", "net").is_ok());
+    }
+
+    #[test]
+    fn link_name_parse_suffixes() {
+        let parsed = |name| parse_prefixed_name("net", name).unwrap();
+
+        assert_eq!(
+            parsed("net12"),
+            PrefixedName {
+                prefix: "net".to_string(),
+                index: 12,
+                suffix: NameSuffix::Plain,
+            }
+        );
+        assert_eq!(parsed("net0").suffix, NameSuffix::Plain);
+        assert_eq!(parsed("net007").index, 7);
+        assert_eq!(parsed("net007").suffix, NameSuffix::ZeroPadded { width: 3 });
+        assert_eq!(parsed("net2v13").index, 2);
+        assert_eq!(
+            parsed("net2v13").suffix,
+            NameSuffix::VirtualFunction { vf: 13 }
+        );
+        assert!(!parsed("net2v13").takes_index());
+
+        for name in [
+            "net", "lan0", "net0a", "netv1", "net0v", "net0v01", "net00v1", "net0v1v2", "net-0",
+        ] {
+            assert!(parse_prefixed_name("net", name).is_err(), "{}", name);
+        }
+        assert!(parse_prefixed_name("", "0").is_err());
+    }
+
+    #[test]
+    fn link_name_invalid() {
+        for name in [
+            "",
+            "net",
+            "0",
+            "net0a",
+            "4net0",
+            "net-0",
+            "../net0",
+            "net0.link",
+            "net0v1",
+            "abcdefghijklmno0",
+            "net99999999999999999999",
+        ] {
+            assert!(LinkName::new(name).is_err(), "{}", name);
+        }
+
+        assert!(LinkName::with_prefix("lan0", "net").is_err());
+        assert!(LinkName::from_index("abcdefghijklmn", 10).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+#[cfg(feature = "tracing-subscriber")]
+use std::io::IsTerminal;
+
+#[cfg(feature = "tracing-subscriber")]
+use tracing_subscriber::fmt::format::FmtSpan;
+#[cfg(feature = "tracing-subscriber")]
+use tracing_subscriber::EnvFilter;
+
+/// When set, the time spent in each phase (cmdline, enumerate, allocate, write) is logged when the
+/// phase ends, e.g. to profile the helper during boot.
+#[cfg(feature = "tracing-subscriber")]
+pub static TRACE_SPANS_ENV: &str = "PREFIXDEVNAME_TRACE_SPANS";
+
+#[cfg(feature = "tracing-subscriber")]
+fn span_events() -> FmtSpan {
+    match std::env::var(TRACE_SPANS_ENV) {
+        Ok(v) if v == "1" || v == "yes" || v == "true" => FmtSpan::CLOSE,
+        _ => FmtSpan::NONE,
+    }
+}
+
+/// Messages are written to stderr and filtered by RUST_LOG as before, only errors are shown by
+/// default. Messages of the log macros are forwarded to the subscriber too. Colors are used only
+/// on a terminal, the journal adds its own timestamps.
+#[cfg(feature = "tracing-subscriber")]
+pub fn try_init_logging() -> Result<(), Box<dyn Error + Send + Sync>> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .without_time()
+        .with_span_events(span_events())
+        .try_init()
+}
+
+#[cfg(not(feature = "tracing-subscriber"))]
+struct StderrLogger;
+
+#[cfg(not(feature = "tracing-subscriber"))]
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Only a plain level is understood, e.g. RUST_LOG=debug
+#[cfg(not(feature = "tracing-subscriber"))]
+fn parse_log_level(value: Option<&str>) -> log::LevelFilter {
+    value
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(log::LevelFilter::Error)
+}
+
+/// Without tracing-subscriber (e.g. the minimal build) messages of the log macros are written
+/// to stderr as they are, the spans aren't reported.
+#[cfg(not(feature = "tracing-subscriber"))]
+pub fn try_init_logging() -> Result<(), Box<dyn Error + Send + Sync>> {
+    log::set_logger(&StderrLogger).map_err(|e| e.to_string())?;
+    log::set_max_level(parse_log_level(std::env::var("RUST_LOG").ok().as_deref()));
+    Ok(())
+}
+
+pub fn init_logging() {
+    if let Err(e) = try_init_logging() {
+        eprintln!("Failed to initialize logging: {}", e);
+    }
+}
+
+#[cfg(all(test, not(feature = "tracing-subscriber")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logging_parse_level() {
+        assert_eq!(parse_log_level(None), log::LevelFilter::Error);
+        assert_eq!(parse_log_level(Some("debug")), log::LevelFilter::Debug);
+        assert_eq!(parse_log_level(Some("WARN")), log::LevelFilter::Warn);
+        assert_eq!(
+            parse_log_level(Some("prefixdevname=debug")),
+            log::LevelFilter::Error
+        );
+    }
+}
@@ -2,106 +2,2706 @@
 
 #[macro_use]
 extern crate log;
-extern crate env_logger;
 extern crate ini;
 extern crate libudev;
+extern crate libudev_sys;
 
+#[cfg(feature = "regex")]
 #[macro_use]
 extern crate lazy_static;
 extern crate libc;
+#[cfg(feature = "regex")]
 extern crate regex;
 
+#[cfg(all(feature = "minimal", feature = "tui"))]
+compile_error!("features \"minimal\" and \"tui\" are mutually exclusive");
+
+// Shadows error! of the log crate, errors are also recorded for the report written when the run
+// fails. The offending path and value, if any, are given before the message, e.g.
+// error!(path = f, value = v; "Failed to ...", ...)
+macro_rules! error {
+    (@record $path:expr, $value:expr; $($arg:tt)+) => {{
+        let message = format!($($arg)+);
+        $crate::error_report::record_error(&message, $path, $value);
+        log::error!("{}", message);
+    }};
+    (path = $path:expr, value = $value:expr; $($arg:tt)+) => {
+        error!(@record Some($path.to_string()), Some($value.to_string()); $($arg)+)
+    };
+    (path = $path:expr; $($arg:tt)+) => {
+        error!(@record Some($path.to_string()), None; $($arg)+)
+    };
+    (value = $value:expr; $($arg:tt)+) => {
+        error!(@record None, Some($value.to_string()); $($arg)+)
+    };
+    ($($arg:tt)+) => {
+        error!(@record None, None; $($arg)+)
+    };
+}
+
+mod audit;
+mod backup;
+#[cfg(not(feature = "minimal"))]
+mod bench;
+#[cfg(not(feature = "minimal"))]
+mod bootloader;
+#[cfg(not(feature = "minimal"))]
+mod cloud_init;
+#[cfg(not(feature = "minimal"))]
+mod cloud_metadata;
+mod cmdline;
+mod conf_loader;
 mod config;
+mod counters;
+#[cfg(not(feature = "minimal"))]
+mod daemon;
+mod debounce;
+mod dmi;
+#[cfg(not(feature = "minimal"))]
+mod drift;
+#[cfg(not(feature = "minimal"))]
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+mod edit;
+mod error_report;
+#[cfg(not(feature = "minimal"))]
+mod healthcheck;
+mod hooks;
+mod index_counter;
+mod index_pool;
+mod legacy;
+mod link_match;
+mod link_name;
+mod logging;
+#[cfg(feature = "manifest")]
+mod manifest;
+#[cfg(feature = "debug-mock")]
+mod mock;
+mod naming_policy;
+#[cfg(not(feature = "minimal"))]
+mod output;
+mod patterns;
+mod plan;
+mod reload;
+mod replay;
+mod resolver;
+mod self_test;
 mod sema;
+mod settings;
+#[cfg(not(feature = "minimal"))]
+mod settle;
+#[cfg(not(feature = "minimal"))]
+mod simulate;
+#[cfg(not(feature = "minimal"))]
+mod stats;
+#[cfg(not(feature = "minimal"))]
+mod status;
+mod transaction;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(not(feature = "minimal"))]
+mod udev_rule;
 mod util;
+mod watchdog;
+
+use audit::*;
+use backup::*;
+#[cfg(not(feature = "minimal"))]
+use bench::*;
+#[cfg(not(feature = "minimal"))]
+use bootloader::*;
+#[cfg(not(feature = "minimal"))]
+use cloud_init::*;
+use cmdline::*;
+use config::*;
+use counters::*;
+#[cfg(not(feature = "minimal"))]
+use daemon::*;
+use debounce::*;
+#[cfg(not(feature = "minimal"))]
+use drift::*;
+#[cfg(feature = "tui")]
+use edit::*;
+use error_report::*;
+#[cfg(not(feature = "minimal"))]
+use healthcheck::*;
+use hooks::*;
+use index_counter::*;
+use legacy::*;
+use link_match::*;
+use link_name::LinkName;
+use logging::*;
+#[cfg(feature = "manifest")]
+use manifest::*;
+use naming_policy::*;
+#[cfg(not(feature = "minimal"))]
+use output::*;
+use plan::*;
+use reload::*;
+use replay::*;
+use resolver::*;
+use self_test::*;
+use sema::*;
+use settings::*;
+#[cfg(not(feature = "minimal"))]
+use settle::*;
+#[cfg(not(feature = "minimal"))]
+use simulate::*;
+#[cfg(not(feature = "minimal"))]
+use stats::*;
+#[cfg(not(feature = "minimal"))]
+use status::*;
+use transaction::*;
+#[cfg(not(feature = "minimal"))]
+use udev_rule::*;
+use util::*;
+use watchdog::*;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+fn path_link_match(reason: &str) -> LinkMatch {
+    match event_device_id_path() {
+        Some(p) => {
+            info!("{}, matching on path {} instead", reason, p);
+            LinkMatch::Path(p)
+        }
+        None => {
+            warn!(
+                "{} and its path is unknown, not generating link file",
+                reason
+            );
+            exit_maybe_unlock(None, 0)
+        }
+    }
+}
+
+/// InfiniBand, CAN or WWAN devices don't have 6 octet MAC address, their link files match on the
+/// path, or on the kernel name when the path is unknown.
+fn non_ethernet_link_match(ifname: &str, link_type: u32) -> LinkMatch {
+    match event_device_id_path() {
+        Some(p) => {
+            info!(
+                "Event device of type {} has no MAC address to match on, matching on path {}",
+                link_type, p
+            );
+            LinkMatch::Path(p)
+        }
+        None => {
+            info!(
+                "Event device of type {} has no MAC address to match on and its path is unknown, matching on its name {}",
+                link_type, ifname
+            );
+            LinkMatch::OriginalName(ifname.to_string())
+        }
+    }
+}
+
+fn property_link_match(reason: &str, property: &str) -> LinkMatch {
+    match std::env::var(property).ok().filter(|v| !v.is_empty()) {
+        Some(v) => {
+            info!(
+                "{}, matching on property {}={} instead",
+                reason, property, v
+            );
+            LinkMatch::Property(format!("{}={}", property, v))
+        }
+        None => {
+            warn!(
+                "{} and it doesn't have property {}, not generating link file",
+                reason, property
+            );
+            exit_maybe_unlock(None, 0)
+        }
+    }
+}
+
+// Exit code used when there is no free index left for the new interface
+pub const EXIT_INDEX_EXHAUSTED: i32 = 3;
+
+// Set by --prefix, --set and --strict, override all other sources
+static OPTIONS: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+// Set by --quiet, commands report the outcome by the exit code only, generate still prints the
+// name for udev
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+#[cfg(not(feature = "minimal"))]
+macro_rules! report {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::SeqCst) {
+            print!($($arg)*);
+        }
+    };
+}
+
+#[cfg(not(feature = "minimal"))]
+macro_rules! reportln {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::SeqCst) {
+            println!($($arg)*);
+        }
+    };
+}
+
+fn options() -> &'static [(String, String)] {
+    OPTIONS.get().map(|o| o.as_slice()).unwrap_or_default()
+}
+
+fn resolver() -> Resolver {
+    match Resolver::load(options()) {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Failed to load settings: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    }
+}
+
+fn load_settings() -> Settings {
+    match resolver().settings() {
+        Ok(mut s) => {
+            match get_ifname_arguments_from_file("/proc/cmdline") {
+                Ok(a) => s.cmdline_ifnames = a,
+                Err(e) => debug!("Failed to read kernel command line: {}", e),
+            }
+            s
+        }
+        Err(e) => {
+            error!("Failed to load settings: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+// Subcommands can't do anything useful without the prefix
+fn required_prefix() -> String {
+    let prefix = resolver().prefix();
+
+    if prefix.is_empty() {
+        error!("No prefix specified");
+        exit_maybe_unlock(None, 1)
+    }
+
+    prefix
+}
+
+#[cfg(not(feature = "minimal"))]
+fn load_config(prefix: &str, settings: &Settings) -> NetSetupLinkConfig {
+    let mut config = NetSetupLinkConfig::new_with_prefix(&prefix);
+    config.apply_settings(settings);
+
+    if let Err(e) = config.load() {
+        error!("Failed to load current state of network links: {}", e);
+        exit_maybe_unlock(None, 1);
+    }
+
+    config
+}
+
+#[cfg(not(feature = "minimal"))]
+// Read-only commands don't take the lock, they must never block or be blocked by naming
+fn verify(format: OutputFormat) {
+    let prefix = required_prefix();
+    let settings = load_settings();
+    let config = load_config(&prefix, &settings);
+
+    let next_link_name = config.next_link_name();
+    let mut record: Record = vec![
+        ("prefix", Value::from(prefix.as_str())),
+        ("maximum_index", Value::from(config.max_index())),
+        ("assigned_names", Value::from(config.links().len() as u64)),
+        (
+            "next_name",
+            match &next_link_name {
+                Ok(n) => Value::status(Status::Ok, n),
+                Err(_) => Value::Null,
+            },
+        ),
+    ];
+
+    // Modified link files may be intentional edits, they are reported but not treated as errors
+    match audit_files(WRITTEN_DIR, NET_SETUP_LINK_CONF_DIR, LINK_FILE_PREFIX) {
+        Ok(d) => {
+            let modified = d
+                .iter()
+                .filter(|d| matches!(d.state, DriftState::Modified(_) | DriftState::Removed))
+                .count();
+
+            record.push((
+                "modified_link_files",
+                if modified == 0 {
+                    Value::from(0)
+                } else {
+                    Value::status(Status::Warning, modified)
+                },
+            ))
+        }
+        Err(e) => warn!("Failed to audit link files: {}", e),
+    }
+
+    let malformed = config.malformed_link_files();
+    record.push((
+        "malformed_link_files",
+        if malformed.is_empty() {
+            Value::from(0)
+        } else {
+            Value::status(Status::Warning, malformed.len())
+        },
+    ));
+    for (path, e) in malformed {
+        warn!("Link file {} is malformed: {}", path.display(), e);
+    }
+
+    match config.find_orphaned_links() {
+        Ok(o) => record.push((
+            "orphaned_link_files",
+            if o.is_empty() {
+                Value::from(0)
+            } else {
+                Value::status(Status::Warning, o.len())
+            },
+        )),
+        Err(e) => warn!("Failed to look for orphaned link files: {}", e),
+    }
+
+    if let Err(e) = &next_link_name {
+        record.push(("error", Value::status(Status::Conflict, e)));
+    }
+
+    report!("{}", render_record(format, &record, color_enabled(format)));
+
+    if let Err(e) = next_link_name {
+        if e.is::<IndexPoolExhausted>() {
+            exit_maybe_unlock(None, EXIT_INDEX_EXHAUSTED);
+        }
+        exit_maybe_unlock(None, 1);
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+fn planned_link_record(l: PlannedLink) -> Record {
+    vec![
+        ("name", Value::from(l.name)),
+        ("hwaddr", Value::from(l.hwaddr)),
+        ("kernel_name", Value::from(l.kernel_name)),
+        ("driver", Value::from(l.driver)),
+        ("speed", Value::from(l.speed.map(|s| format_speed(Some(s))))),
+        ("path", Value::from(l.path)),
+        ("source", Value::status(l.source.status(), &l.source)),
+    ]
+}
+
+#[cfg(not(feature = "minimal"))]
+fn plan(prefix: Option<&str>, format: OutputFormat) {
+    // Prefix can be passed explicitly to check the outcome before it is set on the kernel command line
+    let prefix = match prefix {
+        Some(p) if prefix_ok(&p) => p.to_string(),
+        Some(p) => {
+            error!(value = p; "Invalid prefix \"{}\"", p);
+            exit_maybe_unlock(None, 1)
+        }
+        None => required_prefix(),
+    };
+    let settings = load_settings();
+    let mut config = load_config(&prefix, &settings);
+
+    let links = match plan_links(&mut config, &prefix, &settings) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to predict names of network links: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    let orphans = match config.find_orphaned_links() {
+        Ok(o) => o,
+        Err(e) => {
+            warn!("Failed to look for orphaned link files: {}", e);
+            Vec::new()
+        }
+    };
+
+    let records: Vec<Record> = links
+        .into_iter()
+        .map(planned_link_record)
+        .chain(orphans.iter().map(|o| {
+            let source = PlanSource::Orphaned(o.link_file_path());
+
+            vec![
+                ("name", Value::from(o.name.as_str())),
+                (
+                    "hwaddr",
+                    Value::from(Some(o.hwaddr.as_str()).filter(|a| !a.is_empty())),
+                ),
+                ("kernel_name", Value::Null),
+                ("driver", Value::Null),
+                ("speed", Value::Null),
+                (
+                    "path",
+                    match &o.link_match {
+                        LinkMatch::Path(p) => Value::from(p.as_str()),
+                        _ => Value::Null,
+                    },
+                ),
+                ("source", Value::status(source.status(), &source)),
+            ]
+        }))
+        .collect();
+
+    report!(
+        "{}",
+        render_records(format, &records, color_enabled(format))
+    );
+}
+
+#[cfg(not(feature = "minimal"))]
+// Reproduces the naming of other machine from its device dump, e.g. from the sosreport. Settings of
+// the host aren't used, the configuration file of that machine can be passed by --config.
+fn simulate_naming(args: &[String], format: OutputFormat) {
+    let mut args = args.to_vec();
+    let config_file = match take_option(&mut args, "--config") {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+    let (dump, prefix) = match args.as_slice() {
+        [d, p] => (d, p),
+        _ => {
+            error!("Usage: prefixdevname simulate DUMP PREFIX [--config FILE]");
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    if !prefix_ok(prefix) {
+        error!(value = prefix; "Invalid prefix \"{}\"", prefix);
+        exit_maybe_unlock(None, 1);
+    }
+
+    let settings = match config_file {
+        Some(f) => match ini::Ini::load_from_file(&f)
+            .map_err(From::from)
+            .and_then(|c| Settings::from_ini(&c))
+        {
+            Ok(s) => s,
+            Err(e) => {
+                error!(path = f; "Failed to load configuration file {}: {}", f, e);
+                exit_maybe_unlock(None, 1)
+            }
+        },
+        None => Settings::default(),
+    };
+
+    let links = match load_devices(std::path::Path::new(dump))
+        .and_then(|d| simulate(&d, prefix, &settings))
+    {
+        Ok(l) => l,
+        Err(e) => {
+            error!(path = dump; "Failed to simulate naming of devices in {}: {}", dump, e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    let records: Vec<Record> = links.into_iter().map(planned_link_record).collect();
+
+    report!(
+        "{}",
+        render_records(format, &records, color_enabled(format))
+    );
+}
+
+#[cfg(not(feature = "minimal"))]
+// Not documented in the usage, generates state of a large machine for measuring the scalability,
+// the device list can be passed to the simulate command
+fn bench_prepare(args: &[String]) {
+    let mut args = args.to_vec();
+    let options = take_option(&mut args, "--prefix").and_then(|p| {
+        let new = take_option(&mut args, "--new")?
+            .map(|n| n.parse::<u64>())
+            .transpose()
+            .map_err(|_| "Invalid number of new devices")?;
+        Ok((p.unwrap_or_else(|| "net".to_string()), new.unwrap_or(1)))
+    });
+    let (prefix, new) = match options {
+        Ok(o) => o,
+        Err(e) => {
+            error!("{}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+    let (count, dir) = match args.as_slice() {
+        [c, d] if c.parse::<u64>().is_ok() => (c.parse::<u64>().unwrap(), d),
+        _ => {
+            error!("Usage: prefixdevname bench-prepare COUNT DIR [--prefix PREFIX] [--new N]");
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    if !prefix_ok(&prefix) {
+        error!(value = prefix; "Invalid prefix \"{}\"", prefix);
+        exit_maybe_unlock(None, 1);
+    }
+
+    match write_fake_state(std::path::Path::new(dir), &prefix, count, new) {
+        Ok((links_dir, devices_file)) => reportln!(
+            "Generated {} link files in {} and {} devices in {}",
+            count,
+            links_dir.display(),
+            count + new,
+            devices_file.display()
+        ),
+        Err(e) => {
+            error!(path = dir; "Failed to generate state in {}: {}", dir, e);
+            exit_maybe_unlock(None, 1)
+        }
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+// Validates the kernel command line before the system is rebooted with it, the proposed one or the
+// one of the default boot loader entry, and shows the names the NICs would get
+fn check_cmdline(proposed: &[String], format: OutputFormat) {
+    let (source, cmdline) = if proposed.is_empty() {
+        match default_entry_cmdline() {
+            Ok((path, c)) => (path.display().to_string(), c),
+            Err(e) => {
+                error!(
+                    "Failed to read kernel command line of the default boot entry: {}",
+                    e
+                );
+                exit_maybe_unlock(None, 1)
+            }
+        }
+    } else {
+        ("arguments".to_string(), proposed.join(" "))
+    };
+
+    let mut check = check_prefix_argument(&cmdline);
+    let mut record: Record = vec![
+        ("cmdline_source", Value::from(source)),
+        ("cmdline", Value::from(cmdline.trim())),
+        (
+            "prefix",
+            Value::from(Some(check.prefix.as_str()).filter(|p| !p.is_empty())),
+        ),
+    ];
+    let mut links = Vec::new();
+
+    if check.errors.is_empty() {
+        let mut settings = match resolver().with_kernel_cmdline(&cmdline).settings() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to load settings: {}", e);
+                exit_maybe_unlock(None, 1)
+            }
+        };
+        settings.cmdline_ifnames = ifname_arguments(&cmdline);
+
+        let mut config = load_config(&check.prefix, &settings);
+
+        links = match plan_links(&mut config, &check.prefix, &settings) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Failed to predict names of network links: {}", e);
+                exit_maybe_unlock(None, 1)
+            }
+        };
+
+        for l in &links {
+            if let PlanSource::Failed(e) = &l.source {
+                check
+                    .errors
+                    .push(format!("{} would not be named: {}", l.kernel_name, e));
+            }
+        }
+
+        let free_names = config.free_names();
+        record.push(("maximum_index", Value::from(config.max_index())));
+        record.push((
+            "free_names",
+            if free_names == 0 {
+                Value::status(Status::Warning, free_names)
+            } else {
+                Value::status(Status::Ok, free_names)
+            },
+        ));
+        if free_names == 0 {
+            check
+                .warnings
+                .push("No names are left for NICs added later".to_string());
+        }
+    }
+
+    if !check.warnings.is_empty() {
+        record.push((
+            "warnings",
+            Value::status(Status::Warning, check.warnings.join("; ")),
+        ));
+    }
+    if !check.errors.is_empty() {
+        record.push((
+            "errors",
+            Value::status(Status::Conflict, check.errors.join("; ")),
+        ));
+    }
+
+    report!("{}", render_record(format, &record, color_enabled(format)));
+
+    if !links.is_empty() {
+        let records: Vec<Record> = links.into_iter().map(planned_link_record).collect();
+
+        if format == OutputFormat::Table {
+            reportln!();
+        }
+        report!(
+            "{}",
+            render_records(format, &records, color_enabled(format))
+        );
+    }
+
+    if !check.errors.is_empty() {
+        exit_maybe_unlock(None, 1);
+    }
+}
+
+// Cross-references the generated link files with the current names of the devices
+#[cfg(not(feature = "minimal"))]
+fn status(check: bool, format: OutputFormat) {
+    let prefix = required_prefix();
+    let settings = load_settings();
+    let config = load_config(&prefix, &settings);
+
+    let present = match config.present_devices() {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Failed to enumerate network devices: {}", e);
+            exit_maybe_unlock(None, STATUS_FAILED)
+        }
+    };
+    let initrd = initrd_path();
+    let mut not_applied = 0;
+
+    let records: Vec<Record> = config
+        .file_links()
+        .iter()
+        .map(|l| {
+            let state = rename_state(l, &present);
+            let path = l.link_file_path();
+            let value = match &state {
+                RenameState::Applied => Value::status(Status::Ok, &state),
+                RenameState::NotApplied(_) => {
+                    not_applied += 1;
+
+                    if initrd.as_ref().is_some_and(|i| initrd_older_than(i, &path)) {
+                        Value::status(
+                            Status::Conflict,
+                            format!("{}, initrd is older than the link file", state),
+                        )
+                    } else {
+                        Value::status(Status::Conflict, &state)
+                    }
+                }
+                RenameState::Missing => Value::status(Status::Warning, &state),
+            };
+
+            vec![
+                ("name", Value::from(l.name.as_str())),
+                ("match", Value::from(l.match_value())),
+                ("link_file", Value::from(path.to_string_lossy().as_ref())),
+                ("state", value),
+            ]
+        })
+        .collect();
+
+    report!(
+        "{}",
+        render_records(format, &records, color_enabled(format))
+    );
+
+    if not_applied > 0 {
+        warn!(
+            "{} link files didn't take effect, consider rebuilding initrd image, using \"dracut -f\"",
+            not_applied
+        );
+
+        if check {
+            exit_maybe_unlock(None, STATUS_NOT_APPLIED);
+        }
+    }
+
+    exit_maybe_unlock(None, STATUS_APPLIED);
+}
+
+#[cfg(not(feature = "minimal"))]
+fn run_self_test() {
+    let mut failed = false;
+
+    for (check, result) in self_test() {
+        match result {
+            Ok(()) => reportln!("{}: ok", check),
+            Err(e) => {
+                reportln!("{}: failed ({})", check, e);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        exit_maybe_unlock(None, 1);
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+fn run_healthcheck() {
+    let health = healthcheck(options());
+
+    reportln!("{}", health.status);
+    notify_status(&health.status);
+
+    exit_maybe_unlock(None, health.code);
+}
+
+fn export_metrics(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Counters::load(COUNTERS_FILE)?.write_prometheus_textfile(path)
+}
+
+#[cfg(not(feature = "minimal"))]
+fn metrics(path: Option<&str>) {
+    let settings = load_settings();
+    let path = path
+        .map(|p| p.to_string())
+        .or(settings.prometheus_textfile)
+        .unwrap_or_else(|| PROMETHEUS_TEXTFILE.to_string());
+
+    if let Err(e) = export_metrics(&path) {
+        error!(path = path; "Failed to write metrics to {}: {}", path, e);
+        exit_maybe_unlock(None, 1);
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+fn daemon() {
+    let prefix = required_prefix();
+
+    let mut daemon = match Daemon::new(&prefix, options()) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to start daemon: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    if let Err(e) = daemon.run() {
+        error!("Daemon failed: {}", e);
+        exit_maybe_unlock(None, 1);
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+/// Moves the name to the NIC with the new MAC address, e.g. after the NIC was swapped.
+fn replace(name: &str, hwaddr: &str, format: OutputFormat) {
+    let prefix = required_prefix();
+    let settings = load_settings();
+
+    let hwaddr = match hwaddr_normalize(&hwaddr) {
+        Ok(a) if hwaddr_assigned(&a) => a,
+        _ => {
+            error!(value = hwaddr; "Invalid MAC address \"{}\"", hwaddr);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    let mut sema = match Semaphore::new_with_name(SEMAPHORE_NAME) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to initialize semaphore: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    sema.lock();
+    let config = load_config(&prefix, &settings);
+
+    // Entries of links() may come from the udev database, the link file tells the match to keep
+    let old = match config
+        .file_links()
+        .iter()
+        .find(|l| l.name == name && l.link_file_path().exists())
+    {
+        Some(l) => l.clone(),
+        None => {
+            error!(value = name; "No link file was generated for {}", name);
+            exit_maybe_unlock(Some(&mut sema), 1)
+        }
+    };
+
+    let link = match old.link_match {
+        LinkMatch::MACAddress => PrefixedLink::new_with_hwaddr(&name, &hwaddr.as_str()),
+        LinkMatch::PermanentMACAddress => {
+            PrefixedLink::new_with_permanent_hwaddr(&name, &hwaddr.as_str())
+        }
+        LinkMatch::Path(_) | LinkMatch::OriginalName(_) | LinkMatch::Property(_) => {
+            error!(
+                path = old.link_file_path().display();
+                "Link file {} doesn't match on MAC address, there is nothing to replace",
+                old.link_file_path().display()
+            );
+            exit_maybe_unlock(Some(&mut sema), 1)
+        }
+    };
+    let link = match link {
+        Ok(l) => l,
+        Err(e) => {
+            error!(value = name; "Failed to create link for {}: {}", name, e);
+            exit_maybe_unlock(Some(&mut sema), 1)
+        }
+    };
+
+    match config.for_hwaddr(&hwaddr) {
+        Some(l) if l.name == name => {
+            info!("{} is already assigned MAC address {}", name, hwaddr);
+            exit_maybe_unlock(Some(&mut sema), 0);
+        }
+        Some(l) => {
+            error!(value = hwaddr; "MAC address {} is already assigned to {}", hwaddr, l.name);
+            exit_maybe_unlock(Some(&mut sema), 1);
+        }
+        None => {}
+    }
+
+    let device = MatchedDevice {
+        hwaddr: hwaddr.clone(),
+        permanent_hwaddr: Some(hwaddr.clone()),
+        path: None,
+        driver: None,
+        devtype: None,
+        original_name: String::new(),
+        properties: std::collections::HashMap::new(),
+    };
+    match link_file_claiming_device(&device, settings.strict) {
+        Ok(Some(f)) => {
+            error!(
+                path = f.display(), value = hwaddr;
+                "MAC address {} is already matched by {}",
+                hwaddr,
+                f.display()
+            );
+            exit_maybe_unlock(Some(&mut sema), 1);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Failed to evaluate existing link files: {}", e);
+            exit_maybe_unlock(Some(&mut sema), 1);
+        }
+    }
+
+    if let Err(e) = link.rewrite_link_file(&LinkFileOptions::from_settings(&settings)) {
+        error!(
+            path = link.link_file_path().display();
+            "Failed to update link file {}: {}",
+            link.link_file_path().display(),
+            e
+        );
+        exit_maybe_unlock(Some(&mut sema), 1);
+    }
+
+    sema.unlock();
+    reload_after_write(&settings);
+    run_post_write_hooks(&link.name, &hwaddr, "replace");
+
+    if let Some(log) = &settings.audit_log {
+        let record = AuditRecord::new(&link.name, &hwaddr, link.link_match.key(), "replace", "");
+
+        if let Err(e) = record.record(log) {
+            warn!("Failed to record naming decision in {}: {}", log, e);
+        }
+    }
+
+    info!("Consider rebuilding initrd image, using \"dracut -f\"");
+
+    let record: Record = vec![
+        ("name", Value::from(link.name.as_str())),
+        ("old_hwaddr", Value::from(old.hwaddr.as_str())),
+        ("hwaddr", Value::from(hwaddr.as_str())),
+        (
+            "link_file",
+            Value::from(link.link_file_path().display().to_string()),
+        ),
+    ];
+    report!("{}", render_record(format, &record, color_enabled(format)));
+}
+
+#[cfg(not(feature = "minimal"))]
+fn backups(format: OutputFormat) {
+    let backups = match list_backups(BACKUP_DIR) {
+        Ok(b) => b,
+        Err(e) => {
+            error!(path = BACKUP_DIR; "Failed to list backups in {}: {}", BACKUP_DIR, e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    let records: Vec<Record> = backups
+        .iter()
+        .map(|b| {
+            vec![
+                ("backup", Value::from(b.name.as_str())),
+                ("link_file", Value::from(b.file_name.as_str())),
+                ("time", Value::from(format_timestamp(b.timestamp))),
+            ]
+        })
+        .collect();
+
+    report!(
+        "{}",
+        render_records(format, &records, color_enabled(format))
+    );
+}
+
+#[cfg(not(feature = "minimal"))]
+fn restore(name: &str) {
+    let settings = load_settings();
+
+    let mut sema = match Semaphore::new_with_name(SEMAPHORE_NAME) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to initialize semaphore: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    sema.lock();
+
+    match restore_backup(
+        BACKUP_DIR,
+        name,
+        NET_SETUP_LINK_CONF_DIR,
+        settings.link_file_mode,
+    ) {
+        Ok(p) => {
+            track_written(&p);
+            info!("Restored {} from backup {}", p.display(), name)
+        }
+        Err(e) => {
+            error!(value = name; "Failed to restore backup {}: {}", name, e);
+            exit_maybe_unlock(Some(&mut sema), 1);
+        }
+    }
+
+    sema.unlock();
+    reload_after_write(&settings);
+}
+
+#[cfg(not(feature = "minimal"))]
+fn rollback(format: OutputFormat) {
+    let settings = load_settings();
+
+    let mut sema = match Semaphore::new_with_name(SEMAPHORE_NAME) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to initialize semaphore: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    sema.lock();
+
+    if let Err(e) = recover_transactions(NET_SETUP_LINK_CONF_DIR) {
+        warn!("Failed to roll back interrupted transaction: {}", e);
+    }
+
+    let done = match roll_back_recorded(TRANSACTION_RECORD, BACKUP_DIR, settings.link_file_mode) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to roll back the last transaction: {}", e);
+            exit_maybe_unlock(Some(&mut sema), 1)
+        }
+    };
+
+    for (path, action) in &done {
+        match *action {
+            "removed" => untrack_written(path),
+            _ => track_written(path),
+        }
+    }
+
+    sema.unlock();
+    reload_after_write(&settings);
+
+    let records: Vec<Record> = done
+        .iter()
+        .map(|(path, action)| {
+            vec![
+                ("link_file", Value::from(path.to_string_lossy().as_ref())),
+                ("action", Value::from(*action)),
+            ]
+        })
+        .collect();
+
+    report!(
+        "{}",
+        render_records(format, &records, color_enabled(format))
+    );
+}
+
+// Under udev the reload is only requested, waiting for it could block the event
+fn reload_after_write(settings: &Settings) {
+    reload_services(
+        &settings.reload_after_write,
+        std::env::var_os("ACTION").is_none(),
+    );
+}
+
+fn post_write_hook_event(name: &str, hwaddr: &str, action: &str) -> Option<HookEvent> {
+    match LinkName::new(name) {
+        Ok(n) => Some(HookEvent {
+            name: name.to_string(),
+            hwaddr: hwaddr.to_string(),
+            link_file: link_file_path_for_name(&n),
+            action: action.to_string(),
+        }),
+        Err(e) => {
+            warn!("Not running hooks for {}: {}", name, e);
+            None
+        }
+    }
+}
+
+fn run_post_write_hooks(name: &str, hwaddr: &str, action: &str) {
+    if let Some(event) = post_write_hook_event(name, hwaddr, action) {
+        run_hooks(HOOKS_DIR, &event);
+    }
+}
+
+fn defer_post_write_hooks(name: &str, hwaddr: &str, action: &str) {
+    if let Some(event) = post_write_hook_event(name, hwaddr, action) {
+        if let Err(e) = defer_hooks(DEFERRED_HOOKS_FILE, &event) {
+            warn!("Failed to queue hooks for {}: {}", name, e);
+        }
+    }
+}
+
+// Hooks of the names assigned by the generator
+fn run_deferred_hooks() {
+    match take_deferred_hooks(DEFERRED_HOOKS_FILE) {
+        Ok(events) => events.iter().for_each(|e| run_hooks(HOOKS_DIR, e)),
+        Err(e) => warn!("Failed to read queued hooks: {}", e),
+    }
+}
+
+fn event_action() -> String {
+    std::env::var("ACTION").unwrap_or_else(|_| "manual".to_string())
+}
+
+#[cfg(not(feature = "minimal"))]
+// Read-only, mirrors the current state without taking the lock
+fn cloud_init() {
+    let prefix = required_prefix();
+    let settings = load_settings();
+    let config = load_config(&prefix, &settings);
+
+    report!("{}", network_config_v2(config.links()));
+}
+
+#[cfg(all(feature = "tui", not(feature = "minimal")))]
+fn edit_rows(prefix: &str, settings: &Settings) -> Vec<EditRow> {
+    let mut config = load_config(prefix, settings);
+    let links = match plan_links(&mut config, prefix, settings) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to predict names of network links: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+    let orphans = config.find_orphaned_links().unwrap_or_else(|e| {
+        warn!("Failed to look for orphaned link files: {}", e);
+        Vec::new()
+    });
+
+    links
+        .iter()
+        .map(EditRow::from_planned)
+        .chain(orphans.iter().map(EditRow::from_orphan))
+        .collect()
+}
+
+/// Interactive editing of the assignments. The lock isn't held while the editor is open, changes
+/// are validated again under the lock before they are written.
+#[cfg(all(feature = "tui", not(feature = "minimal")))]
+fn edit() {
+    let prefix = required_prefix();
+    let settings = load_settings();
+    let mut session = EditSession::new(&prefix, edit_rows(&prefix, &settings));
+
+    match tui::run_editor(&mut session) {
+        Ok(true) => {}
+        Ok(false) => exit_maybe_unlock(None, 0),
+        Err(e) => {
+            error!("Editor failed: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    }
+
+    let mut sema = match Semaphore::new_with_name(SEMAPHORE_NAME) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to initialize semaphore: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    sema.lock();
+
+    if let Err(e) = recover_transactions(NET_SETUP_LINK_CONF_DIR) {
+        warn!("Failed to roll back interrupted transaction: {}", e);
+    }
+
+    let session = match session.replay(edit_rows(&prefix, &settings)) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}", e);
+            exit_maybe_unlock(Some(&mut sema), 1)
+        }
+    };
+
+    if let Err(e) = apply_actions(
+        session.pending(),
+        &LinkFileOptions::from_settings(&settings),
+    ) {
+        error!("Failed to write changes: {}", e);
+        exit_maybe_unlock(Some(&mut sema), 1);
+    }
+
+    sema.unlock();
+    reload_after_write(&settings);
+
+    for action in session.pending() {
+        match action {
+            EditAction::Assign { hwaddr, name } | EditAction::Replace { name, hwaddr } => {
+                run_post_write_hooks(name, hwaddr, "edit")
+            }
+            EditAction::Release { .. } => {}
+        }
+        reportln!("{}", action);
+    }
+
+    info!("Consider rebuilding initrd image, using \"dracut -f\"");
+}
+
+#[cfg(not(any(feature = "tui", feature = "minimal")))]
+fn edit() {
+    error!("prefixdevname was built without the \"tui\" feature, \"edit\" isn't available");
+    exit_maybe_unlock(None, 1);
+}
+
+#[cfg(not(feature = "minimal"))]
+/// Summary of the allocated names per prefix, i.e. the prefix and the prefixes of virtual devices,
+/// for capacity planning. Read-only, the lock isn't taken.
+fn stats(format: OutputFormat) {
+    let prefix = required_prefix();
+    let settings = load_settings();
+    let mut prefixes = vec![prefix.clone()];
+    prefixes.extend(settings.virtual_prefixes.iter().map(|(_, p)| p.clone()));
+    for (_, p) in &settings.vendor_prefixes {
+        if !prefixes.contains(p) {
+            prefixes.push(p.clone());
+        }
+    }
+
+    let mut records: Vec<Record> = Vec::new();
+    for p in &prefixes {
+        let mut config = NetSetupLinkConfig::new_with_prefix(p);
+        if *p == prefix {
+            config.apply_settings(&settings);
+        }
+        for q in &prefixes {
+            config.add_sibling_prefix(q);
+        }
+
+        if let Err(e) = config.load() {
+            error!(
+                value = p;
+                "Failed to load current state of links named using {}: {}",
+                p, e
+            );
+            exit_maybe_unlock(None, 1);
+        }
+
+        let orphaned = match config.find_orphaned_links() {
+            Ok(o) => o.len() as u64,
+            Err(e) => {
+                warn!("Failed to look for orphaned link files: {}", e);
+                0
+            }
+        };
+        let s = prefix_stats(
+            p,
+            config.file_links(),
+            config.min_index(),
+            config.free_names(),
+            orphaned,
+        );
+
+        records.push(vec![
+            ("prefix", Value::from(s.prefix.as_str())),
+            ("assigned", Value::from(s.assigned)),
+            ("highest_index", Value::from(s.highest_index)),
+            (
+                "free_gaps",
+                Value::from(Some(format_gaps(&s.gaps)).filter(|g| !g.is_empty())),
+            ),
+            (
+                "free_names",
+                match s.free_names {
+                    0 => Value::status(Status::Conflict, 0),
+                    n => Value::from(n),
+                },
+            ),
+            (
+                "orphaned",
+                match s.orphaned {
+                    0 => Value::from(0),
+                    n => Value::status(Status::Warning, n),
+                },
+            ),
+            (
+                "conflicts",
+                match s.conflicts {
+                    0 => Value::from(0),
+                    n => Value::status(Status::Conflict, n),
+                },
+            ),
+            (
+                "last_assignment",
+                Value::from(s.last_assignment.map(format_timestamp)),
+            ),
+        ]);
+    }
+
+    report!(
+        "{}",
+        render_records(format, &records, color_enabled(format))
+    );
+}
+
+#[cfg(not(feature = "minimal"))]
+fn open_lock() -> Semaphore {
+    match Semaphore::new_with_name(SEMAPHORE_NAME) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to initialize semaphore: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+fn lock_state(sema: &Semaphore) -> LockState {
+    match sema.state() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to read state of semaphore {}: {}", sema.name(), e);
+            exit_maybe_unlock(None, 1)
+        }
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+/// Exits with 1 when the lock was left held by a crashed process and has to be reset.
+fn lock_status(format: OutputFormat) {
+    let sema = open_lock();
+    let state = lock_state(&sema);
+    let value = sema.value().unwrap_or_default();
+
+    let (text, holder) = match state {
+        LockState::Free => (Value::status(Status::Ok, "free"), None),
+        LockState::Held(pid) => (Value::from("held"), Some(pid)),
+        LockState::Orphaned(pid) => (Value::status(Status::Conflict, "orphaned"), Some(pid)),
+        LockState::Unknown => (Value::status(Status::Warning, "held"), None),
+        LockState::Corrupted(_) => (Value::status(Status::Conflict, "corrupted"), None),
+    };
+    let held_since = holder
+        .and_then(|_| last_modified(&[sema.holder_path().to_path_buf()]))
+        .map(format_timestamp);
+
+    let record: Record = vec![
+        ("name", Value::from(sema.name())),
+        ("value", Value::from(value.max(0) as u64)),
+        ("state", text),
+        ("holder", Value::from(holder.map(|p| p as u64))),
+        ("held_since", Value::from(held_since)),
+    ];
+    report!("{}", render_record(format, &record, color_enabled(format)));
+
+    exit_maybe_unlock(None, if state.needs_reset() { 1 } else { 0 });
+}
+
+#[cfg(not(feature = "minimal"))]
+/// Recreates the lock left held by a crashed process. Lock held by a running process is never
+/// reset, lock whose holder isn't known only with --force.
+fn lock_reset(force: bool) {
+    let sema = open_lock();
+
+    match lock_state(&sema) {
+        LockState::Free => {
+            reportln!("Lock {} isn't held, nothing to reset", sema.name());
+            exit_maybe_unlock(None, 0);
+        }
+        LockState::Held(pid) => {
+            error!(
+                "Lock {} is held by running process PID={}, refusing to reset it",
+                sema.name(),
+                pid
+            );
+            exit_maybe_unlock(None, 1);
+        }
+        LockState::Unknown if !force => {
+            error!(
+                "Lock {} is held by unknown process, use --force if no prefixdevname is running",
+                sema.name()
+            );
+            exit_maybe_unlock(None, 1);
+        }
+        _ => {}
+    }
+
+    let name = sema.name();
+    if let Err(e) = sema.reset() {
+        error!("Failed to reset lock {}: {}", name, e);
+        exit_maybe_unlock(None, 1);
+    }
+
+    reportln!("Lock {} was reset", name);
+}
+
+#[cfg(not(feature = "minimal"))]
+/// Reports link files modified or removed outside of prefixdevname, exits with 1 when there are
+/// any. Read-only, the lock isn't taken.
+fn audit(format: OutputFormat) {
+    let drifts = match audit_files(WRITTEN_DIR, NET_SETUP_LINK_CONF_DIR, LINK_FILE_PREFIX) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to audit link files: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    let records: Vec<Record> = drifts
+        .iter()
+        .map(|d| {
+            let status = match d.state {
+                DriftState::Unchanged => Status::Ok,
+                DriftState::Untracked => Status::Warning,
+                DriftState::Modified(_) | DriftState::Removed => Status::Conflict,
+            };
+            let mut record = vec![
+                ("link_file", Value::from(d.file_name.as_str())),
+                ("state", Value::status(status, &d.state)),
+            ];
+
+            // Diff spans several lines, in the table it is printed below
+            if format != OutputFormat::Table {
+                record.push((
+                    "diff",
+                    match &d.state {
+                        DriftState::Modified(diff) => Value::from(diff.as_str()),
+                        _ => Value::Null,
+                    },
+                ));
+            }
+
+            record
+        })
+        .collect();
+
+    report!(
+        "{}",
+        render_records(format, &records, color_enabled(format))
+    );
+
+    if format == OutputFormat::Table {
+        for d in &drifts {
+            if let DriftState::Modified(diff) = &d.state {
+                report!("\n--- {}\n{}", d.file_name, diff);
+            }
+        }
+    }
+
+    let drifted = drifts
+        .iter()
+        .any(|d| matches!(d.state, DriftState::Modified(_) | DriftState::Removed));
+    exit_maybe_unlock(None, if drifted { 1 } else { 0 });
+}
+
+#[cfg(not(feature = "minimal"))]
+/// Rewrites all link files from the current settings, without --force only shows which of them
+/// would change.
+fn regenerate(force: bool, format: OutputFormat) {
+    let prefix = required_prefix();
+    let settings = load_settings();
+    let mut sema = None;
+
+    if force {
+        settle_before_batch(&settings, None);
+
+        let mut s = match Semaphore::new_with_name(SEMAPHORE_NAME) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to initialize semaphore: {}", e);
+                exit_maybe_unlock(None, 1)
+            }
+        };
+
+        s.lock();
+        if let Err(e) = recover_transactions(NET_SETUP_LINK_CONF_DIR) {
+            warn!("Failed to roll back interrupted transaction: {}", e);
+        }
+        sema = Some(s);
+    }
+
+    let mut config = NetSetupLinkConfig::new_with_prefix(&prefix);
+    config.apply_settings(&settings);
+
+    let links = match config
+        .load()
+        .and_then(|_| regenerate_links(&config, &settings, !force))
+    {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to regenerate link files: {}", e);
+            exit_maybe_unlock(sema.as_mut(), 1)
+        }
+    };
+
+    if let Some(mut s) = sema {
+        s.unlock();
+
+        let files: Vec<_> = links.iter().map(|l| l.link.link_file_path()).collect();
+        if let Err(e) = store_manifest(&settings, &files) {
+            error!("{}", e);
+            exit_maybe_unlock(None, 1);
+        }
+
+        reload_after_write(&settings);
+    }
+
+    let records: Vec<Record> = links
+        .iter()
+        .map(|l| {
+            let state = match (l.changed, force) {
+                (false, _) => Value::status(Status::Ok, "unchanged"),
+                (true, true) => Value::status(Status::Ok, "rewritten"),
+                (true, false) => Value::status(Status::Warning, "would change"),
+            };
+
+            vec![
+                ("name", Value::from(l.link.name.as_str())),
+                ("previous_match", Value::from(l.previous_match.as_str())),
+                (
+                    "match",
+                    Value::from(format!(
+                        "{}={}",
+                        l.link.link_match.key(),
+                        l.link.match_value()
+                    )),
+                ),
+                ("state", state),
+            ]
+        })
+        .collect();
+
+    report!(
+        "{}",
+        render_records(format, &records, color_enabled(format))
+    );
+
+    if !force && links.iter().any(|l| l.changed) {
+        info!("Run \"prefixdevname regenerate --force\" to rewrite the link files");
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+// Shows which source each effective value comes from
+fn config_dump(format: OutputFormat) {
+    let records: Vec<Record> = resolver()
+        .dump()
+        .into_iter()
+        .map(|(key, value, source)| {
+            vec![
+                ("key", Value::from(key)),
+                ("value", Value::from(value)),
+                ("source", Value::from(source.to_string())),
+            ]
+        })
+        .collect();
+
+    report!(
+        "{}",
+        render_records(format, &records, color_enabled(format))
+    );
+}
+
+#[cfg(not(feature = "minimal"))]
+fn print_udev_rule(install: bool) {
+    let settings = load_settings();
+    let rule = udev_rule(
+        HELPER_PATH,
+        settings.removed_device_policy != RemovedDevicePolicy::Keep,
+    );
+
+    if !install {
+        report!("{}", rule);
+        return;
+    }
+
+    match install_udev_rule(UDEV_RULES_DIR, &rule) {
+        Ok(p) => info!("Installed udev rule {}", p.display()),
+        Err(e) => {
+            error!(
+                path = UDEV_RULES_DIR;
+                "Failed to install udev rule to {}: {}", UDEV_RULES_DIR, e
+            );
+            exit_maybe_unlock(None, 1);
+        }
+    }
+}
+
+fn main() {
+    init_logging();
+
+    let mut args: Vec<String> = std::env::args().collect();
+    let error_format = take_option(&mut args, "--error-format")
+        .map(|f| f.or_else(|| std::env::var(ERROR_FORMAT_ENV).ok()))
+        .and_then(|f| f.map_or(Ok(()), |f| set_error_format(&f)));
+    if let Err(e) = error_format {
+        error!("{}", e);
+        exit_maybe_unlock(None, 1)
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    let format = match take_output_format(&mut args) {
+        Ok(f) => f,
+        Err(e) => {
+            error!("{}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    let mut options = Vec::new();
+    let settings = take_options(&mut args, "--set").and_then(|s| {
+        s.iter()
+            .map(|o| parse_option_setting(o))
+            .collect::<Result<Vec<_>, _>>()
+    });
+    match settings {
+        Ok(s) => options.extend(s),
+        Err(e) => {
+            error!("{}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    }
+
+    match take_option(&mut args, "--prefix") {
+        Ok(Some(p)) => options.push((PREFIX_KEY.to_string(), p)),
+        Ok(None) => {}
+        Err(e) => {
+            error!("{}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    }
+
+    match take_option(&mut args, "--manifest") {
+        Ok(Some(m)) => options.push(("ManifestFile".to_string(), m)),
+        Ok(None) => {}
+        Err(e) => {
+            error!("{}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    }
+
+    if take_flag(&mut args, "--strict") {
+        options.push(("Strict".to_string(), "yes".to_string()));
+    }
+
+    QUIET.store(take_flag(&mut args, "--quiet"), Ordering::SeqCst);
+
+    OPTIONS.get_or_init(|| options);
+
+    #[cfg(feature = "debug-mock")]
+    {
+        let mock_dir = take_option(&mut args, "--mock-dir")
+            .map(|d| d.or_else(|| std::env::var("PREFIXDEVNAME_MOCK_DIR").ok()));
+        match mock_dir {
+            Ok(Some(d)) => match mock::load_mock_dir(std::path::Path::new(&d)) {
+                Ok(n) => info!("Loaded {} mock devices from {}", n, d),
+                Err(e) => {
+                    error!("{}", e);
+                    exit_maybe_unlock(None, 1)
+                }
+            },
+            Ok(None) => {}
+            Err(e) => {
+                error!("{}", e);
+                exit_maybe_unlock(None, 1)
+            }
+        }
+    }
+
+    match take_option(&mut args, "--syspath") {
+        Ok(Some(p)) => {
+            if let Err(e) = export_device_environment(std::path::Path::new(&p)) {
+                error!(path = p; "Failed to read device {}: {}", p, e);
+                exit_maybe_unlock(None, 1);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("{}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    }
+
+    // Installed as a symlink in the system-generators directory, systemd passes output directories
+    let generator_mode = std::path::Path::new(&args[0])
+        .file_name()
+        .is_some_and(|n| n.to_string_lossy().ends_with("-generator"));
+    if generator_mode {
+        generator();
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    run_command(&args, format);
+    #[cfg(feature = "minimal")]
+    run_command(&args);
+}
+
+// Only the udev event path and the generator are built into the minimal binary for the initrd
+#[cfg(feature = "minimal")]
+fn run_command(args: &[String]) {
+    match args.get(1).map(|a| a.as_str()) {
+        None => generate(),
+        Some("generator") => generator(),
+        Some("generate") if args.len() == 2 => generate(),
+        Some(c) => {
+            error!(value = c; "Command \"{}\" isn't available in the minimal build", c);
+            exit_maybe_unlock(None, 1);
+        }
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+fn run_command(args: &[String], format: OutputFormat) {
+    match args.get(1).map(|a| a.as_str()) {
+        None => generate(),
+        Some("generator") => generator(),
+        Some("generate") => match &args[2..] {
+            [] => generate(),
+            [a] if a == "--all" => generate_batch(None, format),
+            [ifname] => {
+                if let Err(e) =
+                    syspath_for_ifname(ifname).and_then(|p| export_device_environment(&p))
+                {
+                    error!(value = ifname; "Failed to read device {}: {}", ifname, e);
+                    exit_maybe_unlock(None, 1);
+                }
+                generate()
+            }
+            ifnames => generate_batch(Some(ifnames), format),
+        },
+        Some("verify") => verify(format),
+        Some("status") => match &args[2..] {
+            [] => status(false, format),
+            [a] if a == "--check" => status(true, format),
+            _ => {
+                error!("Usage: prefixdevname status [--check]");
+                exit_maybe_unlock(None, 1)
+            }
+        },
+        Some("plan") => plan(args.get(2).map(|a| a.as_str()), format),
+        Some("check-cmdline") => check_cmdline(&args[2..], format),
+        Some("simulate") => simulate_naming(&args[2..], format),
+        Some("metrics") => metrics(args.get(2).map(|a| a.as_str())),
+        Some("daemon") => daemon(),
+        Some("backups") => backups(format),
+        Some("rollback") => rollback(format),
+        Some("restore") => match &args[2..] {
+            [name] => restore(name),
+            _ => {
+                error!("Usage: prefixdevname restore BACKUP");
+                exit_maybe_unlock(None, 1);
+            }
+        },
+        Some("replace") => match &args[2..] {
+            [name, hwaddr] => replace(name, hwaddr, format),
+            _ => {
+                error!("Usage: prefixdevname replace NAME MAC");
+                exit_maybe_unlock(None, 1);
+            }
+        },
+        Some("--self-test") => run_self_test(),
+        Some("healthcheck") => run_healthcheck(),
+        Some("cloud-init") => cloud_init(),
+        Some("config-dump") => config_dump(format),
+        Some("audit") => audit(format),
+        Some("stats") => stats(format),
+        Some("bench-prepare") => bench_prepare(&args[2..]),
+        Some("lock") => match &args[2..] {
+            [a] if a == "--status" => lock_status(format),
+            [a] if a == "--reset" => lock_reset(false),
+            [a, f] if a == "--reset" && f == "--force" => lock_reset(true),
+            _ => {
+                error!("Usage: prefixdevname lock --status|--reset [--force]");
+                exit_maybe_unlock(None, 1)
+            }
+        },
+        Some("edit") => edit(),
+        Some("regenerate") => match &args[2..] {
+            [] => regenerate(false, format),
+            [a] if a == "--force" => regenerate(true, format),
+            _ => {
+                error!("Usage: prefixdevname regenerate [--force]");
+                exit_maybe_unlock(None, 1)
+            }
+        },
+        Some("udev-rule") => match &args[2..] {
+            [] => print_udev_rule(false),
+            [a] if a == "--install" => print_udev_rule(true),
+            _ => {
+                error!("Usage: prefixdevname udev-rule [--install]");
+                exit_maybe_unlock(None, 1)
+            }
+        },
+        Some(c) => {
+            error!(value = c; "Unknown command \"{}\"", c);
+            exit_maybe_unlock(None, 1);
+        }
+    }
+}
+
+// Admin created virtual devices are named using their own prefix and index pool
+fn generate_virtual(kind: &str, prefix: &str, ifname_prefix: &str, settings: &Settings) -> ! {
+    let ifname = event_device_name();
+
+    if prefix.starts_with(ifname_prefix) || ifname_prefix.starts_with(prefix) {
+        error!(
+            value = prefix;
+            "Prefix \"{}\" of {} devices conflicts with prefix \"{}\", ignoring",
+            prefix, kind, ifname_prefix
+        );
+        exit_maybe_unlock(None, 0);
+    }
+
+    if !rename_needed(&ifname, prefix).unwrap() {
+        println!("{}", ifname);
+        exit_maybe_unlock(None, 0);
+    }
+
+    let mut sema = match Semaphore::new_with_name(SEMAPHORE_NAME) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to initialize semaphore: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    set_stage(STAGE_LOCK);
+    sema.lock();
+
+    if let Err(e) = recover_transactions(NET_SETUP_LINK_CONF_DIR) {
+        warn!("Failed to roll back interrupted transaction: {}", e);
+    }
+
+    let mut config = NetSetupLinkConfig::new_with_prefix(&prefix);
+    set_stage(STAGE_ENUMERATE);
+    if let Err(e) = config.load() {
+        error!("Failed to load current state of {} links: {}", kind, e);
+        exit_maybe_unlock(Some(&mut sema), 1);
+    }
+
+    if config.for_original_name(&ifname).is_some() {
+        info!("Found net_setup_link config for the event device, not generating new one");
+        exit_maybe_unlock(Some(&mut sema), 0);
+    }
+
+    let link_config = match config
+        .next_link_name()
+        .and_then(|n| PrefixedLink::new_with_original_name(&n, &ifname))
+    {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to create new name for the {} link: {}", kind, e);
+            exit_maybe_unlock(Some(&mut sema), 1)
+        }
+    };
+
+    set_stage(STAGE_WRITE);
+    if let Err(e) = link_config.write_link_file_with(&LinkFileOptions::from_settings(settings)) {
+        error!(
+            path = link_config.link_file_path().display();
+            "Failed to write link file for {}: {}", link_config.name, e
+        );
+        exit_maybe_unlock(Some(&mut sema), 1);
+    }
+
+    bump_counters(|c| c.assignments += 1);
 
-use config::*;
-use sema::*;
-use util::*;
+    if let Some(log) = &settings.audit_log {
+        let record = AuditRecord::new(
+            &link_config.name,
+            "",
+            link_config.link_match.key(),
+            &event_action(),
+            &std::env::var("DEVPATH").unwrap_or_default(),
+        );
 
-fn main() {
-    env_logger::init();
+        if let Err(e) = record.record(log) {
+            warn!("Failed to record naming decision in {}: {}", log, e);
+        }
+    }
 
-    let prefix = match get_prefix_from_file("/proc/cmdline") {
-        Ok(p) => p,
+    println!("{}", link_config.name);
+
+    sema.unlock();
+    reload_after_write(settings);
+    run_post_write_hooks(&link_config.name, "", &event_action());
+    exit_maybe_unlock(None, 0)
+}
+
+// NICs of the vendors listed in VendorPrefixes= are named using their own prefix and index pool.
+// NICs named before the vendor was listed keep their names.
+fn event_device_prefix(settings: &Settings, prefix: &str) -> String {
+    let hwaddr = hwaddr_from_event_device().unwrap_or_default();
+    let vendor_id = event_device_vendor_id();
+
+    let vendor_prefix = match vendor_prefix_for(settings, prefix, &hwaddr, vendor_id.as_deref()) {
+        Some(p) => p,
+        None => return prefix.to_string(),
+    };
+
+    let mut config = NetSetupLinkConfig::new_with_prefix(&prefix);
+    config.apply_settings(settings);
+    match config.load_link_files() {
+        Ok(()) => {
+            if let Some(l) = config.for_hwaddr(&hwaddr) {
+                info!(
+                    "Event device is already named {}, not using prefix {} of its vendor",
+                    l.name, vendor_prefix
+                );
+                return prefix.to_string();
+            }
+        }
+        Err(e) => {
+            error!("Failed to load link files: {}", e);
+            exit_maybe_unlock(None, 1);
+        }
+    }
+
+    info!(
+        "Event device is named using prefix {} of its vendor",
+        vendor_prefix
+    );
+    vendor_prefix
+}
+
+// Interfaces seen in containers are created by the host
+fn check_container(settings: &Settings) {
+    if let Some(c) = detect_container() {
+        if !settings.name_in_containers {
+            info!(
+                "Running in container ({}), interfaces are named by the host, doing nothing",
+                c
+            );
+            exit_maybe_unlock(None, 0);
+        }
+        debug!("Running in container ({}), naming interfaces anyway", c);
+    }
+}
+
+fn check_cmdline_conflicts(settings: &Settings) {
+    match get_conflicting_arguments_from_file("/proc/cmdline") {
+        Ok(c) if !c.is_empty() => match settings.cmdline_conflict_policy {
+            CmdlineConflictPolicy::Warn => {
+                warn!(
+                    "Kernel command line contains {} together with net.ifnames.prefix, interface naming may be inconsistent",
+                    c.join(" ")
+                );
+            }
+            CmdlineConflictPolicy::Refuse => {
+                error!(
+                    "Kernel command line contains {} together with net.ifnames.prefix, refusing to name interfaces",
+                    c.join(" ")
+                );
+                exit_maybe_unlock(None, 0);
+            }
+        },
+        Ok(_) => {}
+        Err(e) => {
+            error!("Failed to parse kernel command line: {}", e);
+            exit_maybe_unlock(None, 1);
+        }
+    }
+}
+
+/// Generator mode, systemd runs generators before udev processes the coldplug events. NICs
+/// registered by the kernel already are named in one transaction, so that their link files exist
+/// before the coldplug and networkd never sees the old names. The output directories passed by
+/// systemd are not used, nothing is written when the link file directory is read-only (e.g. the
+/// root file system isn't remounted yet) and the udev helper names the NICs later as usual.
+fn generator() {
+    start_watchdog(DEFAULT_TIMEOUT);
+    set_stage(STAGE_SETTINGS);
+
+    let prefix = match Resolver::load(options()).map(|r| r.prefix()) {
+        Ok(p) if !p.is_empty() => p,
+        _ => exit_maybe_unlock(None, 0),
+    };
+
+    if !prefix_ok(&prefix) {
+        warn!("Invalid prefix \"{}\", not generating link files", prefix);
+        exit_maybe_unlock(None, 0);
+    }
+
+    let settings = load_settings();
+    set_timeout(settings.timeout);
+
+    check_container(&settings);
+    check_cmdline_conflicts(&settings);
+
+    if let Err(e) = check_dir_writable(std::path::Path::new(NET_SETUP_LINK_CONF_DIR)) {
+        info!("{}, leaving naming of interfaces to udev", e);
+        exit_maybe_unlock(None, 0);
+    }
+
+    let mut sema = match Semaphore::new_with_name(SEMAPHORE_NAME) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to initialize semaphore: {}", e);
+            exit_maybe_unlock(None, 0)
+        }
+    };
+
+    // Stuck holder of the lock must not hang the boot
+    set_stage(STAGE_LOCK);
+    sema.lock();
+
+    if let Err(e) = recover_transactions(NET_SETUP_LINK_CONF_DIR) {
+        warn!("Failed to roll back interrupted transaction: {}", e);
+    }
+
+    let mut config = NetSetupLinkConfig::new_with_prefix(&prefix);
+    config.apply_settings(&settings);
+
+    match config
+        .load()
+        .and_then(|_| apply_links(&mut config, &prefix, &settings, None))
+    {
+        Ok(links) => {
+            if let Err(e) = store_manifest(&settings, &written_link_files(&links)) {
+                warn!("{}", e);
+            }
+
+            for l in links {
+                match (&l.source, &l.name) {
+                    (
+                        PlanSource::NewName | PlanSource::NamingPolicy(_) | PlanSource::Hwdb,
+                        Some(n),
+                    ) => {
+                        info!("Generated link file for {} ({})", l.kernel_name, n);
+                        defer_post_write_hooks(n, &l.hwaddr, "generator");
+                    }
+                    (PlanSource::Failed(e), _) => {
+                        warn!("Failed to name {}: {}", l.kernel_name, e)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(e) => warn!("Failed to name network links: {}", e),
+    }
+
+    exit_maybe_unlock(Some(&mut sema), 0);
+}
+
+// Link files of the new names, hwdb and the naming policy pick names of new NICs too
+fn written_link_files(links: &[PlannedLink]) -> Vec<std::path::PathBuf> {
+    links
+        .iter()
+        .filter(|l| {
+            matches!(
+                l.source,
+                PlanSource::NewName | PlanSource::NamingPolicy(_) | PlanSource::Hwdb
+            )
+        })
+        .filter_map(|l| l.name.as_ref().and_then(|n| LinkName::new(n).ok()))
+        .map(|n| link_file_path_for_name(&n))
+        .collect()
+}
+
+/// Lists the link files written by the offline and batch modes in ManifestFile=, so that
+/// image-build pipelines can track them.
+#[cfg(feature = "manifest")]
+fn store_manifest(
+    settings: &Settings,
+    files: &[std::path::PathBuf],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match &settings.manifest_file {
+        Some(m) => write_manifest(m, files)
+            .map_err(|e| From::from(format!("Failed to write manifest {}: {}", m, e))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "manifest"))]
+fn store_manifest(
+    settings: &Settings,
+    _files: &[std::path::PathBuf],
+) -> Result<(), Box<dyn std::error::Error>> {
+    match &settings.manifest_file {
+        Some(m) => Err(From::from(format!(
+            "prefixdevname was built without the \"manifest\" feature, can't write manifest {}",
+            m
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Waits for in-flight udev events when SettleTimeoutSec= is set, otherwise names allocated by
+/// the batch could race with the names allocated by the events. The interfaces to name must
+/// still be present afterwards.
+#[cfg(not(feature = "minimal"))]
+fn settle_before_batch(settings: &Settings, ifnames: Option<&[String]>) {
+    if settings.settle_timeout.is_zero() {
+        return;
+    }
+
+    set_stage(STAGE_SETTLE);
+    match wait_for_udev_settle(settings.settle_timeout) {
+        Ok(true) => debug!("udev event queue is empty"),
+        Ok(false) => {
+            error!(
+                "udev event queue didn't settle within {}s",
+                settings.settle_timeout.as_secs()
+            );
+            exit_maybe_unlock(None, 1);
+        }
+        Err(e) => {
+            error!("Failed to wait for udev to settle: {}", e);
+            exit_maybe_unlock(None, 1);
+        }
+    }
+
+    let missing = missing_interfaces(
+        std::path::Path::new(SYSFS_CLASS_NET),
+        ifnames.unwrap_or_default(),
+    );
+    if !missing.is_empty() {
+        error!(
+            "Interfaces {} disappeared while udev settled",
+            missing.join(", ")
+        );
+        exit_maybe_unlock(None, 1);
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+/// Names several interfaces (or all of them) under single lock acquisition, the state of links
+/// is loaded just once.
+fn generate_batch(ifnames: Option<&[String]>, format: OutputFormat) {
+    start_watchdog(DEFAULT_TIMEOUT);
+    set_stage(STAGE_SETTINGS);
+
+    let prefix = required_prefix();
+    let settings = load_settings();
+    // Waiting for udev doesn't count towards the deadline
+    set_timeout(match settings.timeout {
+        t if t.is_zero() => t,
+        t => t + settings.settle_timeout,
+    });
+
+    check_container(&settings);
+    check_cmdline_conflicts(&settings);
+
+    if !prefix_ok(&prefix) {
+        error!(value = prefix; "Invalid prefix \"{}\"", prefix);
+        exit_maybe_unlock(None, 1);
+    }
+
+    settle_before_batch(&settings, ifnames);
+
+    let mut sema = match Semaphore::new_with_name(SEMAPHORE_NAME) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to initialize semaphore: {}", e);
+            exit_maybe_unlock(None, 1)
+        }
+    };
+
+    set_stage(STAGE_LOCK);
+    sema.lock();
+
+    if let Err(e) = recover_transactions(NET_SETUP_LINK_CONF_DIR) {
+        warn!("Failed to roll back interrupted transaction: {}", e);
+    }
+
+    let mut config = NetSetupLinkConfig::new_with_prefix(&prefix);
+    config.apply_settings(&settings);
+
+    set_stage(STAGE_NAMING);
+    let links = match config
+        .load()
+        .and_then(|_| apply_links(&mut config, &prefix, &settings, ifnames))
+    {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to name network links: {}", e);
+            exit_maybe_unlock(Some(&mut sema), 1)
+        }
+    };
+
+    sema.unlock();
+
+    if let Err(e) = store_manifest(&settings, &written_link_files(&links)) {
+        error!("{}", e);
+        exit_maybe_unlock(None, 1);
+    }
+
+    let written: Vec<&PlannedLink> = links
+        .iter()
+        .filter(|l| {
+            matches!(
+                l.source,
+                PlanSource::NewName | PlanSource::NamingPolicy(_) | PlanSource::Hwdb
+            )
+        })
+        .collect();
+
+    if !written.is_empty() {
+        reload_after_write(&settings);
+    }
+
+    for l in written {
+        if let Some(n) = &l.name {
+            run_post_write_hooks(n, &l.hwaddr, "batch");
+        }
+    }
+
+    if let Some(path) = &settings.prometheus_textfile {
+        if let Err(e) = export_metrics(path) {
+            warn!("Failed to write metrics to {}: {}", path, e);
+        }
+    }
+
+    if let Some(n) = ifnames {
+        for ifname in n
+            .iter()
+            .filter(|i| !links.iter().any(|l| &l.kernel_name == *i))
+        {
+            warn!("Interface {} not found or not eligible for naming", ifname);
+        }
+    }
+
+    let failed = links
+        .iter()
+        .any(|l| matches!(l.source, PlanSource::Failed(_)));
+    let records: Vec<Record> = links
+        .into_iter()
+        .map(|l| {
+            vec![
+                ("kernel_name", Value::from(l.kernel_name)),
+                ("name", Value::from(l.name)),
+                ("hwaddr", Value::from(l.hwaddr)),
+                ("source", Value::status(l.source.status(), &l.source)),
+            ]
+        })
+        .collect();
+
+    report!(
+        "{}",
+        render_records(format, &records, color_enabled(format))
+    );
+
+    if failed {
+        exit_maybe_unlock(None, 1);
+    }
+}
+
+/// Handles ACTION==remove, the link file of the removed device is kept, deleted or marked
+/// depending on RemovedDevicePolicy=.
+fn cleanup_removed(prefix: &str, settings: &Settings) -> ! {
+    let ifname = event_device_name();
+
+    let named = std::iter::once(prefix)
+        .chain(settings.vendor_prefixes.iter().map(|(_, p)| p.as_str()))
+        .any(|p| !rename_needed(&ifname, p).unwrap());
+    if settings.removed_device_policy == RemovedDevicePolicy::Keep || !named {
+        exit_maybe_unlock(None, 0);
+    }
+
+    let path = match LinkName::new(&ifname).map(|n| link_file_path_for_name(&n)) {
+        Ok(p) if p.exists() => p,
+        _ => {
+            debug!("No link file generated for removed device {}", ifname);
+            exit_maybe_unlock(None, 0);
+        }
+    };
+
+    let mut sema = match Semaphore::new_with_name(SEMAPHORE_NAME) {
+        Ok(s) => s,
         Err(e) => {
-            error!("Failed to obtain prefix value: {}", e);
+            error!("Failed to initialize semaphore: {}", e);
             exit_maybe_unlock(None, 1)
         }
     };
 
+    set_stage(STAGE_LOCK);
+    sema.lock();
+
+    backup_link_file(&path);
+
+    let result = match settings.removed_device_policy {
+        RemovedDevicePolicy::Delete => {
+            let removed = std::fs::remove_file(&path);
+            #[cfg(not(feature = "minimal"))]
+            if removed.is_ok() {
+                untrack_written(&path);
+            }
+            removed.map_err(From::from)
+        }
+        _ => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            mark_link_file_removed(&path, &format_timestamp(now))
+        }
+    };
+
+    match result {
+        Ok(()) => info!(
+            "Device {} was removed, link file {} {}",
+            ifname,
+            path.display(),
+            if settings.removed_device_policy == RemovedDevicePolicy::Delete {
+                "deleted"
+            } else {
+                "marked"
+            }
+        ),
+        Err(e) => {
+            error!(
+                path = path.display();
+                "Failed to clean up link file {}: {}", path.display(), e
+            );
+            exit_maybe_unlock(Some(&mut sema), 1);
+        }
+    }
+
+    sema.unlock();
+    exit_maybe_unlock(None, 0)
+}
+
+fn generate() {
+    let _span = tracing::info_span!("generate", ifname = %event_device_name()).entered();
+
+    start_watchdog(DEFAULT_TIMEOUT);
+    set_stage(STAGE_SETTINGS);
+
+    let prefix = resolver().prefix();
+
     if prefix.is_empty() {
-        info!("No prefix specified on the kernel command line");
+        info!("No prefix specified");
         exit_maybe_unlock(None, 0);
     }
 
+    let settings = load_settings();
+    set_timeout(settings.timeout);
+
+    check_container(&settings);
+
+    match std::env::var("ACTION") {
+        Ok(a) if a == "remove" => cleanup_removed(&prefix, &settings),
+        Ok(a) if !settings.action_accepted(&a) => {
+            debug!("Called for udev action \"{}\", ignoring", a);
+            exit_maybe_unlock(None, 0);
+        }
+        Ok(_) => {}
+        Err(_) => debug!("ACTION isn't set, assuming manual invocation"),
+    }
+
+    if let Err(e) = event_environment_ok() {
+        error!("Invalid udev event environment: {}", e);
+        exit_maybe_unlock(None, 1);
+    }
+
+    check_cmdline_conflicts(&settings);
+
     if !prefix_ok(&prefix) {
         error!("Invalid prefix, prefix can't be well-known prefix used for NIC naming by other tools and must be shorter than 16 characters");
         exit_maybe_unlock(None, 0);
     }
 
+    if settings.vlan_names {
+        if let Some((parent, vlan_id)) = event_device_vlan() {
+            match vlan_name(&parent, vlan_id, &prefix) {
+                Some(n) => println!("{}", n),
+                None => debug!(
+                    "Parent {} of the VLAN device isn't named using the prefix, ignoring",
+                    parent
+                ),
+            }
+            exit_maybe_unlock(None, 0);
+        }
+    }
+
+    if let Ok(kind) = std::env::var("DEVTYPE") {
+        if let Some(p) = settings.virtual_prefix(&kind) {
+            generate_virtual(&kind, p, &prefix, &settings);
+        }
+    }
+
     if event_device_virtual() {
         debug!("Called for virtual network device, ignoring");
         exit_maybe_unlock(None, 0);
     }
 
+    let link_type = match event_device_link_type() {
+        Some(t) if settings.link_types.contains(&t) => t,
+        t => {
+            debug!(
+                "Called for network device of unexpected type {:?}, ignoring",
+                t
+            );
+            exit_maybe_unlock(None, 0);
+        }
+    };
+
     let ifname = event_device_name();
 
+    match failover_role(&ifname) {
+        Some(FailoverRole::Master) | None => {}
+        Some(r) => {
+            debug!("Called for virtio-net failover {:?} device, ignoring", r);
+            exit_maybe_unlock(None, 0);
+        }
+    }
+
+    let main_prefix = prefix;
+    let prefix = event_device_prefix(&settings, &main_prefix);
+
     if !rename_needed(&ifname, &prefix).unwrap() {
         println!("{}", ifname);
         exit_maybe_unlock(None, 0);
     }
 
-    let mut sema = match Semaphore::new_with_name("net-prefix-ifnames") {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed to initialize semaphore: {}", e);
-            exit_maybe_unlock(None, 1)
-        }
+    let counter = index_counter_path(INDEX_COUNTER_DIR, &prefix);
+    let event_device = EventDevice {
+        hwaddr: hwaddr_from_event_device().ok(),
+        udev_provided_name: event_device_udev_provided_name(&prefix),
+        driver: event_device_driver(),
     };
 
-    sema.lock();
-
-    let mut config = NetSetupLinkConfig::new_with_prefix(&prefix);
-    if let Err(e) = config.load() {
-        error!("Failed to load current state of network links: {}", e);
-        exit_maybe_unlock(Some(&mut sema), 1);
+    match early_answer(&settings, &event_device, &EventState::new(&counter)) {
+        Some(EarlyAnswer::Name(n)) => {
+            println!("{}", n);
+            exit_maybe_unlock(None, 0);
+        }
+        Some(EarlyAnswer::Nothing) => exit_maybe_unlock(None, 0),
+        None => {}
     }
 
+    let debounce_hwaddr = event_device
+        .hwaddr
+        .clone()
+        .filter(|_| !settings.debounce_window.is_zero());
+
+    let remember = |answer: &str| {
+        if let Some(a) = &debounce_hwaddr {
+            if let Err(e) = record_answer(DEBOUNCE_DIR, a, answer) {
+                debug!("Failed to record answer for {}: {}", a, e);
+            }
+        }
+    };
+
     let event_device_hwaddr = match hwaddr_from_event_device() {
         Ok(d) => d,
+        Err(_) if link_type != ARPHRD_ETHER => String::new(),
         Err(e) => {
             error!(
                 "Failed to determine MAC address for the event device: {}",
                 e
             );
-            exit_maybe_unlock(Some(&mut sema), 1)
+            exit_maybe_unlock(None, 1)
         }
     };
 
-    if let Some(_c) = config.for_hwaddr(&event_device_hwaddr) {
-        info!("Found net_setup_link config for the event device, not generating new one");
-        exit_maybe_unlock(Some(&mut sema), 0);
+    if settings.compatibility_mode == CompatibilityMode::Rhel7 {
+        if let Some(n) = legacy_rule_name(&event_device_hwaddr) {
+            info!(
+                "Event device is named {} by {} and ifcfg files, not generating link file",
+                n, NET_RULES_FILE
+            );
+            exit_maybe_unlock(None, 0);
+        }
+    }
+
+    let mut link_match = LinkMatch::MACAddress;
+    let mut match_hwaddr = event_device_hwaddr.clone();
+    // Set whenever one of the policies is "property", see Settings::from_ini()
+    let match_property = settings.match_property.clone().unwrap_or_default();
+
+    if settings.original_name_selected(&ifname) {
+        info!(
+            "Event device is listed in OriginalNameDevices=, matching on its name {}",
+            ifname
+        );
+        link_match = LinkMatch::OriginalName(ifname.clone());
+    } else if link_type != ARPHRD_ETHER {
+        link_match = non_ethernet_link_match(&ifname, link_type);
+    } else if !hwaddr_assigned(&event_device_hwaddr) {
+        let reason = format!(
+            "Event device reports unusable MAC address {}",
+            event_device_hwaddr
+        );
+
+        match settings.invalid_hwaddr_policy {
+            InvalidHwaddrPolicy::Skip => {
+                warn!("{}, not generating link file", reason);
+                exit_maybe_unlock(None, 0);
+            }
+            InvalidHwaddrPolicy::Path => link_match = path_link_match(&reason),
+            InvalidHwaddrPolicy::Property => {
+                link_match = property_link_match(&reason, &match_property)
+            }
+        }
+    } else if hwaddr_locally_administered(&event_device_hwaddr) {
+        let reason = format!(
+            "Event device has locally administered MAC address {}",
+            event_device_hwaddr
+        );
+
+        match settings.local_hwaddr_policy {
+            LocalHwaddrPolicy::Skip => {
+                warn!("{}, not generating link file", reason);
+                exit_maybe_unlock(None, 0);
+            }
+            LocalHwaddrPolicy::Proceed => {
+                warn!(
+                    "{}, link file matching on it may not be stable across reboots",
+                    reason
+                );
+            }
+            LocalHwaddrPolicy::Path => link_match = path_link_match(&reason),
+            LocalHwaddrPolicy::Property => {
+                link_match = property_link_match(&reason, &match_property)
+            }
+            LocalHwaddrPolicy::Permanent => match hwaddr_permanent(&ifname) {
+                Ok(a) => {
+                    info!(
+                        "{}, matching on permanent MAC address {} instead",
+                        reason, a
+                    );
+                    link_match = LinkMatch::PermanentMACAddress;
+                    match_hwaddr = a;
+                }
+                Err(e) => {
+                    warn!(
+                        "{} and its permanent MAC address is unknown ({}), not generating link file",
+                        reason, e
+                    );
+                    exit_maybe_unlock(None, 0);
+                }
+            },
+        }
     }
 
-    let next_link_name = match config.next_link_name() {
-        Ok(n) => n,
+    let mut sema = match Semaphore::new_with_name(SEMAPHORE_NAME) {
+        Ok(s) => s,
         Err(e) => {
-            error!("Failed to create new name for the link: {}", e);
-            exit_maybe_unlock(Some(&mut sema), 1)
+            error!("Failed to initialize semaphore: {}", e);
+            exit_maybe_unlock(None, 1)
         }
     };
 
-    let link_config = match PrefixedLink::new_with_hwaddr(&next_link_name, &event_device_hwaddr) {
+    // Reusing indexes of orphaned link files requires the full state of links under the lock
+    let mode = match settings.allocation_mode {
+        _ if settings.orphan_policy == OrphanPolicy::Reuse => AllocationMode::Locked,
+        m => m,
+    };
+    let counter_start = read_index_counter(&counter).filter(|_| mode != AllocationMode::Locked);
+
+    let mut config = NetSetupLinkConfig::new_with_prefix(&prefix);
+    config.apply_settings(&settings);
+    config.add_sibling_prefix(&main_prefix);
+
+    match naming_policy(settings.naming_policy, Some(&event_device_hwaddr)) {
+        Ok(p) => config.set_naming_policy(p),
+        Err(e) => warn!(
+            "Failed to initialize naming policy, allocating names sequentially: {}",
+            e
+        ),
+    }
+
+    let mut enumeration_ms = None;
+    if counter_start.is_some() {
+        set_stage(STAGE_READ_LINK_FILES);
+        if let Err(e) = config.load_link_files() {
+            error!("Failed to load link files: {}", e);
+            exit_maybe_unlock(Some(&mut sema), 1);
+        }
+    } else {
+        if mode != AllocationMode::Optimistic {
+            set_stage(STAGE_LOCK);
+            sema.lock();
+        }
+
+        let enumeration_start = std::time::Instant::now();
+        set_stage(STAGE_ENUMERATE);
+        if let Err(e) = config.load() {
+            error!("Failed to load current state of network links: {}", e);
+            exit_maybe_unlock(Some(&mut sema), 1);
+        }
+
+        enumeration_ms = Some(enumeration_start.elapsed().as_millis() as u64);
+    }
+
+    let matched_device = MatchedDevice::from_event_device(&event_device_hwaddr);
+    match existing_name(
+        &config,
+        &link_match,
+        &match_hwaddr,
+        &matched_device,
+        settings.strict,
+        enumeration_ms,
+        &EventState::new(&counter),
+    ) {
+        Ok(Some(ExistingName::LinkFile(_))) => {
+            info!("Found net_setup_link config for the event device, not generating new one");
+            stop_watchdog();
+            sema.unlock();
+            run_deferred_hooks();
+            exit_maybe_unlock(None, 0);
+        }
+        Ok(Some(ExistingName::ForeignLinkFile(f))) => {
+            info!(
+                "Event device is already named by {}, not generating link file",
+                f.display()
+            );
+            exit_maybe_unlock(Some(&mut sema), 0);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            error!("Failed to evaluate existing link files: {}", e);
+            exit_maybe_unlock(Some(&mut sema), 1);
+        }
+    }
+
+    set_stage(STAGE_WRITE);
+    let match_driver = event_device_driver().filter(|_| settings.match_driver);
+    let build = |n: &str| {
+        match &link_match {
+            LinkMatch::MACAddress => PrefixedLink::new_with_hwaddr(&n.to_string(), &match_hwaddr),
+            LinkMatch::PermanentMACAddress => {
+                PrefixedLink::new_with_permanent_hwaddr(&n.to_string(), &match_hwaddr)
+            }
+            LinkMatch::Path(p) => PrefixedLink::new_with_path(&n.to_string(), p),
+            LinkMatch::OriginalName(o) => PrefixedLink::new_with_original_name(&n.to_string(), o),
+            LinkMatch::Property(p) => PrefixedLink::new_with_property(&n.to_string(), p),
+        }
+        .map(|l| l.with_driver(match_driver.clone()))
+    };
+    let syspath = std::env::var("DEVPATH")
+        .ok()
+        .map(|d| std::path::PathBuf::from(format!("/sys{}", d)));
+    let policy_device = PolicyDevice::new(&match_hwaddr, syspath.as_deref());
+    let pinned = match event_device_hwdb_pinned_index(&prefix) {
+        Ok(Some(i)) => match config.allocate_link_at(i, build) {
+            Ok(Some(link)) => {
+                info!("Naming event device by hwdb ({})", HWDB_NAME_PROPERTY);
+                Some(Ok(link))
+            }
+            Ok(None) => {
+                warn!(
+                    "Name with index {} pinned by hwdb is taken, allocating other name",
+                    i
+                );
+                None
+            }
+            Err(e) => Some(Err(e)),
+        },
+        Ok(None) => None,
+        Err(e) => {
+            warn!("Ignoring name pinned by hwdb: {}", e);
+            None
+        }
+    };
+    let preferred = match pinned {
+        Some(r) => Some(r),
+        None => match config.allocate_preferred_link(&policy_device, build) {
+            Ok(Some((link, p))) => {
+                info!("Naming event device by the naming policy, {}", p.reason);
+                Some(Ok(link))
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        },
+    };
+    let preferred = match preferred {
+        Some(r) => Some(r),
+        None => match config.allocate_pooled_link(&policy_device, build) {
+            Ok(Some((link, p))) => {
+                info!("Naming event device from the {}", p.reason);
+                Some(Ok(link))
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        },
+    };
+    let allocated = match (preferred, counter_start, mode) {
+        (Some(r), _, _) => r,
+        (None, Some(i), _) => config.allocate_link_from(i, build),
+        (None, None, AllocationMode::Optimistic) => config
+            .next_index()
+            .and_then(|i| config.allocate_link_from(i, build)),
+        (None, None, _) => config.allocate_link(build),
+    };
+    let link_config = match allocated {
         Ok(c) => c,
+        Err(e) if e.is::<IndexPoolExhausted>() => {
+            error!("Failed to create new name for the link: {}", e);
+            exit_maybe_unlock(Some(&mut sema), EXIT_INDEX_EXHAUSTED)
+        }
         Err(e) => {
-            error!("Failed to create link config object: {}", e);
+            error!("Failed to create new name for the link: {}", e);
             exit_maybe_unlock(Some(&mut sema), 1)
         }
     };
-    if let Err(e) = link_config.write_link_file() {
-        error!("Failed to write link file for {}: {}", link_config.name, e);
-        exit_maybe_unlock(Some(&mut sema), 1);
+
+    if let Err(e) = store_index_counter(&counter, link_config.index + 1) {
+        debug!("Failed to store index counter {}: {}", counter.display(), e);
+    }
+
+    bump_counters(|c| c.assignments += 1);
+
+    if let Some(path) = &settings.prometheus_textfile {
+        if let Err(e) = export_metrics(path) {
+            warn!("Failed to write metrics to {}: {}", path, e);
+        }
+    }
+
+    if let Some(log) = &settings.audit_log {
+        let record = AuditRecord::new(
+            &link_config.name,
+            &event_device_hwaddr,
+            link_config.link_match.key(),
+            &event_action(),
+            &std::env::var("DEVPATH").unwrap_or_default(),
+        );
+
+        if let Err(e) = record.record(log) {
+            warn!("Failed to record naming decision in {}: {}", log, e);
+        }
     }
 
     debug!(
@@ -114,7 +2714,13 @@ fn main() {
     );
     debug!("Consider rebuilding initrd image, using \"dracut -f\"");
 
-    println!("{}", next_link_name);
+    // Reloading, hooks and the rest must not make udev drop the name
+    stop_watchdog();
+    println!("{}", link_config.name);
+    remember(&link_config.name);
 
     sema.unlock();
+    reload_after_write(&settings);
+    run_post_write_hooks(&link_config.name, &event_device_hwaddr, &event_action());
+    run_deferred_hooks();
 }
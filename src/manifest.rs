@@ -0,0 +1,180 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ini::Ini;
+use sha2::{Digest, Sha256};
+
+use crate::config::link_from_match_section;
+use crate::util::json_string;
+
+/// Algorithm of the hashes in the manifest, see sha256().
+pub static MANIFEST_HASH_ALGORITHM: &str = "sha256";
+
+/// SHA-256 of the content as lowercase hex, pipelines verify the files with the usual tools,
+/// e.g. sha256sum.
+pub fn sha256(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Link file written by the run, as listed in the manifest for image-build pipelines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub hash: String,
+    pub name: String,
+    pub hwaddr: Option<String>,
+    pub link_match: String,
+}
+
+impl ManifestEntry {
+    /// Name and match are read back from the file, so the entry describes what was written.
+    pub fn from_file(path: &Path) -> Result<ManifestEntry, Box<dyn Error>> {
+        let content = fs::read(path)?;
+        let conf = Ini::load_from_str(&String::from_utf8_lossy(&content))?;
+        let name = conf
+            .get_from(Some("Link"), "Name")
+            .ok_or_else(|| format!("{} doesn't set Name=", path.display()))?;
+        let match_section = conf
+            .section(Some("Match"))
+            .ok_or_else(|| format!("{} doesn't have [Match] section", path.display()))?;
+        let link = link_from_match_section(name, match_section)?;
+
+        Ok(ManifestEntry {
+            path: path.to_path_buf(),
+            hash: sha256(&content),
+            name: link.name.to_string(),
+            hwaddr: Some(link.hwaddr.clone()).filter(|a| !a.is_empty()),
+            link_match: format!("{}={}", link.link_match.key(), link.match_value()),
+        })
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"path\":{},\"hash\":{},\"name\":{},\"hwaddr\":{},\"match\":{}}}",
+            json_string(&self.path.to_string_lossy()),
+            json_string(&self.hash),
+            json_string(&self.name),
+            self.hwaddr
+                .as_deref()
+                .map_or("null".to_string(), json_string),
+            json_string(&self.link_match)
+        )
+    }
+}
+
+pub fn manifest_content(entries: &[ManifestEntry]) -> String {
+    let files = match entries {
+        [] => "[]".to_string(),
+        e => format!(
+            "[\n{}\n  ]",
+            e.iter()
+                .map(|e| format!("    {}", e.to_json()))
+                .collect::<Vec<_>>()
+                .join(",\n")
+        ),
+    };
+
+    format!(
+        "{{\n  \"hash_algorithm\": {},\n  \"files\": {}\n}}\n",
+        json_string(MANIFEST_HASH_ALGORITHM),
+        files
+    )
+}
+
+/// Manifest is written even when no file was created, later stages can rely on its presence.
+/// It is replaced atomically.
+pub fn write_manifest<P: AsRef<Path>>(path: P, files: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let path = path.as_ref();
+    let entries = files
+        .iter()
+        .map(|f| ManifestEntry::from_file(f))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut tmp_name = path.as_os_str().to_os_string();
+    tmp_name.push(".tmp");
+
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    fs::write(&tmp_name, manifest_content(&entries))?;
+    fs::rename(&tmp_name, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_sha256() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn manifest_written() {
+        let dir =
+            std::env::temp_dir().join(format!("prefixdevname-manifest-{}", std::process::id()));
+        let mac = dir.join("71-net-ifnames-prefix-net0.link");
+        let path = dir.join("71-net-ifnames-prefix-net1.link");
+        let manifest = dir.join("out/manifest.json");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            &mac,
+            "[Match]\nMACAddress=52:54:00:1C:08:B7\n\n[Link]\nName=net0\n",
+        )
+        .unwrap();
+        fs::write(
+            &path,
+            "[Match]\nPath=pci-0000:00:04.0\n\n[Link]\nName=net1\n",
+        )
+        .unwrap();
+
+        write_manifest(&manifest, &[mac.clone(), path.clone()]).unwrap();
+        let content = fs::read_to_string(&manifest).unwrap();
+        let entries = [
+            ManifestEntry::from_file(&mac).unwrap(),
+            ManifestEntry::from_file(&path).unwrap(),
+        ];
+        write_manifest(&manifest, &[]).unwrap();
+        let empty = fs::read_to_string(&manifest).unwrap();
+        let missing = write_manifest(&manifest, &[dir.join("missing.link")]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(entries[0].name, "net0");
+        assert_eq!(entries[0].hwaddr.as_deref(), Some("52:54:00:1C:08:B7"));
+        assert_eq!(entries[0].link_match, "MACAddress=52:54:00:1C:08:B7");
+        assert_eq!(entries[0].hash.len(), 64);
+        assert_eq!(entries[1].hwaddr, None);
+        assert_ne!(entries[0].hash, entries[1].hash);
+
+        assert_eq!(content, manifest_content(&entries));
+        assert!(content.contains(&format!(
+            "{{\"path\":\"{}\",\"hash\":\"{}\",\"name\":\"net1\",\"hwaddr\":null,\"match\":\"Path=pci-0000:00:04.0\"}}\n  ]",
+            path.display(),
+            entries[1].hash
+        )));
+        assert_eq!(
+            empty,
+            "{\n  \"hash_algorithm\": \"sha256\",\n  \"files\": []\n}\n"
+        );
+        assert!(missing.is_err());
+    }
+}
@@ -0,0 +1,122 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::raw::{c_char, c_int};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+enum UMockdevTestbed {}
+
+#[repr(C)]
+struct GError {
+    domain: u32,
+    code: c_int,
+    message: *mut c_char,
+}
+
+#[link(name = "umockdev")]
+extern "C" {
+    fn umockdev_testbed_new() -> *mut UMockdevTestbed;
+    fn umockdev_testbed_add_from_string(
+        testbed: *mut UMockdevTestbed,
+        device_description: *const c_char,
+        err: *mut *mut GError,
+    ) -> c_int;
+}
+
+#[link(name = "glib-2.0")]
+extern "C" {
+    fn g_error_free(err: *mut GError);
+}
+
+/// Dumps in the directory, in the order they are added to the testbed. Parents must be added
+/// before their children, umockdev-record dumps of whole trees are already in that order.
+pub fn mockdev_files(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read mock directory {}: {}", dir.display(), e))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "mockdev") && p.is_file())
+        .collect::<Vec<_>>();
+    files.sort();
+
+    if files.is_empty() {
+        return Err(From::from(format!(
+            "No .mockdev files found in {}",
+            dir.display()
+        )));
+    }
+
+    Ok(files)
+}
+
+/// Creates umockdev testbed with the devices dumped in the directory. The binary must run under
+/// umockdev-wrapper, so that libudev and sysfs accesses are redirected to the testbed. The
+/// testbed is never freed, it lives until the process exits.
+pub fn load_mock_dir(dir: &Path) -> Result<usize, Box<dyn Error>> {
+    let files = mockdev_files(dir)?;
+
+    if std::env::var_os("LD_PRELOAD").is_none_or(|p| !p.to_string_lossy().contains("umockdev")) {
+        return Err(From::from(
+            "Mock devices can only be loaded when running under umockdev-wrapper",
+        ));
+    }
+
+    let testbed = unsafe { umockdev_testbed_new() };
+    if testbed.is_null() {
+        return Err(From::from("Failed to create umockdev testbed"));
+    }
+
+    for file in &files {
+        let description = CString::new(fs::read_to_string(file)?)
+            .map_err(|_| format!("{} contains NUL byte", file.display()))?;
+        let mut err: *mut GError = ptr::null_mut();
+
+        let added =
+            unsafe { umockdev_testbed_add_from_string(testbed, description.as_ptr(), &mut err) };
+        if added == 0 {
+            let message = if err.is_null() {
+                "unknown error".to_string()
+            } else {
+                unsafe {
+                    let m = CStr::from_ptr((*err).message)
+                        .to_string_lossy()
+                        .into_owned();
+                    g_error_free(err);
+                    m
+                }
+            };
+
+            return Err(From::from(format!(
+                "Failed to add {} to umockdev testbed: {}",
+                file.display(),
+                message
+            )));
+        }
+
+        debug!("Added {} to umockdev testbed", file.display());
+    }
+
+    Ok(files.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_dir_files_sorted() {
+        let test_dir = Path::new(file!()).parent().unwrap().join("../test");
+        let files = mockdev_files(&test_dir).unwrap();
+
+        assert!(files.len() >= 2);
+        assert!(files.windows(2).all(|w| w[0] < w[1]));
+        assert!(files
+            .iter()
+            .all(|f| f.extension().is_some_and(|e| e == "mockdev")));
+
+        assert!(mockdev_files(&test_dir.join("snapshots")).is_err());
+        assert!(mockdev_files(&test_dir.join("nonexistent")).is_err());
+    }
+}
@@ -0,0 +1,372 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+#[cfg(not(feature = "minimal"))]
+use crate::cloud_metadata::*;
+use crate::dmi::*;
+use crate::index_pool::numa_node_for_syspath;
+use crate::settings::NamingPolicyKind;
+
+static SYSFS_CLASS_NET: &str = "/sys/class/net";
+
+/// What the naming policies know about the device being named.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyDevice {
+    pub hwaddr: String,
+    pub pci_address: Option<String>,
+    pub numa_node: Option<u32>,
+}
+
+impl PolicyDevice {
+    pub fn new(hwaddr: &str, syspath: Option<&Path>) -> PolicyDevice {
+        PolicyDevice {
+            hwaddr: hwaddr.to_string(),
+            pci_address: syspath.and_then(pci_address_for_syspath),
+            numa_node: syspath.and_then(numa_node_for_syspath),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreferredIndex {
+    pub index: u64,
+    pub reason: String,
+}
+
+/// Decides which index the device should get. Devices without preferred index, and devices
+/// whose preferred name is already taken, get the next free index sequentially.
+pub trait NamingPolicy {
+    fn preferred_index(&self, device: &PolicyDevice, max_index: u64) -> Option<PreferredIndex>;
+
+    /// Indexes below the returned one are reserved for the devices with preferred index and
+    /// aren't allocated sequentially.
+    fn reserved_indexes(&self) -> u64 {
+        0
+    }
+}
+
+/// Indexes are allocated in the order in which devices appear, the default.
+pub struct Sequential;
+
+impl NamingPolicy for Sequential {
+    fn preferred_index(&self, _device: &PolicyDevice, _max_index: u64) -> Option<PreferredIndex> {
+        None
+    }
+}
+
+/// Index is the hash of the MAC address, the name doesn't depend on the order in which devices
+/// appear. Collisions are resolved by the sequential allocation.
+pub struct MacHash;
+
+// FNV-1a, the index must not depend on the version of the standard library
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl NamingPolicy for MacHash {
+    fn preferred_index(&self, device: &PolicyDevice, max_index: u64) -> Option<PreferredIndex> {
+        if device.hwaddr.is_empty() {
+            return None;
+        }
+
+        let hash = fnv1a(device.hwaddr.to_lowercase().as_bytes());
+
+        Some(PreferredIndex {
+            index: hash % max_index.saturating_add(1),
+            reason: format!("hash of MAC address {}", device.hwaddr),
+        })
+    }
+}
+
+/// Index is the position of the device among PCI network devices present in the system ordered
+/// by their PCI address.
+pub struct PciOrder {
+    addresses: Vec<String>,
+}
+
+fn is_pci_address(address: &str) -> bool {
+    let b = address.as_bytes();
+
+    b.len() == 12
+        && b[4] == b':'
+        && b[7] == b':'
+        && b[10] == b'.'
+        && address
+            .chars()
+            .enumerate()
+            .all(|(i, c)| matches!(i, 4 | 7 | 10) || c.is_ascii_hexdigit())
+}
+
+impl PciOrder {
+    pub fn new(mut addresses: Vec<String>) -> PciOrder {
+        addresses.retain(|a| is_pci_address(a));
+        addresses.sort();
+        addresses.dedup();
+
+        PciOrder { addresses }
+    }
+
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<PciOrder, Box<dyn Error>> {
+        let mut addresses = Vec::new();
+
+        for entry in fs::read_dir(dir)? {
+            if let Some(a) = pci_address_for_syspath(&entry?.path()) {
+                addresses.push(a);
+            }
+        }
+
+        Ok(PciOrder::new(addresses))
+    }
+}
+
+impl NamingPolicy for PciOrder {
+    fn preferred_index(&self, device: &PolicyDevice, _max_index: u64) -> Option<PreferredIndex> {
+        let address = device.pci_address.as_ref()?;
+        let position = self.addresses.iter().position(|a| a == address)?;
+
+        Some(PreferredIndex {
+            index: position as u64,
+            reason: format!("PCI address {}", address),
+        })
+    }
+
+    fn reserved_indexes(&self) -> u64 {
+        self.addresses.len() as u64
+    }
+}
+
+/// Index is derived from the label of the device in the SMBIOS tables.
+pub struct Smbios {
+    labels: Vec<FirmwareLabel>,
+}
+
+impl Smbios {
+    pub fn new(labels: Vec<FirmwareLabel>) -> Smbios {
+        Smbios { labels }
+    }
+}
+
+impl NamingPolicy for Smbios {
+    fn preferred_index(&self, device: &PolicyDevice, _max_index: u64) -> Option<PreferredIndex> {
+        let label = firmware_label_for_pci_address(&self.labels, device.pci_address.as_ref()?)?;
+
+        Some(PreferredIndex {
+            index: label.index,
+            reason: format!("firmware label \"{}\"", label.label),
+        })
+    }
+
+    fn reserved_indexes(&self) -> u64 {
+        firmware_reserved_indexes(&self.labels)
+    }
+}
+
+/// Index is the NIC index assigned by the cloud provider, so the primary NIC is always named with
+/// index 0 regardless of the order in which the devices appear.
+#[cfg(not(feature = "minimal"))]
+pub struct CloudMetadata {
+    indexes: Vec<(String, u64)>,
+}
+
+#[cfg(not(feature = "minimal"))]
+impl CloudMetadata {
+    pub fn new(indexes: Vec<(String, u64)>) -> CloudMetadata {
+        CloudMetadata { indexes }
+    }
+
+    /// Metadata that can't be fetched isn't fatal, NICs are then named sequentially. Fetched
+    /// indexes are cached for later udev events.
+    pub fn load<P: AsRef<Path>, C: AsRef<Path>>(
+        dmi_dir: P,
+        address: &str,
+        cache: C,
+    ) -> CloudMetadata {
+        let indexes = match detect_provider(dmi_dir) {
+            Some(p) => nic_indexes(p, address).unwrap_or_else(|e| {
+                warn!("Failed to query NIC indexes from {:?} metadata: {}", p, e);
+                Vec::new()
+            }),
+            None => {
+                warn!("Cloud provider not recognized, naming NICs sequentially");
+                Vec::new()
+            }
+        };
+
+        if !indexes.is_empty() {
+            if let Err(e) = write_nic_indexes(cache.as_ref(), &indexes) {
+                debug!(
+                    "Failed to cache NIC indexes in {}: {}",
+                    cache.as_ref().display(),
+                    e
+                );
+            }
+        }
+
+        CloudMetadata::new(indexes)
+    }
+
+    /// Same as load(), but the cached indexes are used when they know the event device, so that
+    /// the metadata service isn't queried for every udev event.
+    pub fn load_for_device<P: AsRef<Path>, C: AsRef<Path>>(
+        dmi_dir: P,
+        address: &str,
+        cache: C,
+        hwaddr: &str,
+    ) -> CloudMetadata {
+        match read_nic_indexes(cache.as_ref()) {
+            Some(i) if i.iter().any(|(a, _)| a.eq_ignore_ascii_case(hwaddr)) => {
+                CloudMetadata::new(i)
+            }
+            // NIC attached after the indexes were cached
+            _ => CloudMetadata::load(dmi_dir, address, cache),
+        }
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+impl NamingPolicy for CloudMetadata {
+    fn preferred_index(&self, device: &PolicyDevice, _max_index: u64) -> Option<PreferredIndex> {
+        let (_, index) = self
+            .indexes
+            .iter()
+            .find(|(a, _)| a.eq_ignore_ascii_case(&device.hwaddr))?;
+
+        Some(PreferredIndex {
+            index: *index,
+            reason: format!("cloud NIC index {}", index),
+        })
+    }
+
+    fn reserved_indexes(&self) -> u64 {
+        self.indexes.iter().map(|(_, i)| i + 1).max().unwrap_or(0)
+    }
+}
+
+/// Policy of the kind, event_hwaddr is the MAC address of the device when naming a single udev
+/// event device.
+pub fn naming_policy(
+    kind: NamingPolicyKind,
+    event_hwaddr: Option<&str>,
+) -> Result<Box<dyn NamingPolicy>, Box<dyn Error>> {
+    Ok(match kind {
+        NamingPolicyKind::Sequential => Box::new(Sequential),
+        NamingPolicyKind::MacHash => Box::new(MacHash),
+        NamingPolicyKind::PciOrder => Box::new(PciOrder::load(SYSFS_CLASS_NET)?),
+        NamingPolicyKind::Smbios => Box::new(Smbios::new(firmware_labels(DMI_ENTRIES_DIR)?)),
+        #[cfg(not(feature = "minimal"))]
+        NamingPolicyKind::CloudMetadata => Box::new(match event_hwaddr {
+            Some(a) => {
+                CloudMetadata::load_for_device(DMI_ID_DIR, METADATA_ADDRESS, NIC_INDEXES_CACHE, a)
+            }
+            None => CloudMetadata::load(DMI_ID_DIR, METADATA_ADDRESS, NIC_INDEXES_CACHE),
+        }),
+        // The metadata client isn't built into the minimal binary
+        #[cfg(feature = "minimal")]
+        NamingPolicyKind::CloudMetadata => {
+            let _ = event_hwaddr;
+            warn!("Cloud metadata isn't available in the minimal build, naming NICs sequentially");
+            Box::new(Sequential)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(hwaddr: &str, pci_address: Option<&str>) -> PolicyDevice {
+        PolicyDevice {
+            hwaddr: hwaddr.to_string(),
+            pci_address: pci_address.map(|a| a.to_string()),
+            numa_node: None,
+        }
+    }
+
+    #[test]
+    fn policy_sequential() {
+        let d = device("00:11:22:33:44:55", Some("0000:00:03.0"));
+
+        assert_eq!(Sequential.preferred_index(&d, 100), None);
+        assert_eq!(Sequential.reserved_indexes(), 0);
+    }
+
+    #[test]
+    fn policy_mac_hash() {
+        let a = device("00:11:22:33:44:55", None);
+        let b = device("00:11:22:33:44:56", None);
+        let index = |d: &PolicyDevice| MacHash.preferred_index(d, 99).unwrap().index;
+
+        assert_eq!(index(&a), index(&device("00:11:22:33:44:55", None)));
+        assert_ne!(index(&a), index(&b));
+        assert!(index(&a) <= 99 && index(&b) <= 99);
+        assert_eq!(MacHash.preferred_index(&device("", None), 99), None);
+    }
+
+    #[test]
+    fn policy_pci_order() {
+        let policy = PciOrder::new(vec![
+            "0000:3b:00.0".to_string(),
+            "0000:00:19.0".to_string(),
+            "0000:3b:00.1".to_string(),
+            "virtio0".to_string(),
+            "0000:00:19.0".to_string(),
+        ]);
+        let index = |a: &str| {
+            policy
+                .preferred_index(&device("00:11:22:33:44:55", Some(a)), 100)
+                .map(|p| p.index)
+        };
+
+        assert_eq!(index("0000:00:19.0"), Some(0));
+        assert_eq!(index("0000:3b:00.0"), Some(1));
+        assert_eq!(index("0000:3b:00.1"), Some(2));
+        assert_eq!(index("0000:5e:00.0"), None);
+        assert_eq!(policy.reserved_indexes(), 3);
+    }
+
+    #[test]
+    fn policy_smbios() {
+        let policy = Smbios::new(vec![FirmwareLabel {
+            label: "Onboard LAN 2".to_string(),
+            pci_address: "0000:01:00.1".to_string(),
+            index: 1,
+        }]);
+        let preferred = policy
+            .preferred_index(&device("00:11:22:33:44:55", Some("0000:01:00.1")), 100)
+            .unwrap();
+
+        assert_eq!(preferred.index, 1);
+        assert_eq!(preferred.reason, "firmware label \"Onboard LAN 2\"");
+        assert_eq!(
+            policy.preferred_index(&device("00:11:22:33:44:55", None), 100),
+            None
+        );
+        assert_eq!(policy.reserved_indexes(), 2);
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn policy_cloud_metadata() {
+        let policy = CloudMetadata::new(vec![
+            ("0E:00:00:00:00:02".to_string(), 1),
+            ("0E:00:00:00:00:01".to_string(), 0),
+        ]);
+        let preferred = policy
+            .preferred_index(&device("0e:00:00:00:00:02", None), 100)
+            .unwrap();
+
+        assert_eq!(preferred.index, 1);
+        assert_eq!(preferred.reason, "cloud NIC index 1");
+        assert_eq!(
+            policy.preferred_index(&device("0E:00:00:00:00:03", None), 100),
+            None
+        );
+        assert_eq!(policy.reserved_indexes(), 2);
+        assert_eq!(CloudMetadata::new(Vec::new()).reserved_indexes(), 0);
+    }
+}
@@ -0,0 +1,344 @@
+// SPDX-License-Identifier:  MIT
+
+use std::env;
+use std::error::Error;
+use std::io::IsTerminal;
+use std::str::FromStr;
+
+use crate::util::{json_string, take_option};
+
+/// Format of the results printed by subcommands. Table is meant for humans, json and kv for
+/// scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Kv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "kv" => Ok(OutputFormat::Kv),
+            _ => Err(From::from(format!(
+                "Invalid output format \"{}\", expected \"table\", \"json\" or \"kv\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// Removes "--output FORMAT" (or "--output=FORMAT") from the command line arguments and returns
+/// the requested format, table is used by default.
+pub fn take_output_format(args: &mut Vec<String>) -> Result<OutputFormat, Box<dyn Error>> {
+    match take_option(args, "--output")? {
+        Some(f) => f.parse(),
+        None => Ok(OutputFormat::Table),
+    }
+}
+
+/// Status of the value, shown in color when the output goes to the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Conflict,
+}
+
+impl Status {
+    fn color_code(self) -> &'static str {
+        match self {
+            Status::Ok => "32",
+            Status::Warning => "33",
+            Status::Conflict => "31",
+        }
+    }
+}
+
+/// Colors are used only for the table format written to the terminal, unless disabled by
+/// the NO_COLOR environment variable.
+pub fn color_enabled(format: OutputFormat) -> bool {
+    format == OutputFormat::Table
+        && !matches!(env::var_os("NO_COLOR"), Some(v) if !v.is_empty())
+        && std::io::stdout().is_terminal()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Text(String),
+    Number(u64),
+    Status(Status, String),
+    Null,
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Text(s)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map(|v| v.into()).unwrap_or(Value::Null)
+    }
+}
+
+impl Value {
+    pub fn status<T: ToString>(status: Status, text: T) -> Value {
+        Value::Status(status, text.to_string())
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Value::Text(s) | Value::Status(_, s) => s.clone(),
+            Value::Number(n) => n.to_string(),
+            Value::Null => "-".to_string(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        match self {
+            Value::Text(s) | Value::Status(_, s) => json_string(s),
+            Value::Number(n) => n.to_string(),
+            Value::Null => "null".to_string(),
+        }
+    }
+
+    fn to_kv(&self) -> String {
+        match self {
+            Value::Text(s) | Value::Status(_, s)
+                if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '"') =>
+            {
+                format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            Value::Null => String::new(),
+            v => v.to_text(),
+        }
+    }
+}
+
+pub type Record = Vec<(&'static str, Value)>;
+
+// Escape sequences don't take any space on the screen, hence padding is added after them
+fn colorize(value: &Value, width: usize, color: bool) -> String {
+    let text = value.to_text();
+    let padding = " ".repeat(width.saturating_sub(text.chars().count()));
+
+    match value {
+        Value::Status(s, _) if color => {
+            format!("\x1b[{}m{}\x1b[0m{}", s.color_code(), text, padding)
+        }
+        _ => text + &padding,
+    }
+}
+
+fn json_object(record: &Record) -> String {
+    let fields: Vec<String> = record
+        .iter()
+        .map(|(k, v)| format!("{}:{}", json_string(k), v.to_json()))
+        .collect();
+
+    format!("{{{}}}", fields.join(","))
+}
+
+fn kv_line(record: &Record) -> String {
+    record
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v.to_kv()))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Keys are snake_case, e.g. "max_index" is labeled "Max index"
+fn label(key: &str) -> String {
+    let label = key.replace('_', " ");
+    let mut chars = label.chars();
+
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => label,
+    }
+}
+
+/// Renders single record, e.g. summary printed by verify.
+pub fn render_record(format: OutputFormat, record: &Record, color: bool) -> String {
+    match format {
+        OutputFormat::Table => {
+            let width = record.iter().map(|(k, _)| k.len() + 1).max().unwrap_or(0);
+
+            record
+                .iter()
+                .map(|(k, v)| {
+                    format!(
+                        "{:<w$} {}\n",
+                        label(k) + ":",
+                        colorize(v, 0, color),
+                        w = width
+                    )
+                })
+                .collect()
+        }
+        OutputFormat::Json => format!("{}\n", json_object(record)),
+        OutputFormat::Kv => record
+            .iter()
+            .map(|(k, v)| format!("{}={}\n", k, v.to_kv()))
+            .collect(),
+    }
+}
+
+/// Renders list of records with the same keys, e.g. one record per network link.
+pub fn render_records(format: OutputFormat, records: &[Record], color: bool) -> String {
+    match format {
+        OutputFormat::Table => {
+            let header: Vec<String> = match records.first() {
+                Some(r) => r.iter().map(|(k, _)| label(k).to_uppercase()).collect(),
+                None => return String::new(),
+            };
+            let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+            for r in records {
+                for (w, (_, v)) in widths.iter_mut().zip(r) {
+                    *w = (*w).max(v.to_text().chars().count());
+                }
+            }
+
+            let line = |cells: Vec<String>| format!("{}\n", cells.join("  ").trim_end());
+            let mut out = line(
+                header
+                    .iter()
+                    .zip(&widths)
+                    .map(|(h, w)| format!("{:<w$}", h, w = w))
+                    .collect(),
+            );
+
+            for r in records {
+                out.push_str(&line(
+                    r.iter()
+                        .zip(&widths)
+                        .map(|((_, v), w)| colorize(v, *w, color))
+                        .collect(),
+                ));
+            }
+
+            out
+        }
+        OutputFormat::Json => {
+            let objects: Vec<String> = records.iter().map(json_object).collect();
+            format!("[{}]\n", objects.join(","))
+        }
+        OutputFormat::Kv => records
+            .iter()
+            .map(|r| format!("{}\n", kv_line(r)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> Record {
+        vec![
+            ("name", Value::from("net0")),
+            ("max_index", Value::from(99)),
+            ("path", Value::from(None::<String>)),
+            ("source", Value::from("link file \"a\"")),
+        ]
+    }
+
+    #[test]
+    fn output_format_from_str() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn output_take_format() {
+        let mut args: Vec<String> = ["prefixdevname", "plan", "--output", "kv", "net"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+
+        assert_eq!(take_output_format(&mut args).unwrap(), OutputFormat::Kv);
+        assert_eq!(args, vec!["prefixdevname", "plan", "net"]);
+
+        let mut args = vec!["plan".to_string(), "--output=json".to_string()];
+        assert_eq!(take_output_format(&mut args).unwrap(), OutputFormat::Json);
+        assert_eq!(args, vec!["plan"]);
+
+        let mut args = vec!["verify".to_string()];
+        assert_eq!(take_output_format(&mut args).unwrap(), OutputFormat::Table);
+
+        let mut args = vec!["verify".to_string(), "--output".to_string()];
+        assert!(take_output_format(&mut args).is_err());
+    }
+
+    #[test]
+    fn output_record() {
+        assert_eq!(
+            render_record(OutputFormat::Table, &record(), false),
+            "Name:      net0\nMax index: 99\nPath:      -\nSource:    link file \"a\"\n"
+        );
+        assert_eq!(
+            render_record(OutputFormat::Json, &record(), false),
+            "{\"name\":\"net0\",\"max_index\":99,\"path\":null,\"source\":\"link file \\\"a\\\"\"}\n"
+        );
+        assert_eq!(
+            render_record(OutputFormat::Kv, &record(), false),
+            "name=net0\nmax_index=99\npath=\nsource=\"link file \\\"a\\\"\"\n"
+        );
+    }
+
+    #[test]
+    fn output_records_table() {
+        let records = vec![
+            vec![("name", Value::from("net0")), ("hwaddr", Value::from("A"))],
+            vec![("name", Value::from("net10")), ("hwaddr", Value::Null)],
+        ];
+
+        assert_eq!(
+            render_records(OutputFormat::Table, &records, false),
+            "NAME   HWADDR\nnet0   A\nnet10  -\n"
+        );
+        assert_eq!(render_records(OutputFormat::Table, &[], false), "");
+        assert_eq!(render_records(OutputFormat::Json, &[], false), "[]\n");
+    }
+
+    #[test]
+    fn output_color() {
+        let records = vec![
+            vec![
+                ("name", Value::status(Status::Ok, "net0")),
+                ("hwaddr", Value::from("A")),
+            ],
+            vec![
+                ("name", Value::status(Status::Conflict, "net10")),
+                ("hwaddr", Value::Null),
+            ],
+        ];
+
+        assert_eq!(
+            render_records(OutputFormat::Table, &records, true),
+            "NAME   HWADDR\n\x1b[32mnet0\x1b[0m   A\n\x1b[31mnet10\x1b[0m  -\n"
+        );
+        assert_eq!(
+            render_records(OutputFormat::Kv, &records, true),
+            "name=net0 hwaddr=A\nname=net10 hwaddr=\n"
+        );
+    }
+}
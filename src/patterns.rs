@@ -0,0 +1,171 @@
+// SPDX-License-Identifier:  MIT
+
+// Patterns matched on interface names and the kernel command line are trivial. Without the
+// "regex" feature they are matched by hand, which keeps the regex crate out of the build.
+
+#[cfg(feature = "regex")]
+mod with_regex {
+    use regex::Regex;
+
+    pub fn name_prefix(name: &str) -> String {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"([[:alpha:]]+)\d+").unwrap();
+        }
+
+        match RE.captures(name) {
+            Some(c) => c[1].to_string(),
+            None => "".to_string(),
+        }
+    }
+
+    pub fn contains_prefixed_index(ifname: &str, prefix: &str) -> bool {
+        let re: Regex = Regex::new(&format!("{}\\d+", prefix)).unwrap();
+
+        re.is_match(ifname)
+    }
+
+    pub fn is_prefixed_index(name: &str, prefix: &str) -> bool {
+        Regex::new(&format!("^{}\\d+$", prefix)).is_ok_and(|re| re.is_match(name))
+    }
+
+    pub fn prefix_argument_value(content: &str) -> String {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"net.ifnames.prefix=([[:alpha:]]+)").unwrap();
+        }
+
+        match RE.captures(content) {
+            Some(c) => c[1].to_string(),
+            None => "".to_string(),
+        }
+    }
+}
+
+#[cfg_attr(feature = "regex", allow(dead_code))]
+mod manual {
+    fn leading_letters(s: &str) -> &str {
+        let end = s
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(s.len());
+
+        &s[..end]
+    }
+
+    pub fn name_prefix(name: &str) -> String {
+        let mut rest = name;
+
+        // The first run of letters followed by a digit
+        while let Some(start) = rest.find(|c: char| c.is_ascii_alphabetic()) {
+            let letters = leading_letters(&rest[start..]);
+            let after = &rest[start + letters.len()..];
+
+            if after.starts_with(|c: char| c.is_ascii_digit()) {
+                return letters.to_string();
+            }
+
+            rest = after;
+        }
+
+        "".to_string()
+    }
+
+    pub fn contains_prefixed_index(ifname: &str, prefix: &str) -> bool {
+        ifname
+            .match_indices(prefix)
+            .any(|(i, _)| ifname[i + prefix.len()..].starts_with(|c: char| c.is_ascii_digit()))
+    }
+
+    pub fn is_prefixed_index(name: &str, prefix: &str) -> bool {
+        name.strip_prefix(prefix)
+            .is_some_and(|i| !i.is_empty() && i.chars().all(|c| c.is_ascii_digit()))
+    }
+
+    pub fn prefix_argument_value(content: &str) -> String {
+        let key = "net.ifnames.prefix=";
+
+        content
+            .match_indices(key)
+            .map(|(i, _)| leading_letters(&content[i + key.len()..]))
+            .find(|v| !v.is_empty())
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
+#[cfg(not(feature = "regex"))]
+pub use manual::*;
+#[cfg(feature = "regex")]
+pub use with_regex::*;
+
+#[cfg(test)]
+mod tests {
+    struct Patterns {
+        name_prefix: fn(&str) -> String,
+        contains_prefixed_index: fn(&str, &str) -> bool,
+        is_prefixed_index: fn(&str, &str) -> bool,
+        prefix_argument_value: fn(&str) -> String,
+    }
+
+    // Both implementations must agree on all the cases
+    fn check_patterns(p: Patterns) {
+        assert_eq!((p.name_prefix)("net0"), "net");
+        assert_eq!((p.name_prefix)("internal123"), "internal");
+        assert_eq!((p.name_prefix)("42net7"), "net");
+        assert_eq!((p.name_prefix)("net-lan0"), "lan");
+        assert_eq!((p.name_prefix)("net"), "");
+        assert_eq!((p.name_prefix)("0"), "");
+
+        assert!((p.contains_prefixed_index)("net0", "net"));
+        assert!((p.contains_prefixed_index)("xnet12y", "net"));
+        assert!((p.contains_prefixed_index)("netnet1", "net"));
+        assert!(!(p.contains_prefixed_index)("eth0", "net"));
+        assert!(!(p.contains_prefixed_index)("net", "net"));
+        assert!(!(p.contains_prefixed_index)("netx0", "net"));
+
+        assert!((p.is_prefixed_index)("net2", "net"));
+        assert!((p.is_prefixed_index)("net123", "net"));
+        assert!(!(p.is_prefixed_index)("net", "net"));
+        assert!(!(p.is_prefixed_index)("net2a", "net"));
+        assert!(!(p.is_prefixed_index)("xnet2", "net"));
+        assert!(!(p.is_prefixed_index)("eth2", "net"));
+
+        assert_eq!(
+            (p.prefix_argument_value)("ro net.ifnames.prefix=net quiet"),
+            "net"
+        );
+        assert_eq!(
+            (p.prefix_argument_value)("net.ifnames.prefix=lan_0 net.ifnames.prefix=net"),
+            "lan"
+        );
+        assert_eq!(
+            (p.prefix_argument_value)("net.ifnames.prefix= net.ifnames.prefix=net"),
+            "net"
+        );
+        assert_eq!((p.prefix_argument_value)("net.ifnames.prefix=0"), "");
+        assert_eq!((p.prefix_argument_value)("ro quiet"), "");
+    }
+
+    #[test]
+    fn patterns_manual() {
+        use super::manual::*;
+
+        check_patterns(Patterns {
+            name_prefix,
+            contains_prefixed_index,
+            is_prefixed_index,
+            prefix_argument_value,
+        });
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn patterns_regex() {
+        use super::with_regex::*;
+
+        check_patterns(Patterns {
+            name_prefix,
+            contains_prefixed_index,
+            is_prefixed_index,
+            prefix_argument_value,
+        });
+    }
+}
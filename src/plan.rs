@@ -0,0 +1,843 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fmt;
+#[cfg(not(feature = "minimal"))]
+use std::fs;
+use std::path::PathBuf;
+
+use crate::audit::AuditRecord;
+use crate::backup::BACKUP_DIR;
+use crate::config::*;
+use crate::counters::bump_counters;
+#[cfg(not(feature = "minimal"))]
+use crate::drift::track_written;
+use crate::legacy::*;
+use crate::link_match::*;
+use crate::naming_policy::*;
+#[cfg(not(feature = "minimal"))]
+use crate::output::Status;
+use crate::settings::*;
+use crate::transaction::{CommitConflict, Transaction, TRANSACTION_RECORD};
+use crate::util::*;
+
+/// What decides the name of the device on the next boot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanSource {
+    LinkFile(PathBuf),
+    ForeignLinkFile(PathBuf),
+    CurrentName,
+    Udev(String),
+    NewName,
+    NamingPolicy(String),
+    Skipped(String),
+    #[cfg(not(feature = "minimal"))]
+    Orphaned(PathBuf),
+    Failed(String),
+    KernelCommandLine,
+    Hwdb,
+}
+
+impl fmt::Display for PlanSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanSource::LinkFile(p) => write!(f, "link file {}", p.display()),
+            PlanSource::ForeignLinkFile(p) => write!(f, "foreign link file {}", p.display()),
+            PlanSource::CurrentName => write!(f, "current name"),
+            PlanSource::Udev(p) => write!(f, "udev ({})", p),
+            PlanSource::NewName => write!(f, "new name"),
+            PlanSource::NamingPolicy(r) => write!(f, "naming policy ({})", r),
+            PlanSource::Skipped(r) => write!(f, "skipped ({})", r),
+            #[cfg(not(feature = "minimal"))]
+            PlanSource::Orphaned(p) => write!(f, "orphaned link file {}", p.display()),
+            PlanSource::Failed(e) => write!(f, "failed ({})", e),
+            PlanSource::KernelCommandLine => write!(f, "kernel command line (ifname=)"),
+            PlanSource::Hwdb => write!(f, "hwdb ({})", HWDB_NAME_PROPERTY),
+        }
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+impl PlanSource {
+    /// Names provided by other configuration conflict with our naming scheme.
+    pub fn status(&self) -> Status {
+        match self {
+            PlanSource::LinkFile(_)
+            | PlanSource::CurrentName
+            | PlanSource::NewName
+            | PlanSource::NamingPolicy(_)
+            | PlanSource::KernelCommandLine
+            | PlanSource::Hwdb => Status::Ok,
+            PlanSource::ForeignLinkFile(_) | PlanSource::Udev(_) | PlanSource::Failed(_) => {
+                Status::Conflict
+            }
+            PlanSource::Skipped(_) | PlanSource::Orphaned(_) => Status::Warning,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedLink {
+    pub hwaddr: String,
+    pub name: Option<String>,
+    pub source: PlanSource,
+    pub kernel_name: String,
+    pub path: Option<String>,
+    pub driver: Option<String>,
+    pub speed: Option<u64>,
+}
+
+/// Link speed in Mb/s as reported in sysfs. Kernel reports -1 (or fails the read) when the link
+/// is down or the driver doesn't know the speed.
+pub fn parse_speed(speed: &str) -> Option<u64> {
+    speed
+        .trim()
+        .parse::<i64>()
+        .ok()
+        .filter(|s| *s > 0)
+        .map(|s| s as u64)
+}
+
+#[cfg(not(feature = "minimal"))]
+pub fn format_speed(speed: Option<u64>) -> String {
+    match speed {
+        Some(s) if s >= 1000 && s % 1000 == 0 => format!("{}Gb/s", s / 1000),
+        Some(s) => format!("{}Mb/s", s),
+        None => "-".to_string(),
+    }
+}
+
+/// Decision about the name of the new link that depends on its MAC address, mirrors the MAC
+/// address policies applied when the link is named by udev.
+pub enum HwaddrDecision {
+    Match(LinkMatch, String),
+    Skip(String),
+}
+
+pub fn hwaddr_decision(settings: &Settings, device: &MatchedDevice) -> HwaddrDecision {
+    let path_match = |reason: &str| match &device.path {
+        Some(p) => HwaddrDecision::Match(LinkMatch::Path(p.clone()), device.hwaddr.clone()),
+        None => HwaddrDecision::Skip(format!("{}, path unknown", reason)),
+    };
+    let property_match = |reason: &str| {
+        let property = settings.match_property.as_deref().unwrap_or_default();
+
+        match device.properties.get(property).filter(|v| !v.is_empty()) {
+            Some(v) => HwaddrDecision::Match(
+                LinkMatch::Property(format!("{}={}", property, v)),
+                device.hwaddr.clone(),
+            ),
+            None => HwaddrDecision::Skip(format!("{}, property {} unknown", reason, property)),
+        }
+    };
+
+    if settings.original_name_selected(&device.original_name) {
+        return HwaddrDecision::Match(
+            LinkMatch::OriginalName(device.original_name.clone()),
+            device.hwaddr.clone(),
+        );
+    }
+
+    if !hwaddr_assigned(&device.hwaddr) {
+        return match settings.invalid_hwaddr_policy {
+            InvalidHwaddrPolicy::Skip => HwaddrDecision::Skip("unusable MAC address".to_string()),
+            InvalidHwaddrPolicy::Path => path_match("unusable MAC address"),
+            InvalidHwaddrPolicy::Property => property_match("unusable MAC address"),
+        };
+    }
+
+    if hwaddr_locally_administered(&device.hwaddr) {
+        return match settings.local_hwaddr_policy {
+            LocalHwaddrPolicy::Skip => {
+                HwaddrDecision::Skip("locally administered MAC address".to_string())
+            }
+            LocalHwaddrPolicy::Proceed => {
+                HwaddrDecision::Match(LinkMatch::MACAddress, device.hwaddr.clone())
+            }
+            LocalHwaddrPolicy::Path => path_match("locally administered MAC address"),
+            LocalHwaddrPolicy::Property => property_match("locally administered MAC address"),
+            LocalHwaddrPolicy::Permanent => match &device.permanent_hwaddr {
+                Some(a) => HwaddrDecision::Match(LinkMatch::PermanentMACAddress, a.clone()),
+                None => HwaddrDecision::Skip(
+                    "locally administered MAC address, permanent address unknown".to_string(),
+                ),
+            },
+        };
+    }
+
+    HwaddrDecision::Match(LinkMatch::MACAddress, device.hwaddr.clone())
+}
+
+fn record_assignment(settings: &Settings, staged: &StagedLink) {
+    bump_counters(|c| c.assignments += 1);
+
+    if let Some(log) = &settings.audit_log {
+        let record = AuditRecord::new(
+            &staged.link.name,
+            &staged.hwaddr,
+            staged.link.link_match.key(),
+            "batch",
+            &staged.devpath,
+        );
+
+        if let Err(e) = record.record(log) {
+            warn!("Failed to record naming decision in {}: {}", log, e);
+        }
+    }
+}
+
+/// Link whose name was allocated by the plan, its link file is written when the plan is applied.
+struct StagedLink {
+    position: usize,
+    // Prefix of the config the name was allocated from
+    prefix: String,
+    link: PrefixedLink,
+    hwaddr: String,
+    devpath: String,
+}
+
+pub fn build_link(
+    name: &str,
+    link_match: &LinkMatch,
+    hwaddr: &str,
+) -> Result<PrefixedLink, Box<dyn Error>> {
+    let name = name.to_string();
+
+    match link_match {
+        LinkMatch::MACAddress => PrefixedLink::new_with_hwaddr(&name, &hwaddr.to_string()),
+        LinkMatch::PermanentMACAddress => {
+            PrefixedLink::new_with_permanent_hwaddr(&name, &hwaddr.to_string())
+        }
+        LinkMatch::Path(p) => PrefixedLink::new_with_path(&name, p),
+        LinkMatch::OriginalName(n) => PrefixedLink::new_with_original_name(&name, n),
+        LinkMatch::Property(p) => PrefixedLink::new_with_property(&name, p),
+    }
+}
+
+/// Predicts the name of every physical NIC present in the system without writing anything.
+/// Names for NICs that don't have one yet are allocated in enumeration order.
+#[cfg(not(feature = "minimal"))]
+pub fn plan_links(
+    config: &mut NetSetupLinkConfig,
+    prefix: &str,
+    settings: &Settings,
+) -> Result<Vec<PlannedLink>, Box<dyn Error>> {
+    plan_devices(config, prefix, settings, None).map(|(plan, _, _)| plan)
+}
+
+/// Names the given NICs (all physical NICs when None) in one go and writes their link files.
+/// Link files are written in one transaction, either all of them are written or none when
+/// naming of any NIC fails. The state must be loaded under the lock, which has to be held until
+/// this returns.
+pub fn apply_links(
+    config: &mut NetSetupLinkConfig,
+    prefix: &str,
+    settings: &Settings,
+    ifnames: Option<&[String]>,
+) -> Result<Vec<PlannedLink>, Box<dyn Error>> {
+    let (mut plan, mut staged, mut vendor_configs) =
+        plan_devices(config, prefix, settings, ifnames)?;
+
+    let failed = plan
+        .iter()
+        .any(|l| matches!(l.source, PlanSource::Failed(_)));
+    let committed = if failed {
+        Err(From::from("naming of other link failed"))
+    } else {
+        commit_staged(config, &mut vendor_configs, &mut plan, &mut staged)
+    };
+
+    match committed {
+        Ok(_files) => {
+            #[cfg(not(feature = "minimal"))]
+            _files.iter().for_each(|f| track_written(f));
+
+            for s in &staged {
+                record_assignment(settings, s);
+            }
+        }
+        Err(e) => {
+            for s in &staged {
+                plan[s.position].name = None;
+                plan[s.position].source =
+                    PlanSource::Failed(format!("link file not written, {}", e));
+            }
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Commits link files of all staged links. The reserved name may be taken by other naming source
+/// before the commit, e.g. by the udev event of other device allocating from the counter file
+/// without the lock. Its link file is then staged again with the next free name.
+fn commit_staged(
+    config: &mut NetSetupLinkConfig,
+    vendor_configs: &mut [NetSetupLinkConfig],
+    plan: &mut [PlannedLink],
+    staged: &mut [StagedLink],
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+
+        let committed = Transaction::new(NET_SETUP_LINK_CONF_DIR).and_then(|mut t| {
+            t.record_to(TRANSACTION_RECORD, BACKUP_DIR);
+            for s in staged.iter() {
+                vendor_configs
+                    .iter()
+                    .find(|c| c.prefix() == s.prefix)
+                    .unwrap_or(config)
+                    .stage_link(&mut t, &s.link)?;
+            }
+            t.commit()
+        });
+
+        let conflict = match &committed {
+            Err(e) if attempts < MAX_ALLOCATION_ATTEMPTS => {
+                e.downcast_ref::<CommitConflict>().and_then(|c| {
+                    staged
+                        .iter()
+                        .position(|s| s.link.link_file_path() == c.path)
+                })
+            }
+            _ => None,
+        };
+        let s = match conflict {
+            Some(i) => &mut staged[i],
+            None => return committed,
+        };
+        let config = match vendor_configs.iter_mut().find(|c| c.prefix() == s.prefix) {
+            Some(c) => c,
+            None => &mut *config,
+        };
+
+        warn!(
+            "Name {} is taken ({} already exists), trying next index",
+            s.link.name,
+            s.link.link_file_path().display()
+        );
+
+        config.skip_link_name(&s.link.name)?;
+        let name = config.reserve_link_name()?;
+        let link = build_link(&name, &s.link.link_match, &s.link.hwaddr)?
+            .with_driver(s.link.driver.clone());
+
+        config.add_link(link.clone());
+        plan[s.position].name = Some(name);
+        plan[s.position].source = PlanSource::NewName;
+        s.link = link;
+    }
+}
+
+/// Link file rewritten from the current settings.
+#[cfg(not(feature = "minimal"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegeneratedLink {
+    pub link: PrefixedLink,
+    pub previous_match: String,
+    pub changed: bool,
+}
+
+/// Link of the device built again with the current MAC address policies, the name is kept.
+/// Link is left as it is when the policies would skip the device now.
+#[cfg(not(feature = "minimal"))]
+fn regenerated_link(
+    settings: &Settings,
+    existing: &PrefixedLink,
+    device: &MatchedDevice,
+) -> Result<PrefixedLink, Box<dyn Error>> {
+    match hwaddr_decision(settings, device) {
+        HwaddrDecision::Match(link_match, hwaddr) => {
+            build_link(&existing.name, &link_match, &hwaddr)
+                .map(|l| l.with_driver(device.driver.clone().filter(|_| settings.match_driver)))
+        }
+        HwaddrDecision::Skip(reason) => {
+            warn!("Keeping the match of {}, {}", existing.name, reason);
+            Ok(existing.clone())
+        }
+    }
+}
+
+/// Rewrites all our link files from scratch, e.g. after the MAC address policy changed. Links
+/// of present devices get the match the device would get now, the other link files keep their
+/// match. Keys added by the administrator are dropped. Nothing is written when dry_run is set,
+/// otherwise all link files are written in one transaction. The state must be loaded under the
+/// lock.
+#[cfg(not(feature = "minimal"))]
+pub fn regenerate_links(
+    config: &NetSetupLinkConfig,
+    settings: &Settings,
+    dry_run: bool,
+) -> Result<Vec<RegeneratedLink>, Box<dyn Error>> {
+    let udev = libudev::Context::new()?;
+    let mut enumerate = libudev::Enumerator::new(&udev)?;
+    let options = LinkFileOptions::from_settings(settings);
+    let mut regenerated: Vec<(PrefixedLink, PrefixedLink)> = Vec::new();
+
+    config.match_links(&mut enumerate)?;
+
+    for device in enumerate.scan_devices()? {
+        if !config.link_type_accepted(&device) || device_stacked(&device) {
+            continue;
+        }
+
+        let hwaddr = device_hwaddr(&device)?;
+        let matched_device = MatchedDevice::from_device(&device, &hwaddr);
+
+        if let Some(existing) = existing_link(config, &matched_device)
+            .filter(|e| !regenerated.iter().any(|(p, _)| p.name == e.name))
+        {
+            let link = regenerated_link(settings, &existing, &matched_device)?;
+            regenerated.push((existing, link));
+        }
+    }
+
+    for link in config.file_links() {
+        if !regenerated.iter().any(|(p, _)| p.name == link.name) {
+            regenerated.push((link.clone(), link.clone()));
+        }
+    }
+
+    regenerated.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let mut result = Vec::new();
+    let mut staged = Vec::new();
+
+    for (previous, link) in regenerated {
+        let content = link.link_file_content(&options.extra_keys);
+        let changed = fs::read_to_string(link.link_file_path())
+            .map(|c| strip_provenance(&c) != content)
+            .unwrap_or(true);
+
+        staged.push((link.clone(), content));
+        result.push(RegeneratedLink {
+            link,
+            previous_match: format!("{}={}", previous.link_match.key(), previous.match_value()),
+            changed,
+        });
+    }
+
+    if dry_run || staged.is_empty() {
+        return Ok(result);
+    }
+
+    let mut t = Transaction::new(NET_SETUP_LINK_CONF_DIR)?;
+    t.record_to(TRANSACTION_RECORD, BACKUP_DIR);
+    for (link, content) in &staged {
+        let path = link.link_file_path();
+        let file_name = path
+            .file_name()
+            .ok_or("Link file path doesn't name a file")?
+            .to_string_lossy();
+
+        t.stage(&file_name, &with_provenance(content), options.mode, true)?;
+    }
+
+    t.commit()?.iter().for_each(|f| track_written(f));
+
+    Ok(result)
+}
+
+// Bonding changes the MAC address of enslaved devices, their permanent address is used instead
+fn device_hwaddr(device: &libudev::Device) -> Result<String, Box<dyn Error>> {
+    let hwaddr = if device_enslaved(device) {
+        hwaddr_enslaved(device).ok()
+    } else {
+        None
+    };
+
+    match hwaddr {
+        Some(a) => Ok(a),
+        None => device
+            .attribute_value("address")
+            .and_then(|a| a.to_str())
+            .map(|a| hwaddr_normalize(&a).unwrap_or_else(|_| a.to_string()))
+            .ok_or_else(|| From::from("Failed to read value of the 'address' sysfs attribute")),
+    }
+}
+
+// Name pinned by hwdb is used only when it is free, invalid values are ignored
+fn hwdb_link_name(
+    config: &NetSetupLinkConfig,
+    device: &libudev::Device,
+    prefix: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let property = |p: &str| {
+        device
+            .property_value(p)
+            .map(|v| v.to_string_lossy().to_string())
+    };
+
+    match hwdb_pinned_index(property, prefix) {
+        Ok(Some(i)) => {
+            let name = config.free_link_name_at(i)?;
+            if name.is_none() {
+                warn!(
+                    "Name with index {} pinned by hwdb is taken, allocating other name",
+                    i
+                );
+            }
+            Ok(name)
+        }
+        Ok(None) => Ok(None),
+        Err(e) => {
+            warn!("Ignoring name pinned by hwdb: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Prefix the NIC is named with when its vendor is listed in VendorPrefixes= with other prefix
+/// than the one from the kernel command line. Prefixes overlapping with it are ignored.
+pub fn vendor_prefix_for(
+    settings: &Settings,
+    prefix: &str,
+    hwaddr: &str,
+    vendor_id: Option<&str>,
+) -> Option<String> {
+    match settings.vendor_prefix(hwaddr, vendor_id) {
+        Some(p) if p == prefix => None,
+        Some(p) if !prefix_ok(&p) || p.starts_with(prefix) || prefix.starts_with(p) => {
+            warn!(
+                "Prefix \"{}\" of VendorPrefixes= conflicts with prefix \"{}\", ignoring it",
+                p, prefix
+            );
+            None
+        }
+        p => p.map(|p| p.to_string()),
+    }
+}
+
+// Config of the vendor prefix is loaded when the first NIC of the vendor is seen
+fn vendor_config<'a>(
+    configs: &'a mut Vec<NetSetupLinkConfig>,
+    prefix: &str,
+    main_prefix: &str,
+    settings: &Settings,
+) -> Result<&'a mut NetSetupLinkConfig, Box<dyn Error>> {
+    let i = match configs.iter().position(|c| c.prefix() == prefix) {
+        Some(i) => i,
+        None => {
+            let mut config = NetSetupLinkConfig::new_with_prefix(&prefix);
+
+            config.apply_settings(settings);
+            config.add_sibling_prefix(main_prefix);
+            config.set_naming_policy(naming_policy(settings.naming_policy, None)?);
+            config.load()?;
+            configs.push(config);
+            configs.len() - 1
+        }
+    };
+
+    Ok(&mut configs[i])
+}
+
+/// Link whose link file names the device.
+fn existing_link(config: &NetSetupLinkConfig, device: &MatchedDevice) -> Option<PrefixedLink> {
+    config
+        .for_hwaddr(&device.hwaddr)
+        .or_else(|| {
+            device
+                .permanent_hwaddr
+                .as_ref()
+                .and_then(|a| config.for_hwaddr(a))
+        })
+        .or_else(|| device.path.as_ref().and_then(|p| config.for_path(p)))
+        .or_else(|| config.for_properties(&device.properties))
+        .or_else(|| config.for_original_name(&device.original_name))
+        .filter(|l| l.link_file_path().exists())
+}
+
+// Configs of the vendor prefixes are returned too, names staged from them are committed with them
+type DevicesPlan = (Vec<PlannedLink>, Vec<StagedLink>, Vec<NetSetupLinkConfig>);
+
+fn plan_devices(
+    config: &mut NetSetupLinkConfig,
+    prefix: &str,
+    settings: &Settings,
+    ifnames: Option<&[String]>,
+) -> Result<DevicesPlan, Box<dyn Error>> {
+    let udev = libudev::Context::new()?;
+    let mut enumerate = libudev::Enumerator::new(&udev)?;
+    let mut plan = Vec::new();
+    let mut staged = Vec::new();
+    let mut vendor_configs = Vec::new();
+
+    config.set_naming_policy(naming_policy(settings.naming_policy, None)?);
+
+    config.match_links(&mut enumerate)?;
+
+    for device in enumerate.scan_devices()? {
+        if !config.link_type_accepted(&device) || device_stacked(&device) {
+            continue;
+        }
+
+        let ifname = match device.sysname().and_then(|n| n.to_str()) {
+            Some(n) => n.to_string(),
+            None => {
+                warn!(
+                    "Skipping device {}, interface name is not valid UTF-8",
+                    device.sysname().unwrap_or_default().to_string_lossy()
+                );
+                continue;
+            }
+        };
+
+        if matches!(ifnames, Some(n) if !n.contains(&ifname)) {
+            continue;
+        }
+
+        let hwaddr = device_hwaddr(&device)?;
+        let matched_device = MatchedDevice::from_device(&device, &hwaddr);
+        let speed = device
+            .attribute_value("speed")
+            .and_then(|s| s.to_str())
+            .and_then(parse_speed);
+
+        let vendor_id = device.syspath().and_then(|p| {
+            device_vendor_id(
+                p,
+                device
+                    .property_value("ID_VENDOR_ID")
+                    .map(|v| v.to_string_lossy().to_string()),
+            )
+        });
+        let vendor_prefix = vendor_prefix_for(settings, prefix, &hwaddr, vendor_id.as_deref());
+        let existing = existing_link(config, &matched_device);
+
+        // NICs of the vendors listed in VendorPrefixes= are named from the config of their prefix,
+        // its own index pool
+        let (config, prefix) = match &vendor_prefix {
+            Some(p) => (
+                vendor_config(&mut vendor_configs, p, prefix, settings)?,
+                p.as_str(),
+            ),
+            None => (&mut *config, prefix),
+        };
+        let existing = existing.or_else(|| existing_link(config, &matched_device));
+
+        let (name, source) = if let Some(n) = settings.cmdline_ifname(&hwaddr) {
+            (Some(n.to_string()), PlanSource::KernelCommandLine)
+        } else if let Some(n) = (settings.compatibility_mode == CompatibilityMode::Rhel7)
+            .then(|| legacy_rule_name(&hwaddr))
+            .flatten()
+        {
+            (Some(n), PlanSource::Udev(NET_RULES_FILE.to_string()))
+        } else if let Some(l) = existing {
+            (
+                Some(l.name.to_string()),
+                PlanSource::LinkFile(l.link_file_path()),
+            )
+        } else if let Some(f) = link_file_claiming_device(&matched_device, settings.strict)? {
+            (link_file_name(&f), PlanSource::ForeignLinkFile(f))
+        } else if !rename_needed(&ifname, prefix)? {
+            (Some(ifname.clone()), PlanSource::CurrentName)
+        } else if let Some((property, name)) = udev_provided_name(
+            |p| {
+                device
+                    .property_value(p)
+                    .map(|v| v.to_string_lossy().to_string())
+            },
+            &ifname,
+            prefix,
+        )
+        .filter(|_| settings.respect_udev_names)
+        {
+            (Some(name), PlanSource::Udev(property))
+        } else if let Some(d) = matched_device
+            .driver
+            .as_ref()
+            .filter(|d| settings.driver_skipped(d))
+        {
+            (
+                None,
+                PlanSource::Skipped(format!("driver {} is skipped", d)),
+            )
+        } else {
+            match hwaddr_decision(settings, &matched_device) {
+                HwaddrDecision::Skip(reason) => (None, PlanSource::Skipped(reason)),
+                HwaddrDecision::Match(link_match, match_hwaddr) => {
+                    let driver = matched_device
+                        .driver
+                        .clone()
+                        .filter(|_| settings.match_driver);
+                    let build = |n: &str| {
+                        build_link(n, &link_match, &match_hwaddr)
+                            .map(|l| l.with_driver(driver.clone()))
+                    };
+                    let policy_device = PolicyDevice::new(&match_hwaddr, device.syspath());
+
+                    let allocated = hwdb_link_name(config, &device, prefix)
+                        .and_then(|h| match h {
+                            Some(n) => Ok(Some((n, PlanSource::Hwdb))),
+                            None => config
+                                .preferred_link_name(&policy_device)
+                                .and_then(|p| match p {
+                                    Some(p) => Ok(Some(p)),
+                                    None => config.pooled_link_name(&policy_device),
+                                })
+                                .map(|p| p.map(|(n, p)| (n, PlanSource::NamingPolicy(p.reason)))),
+                        })
+                        .and_then(|p| {
+                            let (name, source) = match p {
+                                Some(p) => p,
+                                None => (config.reserve_link_name()?, PlanSource::NewName),
+                            };
+                            Ok((build(&name)?, source))
+                        });
+
+                    match allocated {
+                        Ok((link, source)) => {
+                            config.add_link(link.clone());
+                            staged.push(StagedLink {
+                                position: plan.len(),
+                                prefix: prefix.to_string(),
+                                link: link.clone(),
+                                hwaddr: hwaddr.clone(),
+                                devpath: device
+                                    .devpath()
+                                    .map(|p| p.to_string_lossy().to_string())
+                                    .unwrap_or_default(),
+                            });
+                            (Some(link.name.into()), source)
+                        }
+                        Err(e) => (None, PlanSource::Failed(e.to_string())),
+                    }
+                }
+            }
+        };
+
+        plan.push(PlannedLink {
+            hwaddr,
+            name,
+            source,
+            kernel_name: ifname,
+            path: matched_device.path,
+            driver: matched_device.driver,
+            speed,
+        });
+    }
+
+    Ok((plan, staged, vendor_configs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(hwaddr: &str) -> MatchedDevice {
+        MatchedDevice {
+            hwaddr: hwaddr.to_string(),
+            path: Some("pci-0000:00:03.0".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn plan_hwaddr_decision() {
+        let mut settings = Settings::default();
+
+        assert!(matches!(
+            hwaddr_decision(&settings, &device("A4:BB:6D:1C:08:B7")),
+            HwaddrDecision::Match(LinkMatch::MACAddress, _)
+        ));
+        assert!(matches!(
+            hwaddr_decision(&settings, &device("00:00:00:00:00:00")),
+            HwaddrDecision::Skip(_)
+        ));
+
+        settings.local_hwaddr_policy = LocalHwaddrPolicy::Path;
+        assert!(matches!(
+            hwaddr_decision(&settings, &device("52:54:00:1C:08:B7")),
+            HwaddrDecision::Match(LinkMatch::Path(_), _)
+        ));
+
+        settings.local_hwaddr_policy = LocalHwaddrPolicy::Permanent;
+        assert!(matches!(
+            hwaddr_decision(&settings, &device("52:54:00:1C:08:B7")),
+            HwaddrDecision::Skip(_)
+        ));
+
+        settings.original_name_devices = vec!["usb*".to_string()];
+        let mut d = device("00:00:00:00:00:00");
+        d.original_name = "usb0".to_string();
+        assert!(matches!(
+            hwaddr_decision(&settings, &d),
+            HwaddrDecision::Match(LinkMatch::OriginalName(n), _) if n == "usb0"
+        ));
+        settings.original_name_devices.clear();
+
+        settings.local_hwaddr_policy = LocalHwaddrPolicy::Property;
+        settings.match_property = Some("ID_SERIAL".to_string());
+        assert!(matches!(
+            hwaddr_decision(&settings, &device("52:54:00:1C:08:B7")),
+            HwaddrDecision::Skip(_)
+        ));
+        let mut d = device("52:54:00:1C:08:B7");
+        d.properties
+            .insert("ID_SERIAL".to_string(), "0x1234".to_string());
+        assert!(matches!(
+            hwaddr_decision(&settings, &d),
+            HwaddrDecision::Match(LinkMatch::Property(p), _) if p == "ID_SERIAL=0x1234"
+        ));
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn plan_regenerated_link() {
+        let mut settings = Settings::default();
+        let existing = PrefixedLink::new_with_hwaddr(&"net3", &"52:54:00:1C:08:B7").unwrap();
+        let device = MatchedDevice {
+            permanent_hwaddr: Some("A4:BB:6D:1C:08:B7".to_string()),
+            ..device("52:54:00:1C:08:B7")
+        };
+
+        assert_eq!(
+            regenerated_link(&settings, &existing, &device).unwrap(),
+            existing
+        );
+
+        settings.local_hwaddr_policy = LocalHwaddrPolicy::Permanent;
+        let link = regenerated_link(&settings, &existing, &device).unwrap();
+        assert_eq!(link.name, "net3");
+        assert_eq!(link.link_match, LinkMatch::PermanentMACAddress);
+        assert_eq!(link.match_value(), "A4:BB:6D:1C:08:B7");
+
+        // Skipped device keeps its link
+        settings.local_hwaddr_policy = LocalHwaddrPolicy::Skip;
+        assert_eq!(
+            regenerated_link(&settings, &existing, &device).unwrap(),
+            existing
+        );
+    }
+
+    #[test]
+    fn plan_speed() {
+        assert_eq!(parse_speed("1000\n"), Some(1000));
+        assert_eq!(parse_speed("-1"), None);
+        assert_eq!(parse_speed(""), None);
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn plan_format_speed() {
+        assert_eq!(format_speed(Some(10000)), "10Gb/s");
+        assert_eq!(format_speed(Some(100)), "100Mb/s");
+        assert_eq!(format_speed(Some(2500)), "2500Mb/s");
+        assert_eq!(format_speed(None), "-");
+    }
+
+    #[test]
+    fn plan_source_display() {
+        assert_eq!(
+            PlanSource::Udev("ID_NET_NAME".to_string()).to_string(),
+            "udev (ID_NET_NAME)"
+        );
+        assert_eq!(
+            PlanSource::Skipped("unusable MAC address".to_string()).to_string(),
+            "skipped (unusable MAC address)"
+        );
+    }
+}
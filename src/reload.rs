@@ -0,0 +1,38 @@
+// SPDX-License-Identifier:  MIT
+
+use std::process::{Command, Stdio};
+
+use crate::settings::ReloadTarget;
+
+fn reload_command(target: ReloadTarget) -> (&'static str, &'static [&'static str]) {
+    match target {
+        ReloadTarget::Udev => ("udevadm", &["control", "--reload"]),
+        ReloadTarget::Networkd => ("networkctl", &["reload"]),
+    }
+}
+
+/// Tells the services to reload their configuration, so that the new link file applies to the
+/// devices appearing later in the same boot. Failures are only logged, the link file is written
+/// already. When run from the udev event the commands aren't waited for, udevd handles the reload
+/// request in its main loop and networkd may wait for udev to settle.
+pub fn reload_services(targets: &[ReloadTarget], wait: bool) {
+    for target in targets {
+        let (program, args) = reload_command(*target);
+        let child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+
+        match child {
+            Ok(mut c) if wait => match c.wait() {
+                Ok(s) if s.success() => debug!("Reloaded {:?} using {}", target, program),
+                Ok(s) => warn!("{} {} failed: {}", program, args.join(" "), s),
+                Err(e) => warn!("Failed to wait for {}: {}", program, e),
+            },
+            Ok(_) => debug!("Requested reload of {:?} using {}", target, program),
+            Err(e) => warn!("Failed to run {}: {}", program, e),
+        }
+    }
+}
@@ -0,0 +1,367 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::config::{
+    link_file_path_for_name_in, LinkMatch, NetSetupLinkConfig, PrefixedLink,
+    NET_SETUP_LINK_CONF_DIR,
+};
+use crate::counters::{count_conflict, Counters, CONFLICTS_DIR, COUNTERS_FILE};
+use crate::debounce::{cached_answer, DEBOUNCE_DIR};
+use crate::index_counter::store_index_counter;
+use crate::link_match::{link_file_claiming_device_in, MatchedDevice, LINK_FILE_SEARCH_PATH};
+use crate::link_name::LinkName;
+use crate::settings::Settings;
+
+/// Runtime state the udev event updates besides the link files.
+pub struct EventState<'a> {
+    pub link_dir: &'a Path,
+    pub link_file_search_path: &'a [&'a str],
+    pub debounce_dir: &'a Path,
+    pub counters_file: &'a Path,
+    pub conflicts_dir: &'a Path,
+    pub index_counter: &'a Path,
+}
+
+impl<'a> EventState<'a> {
+    pub fn new(index_counter: &'a Path) -> Self {
+        EventState {
+            link_dir: Path::new(NET_SETUP_LINK_CONF_DIR),
+            link_file_search_path: &LINK_FILE_SEARCH_PATH,
+            debounce_dir: Path::new(DEBOUNCE_DIR),
+            counters_file: Path::new(COUNTERS_FILE),
+            conflicts_dir: Path::new(CONFLICTS_DIR),
+            index_counter,
+        }
+    }
+}
+
+/// Properties of the event device the early answer depends on.
+#[derive(Debug, Default)]
+pub struct EventDevice {
+    // None when the device doesn't have 6 octet MAC address
+    pub hwaddr: Option<String>,
+    pub udev_provided_name: Option<(String, String)>,
+    pub driver: Option<String>,
+}
+
+/// Answer to the event given before the lock is taken.
+#[derive(Debug, PartialEq)]
+pub enum EarlyAnswer {
+    /// Name printed for udev
+    Name(String),
+    /// Nothing is printed, the device isn't named by us
+    Nothing,
+}
+
+/// Answers the event without loading the links when the device is named otherwise (by udev or
+/// ifname= on the kernel command line), its driver is skipped, or the event repeats within the
+/// debounce window. None when the event continues to the lookup of the existing name.
+pub fn early_answer(
+    settings: &Settings,
+    device: &EventDevice,
+    state: &EventState,
+) -> Option<EarlyAnswer> {
+    if settings.respect_udev_names {
+        if let Some((property, name)) = &device.udev_provided_name {
+            info!(
+                "Event device is named {} by udev ({}), not generating link file",
+                name, property
+            );
+            let hwaddr = device.hwaddr.as_deref().unwrap_or_default();
+            if let Err(e) = count_conflict(state.conflicts_dir, state.counters_file, hwaddr) {
+                debug!("Failed to count conflict of {}: {}", hwaddr, e);
+            }
+            return Some(EarlyAnswer::Nothing);
+        }
+    }
+
+    if let Some(driver) = device
+        .driver
+        .as_deref()
+        .filter(|d| settings.driver_skipped(d))
+    {
+        info!(
+            "Driver {} of the event device is listed in SkipDrivers=, ignoring",
+            driver
+        );
+        return Some(EarlyAnswer::Nothing);
+    }
+
+    let hwaddr = device.hwaddr.as_deref()?;
+
+    if !settings.debounce_window.is_zero() {
+        // Name recorded earlier is used only if its link file still exists
+        match cached_answer(state.debounce_dir, hwaddr, settings.debounce_window) {
+            Some(n)
+                if LinkName::new(&n)
+                    .is_ok_and(|l| link_file_path_for_name_in(state.link_dir, &l).exists()) =>
+            {
+                debug!(
+                    "Repeated event for {}, using name {} assigned earlier",
+                    hwaddr, n
+                );
+                return Some(EarlyAnswer::Name(n));
+            }
+            _ => {}
+        }
+    }
+
+    // dracut renames the device according to ifname= too, the names must agree
+    if let Some(n) = settings.cmdline_ifname(hwaddr) {
+        info!(
+            "Event device is named {} by ifname= on the kernel command line, not generating link file",
+            n
+        );
+        return Some(EarlyAnswer::Name(n.to_string()));
+    }
+
+    None
+}
+
+/// Configuration naming the event device already.
+#[derive(Debug, PartialEq)]
+pub enum ExistingName {
+    LinkFile(PrefixedLink),
+    ForeignLinkFile(PathBuf),
+}
+
+/// Looks up the name the event device got earlier. Events replayed by "udevadm trigger" at boot
+/// and "change" events end here, they must not write anything nor move the counters. Statistics
+/// of the enumeration and the index counter are stored only for devices that need a new name.
+pub fn existing_name(
+    config: &NetSetupLinkConfig,
+    link_match: &LinkMatch,
+    match_hwaddr: &str,
+    device: &MatchedDevice,
+    strict: bool,
+    enumeration_ms: Option<u64>,
+    state: &EventState,
+) -> Result<Option<ExistingName>, Box<dyn Error>> {
+    if let Some(l) = config.for_link_match(link_match, match_hwaddr) {
+        return Ok(Some(ExistingName::LinkFile(l)));
+    }
+
+    if let Some(f) = link_file_claiming_device_in(state.link_file_search_path, device, strict)? {
+        if let Err(e) = count_conflict(state.conflicts_dir, state.counters_file, &device.hwaddr) {
+            debug!("Failed to count conflict of {}: {}", device.hwaddr, e);
+        }
+        return Ok(Some(ExistingName::ForeignLinkFile(f)));
+    }
+
+    if let Some(ms) = enumeration_ms {
+        let update = Counters::update(state.counters_file, |c| {
+            c.enumerations += 1;
+            c.enumeration_ms_total += ms;
+            c.enumeration_ms_last = ms;
+        });
+        if let Err(e) = update {
+            debug!(
+                "Failed to update counters in {}: {}",
+                state.counters_file.display(),
+                e
+            );
+        }
+
+        if let Ok(i) = config.next_index() {
+            if let Err(e) = store_index_counter(state.index_counter, i) {
+                debug!(
+                    "Failed to store index counter {}: {}",
+                    state.index_counter.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::config::LINK_FILE_PREFIX;
+    use crate::debounce::record_answer;
+
+    // Content and modification time of every file under the directory
+    fn snapshot(dir: &Path) -> BTreeMap<PathBuf, (Vec<u8>, SystemTime)> {
+        let mut files = BTreeMap::new();
+
+        for entry in fs::read_dir(dir).unwrap().map(|e| e.unwrap()) {
+            let path = entry.path();
+
+            if path.is_dir() {
+                files.extend(snapshot(&path));
+            } else {
+                let modified = fs::metadata(&path).unwrap().modified().unwrap();
+                files.insert(path.clone(), (fs::read(&path).unwrap(), modified));
+            }
+        }
+
+        files
+    }
+
+    fn event_device(hwaddr: &str) -> EventDevice {
+        EventDevice {
+            hwaddr: Some(hwaddr.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn replayed_events_side_effects() {
+        let dir = std::env::temp_dir().join(format!("prefixdevname-replay-{}", std::process::id()));
+        let link_dir = dir.join("network");
+        let foreign_dir = dir.join("foreign");
+        let debounce_dir = dir.join("events");
+        let counters_file = dir.join("counters");
+        let conflicts_dir = dir.join("conflicts");
+        let index_counter = dir.join("net.next-index");
+        let search_path = [foreign_dir.to_str().unwrap()];
+        let state = EventState {
+            link_dir: &link_dir,
+            link_file_search_path: &search_path,
+            debounce_dir: &debounce_dir,
+            counters_file: &counters_file,
+            conflicts_dir: &conflicts_dir,
+            index_counter: &index_counter,
+        };
+
+        let ours = "52:54:00:1C:08:B7";
+        let foreign = "52:54:00:1C:08:B8";
+        let new = "52:54:00:1C:08:B9";
+        let cmdline = "52:54:00:1C:08:BA";
+        let udev_named = EventDevice {
+            udev_provided_name: Some(("ID_NET_NAME".to_string(), "eno1".to_string())),
+            ..event_device("52:54:00:1C:08:BB")
+        };
+        let mut settings = Settings {
+            respect_udev_names: true,
+            debounce_window: Duration::from_secs(60),
+            cmdline_ifnames: vec![("boot0".to_string(), cmdline.to_string())],
+            ..Default::default()
+        };
+
+        fs::create_dir_all(&link_dir).unwrap();
+        fs::create_dir_all(&foreign_dir).unwrap();
+        fs::write(
+            link_dir.join(format!("{}net0.link", LINK_FILE_PREFIX)),
+            format!("[Match]\nMACAddress={}\n\n[Link]\nName=net0\n", ours),
+        )
+        .unwrap();
+        fs::write(
+            foreign_dir.join("10-uplink.link"),
+            format!("[Match]\nMACAddress={}\n\n[Link]\nName=uplink0\n", foreign),
+        )
+        .unwrap();
+
+        let mut config = NetSetupLinkConfig::new_with_prefix(&"net");
+        config.set_offline(true);
+        config.load_link_files_in(&link_dir).unwrap();
+
+        // Early answer, then the lookup of the existing name, as generate() does
+        let lookup = |hwaddr: &str, enumeration_ms| {
+            let device = MatchedDevice {
+                hwaddr: hwaddr.to_string(),
+                ..Default::default()
+            };
+            existing_name(
+                &config,
+                &LinkMatch::MACAddress,
+                hwaddr,
+                &device,
+                false,
+                enumeration_ms,
+                &state,
+            )
+            .unwrap()
+        };
+
+        // First events, the answer is remembered and the counters move
+        assert_eq!(early_answer(&settings, &event_device(ours), &state), None);
+        assert!(
+            matches!(lookup(ours, Some(5)), Some(ExistingName::LinkFile(l)) if l.name == "net0")
+        );
+        record_answer(&debounce_dir, ours, "net0").unwrap();
+        assert_eq!(
+            early_answer(&settings, &event_device(foreign), &state),
+            None
+        );
+        assert_eq!(
+            lookup(foreign, Some(5)),
+            Some(ExistingName::ForeignLinkFile(
+                foreign_dir.join("10-uplink.link")
+            ))
+        );
+        assert_eq!(early_answer(&settings, &event_device(new), &state), None);
+        assert_eq!(lookup(new, Some(5)), None);
+        assert_eq!(
+            early_answer(&settings, &udev_named, &state),
+            Some(EarlyAnswer::Nothing)
+        );
+
+        let counters = fs::read(&counters_file).unwrap();
+        assert_eq!(Counters::load(&counters_file).unwrap().conflicts, 2);
+        assert!(index_counter.exists());
+
+        // Replays of the handled devices, with and without the debounce window. Repeated event
+        // prints the name remembered earlier, event of the device with our link file prints
+        // nothing, ifname= on the kernel command line wins over the lookup.
+        let before = snapshot(&dir);
+        std::thread::sleep(Duration::from_millis(10));
+        for _ in 0..3 {
+            for window in [Duration::from_secs(60), Duration::ZERO] {
+                settings.debounce_window = window;
+
+                let expected = match window.is_zero() {
+                    true => None,
+                    false => Some(EarlyAnswer::Name("net0".to_string())),
+                };
+                assert_eq!(
+                    early_answer(&settings, &event_device(ours), &state),
+                    expected
+                );
+                assert_eq!(
+                    early_answer(&settings, &event_device(foreign), &state),
+                    None
+                );
+                assert_eq!(
+                    early_answer(&settings, &event_device(cmdline), &state),
+                    Some(EarlyAnswer::Name("boot0".to_string()))
+                );
+                assert_eq!(
+                    early_answer(&settings, &udev_named, &state),
+                    Some(EarlyAnswer::Nothing)
+                );
+
+                for enumeration_ms in [None, Some(5)] {
+                    assert!(matches!(
+                        lookup(ours, enumeration_ms),
+                        Some(ExistingName::LinkFile(_))
+                    ));
+                    assert!(matches!(
+                        lookup(foreign, enumeration_ms),
+                        Some(ExistingName::ForeignLinkFile(_))
+                    ));
+                }
+            }
+        }
+        let after = snapshot(&dir);
+        let counters_after = fs::read(&counters_file).unwrap();
+
+        // Remembered name whose link file is gone isn't used
+        settings.debounce_window = Duration::from_secs(60);
+        fs::remove_file(link_dir.join(format!("{}net0.link", LINK_FILE_PREFIX))).unwrap();
+        let stale = early_answer(&settings, &event_device(ours), &state);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(before, after);
+        assert_eq!(counters, counters_after);
+        assert_eq!(stale, None);
+    }
+}
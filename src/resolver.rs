@@ -0,0 +1,362 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use ini::Ini;
+
+use crate::audit::AUDIT_LOG;
+use crate::cmdline::{naming_policy_argument, prefix_argument, read_cmdline};
+use crate::conf_loader::{config_files, load_config_files};
+use crate::settings::*;
+
+pub static ENVIRONMENT_PREFIX: &str = "PREFIXDEVNAME_";
+pub static CREDENTIAL_PREFIX: &str = "prefixdevname.";
+pub static PREFIX_KEY: &str = "Prefix";
+
+/// Keys that can be resolved, the prefix and the keys of the [Naming] section.
+pub static SETTING_KEYS: [&str; 34] = [
+    "Prefix",
+    "InvalidMACAddressPolicy",
+    "LocalMACAddressPolicy",
+    "RespectUdevNames",
+    "ConflictingKernelArgumentsPolicy",
+    "MaxIndex",
+    "AuditLog",
+    "PrometheusTextfile",
+    "ManifestFile",
+    "LinkTypes",
+    "VLANNames",
+    "VirtualDevicePrefixes",
+    "VendorPrefixes",
+    "PrefixCollisionPolicy",
+    "OrphanedLinkFilePolicy",
+    "OrphanReuseDelaySec",
+    "NameInContainers",
+    "SkipDrivers",
+    "MatchDriver",
+    "MatchProperty",
+    "OriginalNameDevices",
+    "RemovedDevicePolicy",
+    "Actions",
+    "AllocationMode",
+    "Strict",
+    "FirmwareLabels",
+    "NamingPolicy",
+    "IndexPools",
+    "LinkFileMode",
+    "ReloadAfterWrite",
+    "TimeoutSec",
+    "SettleTimeoutSec",
+    "DebounceWindowSec",
+    "CompatibilityMode",
+];
+
+// Values equivalent to Settings::default(), empty value means unset
+fn default_value(key: &str) -> &'static str {
+    match key {
+        "InvalidMACAddressPolicy" => "skip",
+        "LocalMACAddressPolicy" => "proceed",
+        "RespectUdevNames" | "VLANNames" | "NameInContainers" | "Strict" | "FirmwareLabels"
+        | "MatchDriver" => "no",
+        "ConflictingKernelArgumentsPolicy" | "PrefixCollisionPolicy" => "warn",
+        "AuditLog" => AUDIT_LOG,
+        "LinkTypes" => "ether",
+        "OrphanedLinkFilePolicy" => "occupied",
+        "RemovedDevicePolicy" => "keep",
+        "Actions" => "add",
+        "AllocationMode" => "locked",
+        "NamingPolicy" => "sequential",
+        "LinkFileMode" => "0644",
+        "TimeoutSec" => "30",
+        "DebounceWindowSec" => "5",
+        "OrphanReuseDelaySec" => "300",
+        "CompatibilityMode" => "none",
+        "SettleTimeoutSec" => "0",
+        _ => "",
+    }
+}
+
+/// Where the effective value comes from, ordered from the most important source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConfigSource {
+    CommandLine,
+    Environment,
+    Credential,
+    KernelCommandLine,
+    ConfigFile,
+    Default,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::CommandLine => write!(f, "command line option"),
+            ConfigSource::Environment => write!(f, "environment"),
+            ConfigSource::Credential => write!(f, "credential"),
+            ConfigSource::KernelCommandLine => write!(f, "kernel command line"),
+            ConfigSource::ConfigFile => write!(f, "configuration file"),
+            ConfigSource::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// Resolves the prefix and the settings from all sources, the command line options of
+/// prefixdevname (--prefix and --set KEY=VALUE) win over PREFIXDEVNAME_<KEY> environment
+/// variables, those over systemd credentials prefixdevname.<key>, those over the kernel command
+/// line (net.ifnames.prefix= and net.ifnames.policy=), those over the configuration files.
+pub struct Resolver {
+    options: Vec<(String, String)>,
+    environment: Vec<(String, String)>,
+    credentials: Option<PathBuf>,
+    kernel_cmdline: String,
+    files: Ini,
+}
+
+impl Resolver {
+    pub fn new(
+        options: Vec<(String, String)>,
+        environment: Vec<(String, String)>,
+        credentials: Option<PathBuf>,
+        kernel_cmdline: String,
+        files: Ini,
+    ) -> Resolver {
+        Resolver {
+            options,
+            environment,
+            credentials,
+            kernel_cmdline,
+            files,
+        }
+    }
+
+    /// Reads all sources of the running system, options are given by the caller.
+    pub fn load(options: &[(String, String)]) -> Result<Resolver, Box<dyn Error>> {
+        let environment = std::env::vars()
+            .filter(|(k, _)| k.starts_with(ENVIRONMENT_PREFIX))
+            .collect();
+        let credentials = std::env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from);
+        let kernel_cmdline = read_cmdline("/proc/cmdline").unwrap_or_else(|e| {
+            debug!("Failed to read kernel command line: {}", e);
+            String::new()
+        });
+        let files = load_config_files(&config_files(&SETTINGS_DIRS, SETTINGS_NAME)?)?;
+
+        Ok(Resolver::new(
+            options.to_vec(),
+            environment,
+            credentials,
+            kernel_cmdline,
+            files,
+        ))
+    }
+
+    fn kernel_argument(&self, key: &str) -> Option<String> {
+        match key {
+            "Prefix" => prefix_argument(&self.kernel_cmdline)
+                .ok()
+                .filter(|p| !p.is_empty()),
+            "NamingPolicy" => naming_policy_argument(&self.kernel_cmdline),
+            _ => None,
+        }
+    }
+
+    fn credential(&self, key: &str) -> Option<String> {
+        let path = self.credentials.as_ref()?.join(format!(
+            "{}{}",
+            CREDENTIAL_PREFIX,
+            key.to_ascii_lowercase()
+        ));
+
+        fs::read_to_string(path).ok().map(|v| v.trim().to_string())
+    }
+
+    /// Effective value of the key and its source.
+    pub fn resolve(&self, key: &str) -> (String, ConfigSource) {
+        let environment = format!("{}{}", ENVIRONMENT_PREFIX, key.to_ascii_uppercase());
+
+        if let Some((_, v)) = self.options.iter().rev().find(|(k, _)| k == key) {
+            (v.clone(), ConfigSource::CommandLine)
+        } else if let Some((_, v)) = self.environment.iter().find(|(k, _)| *k == environment) {
+            (v.clone(), ConfigSource::Environment)
+        } else if let Some(v) = self.credential(key) {
+            (v, ConfigSource::Credential)
+        } else if let Some(v) = self.kernel_argument(key) {
+            (v, ConfigSource::KernelCommandLine)
+        } else if let Some(v) = self.files.get_from(Some(SETTINGS_SECTION), key) {
+            (v.to_string(), ConfigSource::ConfigFile)
+        } else {
+            (default_value(key).to_string(), ConfigSource::Default)
+        }
+    }
+
+    /// Resolves against the given kernel command line instead of the one of the running kernel.
+    #[cfg(not(feature = "minimal"))]
+    pub fn with_kernel_cmdline(self, kernel_cmdline: &str) -> Resolver {
+        Resolver {
+            kernel_cmdline: kernel_cmdline.to_string(),
+            ..self
+        }
+    }
+
+    /// Prefix is empty when it isn't given by any source.
+    pub fn prefix(&self) -> String {
+        self.resolve(PREFIX_KEY).0
+    }
+
+    /// Configuration with the effective values of all keys, the [Link] section comes from the
+    /// configuration files only.
+    pub fn effective_config(&self) -> Ini {
+        let mut conf = Ini::new();
+
+        if let Some(section) = self.files.section(Some(LINK_KEYS_SECTION)) {
+            for (k, v) in section.iter() {
+                conf.with_section(Some(LINK_KEYS_SECTION)).set(k, v);
+            }
+        }
+
+        for key in SETTING_KEYS.iter().filter(|k| **k != PREFIX_KEY) {
+            match self.resolve(key) {
+                (_, ConfigSource::Default) => {}
+                (v, _) => {
+                    conf.with_section(Some(SETTINGS_SECTION)).set(*key, v);
+                }
+            }
+        }
+
+        conf
+    }
+
+    pub fn settings(&self) -> Result<Settings, Box<dyn Error>> {
+        Settings::from_ini(&self.effective_config())
+    }
+
+    /// All keys with their effective values and sources, for config-dump.
+    #[cfg(any(test, not(feature = "minimal")))]
+    pub fn dump(&self) -> Vec<(&'static str, String, ConfigSource)> {
+        SETTING_KEYS
+            .iter()
+            .map(|k| {
+                let (v, source) = self.resolve(k);
+                (*k, v, source)
+            })
+            .collect()
+    }
+}
+
+/// Parses KEY=VALUE given to --set, the key must be known.
+pub fn parse_option_setting(value: &str) -> Result<(String, String), Box<dyn Error>> {
+    let (k, v) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid setting \"{}\", expected KEY=VALUE", value))?;
+
+    if !SETTING_KEYS.contains(&k) {
+        return Err(From::from(format!("Unknown setting \"{}\"", k)));
+    }
+
+    Ok((k.to_string(), v.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolver_precedence() {
+        let credentials =
+            std::env::temp_dir().join(format!("prefixdevname-creds-{}", std::process::id()));
+        let files = Ini::load_from_str(
+            "[Naming]\nNamingPolicy=mac-hash\nMaxIndex=10\nTimeoutSec=10\nStrict=yes\n[Link]\nMTUBytes=9000\n",
+        )
+        .unwrap();
+
+        fs::create_dir_all(&credentials).unwrap();
+        fs::write(credentials.join("prefixdevname.maxindex"), "20\n").unwrap();
+        fs::write(credentials.join("prefixdevname.timeoutsec"), "20\n").unwrap();
+
+        let resolver = Resolver::new(
+            vec![("TimeoutSec".to_string(), "40".to_string())],
+            vec![
+                ("PREFIXDEVNAME_TIMEOUTSEC".to_string(), "30".to_string()),
+                ("PREFIXDEVNAME_PREFIX".to_string(), "lan".to_string()),
+            ],
+            Some(credentials.clone()),
+            "ro net.ifnames.prefix=net net.ifnames.policy=pci-order".to_string(),
+            files,
+        );
+
+        assert_eq!(
+            resolver.resolve("TimeoutSec"),
+            ("40".to_string(), ConfigSource::CommandLine)
+        );
+        assert_eq!(
+            resolver.resolve("Prefix"),
+            ("lan".to_string(), ConfigSource::Environment)
+        );
+        assert_eq!(
+            resolver.resolve("MaxIndex"),
+            ("20".to_string(), ConfigSource::Credential)
+        );
+        assert_eq!(
+            resolver.resolve("NamingPolicy"),
+            ("pci-order".to_string(), ConfigSource::KernelCommandLine)
+        );
+        assert_eq!(
+            resolver.resolve("Strict"),
+            ("yes".to_string(), ConfigSource::ConfigFile)
+        );
+        assert_eq!(
+            resolver.resolve("Actions"),
+            ("add".to_string(), ConfigSource::Default)
+        );
+
+        let settings = resolver.settings().unwrap();
+        assert_eq!(settings.timeout, std::time::Duration::from_secs(40));
+        assert_eq!(settings.max_index, Some(20));
+        assert_eq!(settings.naming_policy, NamingPolicyKind::PciOrder);
+        assert!(settings.strict);
+        assert_eq!(
+            settings.link_keys,
+            vec![("MTUBytes".to_string(), "9000".to_string())]
+        );
+        assert_eq!(resolver.dump().len(), SETTING_KEYS.len());
+
+        fs::remove_dir_all(&credentials).unwrap();
+    }
+
+    // Defaults must be accepted by the parser and produce the default settings
+    #[test]
+    fn resolver_defaults() {
+        let mut conf = Ini::new();
+
+        for key in SETTING_KEYS
+            .iter()
+            .filter(|k| **k != PREFIX_KEY && !default_value(k).is_empty())
+        {
+            conf.with_section(Some(SETTINGS_SECTION))
+                .set(*key, default_value(key));
+        }
+
+        let settings = Settings::from_ini(&conf).unwrap();
+        let default = Settings::default();
+        assert_eq!(settings.timeout, default.timeout);
+        assert_eq!(settings.link_types, default.link_types);
+        assert_eq!(settings.audit_log, default.audit_log);
+        assert_eq!(settings.naming_policy, default.naming_policy);
+
+        let resolver = Resolver::new(Vec::new(), Vec::new(), None, String::new(), Ini::new());
+        assert_eq!(resolver.prefix(), "");
+    }
+
+    #[test]
+    fn resolver_option_setting() {
+        assert_eq!(
+            parse_option_setting("MaxIndex=5").unwrap(),
+            ("MaxIndex".to_string(), "5".to_string())
+        );
+        assert!(parse_option_setting("MaxIndex").is_err());
+        assert!(parse_option_setting("Bogus=1").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+#[cfg(not(feature = "minimal"))]
+use crate::cmdline::get_prefix_from_file;
+#[cfg(not(feature = "minimal"))]
+use crate::config::NET_SETUP_LINK_CONF_DIR;
+#[cfg(not(feature = "minimal"))]
+use crate::sema::{Semaphore, SEMAPHORE_NAME};
+#[cfg(not(feature = "minimal"))]
+use crate::settings::Settings;
+
+#[cfg(not(feature = "minimal"))]
+pub type CheckResult = (&'static str, Result<(), Box<dyn Error>>);
+
+#[cfg(not(feature = "minimal"))]
+fn check_udev() -> Result<(), Box<dyn Error>> {
+    let udev = libudev::Context::new()?;
+    let mut enumerate = libudev::Enumerator::new(&udev)?;
+
+    enumerate.match_subsystem("net")?;
+    enumerate.scan_devices()?;
+
+    Ok(())
+}
+
+// Semaphore is only opened, taking it could block while udev processes events
+#[cfg(not(feature = "minimal"))]
+fn check_semaphore() -> Result<(), Box<dyn Error>> {
+    Semaphore::new_with_name(SEMAPHORE_NAME)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "minimal"))]
+fn check_cmdline() -> Result<(), Box<dyn Error>> {
+    get_prefix_from_file("/proc/cmdline")?;
+    Ok(())
+}
+
+#[cfg(not(feature = "minimal"))]
+fn check_settings() -> Result<(), Box<dyn Error>> {
+    Settings::load()?;
+    Ok(())
+}
+
+/// Creates and removes file in the directory. The file is hidden and doesn't have .link suffix,
+/// hence systemd-udevd never considers it.
+pub fn check_dir_writable(dir: &Path) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+
+    let path = dir.join(format!(".prefixdevname-self-test-{}", std::process::id()));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+
+    let written = file.write_all(b"[Match]\n");
+    let removed = fs::remove_file(&path);
+
+    written.map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    removed.map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+
+    Ok(())
+}
+
+/// Checks that the environment allows the helper to do its job, meant to be run from packaging
+/// scripts and CI to catch broken SELinux policy or missing directories at install time.
+#[cfg(not(feature = "minimal"))]
+pub fn self_test() -> Vec<CheckResult> {
+    vec![
+        ("udev", check_udev()),
+        ("semaphore", check_semaphore()),
+        ("kernel command line", check_cmdline()),
+        ("settings", check_settings()),
+        (
+            "link file directory",
+            check_dir_writable(Path::new(NET_SETUP_LINK_CONF_DIR)),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_test_dir_writable() {
+        let dir =
+            std::env::temp_dir().join(format!("prefixdevname-self-test-{}", std::process::id()));
+
+        check_dir_writable(&dir).unwrap();
+        let empty = fs::read_dir(&dir).unwrap().next().is_none();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(empty);
+    }
+}
@@ -2,15 +2,137 @@
 
 use std::error::Error;
 use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Name of the semaphore serializing allocation of names.
+pub static SEMAPHORE_NAME: &str = "net-prefix-ifnames";
+
+/// Environment variable prepended to the semaphore names, so that independent instances (e.g.
+/// parallel CI jobs on one host) don't share the lock.
+pub static SEMAPHORE_NAMESPACE_ENV: &str = "PREFIXDEVNAME_SEMAPHORE_NAMESPACE";
+
+/// Semaphores don't keep track of their holder, the PID of the process holding the lock is
+/// recorded in this directory instead.
+pub static LOCK_HOLDER_DIR: &str = "/run/prefixdevname";
+
+// Semaphores held by the process, the watchdog releases them before the process is aborted.
+// Whoever removes the semaphore from the list releases it, the watchdog thread or the holder,
+// never both.
+static HELD_LOCKS: Mutex<Vec<HeldLock>> = Mutex::new(Vec::new());
+
+// Taking the lock and adding it to HELD_LOCKS is one step for the watchdog, it never exits in
+// between. The wait is done in short slices, so that the watchdog doesn't wait long for its turn.
+static LOCK_TRANSITION: Mutex<()> = Mutex::new(());
+static EXITING: AtomicBool = AtomicBool::new(false);
+const LOCK_WAIT_SLICE: Duration = Duration::from_millis(100);
+
+struct HeldLock {
+    // Address of the sem_t of the holding instance, it stays open while the lock is held
+    raw_sema: usize,
+    holder: PathBuf,
+}
+
+impl HeldLock {
+    fn release(&self) {
+        let _ = fs::remove_file(&self.holder);
+
+        unsafe {
+            debug!("lock released by PID={}", libc::getpid());
+            libc::sem_post(self.raw_sema as *mut libc::sem_t);
+        }
+    }
+}
+
+fn held_locks() -> MutexGuard<'static, Vec<HeldLock>> {
+    HELD_LOCKS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn namespaced_name(namespace: Option<&str>, name: &str) -> Result<String, Box<dyn Error>> {
+    match namespace.filter(|n| !n.is_empty()) {
+        Some(n) if n.contains('/') => Err(From::from(format!(
+            "Invalid semaphore namespace \"{}\", it must not contain \"/\"",
+            n
+        ))),
+        Some(n) => Ok(format!("{}-{}", n, name)),
+        None => Ok(name.to_string()),
+    }
+}
+
+pub fn lock_holder_path<P: AsRef<Path>>(dir: P, name: &str) -> PathBuf {
+    dir.as_ref().join(format!("{}.holder", name))
+}
+
+#[cfg(not(feature = "minimal"))]
+pub fn read_lock_holder<P: AsRef<Path>>(path: P) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn store_lock_holder(path: &Path) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, format!("{}\n", std::process::id()))?;
+
+    Ok(())
+}
+
+/// PIDs are reused, the process must still be prefixdevname to be considered the holder.
+#[cfg(not(feature = "minimal"))]
+pub fn holder_alive(pid: u32) -> bool {
+    fs::read_to_string(format!("/proc/{}/comm", pid)).is_ok_and(|c| c.starts_with("prefixdevname"))
+}
+
+#[cfg(not(feature = "minimal"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockState {
+    Free,
+    Held(u32),
+    /// Holder exited without releasing the lock.
+    Orphaned(u32),
+    /// Lock is taken, but the holder isn't known, e.g. it has just taken the lock.
+    Unknown,
+    /// Released more times than taken, more processes can enter the critical section at once.
+    Corrupted(i32),
+}
+
+#[cfg(not(feature = "minimal"))]
+impl LockState {
+    pub fn new(value: i32, holder: Option<u32>, alive: impl Fn(u32) -> bool) -> LockState {
+        match (value, holder) {
+            (1, _) => LockState::Free,
+            (v, _) if v > 1 => LockState::Corrupted(v),
+            (_, Some(pid)) if alive(pid) => LockState::Held(pid),
+            (_, Some(pid)) => LockState::Orphaned(pid),
+            (_, None) => LockState::Unknown,
+        }
+    }
+
+    pub fn needs_reset(self) -> bool {
+        matches!(self, LockState::Orphaned(_) | LockState::Corrupted(_))
+    }
+}
 
-#[allow(dead_code)]
 pub struct Semaphore {
     raw_sema: *mut libc::sem_t,
+    #[cfg(any(test, not(feature = "minimal")))]
     name: CString,
+    holder: PathBuf,
+    held: bool,
 }
 
 impl Semaphore {
     pub fn new_with_name(name: &str) -> Result<Semaphore, Box<dyn Error>> {
+        let namespace = std::env::var(SEMAPHORE_NAMESPACE_ENV).ok();
+
+        Semaphore::open(&namespaced_name(namespace.as_deref(), name)?)
+    }
+
+    fn open(name: &str) -> Result<Semaphore, Box<dyn Error>> {
         let raw_sema_name = CString::new(name)?;
 
         let s;
@@ -30,27 +152,146 @@ impl Semaphore {
 
         Ok(Semaphore {
             raw_sema: s,
+            #[cfg(any(test, not(feature = "minimal")))]
             name: raw_sema_name,
+            holder: lock_holder_path(LOCK_HOLDER_DIR, name),
+            held: false,
         })
     }
 
-    pub fn lock(&mut self) {
+    #[cfg(not(feature = "minimal"))]
+    pub fn name(&self) -> String {
+        self.name.to_string_lossy().into_owned()
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    pub fn holder_path(&self) -> &Path {
+        &self.holder
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    pub fn value(&self) -> Result<i32, Box<dyn Error>> {
+        let mut value: libc::c_int = 0;
+
+        if unsafe { libc::sem_getvalue(self.raw_sema, &mut value) } < 0 {
+            return Err(From::from(format!(
+                "sem_getvalue() failed: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        Ok(value)
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    pub fn state(&self) -> Result<LockState, Box<dyn Error>> {
+        Ok(LockState::new(
+            self.value()?,
+            read_lock_holder(&self.holder),
+            holder_alive,
+        ))
+    }
+
+    /// Unlinks the semaphore and creates new one that isn't held. Processes already waiting for
+    /// the old semaphore stay blocked until the watchdog aborts them.
+    #[cfg(not(feature = "minimal"))]
+    pub fn reset(self) -> Result<Semaphore, Box<dyn Error>> {
+        let name = self.name();
+
+        if unsafe { libc::sem_unlink(self.name.as_ptr()) } < 0 {
+            return Err(From::from(format!(
+                "Failed to unlink semaphore {}: {}",
+                name,
+                std::io::Error::last_os_error()
+            )));
+        }
+        let _ = fs::remove_file(&self.holder);
+        drop(self);
+
+        Semaphore::open(&name)
+    }
+
+    fn wait_slice(&self) -> bool {
         unsafe {
-            libc::sem_wait(self.raw_sema);
-            debug!("lock taken by PID={}", libc::getpid());
+            let mut deadline = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            libc::clock_gettime(libc::CLOCK_REALTIME, &mut deadline);
+
+            let nsec = deadline.tv_nsec as u64 + LOCK_WAIT_SLICE.subsec_nanos() as u64;
+            deadline.tv_sec += (LOCK_WAIT_SLICE.as_secs() + nsec / 1_000_000_000) as libc::time_t;
+            deadline.tv_nsec = (nsec % 1_000_000_000) as libc::c_long;
+
+            libc::sem_timedwait(self.raw_sema, &deadline) == 0
+        }
+    }
+
+    pub fn lock(&mut self) {
+        loop {
+            let transition = LOCK_TRANSITION.lock().unwrap_or_else(|e| e.into_inner());
+
+            // The watchdog is aborting the run, the lock must not be taken anymore
+            if EXITING.load(Ordering::SeqCst) {
+                drop(transition);
+                loop {
+                    thread::park();
+                }
+            }
+
+            if self.wait_slice() {
+                held_locks().push(HeldLock {
+                    raw_sema: self.raw_sema as usize,
+                    holder: self.holder.clone(),
+                });
+                self.held = true;
+                break;
+            }
+        }
+
+        debug!("lock taken by PID={}", std::process::id());
+
+        if let Err(e) = store_lock_holder(&self.holder) {
+            debug!("Failed to record lock holder: {}", e);
         }
     }
 
+    /// Does nothing unless the lock is held, hence it is safe to call on every exit path.
     pub fn unlock(&mut self) {
-        unsafe {
-            debug!("lock released by PID={}", libc::getpid());
-            libc::sem_post(self.raw_sema);
+        if !self.held {
+            return;
         }
+        self.held = false;
+
+        let mut held = held_locks();
+
+        // Released by the watchdog already
+        if let Some(i) = held
+            .iter()
+            .position(|h| h.raw_sema == self.raw_sema as usize)
+        {
+            held.remove(i).release();
+        }
+    }
+}
+
+/// Releases the locks held by the process from other thread right before the process exits, i.e.
+/// from the watchdog. The process doesn't take the lock anymore.
+pub fn release_lock_for_exit() {
+    EXITING.store(true, Ordering::SeqCst);
+    std::mem::forget(LOCK_TRANSITION.lock().unwrap_or_else(|e| e.into_inner()));
+
+    let mut held = held_locks();
+    for h in held.drain(..) {
+        h.release();
     }
+    std::mem::forget(held);
 }
 
 impl Drop for Semaphore {
     fn drop(&mut self) {
+        self.unlock();
+
         unsafe {
             libc::sem_close(self.raw_sema);
         }
@@ -60,73 +301,167 @@ impl Drop for Semaphore {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use env_logger;
+    use crate::logging::try_init_logging;
+    use std::sync::Arc;
     use std::{thread, time};
 
+    // Tests run in parallel, also with other test runs on the same host
+    fn unique_name(test: &str) -> String {
+        format!("test-{}-{}", std::process::id(), test)
+    }
+
+    // Named semaphores outlive the test run in /dev/shm, they are unlinked even when the test
+    // panics
+    struct Unlink(CString);
+
+    impl Drop for Unlink {
+        fn drop(&mut self) {
+            unsafe {
+                libc::sem_unlink(self.0.as_ptr());
+            }
+        }
+    }
+
     #[test]
     fn sema_sanity() {
-        let _ = env_logger::try_init();
-        let s = Semaphore::new_with_name(&"test").unwrap();
-        unsafe {
-            libc::sem_unlink(s.name.as_ptr());
-        }
+        let _ = try_init_logging();
+        let s = Semaphore::new_with_name(&unique_name("sanity")).unwrap();
+        let _unlink = Unlink(s.name.clone());
     }
 
     #[test]
     fn sema_concurent() {
-        let _ = env_logger::try_init();
-        let sema = Semaphore::new_with_name(&"test").expect("Failed to create semaphore");
-
-        let t1 = thread::Builder::new()
-            .spawn(|| {
-                warn!("T1 spawned");
-                let mut s = Semaphore::new_with_name(&"test").expect("Failed to create semaphore");
-
-                s.lock();
-                warn!("T1 in critical section");
-                thread::sleep(time::Duration::from_millis(100));
-                warn!("T1 leaving critical section");
-                s.unlock();
-            })
-            .unwrap();
+        let _ = try_init_logging();
+        let name = unique_name("concurrent");
+        let sema = Semaphore::new_with_name(&name).expect("Failed to create semaphore");
+        let _unlink = Unlink(sema.name.clone());
+        let sections = Arc::new(Mutex::new(Vec::new()));
 
-        thread::sleep(time::Duration::from_millis(50));
+        let spawn = |thread_name: &'static str, hold: u64| {
+            let name = name.clone();
+            let sections = Arc::clone(&sections);
 
-        let t2 = thread::Builder::new()
-            .spawn(|| {
-                warn!("T2 spawned");
-                let mut s = Semaphore::new_with_name(&"test").expect("Failed to create semaphore");
+            thread::Builder::new()
+                .spawn(move || {
+                    warn!("{} spawned", thread_name);
+                    let mut s =
+                        Semaphore::new_with_name(&name).expect("Failed to create semaphore");
 
-                s.lock();
-                warn!("T2 in critical section");
-                thread::sleep(time::Duration::from_millis(3000));
-                warn!("T2 leaving critical section");
-                s.unlock();
-            })
-            .unwrap();
+                    s.lock();
+                    warn!("{} in critical section", thread_name);
+                    sections
+                        .lock()
+                        .unwrap()
+                        .push(format!("{} entered", thread_name));
+                    thread::sleep(time::Duration::from_millis(hold));
+                    sections
+                        .lock()
+                        .unwrap()
+                        .push(format!("{} left", thread_name));
+                    warn!("{} leaving critical section", thread_name);
+                    s.unlock();
+                })
+                .unwrap()
+        };
 
+        let t1 = spawn("T1", 100);
+        thread::sleep(time::Duration::from_millis(50));
+        let t2 = spawn("T2", 3000);
         thread::sleep(time::Duration::from_millis(200));
-
-        let t3 = thread::Builder::new()
-            .spawn(|| {
-                warn!("T3 spawned");
-                let mut s = Semaphore::new_with_name(&"test").expect("Failed to create semaphore");
-
-                s.lock();
-                warn!("T3 in critical section");
-                warn!("T3 leaving critical section");
-                s.unlock();
-            })
-            .unwrap();
+        let t3 = spawn("T3", 0);
 
         t1.join().unwrap();
         t2.join().unwrap();
         t3.join().unwrap();
 
+        // Critical sections don't overlap and are entered in the order of the threads
+        assert_eq!(
+            *sections.lock().unwrap(),
+            [
+                "T1 entered",
+                "T1 left",
+                "T2 entered",
+                "T2 left",
+                "T3 entered",
+                "T3 left"
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn sema_held_per_instance() {
+        let _ = try_init_logging();
+        let mut a = Semaphore::new_with_name(&unique_name("held-a")).unwrap();
+        let _unlink_a = Unlink(a.name.clone());
+        let mut b = Semaphore::new_with_name(&unique_name("held-b")).unwrap();
+        let _unlink_b = Unlink(b.name.clone());
+
+        a.lock();
+        b.lock();
+        a.unlock();
+        a.unlock();
+        assert_eq!(a.value().unwrap(), 1);
+        assert_eq!(b.value().unwrap(), 0);
+
+        b.unlock();
+        assert_eq!(b.value().unwrap(), 1);
+
+        // Dropping the holder releases the lock
+        b.lock();
+        drop(b);
+        let b = Semaphore::new_with_name(&unique_name("held-b")).unwrap();
+        assert_eq!(b.value().unwrap(), 1);
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn sema_reset() {
+        let _ = try_init_logging();
+        let name = unique_name("reset");
+        let s = Semaphore::new_with_name(&name).unwrap();
+        let _unlink = Unlink(s.name.clone());
+
+        assert_eq!(s.value().unwrap(), 1);
+
+        // Taken behind the back of lock(), as if the holder crashed
         unsafe {
-            libc::sem_unlink(sema.name.as_ptr());
+            libc::sem_wait(s.raw_sema);
         }
+        assert_eq!(s.value().unwrap(), 0);
+        assert_eq!(s.state().unwrap(), LockState::Unknown);
+
+        let s = s.reset().unwrap();
+        assert_eq!(s.value().unwrap(), 1);
+        assert!(!s.holder_path().exists());
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn lock_state() {
+        let alive = |pid| pid == 42;
+
+        assert_eq!(LockState::new(1, None, alive), LockState::Free);
+        assert_eq!(LockState::new(1, Some(7), alive), LockState::Free);
+        assert_eq!(LockState::new(0, Some(42), alive), LockState::Held(42));
+        assert_eq!(LockState::new(0, Some(7), alive), LockState::Orphaned(7));
+        assert_eq!(LockState::new(0, None, alive), LockState::Unknown);
+        assert_eq!(LockState::new(2, None, alive), LockState::Corrupted(2));
 
-        // XXX: actually check that critical section where taken in right order
+        assert!(LockState::Orphaned(7).needs_reset());
+        assert!(LockState::Corrupted(2).needs_reset());
+        assert!(!LockState::Held(42).needs_reset());
+        assert!(!LockState::Unknown.needs_reset());
+    }
+
+    #[test]
+    fn sema_namespace() {
+        assert_eq!(namespaced_name(None, "lock").unwrap(), "lock");
+        assert_eq!(namespaced_name(Some(""), "lock").unwrap(), "lock");
+        assert_eq!(
+            namespaced_name(Some("ci-42"), "lock").unwrap(),
+            "ci-42-lock"
+        );
+        assert!(namespaced_name(Some("a/b"), "lock").is_err());
     }
 }
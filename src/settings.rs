@@ -0,0 +1,1322 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::str::FromStr;
+use std::time::Duration;
+
+use ini::Ini;
+
+use crate::audit::AUDIT_LOG;
+use crate::index_pool::{parse_index_pools, IndexPool};
+use crate::link_match::fnmatch;
+#[cfg(not(feature = "minimal"))]
+use crate::resolver::Resolver;
+
+pub static SETTINGS_NAME: &str = "prefixdevname.conf";
+// From the most important, /usr/lib holds defaults shipped by the vendor or image builder
+pub static SETTINGS_DIRS: [&str; 2] = ["/etc", "/usr/lib"];
+pub static SETTINGS_SECTION: &str = "Naming";
+pub static LINK_KEYS_SECTION: &str = "Link";
+
+// Keys that would take the naming of the interface away from us
+const RESERVED_LINK_KEYS: [&str; 2] = ["Name", "NamePolicy"];
+
+/// What to do with a device that reports all-zero or broadcast MAC address, e.g. because its
+/// firmware wasn't fully initialized yet when the device appeared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidHwaddrPolicy {
+    Skip,
+    Path,
+    Property,
+}
+
+impl FromStr for InvalidHwaddrPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(InvalidHwaddrPolicy::Skip),
+            "path" => Ok(InvalidHwaddrPolicy::Path),
+            "property" => Ok(InvalidHwaddrPolicy::Property),
+            _ => Err(From::from(format!(
+                "Invalid value \"{}\" of InvalidMACAddressPolicy=, expected \"skip\", \"path\" or \"property\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// What to do with a device whose MAC address is locally administered. Such address is often
+/// randomly generated by the driver and hence matching on it may not be stable across reboots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalHwaddrPolicy {
+    Skip,
+    Proceed,
+    Path,
+    Permanent,
+    Property,
+}
+
+impl FromStr for LocalHwaddrPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(LocalHwaddrPolicy::Skip),
+            "proceed" => Ok(LocalHwaddrPolicy::Proceed),
+            "path" => Ok(LocalHwaddrPolicy::Path),
+            "permanent" => Ok(LocalHwaddrPolicy::Permanent),
+            "property" => Ok(LocalHwaddrPolicy::Property),
+            _ => Err(From::from(format!(
+                "Invalid value \"{}\" of LocalMACAddressPolicy=, expected \"skip\", \"proceed\", \"path\", \"permanent\" or \"property\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// What to do when kernel command line contains arguments that make other tools rename network
+/// interfaces as well (net.ifnames=0, biosdevname=1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdlineConflictPolicy {
+    Warn,
+    Refuse,
+}
+
+impl FromStr for CmdlineConflictPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(CmdlineConflictPolicy::Warn),
+            "refuse" => Ok(CmdlineConflictPolicy::Refuse),
+            _ => Err(From::from(format!(
+                "Invalid value \"{}\" of ConflictingKernelArgumentsPolicy=, expected \"warn\" or \"refuse\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// What to do when some interface name starts with the prefix but doesn't follow our naming
+/// scheme, e.g. "netmon0" for prefix "net". Such names come from other naming scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixCollisionPolicy {
+    Warn,
+    Refuse,
+}
+
+impl FromStr for PrefixCollisionPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(PrefixCollisionPolicy::Warn),
+            "refuse" => Ok(PrefixCollisionPolicy::Refuse),
+            _ => Err(From::from(format!(
+                "Invalid value \"{}\" of PrefixCollisionPolicy=, expected \"warn\" or \"refuse\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// Whether index of the link file that doesn't match any present device can be assigned again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanPolicy {
+    Occupied,
+    Reuse,
+    Flag,
+}
+
+impl FromStr for OrphanPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "occupied" => Ok(OrphanPolicy::Occupied),
+            "reuse" => Ok(OrphanPolicy::Reuse),
+            "flag" => Ok(OrphanPolicy::Flag),
+            _ => Err(From::from(format!(
+                "Invalid value \"{}\" of OrphanedLinkFilePolicy=, expected \"occupied\", \"reuse\" or \"flag\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// What to do with the link file when its device is removed (ACTION==remove).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovedDevicePolicy {
+    Keep,
+    Delete,
+    Mark,
+}
+
+impl FromStr for RemovedDevicePolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(RemovedDevicePolicy::Keep),
+            "delete" => Ok(RemovedDevicePolicy::Delete),
+            "mark" => Ok(RemovedDevicePolicy::Mark),
+            _ => Err(From::from(format!(
+                "Invalid value \"{}\" of RemovedDevicePolicy=, expected \"keep\", \"delete\" or \"mark\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// How concurrent invocations agree on the next free index. Locked takes the global lock and
+/// loads the full state of links every time. Counter does that only when the index counter is
+/// missing. Optimistic never takes the lock, exclusive creation of the link file arbitrates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationMode {
+    Locked,
+    Counter,
+    Optimistic,
+}
+
+impl FromStr for AllocationMode {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "locked" => Ok(AllocationMode::Locked),
+            "counter" => Ok(AllocationMode::Counter),
+            "optimistic" => Ok(AllocationMode::Optimistic),
+            _ => Err(From::from(format!(
+                "Invalid value \"{}\" of AllocationMode=, expected \"locked\", \"counter\" or \"optimistic\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// Handling of names assigned by the initscripts of RHEL 7, see the legacy module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompatibilityMode {
+    None,
+    Rhel7,
+}
+
+impl FromStr for CompatibilityMode {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(CompatibilityMode::None),
+            "rhel7" => Ok(CompatibilityMode::Rhel7),
+            _ => Err(From::from(format!(
+                "Invalid value \"{}\" of CompatibilityMode=, expected \"none\" or \"rhel7\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// How indexes are chosen, see the naming_policy module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamingPolicyKind {
+    Sequential,
+    MacHash,
+    PciOrder,
+    Smbios,
+    CloudMetadata,
+}
+
+impl FromStr for NamingPolicyKind {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(NamingPolicyKind::Sequential),
+            "mac-hash" => Ok(NamingPolicyKind::MacHash),
+            "pci-order" => Ok(NamingPolicyKind::PciOrder),
+            "smbios" => Ok(NamingPolicyKind::Smbios),
+            "cloud-metadata" => Ok(NamingPolicyKind::CloudMetadata),
+            _ => Err(From::from(format!(
+                "Invalid value \"{}\" of NamingPolicy=, expected \"sequential\", \"mac-hash\", \"pci-order\", \"smbios\" or \"cloud-metadata\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// Service caching link files that is told to reload them after a link file is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReloadTarget {
+    Udev,
+    Networkd,
+}
+
+impl FromStr for ReloadTarget {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udev" => Ok(ReloadTarget::Udev),
+            "networkd" => Ok(ReloadTarget::Networkd),
+            _ => Err(From::from(format!(
+                "Invalid value \"{}\" of ReloadAfterWrite=, expected \"udev\" or \"networkd\"",
+                s
+            ))),
+        }
+    }
+}
+
+/// Parses whitespace separated list of services reloaded after a link file is written.
+pub fn parse_reload_targets(value: &str) -> Result<Vec<ReloadTarget>, Box<dyn Error>> {
+    let mut targets = Vec::new();
+
+    for t in value.split_whitespace() {
+        let target = t.parse()?;
+
+        if !targets.contains(&target) {
+            targets.push(target);
+        }
+    }
+
+    Ok(targets)
+}
+
+/// Keys of the [Link] section copied to every generated link file, e.g. MTUBytes=.
+pub fn parse_link_keys<'a, I>(keys: I) -> Result<Vec<(String, String)>, Box<dyn Error>>
+where
+    I: Iterator<Item = (&'a str, &'a str)>,
+{
+    let mut link_keys = Vec::new();
+
+    for (k, v) in keys {
+        if RESERVED_LINK_KEYS.contains(&k) {
+            return Err(From::from(format!(
+                "{}= can't be set in the [{}] section, names are assigned by prefixdevname",
+                k, LINK_KEYS_SECTION
+            )));
+        }
+
+        link_keys.push((k.to_string(), v.to_string()));
+    }
+
+    Ok(link_keys)
+}
+
+/// Octal file mode, e.g. "0644". Only permission bits are accepted.
+pub fn parse_file_mode(key: &str, value: &str) -> Result<u32, Box<dyn Error>> {
+    match u32::from_str_radix(value, 8) {
+        Ok(m) if m <= 0o777 => Ok(m),
+        _ => Err(From::from(format!(
+            "Invalid value \"{}\" of {}=, expected octal file mode, e.g. \"0644\"",
+            value, key
+        ))),
+    }
+}
+
+pub fn parse_boolean(key: &str, value: &str) -> Result<bool, Box<dyn Error>> {
+    match value {
+        "1" | "yes" | "y" | "true" | "t" | "on" => Ok(true),
+        "0" | "no" | "n" | "false" | "f" | "off" => Ok(false),
+        _ => Err(From::from(format!(
+            "Invalid value \"{}\" of {}=, expected boolean",
+            value, key
+        ))),
+    }
+}
+
+/// Interface types (ARPHRD_* values from if_arp.h) that can be referred to by name in LinkTypes=.
+static LINK_TYPE_NAMES: [(&str, u32); 5] = [
+    ("ether", 1),
+    ("infiniband", 32),
+    ("can", 280),
+    ("rawip", 519),
+    ("none", 65534),
+];
+
+pub static DEFAULT_LINK_TYPES: [u32; 1] = [1];
+
+// Removal of devices is handled separately according to RemovedDevicePolicy=
+static NAMING_ACTIONS: [&str; 7] = [
+    "add", "change", "move", "online", "offline", "bind", "unbind",
+];
+
+// udev kills the worker after 180 seconds by default
+pub static DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const DEFAULT_LINK_FILE_MODE: u32 = 0o644;
+
+pub static DEFAULT_DEBOUNCE_WINDOW: Duration = Duration::from_secs(5);
+
+// Coldplug is over by then, NICs not probed yet would look like orphans before
+pub static DEFAULT_ORPHAN_REUSE_DELAY: Duration = Duration::from_secs(300);
+
+pub static DEFAULT_ACTIONS: [&str; 1] = ["add"];
+
+/// Parses whitespace separated list of udev actions for which interfaces are named.
+pub fn parse_actions(value: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut actions = Vec::new();
+
+    for a in value.split_whitespace() {
+        if !NAMING_ACTIONS.contains(&a) {
+            return Err(From::from(format!(
+                "Invalid value \"{}\" of Actions=, expected one of {}",
+                a,
+                NAMING_ACTIONS
+                    .iter()
+                    .map(|n| format!("\"{}\"", n))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )));
+        }
+
+        if !actions.iter().any(|x| x == a) {
+            actions.push(a.to_string());
+        }
+    }
+
+    if actions.is_empty() {
+        return Err(From::from("Actions= can't be empty"));
+    }
+
+    Ok(actions)
+}
+
+/// Parses whitespace separated list of interface types, given either by name or by number.
+pub fn parse_link_types(value: &str) -> Result<Vec<u32>, Box<dyn Error>> {
+    let mut types = Vec::new();
+
+    for t in value.split_whitespace() {
+        let link_type = match LINK_TYPE_NAMES.iter().find(|(n, _)| *n == t) {
+            Some((_, v)) => *v,
+            None => match t.parse::<u16>() {
+                Ok(v) => v as u32,
+                Err(_) => {
+                    return Err(From::from(format!(
+                        "Invalid value \"{}\" of LinkTypes=, expected interface type number or one of {}",
+                        t,
+                        LINK_TYPE_NAMES
+                            .iter()
+                            .map(|(n, _)| format!("\"{}\"", n))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    )))
+                }
+            },
+        };
+
+        if !types.contains(&link_type) {
+            types.push(link_type);
+        }
+    }
+
+    if types.is_empty() {
+        return Err(From::from("LinkTypes= can't be empty"));
+    }
+
+    Ok(types)
+}
+
+// Kinds of virtual devices (DEVTYPE) that can be named using their own prefix
+static VIRTUAL_DEVICE_KINDS: [&str; 2] = ["bond", "bridge"];
+
+/// Vendor of the NIC, either the OUI of its MAC address or the vendor ID of the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VendorMatch {
+    Oui([u8; 3]),
+    VendorId(u16),
+}
+
+// With "0x" as in sysfs (PCI) or without it as in ID_VENDOR_ID (USB)
+fn parse_vendor_id(id: &str) -> Option<u16> {
+    let id = id.strip_prefix("0x").unwrap_or(id);
+
+    if id.len() != 4 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    u16::from_str_radix(id, 16).ok()
+}
+
+fn parse_oui(oui: &str) -> Option<[u8; 3]> {
+    let octets: Vec<u8> = oui
+        .split([':', '-'])
+        .map(|o| match o.len() {
+            2 => u8::from_str_radix(o, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+
+    octets.try_into().ok()
+}
+
+/// Entries are VENDOR=PREFIX, where VENDOR is either an OUI (e.g. 00:02:C9) or a vendor ID
+/// (e.g. 0x15b3 or 15b3). Several vendors can share a prefix.
+pub fn parse_vendor_prefixes(value: &str) -> Result<Vec<(VendorMatch, String)>, Box<dyn Error>> {
+    let mut prefixes: Vec<(VendorMatch, String)> = Vec::new();
+
+    for pair in value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|p| !p.is_empty())
+    {
+        let (vendor, prefix) = match pair.split_once('=').and_then(|(v, p)| {
+            parse_oui(v)
+                .map(VendorMatch::Oui)
+                .or_else(|| parse_vendor_id(v).map(VendorMatch::VendorId))
+                .map(|v| (v, p))
+        }) {
+            Some(p) => p,
+            None => {
+                return Err(From::from(format!(
+                "Invalid value \"{}\" of VendorPrefixes=, expected OUI=PREFIX or VENDORID=PREFIX",
+                pair
+            )))
+            }
+        };
+
+        if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(From::from(format!(
+                "Invalid prefix \"{}\" in VendorPrefixes=, prefix must consist of letters",
+                prefix
+            )));
+        }
+
+        // Vendors may share the prefix, but distinct prefixes must not overlap
+        if let Some((_, p)) = prefixes.iter().find(|(v, p)| {
+            *v == vendor
+                || (p != prefix && (p.starts_with(prefix) || prefix.starts_with(p.as_str())))
+        }) {
+            return Err(From::from(format!(
+                "Entry \"{}\" conflicts with other entry using prefix \"{}\" in VendorPrefixes=",
+                pair, p
+            )));
+        }
+
+        prefixes.push((vendor, prefix.to_string()));
+    }
+
+    Ok(prefixes)
+}
+
+/// Parses list of kind=prefix pairs separated by whitespace or commas, e.g. "bond=bd, bridge=br".
+pub fn parse_virtual_prefixes(value: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut prefixes: Vec<(String, String)> = Vec::new();
+
+    for pair in value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|p| !p.is_empty())
+    {
+        let (kind, prefix) = match pair.split_once('=') {
+            Some((k, p)) if VIRTUAL_DEVICE_KINDS.contains(&k) => (k, p),
+            _ => {
+                return Err(From::from(format!(
+                    "Invalid value \"{}\" of VirtualDevicePrefixes=, expected KIND=PREFIX where KIND is one of {}",
+                    pair,
+                    VIRTUAL_DEVICE_KINDS.join(", ")
+                )))
+            }
+        };
+
+        if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(From::from(format!(
+                "Invalid prefix \"{}\" in VirtualDevicePrefixes=, prefix must consist of letters",
+                prefix
+            )));
+        }
+
+        // Overlapping prefixes would make index pools of different kinds indistinguishable
+        if let Some((k, p)) = prefixes
+            .iter()
+            .find(|(k, p)| k == kind || p.starts_with(prefix) || prefix.starts_with(p.as_str()))
+        {
+            return Err(From::from(format!(
+                "Conflicting entries \"{}={}\" and \"{}\" in VirtualDevicePrefixes=",
+                k, p, pair
+            )));
+        }
+
+        prefixes.push((kind.to_string(), prefix.to_string()));
+    }
+
+    Ok(prefixes)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub invalid_hwaddr_policy: InvalidHwaddrPolicy,
+    pub local_hwaddr_policy: LocalHwaddrPolicy,
+    pub respect_udev_names: bool,
+    pub cmdline_conflict_policy: CmdlineConflictPolicy,
+    pub max_index: Option<u64>,
+    pub audit_log: Option<String>,
+    pub prometheus_textfile: Option<String>,
+    pub manifest_file: Option<String>,
+    pub link_types: Vec<u32>,
+    pub vlan_names: bool,
+    pub virtual_prefixes: Vec<(String, String)>,
+    pub vendor_prefixes: Vec<(VendorMatch, String)>,
+    pub prefix_collision_policy: PrefixCollisionPolicy,
+    pub orphan_policy: OrphanPolicy,
+    pub orphan_reuse_delay: Duration,
+    pub name_in_containers: bool,
+    pub skip_drivers: Vec<String>,
+    pub match_driver: bool,
+    pub match_property: Option<String>,
+    pub original_name_devices: Vec<String>,
+    pub removed_device_policy: RemovedDevicePolicy,
+    pub actions: Vec<String>,
+    pub debounce_window: Duration,
+    pub timeout: Duration,
+    pub settle_timeout: Duration,
+    pub allocation_mode: AllocationMode,
+    pub strict: bool,
+    pub naming_policy: NamingPolicyKind,
+    pub index_pools: Vec<IndexPool>,
+    pub link_keys: Vec<(String, String)>,
+    pub link_file_mode: u32,
+    pub reload_after_write: Vec<ReloadTarget>,
+    pub compatibility_mode: CompatibilityMode,
+    // From ifname= on the kernel command line, not from the configuration file
+    pub cmdline_ifnames: Vec<(String, String)>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            invalid_hwaddr_policy: InvalidHwaddrPolicy::Skip,
+            local_hwaddr_policy: LocalHwaddrPolicy::Proceed,
+            respect_udev_names: false,
+            cmdline_conflict_policy: CmdlineConflictPolicy::Warn,
+            max_index: None,
+            audit_log: Some(AUDIT_LOG.to_string()),
+            prometheus_textfile: None,
+            manifest_file: None,
+            link_types: DEFAULT_LINK_TYPES.to_vec(),
+            vlan_names: false,
+            virtual_prefixes: Vec::new(),
+            vendor_prefixes: Vec::new(),
+            prefix_collision_policy: PrefixCollisionPolicy::Warn,
+            orphan_policy: OrphanPolicy::Occupied,
+            orphan_reuse_delay: DEFAULT_ORPHAN_REUSE_DELAY,
+            name_in_containers: false,
+            skip_drivers: Vec::new(),
+            match_driver: false,
+            match_property: None,
+            original_name_devices: Vec::new(),
+            removed_device_policy: RemovedDevicePolicy::Keep,
+            actions: DEFAULT_ACTIONS.iter().map(|a| a.to_string()).collect(),
+            debounce_window: DEFAULT_DEBOUNCE_WINDOW,
+            timeout: DEFAULT_TIMEOUT,
+            settle_timeout: Duration::ZERO,
+            allocation_mode: AllocationMode::Locked,
+            strict: false,
+            naming_policy: NamingPolicyKind::Sequential,
+            index_pools: Vec::new(),
+            link_keys: Vec::new(),
+            link_file_mode: DEFAULT_LINK_FILE_MODE,
+            reload_after_write: Vec::new(),
+            compatibility_mode: CompatibilityMode::None,
+            cmdline_ifnames: Vec::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Settings resolved from all sources except the command line options, see the resolver module.
+    #[cfg(not(feature = "minimal"))]
+    pub fn load() -> Result<Settings, Box<dyn Error>> {
+        Resolver::load(&[])?.settings()
+    }
+
+    pub fn from_ini(conf: &Ini) -> Result<Settings, Box<dyn Error>> {
+        let mut settings = Settings::default();
+
+        if let Some(section) = conf.section(Some(LINK_KEYS_SECTION)) {
+            settings.link_keys = parse_link_keys(section.iter())?;
+        }
+
+        let section = match conf.section(Some(SETTINGS_SECTION)) {
+            Some(s) => s,
+            None => return Ok(settings),
+        };
+
+        if let Some(v) = section.get("InvalidMACAddressPolicy") {
+            settings.invalid_hwaddr_policy = v.parse()?;
+        }
+
+        if let Some(v) = section.get("LocalMACAddressPolicy") {
+            settings.local_hwaddr_policy = v.parse()?;
+        }
+
+        if let Some(v) = section.get("RespectUdevNames") {
+            settings.respect_udev_names = parse_boolean("RespectUdevNames", v)?;
+        }
+
+        if let Some(v) = section.get("ConflictingKernelArgumentsPolicy") {
+            settings.cmdline_conflict_policy = v.parse()?;
+        }
+
+        if let Some(v) = section.get("MaxIndex") {
+            settings.max_index =
+                Some(v.parse().map_err(|_| {
+                    format!("Invalid value \"{}\" of MaxIndex=, expected number", v)
+                })?);
+        }
+
+        if let Some(v) = section.get("AuditLog") {
+            settings.audit_log = Some(v.to_string()).filter(|p| !p.is_empty());
+        }
+
+        if let Some(v) = section.get("PrometheusTextfile") {
+            settings.prometheus_textfile = Some(v.to_string()).filter(|p| !p.is_empty());
+        }
+
+        if let Some(v) = section.get("ManifestFile") {
+            settings.manifest_file = Some(v.to_string()).filter(|p| !p.is_empty());
+        }
+
+        if let Some(v) = section.get("LinkTypes") {
+            settings.link_types = parse_link_types(v)?;
+        }
+
+        if let Some(v) = section.get("VLANNames") {
+            settings.vlan_names = parse_boolean("VLANNames", v)?;
+        }
+
+        if let Some(v) = section.get("VirtualDevicePrefixes") {
+            settings.virtual_prefixes = parse_virtual_prefixes(v)?;
+        }
+
+        if let Some(v) = section.get("VendorPrefixes") {
+            settings.vendor_prefixes = parse_vendor_prefixes(v)?;
+        }
+
+        if let Some(v) = section.get("PrefixCollisionPolicy") {
+            settings.prefix_collision_policy = v.parse()?;
+        }
+
+        if let Some(v) = section.get("OrphanedLinkFilePolicy") {
+            settings.orphan_policy = v.parse()?;
+        }
+
+        if let Some(v) = section.get("NameInContainers") {
+            settings.name_in_containers = parse_boolean("NameInContainers", v)?;
+        }
+
+        if let Some(v) = section.get("SkipDrivers") {
+            settings.skip_drivers = v.split_whitespace().map(|d| d.to_string()).collect();
+        }
+
+        if let Some(v) = section.get("MatchDriver") {
+            settings.match_driver = parse_boolean("MatchDriver", v)?;
+        }
+
+        if let Some(v) = section.get("MatchProperty") {
+            settings.match_property = Some(v.to_string()).filter(|p| !p.is_empty());
+        }
+
+        if let Some(v) = section.get("OriginalNameDevices") {
+            settings.original_name_devices = v.split_whitespace().map(|p| p.to_string()).collect();
+        }
+
+        if let Some(v) = section.get("RemovedDevicePolicy") {
+            settings.removed_device_policy = v.parse()?;
+        }
+
+        if let Some(v) = section.get("Actions") {
+            settings.actions = parse_actions(v)?;
+        }
+
+        if let Some(v) = section.get("AllocationMode") {
+            settings.allocation_mode = v.parse()?;
+        }
+
+        if let Some(v) = section.get("Strict") {
+            settings.strict = parse_boolean("Strict", v)?;
+        }
+
+        // FirmwareLabels=yes predates NamingPolicy=, NamingPolicy= wins when both are set
+        if let Some(v) = section.get("FirmwareLabels") {
+            if parse_boolean("FirmwareLabels", v)? {
+                settings.naming_policy = NamingPolicyKind::Smbios;
+            }
+        }
+
+        if let Some(v) = section.get("NamingPolicy") {
+            settings.naming_policy = v.parse()?;
+        }
+
+        if let Some(v) = section.get("IndexPools") {
+            settings.index_pools = parse_index_pools(v)?;
+        }
+
+        if let Some(v) = section.get("LinkFileMode") {
+            settings.link_file_mode = parse_file_mode("LinkFileMode", v)?;
+        }
+
+        if let Some(v) = section.get("ReloadAfterWrite") {
+            settings.reload_after_write = parse_reload_targets(v)?;
+        }
+
+        if let Some(v) = section.get("CompatibilityMode") {
+            settings.compatibility_mode = v.parse()?;
+        }
+
+        if let Some(v) = section.get("TimeoutSec") {
+            settings.timeout =
+                Duration::from_secs(v.parse().map_err(|_| {
+                    format!("Invalid value \"{}\" of TimeoutSec=, expected number", v)
+                })?);
+        }
+
+        if let Some(v) = section.get("SettleTimeoutSec") {
+            settings.settle_timeout = Duration::from_secs(v.parse().map_err(|_| {
+                format!(
+                    "Invalid value \"{}\" of SettleTimeoutSec=, expected number",
+                    v
+                )
+            })?);
+        }
+
+        if let Some(v) = section.get("OrphanReuseDelaySec") {
+            settings.orphan_reuse_delay = Duration::from_secs(v.parse().map_err(|_| {
+                format!(
+                    "Invalid value \"{}\" of OrphanReuseDelaySec=, expected number",
+                    v
+                )
+            })?);
+        }
+
+        if let Some(v) = section.get("DebounceWindowSec") {
+            settings.debounce_window = Duration::from_secs(v.parse().map_err(|_| {
+                format!(
+                    "Invalid value \"{}\" of DebounceWindowSec=, expected number",
+                    v
+                )
+            })?);
+        }
+
+        let property_policy = settings.invalid_hwaddr_policy == InvalidHwaddrPolicy::Property
+            || settings.local_hwaddr_policy == LocalHwaddrPolicy::Property;
+        if property_policy && settings.match_property.is_none() {
+            return Err(From::from(
+                "MAC address policy \"property\" requires MatchProperty= to be set",
+            ));
+        }
+
+        Ok(settings)
+    }
+
+    pub fn virtual_prefix(&self, kind: &str) -> Option<&str> {
+        self.virtual_prefixes
+            .iter()
+            .find(|(k, _)| k == kind)
+            .map(|(_, p)| p.as_str())
+    }
+
+    /// Prefix of the first VendorPrefixes= entry matching the MAC address or the vendor ID of the
+    /// device.
+    pub fn vendor_prefix(&self, hwaddr: &str, vendor_id: Option<&str>) -> Option<&str> {
+        let vendor_id = vendor_id.and_then(parse_vendor_id);
+
+        self.vendor_prefixes
+            .iter()
+            .find(|(m, _)| match m {
+                VendorMatch::Oui(oui) => hwaddr.get(..8).and_then(parse_oui) == Some(*oui),
+                VendorMatch::VendorId(id) => vendor_id == Some(*id),
+            })
+            .map(|(_, p)| p.as_str())
+    }
+
+    pub fn action_accepted(&self, action: &str) -> bool {
+        self.actions.iter().any(|a| a == action)
+    }
+
+    /// Name pinned to the MAC address by ifname= on the kernel command line, the last one wins.
+    pub fn cmdline_ifname(&self, hwaddr: &str) -> Option<&str> {
+        self.cmdline_ifnames
+            .iter()
+            .rev()
+            .find(|(_, a)| a.eq_ignore_ascii_case(hwaddr))
+            .map(|(n, _)| n.as_str())
+    }
+
+    /// Devices whose kernel name matches OriginalNameDevices= are matched by the name, as the
+    /// last resort when neither their MAC address nor their path is stable.
+    pub fn original_name_selected(&self, ifname: &str) -> bool {
+        self.original_name_devices
+            .iter()
+            .any(|p| fnmatch(p, ifname))
+    }
+
+    pub fn driver_skipped(&self, driver: &str) -> bool {
+        self.skip_drivers.iter().any(|d| d == driver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_default() {
+        let conf = Ini::load_from_str("").unwrap();
+        assert_eq!(Settings::from_ini(&conf).unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn settings_invalid_hwaddr_policy_path() {
+        let conf = Ini::load_from_str("[Naming]\nInvalidMACAddressPolicy=path\n").unwrap();
+        let settings = Settings::from_ini(&conf).unwrap();
+
+        assert_eq!(settings.invalid_hwaddr_policy, InvalidHwaddrPolicy::Path);
+    }
+
+    #[test]
+    fn settings_invalid_hwaddr_policy_bogus() {
+        let conf = Ini::load_from_str("[Naming]\nInvalidMACAddressPolicy=maybe\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_local_hwaddr_policy_permanent() {
+        let conf = Ini::load_from_str("[Naming]\nLocalMACAddressPolicy=permanent\n").unwrap();
+        let settings = Settings::from_ini(&conf).unwrap();
+
+        assert_eq!(settings.local_hwaddr_policy, LocalHwaddrPolicy::Permanent);
+        assert_eq!(settings.invalid_hwaddr_policy, InvalidHwaddrPolicy::Skip);
+    }
+
+    #[test]
+    fn settings_respect_udev_names() {
+        let conf = Ini::load_from_str("[Naming]\nRespectUdevNames=yes\n").unwrap();
+        assert!(Settings::from_ini(&conf).unwrap().respect_udev_names);
+
+        let conf = Ini::load_from_str("[Naming]\nRespectUdevNames=sometimes\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_cmdline_conflict_policy() {
+        let conf =
+            Ini::load_from_str("[Naming]\nConflictingKernelArgumentsPolicy=refuse\n").unwrap();
+        let settings = Settings::from_ini(&conf).unwrap();
+
+        assert_eq!(
+            settings.cmdline_conflict_policy,
+            CmdlineConflictPolicy::Refuse
+        );
+    }
+
+    #[test]
+    fn settings_max_index() {
+        let conf = Ini::load_from_str("[Naming]\nMaxIndex=31\n").unwrap();
+        assert_eq!(Settings::from_ini(&conf).unwrap().max_index, Some(31));
+
+        let conf = Ini::load_from_str("[Naming]\nMaxIndex=-1\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_manifest_file() {
+        assert_eq!(Settings::default().manifest_file, None);
+
+        let conf = Ini::load_from_str("[Naming]\nManifestFile=/run/build/links.json\n").unwrap();
+        assert_eq!(
+            Settings::from_ini(&conf).unwrap().manifest_file.as_deref(),
+            Some("/run/build/links.json")
+        );
+    }
+
+    #[test]
+    fn settings_audit_log_disabled() {
+        let conf = Ini::load_from_str("[Naming]\nAuditLog=\n").unwrap();
+        assert_eq!(Settings::from_ini(&conf).unwrap().audit_log, None);
+    }
+
+    #[test]
+    fn settings_link_types() {
+        let conf = Ini::load_from_str("[Naming]\nLinkTypes=ether infiniband 280 ether\n").unwrap();
+        assert_eq!(
+            Settings::from_ini(&conf).unwrap().link_types,
+            vec![1, 32, 280]
+        );
+
+        let conf = Ini::load_from_str("[Naming]\nLinkTypes=ether wifi\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+
+        let conf = Ini::load_from_str("[Naming]\nLinkTypes=\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+
+        let conf = Ini::load_from_str("[Naming]\nLinkTypes=70000\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_vlan_names() {
+        let conf = Ini::load_from_str("[Naming]\nVLANNames=yes\n").unwrap();
+        assert!(Settings::from_ini(&conf).unwrap().vlan_names);
+        assert!(!Settings::default().vlan_names);
+    }
+
+    #[test]
+    fn settings_removed_device_policy() {
+        let conf = Ini::load_from_str("[Naming]\nRemovedDevicePolicy=mark\n").unwrap();
+        assert_eq!(
+            Settings::from_ini(&conf).unwrap().removed_device_policy,
+            RemovedDevicePolicy::Mark
+        );
+        assert_eq!(
+            Settings::default().removed_device_policy,
+            RemovedDevicePolicy::Keep
+        );
+
+        let conf = Ini::load_from_str("[Naming]\nRemovedDevicePolicy=drop\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_allocation_mode() {
+        let conf = Ini::load_from_str("[Naming]\nAllocationMode=optimistic\n").unwrap();
+        assert_eq!(
+            Settings::from_ini(&conf).unwrap().allocation_mode,
+            AllocationMode::Optimistic
+        );
+        assert_eq!(Settings::default().allocation_mode, AllocationMode::Locked);
+
+        let conf = Ini::load_from_str("[Naming]\nAllocationMode=lockless\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_timeout() {
+        let conf = Ini::load_from_str("[Naming]\nTimeoutSec=10\n").unwrap();
+        assert_eq!(
+            Settings::from_ini(&conf).unwrap().timeout,
+            Duration::from_secs(10)
+        );
+        assert_eq!(Settings::default().timeout, Duration::from_secs(30));
+
+        let conf = Ini::load_from_str("[Naming]\nTimeoutSec=-1\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_settle_timeout() {
+        let conf = Ini::load_from_str("[Naming]\nSettleTimeoutSec=60\n").unwrap();
+        assert_eq!(
+            Settings::from_ini(&conf).unwrap().settle_timeout,
+            Duration::from_secs(60)
+        );
+        assert!(Settings::default().settle_timeout.is_zero());
+
+        let conf = Ini::load_from_str("[Naming]\nSettleTimeoutSec=1m\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_debounce_window() {
+        let conf = Ini::load_from_str("[Naming]\nDebounceWindowSec=0\n").unwrap();
+        assert!(Settings::from_ini(&conf).unwrap().debounce_window.is_zero());
+        assert_eq!(Settings::default().debounce_window, Duration::from_secs(5));
+
+        let conf = Ini::load_from_str("[Naming]\nDebounceWindowSec=5s\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_orphan_reuse_delay() {
+        let conf = Ini::load_from_str("[Naming]\nOrphanReuseDelaySec=0\n").unwrap();
+        assert!(Settings::from_ini(&conf)
+            .unwrap()
+            .orphan_reuse_delay
+            .is_zero());
+        assert_eq!(
+            Settings::default().orphan_reuse_delay,
+            Duration::from_secs(300)
+        );
+
+        let conf = Ini::load_from_str("[Naming]\nOrphanReuseDelaySec=5m\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_actions() {
+        let conf = Ini::load_from_str("[Naming]\nActions=add change add\n").unwrap();
+        let settings = Settings::from_ini(&conf).unwrap();
+
+        assert_eq!(settings.actions, vec!["add", "change"]);
+        assert!(settings.action_accepted("change"));
+        assert!(!Settings::default().action_accepted("move"));
+        assert!(Settings::default().action_accepted("add"));
+
+        for v in ["", "remove", "foo"] {
+            let conf = Ini::load_from_str(&format!("[Naming]\nActions={}\n", v)).unwrap();
+            assert!(Settings::from_ini(&conf).is_err());
+        }
+    }
+
+    #[test]
+    fn settings_skip_drivers() {
+        let conf = Ini::load_from_str("[Naming]\nSkipDrivers=hv_netvsc  mlx5_core\n").unwrap();
+        let settings = Settings::from_ini(&conf).unwrap();
+
+        assert_eq!(settings.skip_drivers, vec!["hv_netvsc", "mlx5_core"]);
+        assert!(settings.driver_skipped("hv_netvsc"));
+        assert!(!settings.driver_skipped("e1000e"));
+        assert!(Settings::default().skip_drivers.is_empty());
+    }
+
+    #[test]
+    fn settings_property_policy() {
+        let conf = Ini::load_from_str(
+            "[Naming]\nLocalMACAddressPolicy=property\nMatchProperty=ID_SERIAL\n",
+        )
+        .unwrap();
+        let settings = Settings::from_ini(&conf).unwrap();
+
+        assert_eq!(settings.local_hwaddr_policy, LocalHwaddrPolicy::Property);
+        assert_eq!(settings.match_property.as_deref(), Some("ID_SERIAL"));
+
+        let conf = Ini::load_from_str("[Naming]\nInvalidMACAddressPolicy=property\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_original_name_devices() {
+        let conf = Ini::load_from_str("[Naming]\nOriginalNameDevices=usb* wwan0\n").unwrap();
+        let settings = Settings::from_ini(&conf).unwrap();
+
+        assert!(settings.original_name_selected("usb0"));
+        assert!(settings.original_name_selected("wwan0"));
+        assert!(!settings.original_name_selected("wwan1"));
+        assert!(!Settings::default().original_name_selected("usb0"));
+    }
+
+    #[test]
+    fn settings_match_driver() {
+        let conf = Ini::load_from_str("[Naming]\nMatchDriver=yes\n").unwrap();
+        assert!(Settings::from_ini(&conf).unwrap().match_driver);
+        assert!(!Settings::default().match_driver);
+
+        let conf = Ini::load_from_str("[Naming]\nMatchDriver=sometimes\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_name_in_containers() {
+        let conf = Ini::load_from_str("[Naming]\nNameInContainers=yes\n").unwrap();
+        assert!(Settings::from_ini(&conf).unwrap().name_in_containers);
+        assert!(!Settings::default().name_in_containers);
+
+        let conf = Ini::load_from_str("[Naming]\nNameInContainers=maybe\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_link_keys() {
+        let conf =
+            Ini::load_from_str("[Link]\nMTUBytes=9000\nWakeOnLan=magic\n\n[Naming]\nStrict=yes\n")
+                .unwrap();
+        let settings = Settings::from_ini(&conf).unwrap();
+
+        assert_eq!(
+            settings.link_keys,
+            vec![
+                ("MTUBytes".to_string(), "9000".to_string()),
+                ("WakeOnLan".to_string(), "magic".to_string())
+            ]
+        );
+        assert!(settings.strict);
+        assert!(Settings::default().link_keys.is_empty());
+
+        let conf = Ini::load_from_str("[Link]\nMTUBytes=1500\n").unwrap();
+        assert_eq!(Settings::from_ini(&conf).unwrap().link_keys.len(), 1);
+
+        let conf = Ini::load_from_str("[Link]\nName=eth0\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_link_file_mode() {
+        let mode =
+            |s: &str| Settings::from_ini(&Ini::load_from_str(s).unwrap()).map(|s| s.link_file_mode);
+
+        assert_eq!(Settings::default().link_file_mode, 0o644);
+        assert_eq!(mode("[Naming]\nLinkFileMode=0640\n").unwrap(), 0o640);
+        assert_eq!(mode("[Naming]\nLinkFileMode=600\n").unwrap(), 0o600);
+        assert!(mode("[Naming]\nLinkFileMode=4755\n").is_err());
+        assert!(mode("[Naming]\nLinkFileMode=rw-r--r--\n").is_err());
+    }
+
+    #[test]
+    fn settings_index_pools() {
+        let pools =
+            |s: &str| Settings::from_ini(&Ini::load_from_str(s).unwrap()).map(|s| s.index_pools);
+
+        assert!(Settings::default().index_pools.is_empty());
+        assert_eq!(
+            pools("[Naming]\nIndexPools=numa:0=0-31 numa:1=32-\n")
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(pools("[Naming]\nIndexPools=numa:0=0-31 numa:1=8-\n").is_err());
+    }
+
+    #[test]
+    fn settings_reload_after_write() {
+        let reload = |s: &str| {
+            Settings::from_ini(&Ini::load_from_str(s).unwrap()).map(|s| s.reload_after_write)
+        };
+
+        assert!(Settings::default().reload_after_write.is_empty());
+        assert_eq!(
+            reload("[Naming]\nReloadAfterWrite=networkd udev networkd\n").unwrap(),
+            vec![ReloadTarget::Networkd, ReloadTarget::Udev]
+        );
+        assert!(reload("[Naming]\nReloadAfterWrite=\n").unwrap().is_empty());
+        assert!(reload("[Naming]\nReloadAfterWrite=udev nm\n").is_err());
+    }
+
+    #[test]
+    fn settings_strict() {
+        let conf = Ini::load_from_str("[Naming]\nStrict=true\n").unwrap();
+        assert!(Settings::from_ini(&conf).unwrap().strict);
+        assert!(!Settings::default().strict);
+
+        let conf = Ini::load_from_str("[Naming]\nStrict=sometimes\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_compatibility_mode() {
+        let conf = Ini::load_from_str("[Naming]\nCompatibilityMode=rhel7\n").unwrap();
+        assert_eq!(
+            Settings::from_ini(&conf).unwrap().compatibility_mode,
+            CompatibilityMode::Rhel7
+        );
+        assert_eq!(
+            Settings::default().compatibility_mode,
+            CompatibilityMode::None
+        );
+
+        let conf = Ini::load_from_str("[Naming]\nCompatibilityMode=rhel6\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_naming_policy() {
+        let policy =
+            |s: &str| Settings::from_ini(&Ini::load_from_str(s).unwrap()).map(|s| s.naming_policy);
+
+        assert_eq!(
+            Settings::default().naming_policy,
+            NamingPolicyKind::Sequential
+        );
+        assert_eq!(
+            policy("[Naming]\nNamingPolicy=pci-order\n").unwrap(),
+            NamingPolicyKind::PciOrder
+        );
+        assert_eq!(
+            policy("[Naming]\nFirmwareLabels=yes\n").unwrap(),
+            NamingPolicyKind::Smbios
+        );
+        assert_eq!(
+            policy("[Naming]\nFirmwareLabels=yes\nNamingPolicy=mac-hash\n").unwrap(),
+            NamingPolicyKind::MacHash
+        );
+        assert_eq!(
+            policy("[Naming]\nNamingPolicy=cloud-metadata\n").unwrap(),
+            NamingPolicyKind::CloudMetadata
+        );
+        assert!(policy("[Naming]\nNamingPolicy=random\n").is_err());
+    }
+
+    #[test]
+    fn settings_vendor_prefixes() {
+        let conf =
+            Ini::load_from_str("[Naming]\nVendorPrefixes=00:02:c9=fab, 0x15b3=fab 0x8086=lan\n")
+                .unwrap();
+        let settings = Settings::from_ini(&conf).unwrap();
+
+        assert_eq!(
+            settings.vendor_prefix("00:02:C9:12:34:56", None),
+            Some("fab")
+        );
+        assert_eq!(
+            settings.vendor_prefix("E4:1D:2D:12:34:56", Some("0x15b3")),
+            Some("fab")
+        );
+        assert_eq!(
+            settings.vendor_prefix("A4:BB:6D:1C:08:B7", Some("0x8086")),
+            Some("lan")
+        );
+        assert_eq!(
+            settings.vendor_prefix("A4:BB:6D:1C:08:B7", Some("8086")),
+            Some("lan")
+        );
+        assert_eq!(
+            settings.vendor_prefix("A4:BB:6D:1C:08:B7", Some("0x14e4")),
+            None
+        );
+        assert_eq!(
+            Settings::default().vendor_prefix("00:02:C9:12:34:56", None),
+            None
+        );
+
+        assert!(parse_vendor_prefixes("intel=lan").is_err());
+        assert!(parse_vendor_prefixes("0x808=lan").is_err());
+        assert!(parse_vendor_prefixes("+abc=lan").is_err());
+        assert_eq!(
+            parse_vendor_prefixes("15b3=fab").unwrap(),
+            vec![(VendorMatch::VendorId(0x15b3), "fab".to_string())]
+        );
+        assert!(parse_vendor_prefixes("00:02=fab").is_err());
+        assert!(parse_vendor_prefixes("0x8086=l4n").is_err());
+        assert!(parse_vendor_prefixes("0x8086=lan 0x8086=fab").is_err());
+        assert!(parse_vendor_prefixes("0x8086=lan 0x15b3=lanx").is_err());
+        assert_eq!(parse_vendor_prefixes("").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn settings_virtual_prefixes() {
+        let conf =
+            Ini::load_from_str("[Naming]\nVirtualDevicePrefixes=bond=bd, bridge=br\n").unwrap();
+        let settings = Settings::from_ini(&conf).unwrap();
+
+        assert_eq!(settings.virtual_prefix("bond"), Some("bd"));
+        assert_eq!(settings.virtual_prefix("bridge"), Some("br"));
+        assert_eq!(settings.virtual_prefix("vlan"), None);
+
+        assert!(parse_virtual_prefixes("team=tm").is_err());
+        assert!(parse_virtual_prefixes("bond=b0").is_err());
+        assert!(parse_virtual_prefixes("bond=bd bridge=bdx").is_err());
+        assert!(parse_virtual_prefixes("bond=bd bond=bo").is_err());
+        assert_eq!(parse_virtual_prefixes("").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn settings_prefix_collision_policy() {
+        let conf = Ini::load_from_str("[Naming]\nPrefixCollisionPolicy=refuse\n").unwrap();
+        assert_eq!(
+            Settings::from_ini(&conf).unwrap().prefix_collision_policy,
+            PrefixCollisionPolicy::Refuse
+        );
+
+        let conf = Ini::load_from_str("[Naming]\nPrefixCollisionPolicy=ignore\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+
+    #[test]
+    fn settings_orphan_policy() {
+        let conf = Ini::load_from_str("[Naming]\nOrphanedLinkFilePolicy=reuse\n").unwrap();
+        assert_eq!(
+            Settings::from_ini(&conf).unwrap().orphan_policy,
+            OrphanPolicy::Reuse
+        );
+
+        let conf = Ini::load_from_str("[Naming]\nOrphanedLinkFilePolicy=delete\n").unwrap();
+        assert!(Settings::from_ini(&conf).is_err());
+    }
+}
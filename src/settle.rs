@@ -0,0 +1,117 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SETTLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether udev has no events queued or being processed.
+pub fn udev_queue_empty() -> Result<bool, Box<dyn Error>> {
+    unsafe {
+        let udev = libudev_sys::udev_new();
+        if udev.is_null() {
+            return Err(From::from("Failed to create udev context"));
+        }
+
+        let queue = libudev_sys::udev_queue_new(udev);
+        if queue.is_null() {
+            libudev_sys::udev_unref(udev);
+            return Err(From::from("Failed to open udev event queue"));
+        }
+
+        let empty = libudev_sys::udev_queue_get_queue_is_empty(queue) > 0;
+
+        libudev_sys::udev_queue_unref(queue);
+        libudev_sys::udev_unref(udev);
+
+        Ok(empty)
+    }
+}
+
+/// Polls the condition until it holds or the timeout passes, returns whether it held.
+pub fn wait_until<F>(timeout: Duration, interval: Duration, mut condition: F) -> bool
+where
+    F: FnMut() -> bool,
+{
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if condition() {
+            return true;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+
+        thread::sleep(interval.min(deadline - now));
+    }
+}
+
+/// Waits until udev processed all queued events, e.g. coldplug events of NICs still being
+/// probed. Must be called without holding our lock, the queued events run prefixdevname too.
+pub fn wait_for_udev_settle(timeout: Duration) -> Result<bool, Box<dyn Error>> {
+    let mut failure = None;
+
+    let settled = wait_until(timeout, SETTLE_POLL_INTERVAL, || match udev_queue_empty() {
+        Ok(e) => e,
+        Err(e) => {
+            failure = Some(e);
+            true
+        }
+    });
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(settled),
+    }
+}
+
+/// Interfaces of the list that disappeared, e.g. were renamed or unplugged while udev settled.
+pub fn missing_interfaces(sysfs_net: &Path, ifnames: &[String]) -> Vec<String> {
+    ifnames
+        .iter()
+        .filter(|n| !sysfs_net.join(n).exists())
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settle_wait_until() {
+        let mut polls = 0;
+        assert!(wait_until(Duration::from_secs(5), Duration::ZERO, || {
+            polls += 1;
+            polls == 3
+        }));
+        assert_eq!(polls, 3);
+
+        let start = Instant::now();
+        assert!(!wait_until(
+            Duration::from_millis(50),
+            Duration::from_millis(10),
+            || false
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // The condition is checked at least once even without any timeout
+        assert!(wait_until(Duration::ZERO, Duration::ZERO, || true));
+    }
+
+    #[test]
+    fn settle_missing_interfaces() {
+        let dir = std::env::temp_dir().join(format!("prefixdevname-settle-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("eth0")).unwrap();
+
+        let ifnames = vec!["eth0".to_string(), "eth1".to_string()];
+        assert_eq!(missing_interfaces(&dir, &ifnames), vec!["eth1"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,453 @@
+// SPDX-License-Identifier:  MIT
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::config::{LinkMatch, NetSetupLinkConfig};
+use crate::link_match::MatchedDevice;
+use crate::naming_policy::*;
+use crate::plan::*;
+use crate::settings::{NamingPolicyKind, Settings};
+use crate::util::{hwaddr_normalize, rename_needed};
+
+/// NIC of the simulated machine as described by the device dump.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimulatedDevice {
+    pub name: String,
+    pub hwaddr: String,
+    pub path: Option<String>,
+    pub driver: Option<String>,
+    pub link_type: Option<u32>,
+    pub pci_address: Option<String>,
+    pub numa_node: Option<u32>,
+    pub vendor: Option<String>,
+}
+
+// One device of the umockdev dump, "P: devpath" followed by properties (E:), attributes (A:) and
+// symlinks (L:)
+#[derive(Default)]
+struct DumpEntry {
+    devpath: String,
+    properties: HashMap<String, String>,
+    attributes: HashMap<String, String>,
+    links: HashMap<String, String>,
+}
+
+fn parse_dump_entries(content: &str) -> Vec<DumpEntry> {
+    let mut entries: Vec<DumpEntry> = Vec::new();
+
+    for line in content.lines() {
+        let (kind, value) = match line.split_once(": ") {
+            Some(l) => l,
+            None => continue,
+        };
+
+        if kind == "P" {
+            entries.push(DumpEntry {
+                devpath: value.to_string(),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let (entry, (k, v)) = match (entries.last_mut(), value.split_once('=')) {
+            (Some(e), Some(kv)) => (e, kv),
+            _ => continue,
+        };
+        let map = match kind {
+            "E" => &mut entry.properties,
+            "A" => &mut entry.attributes,
+            "L" => &mut entry.links,
+            _ => continue,
+        };
+
+        map.insert(k.to_string(), v.to_string());
+    }
+
+    entries
+}
+
+fn last_component(path: &str) -> Option<String> {
+    path.rsplit('/')
+        .next()
+        .filter(|c| !c.is_empty() && *c != "..")
+        .map(|c| c.to_string())
+}
+
+/// Network devices of the dump created by "umockdev-record", in the order of the dump. The PCI
+/// address and the NUMA node are taken from the parent device when it is recorded too.
+pub fn parse_umockdev(content: &str) -> Vec<SimulatedDevice> {
+    let entries = parse_dump_entries(content);
+
+    entries
+        .iter()
+        .filter(|e| e.properties.get("SUBSYSTEM").is_some_and(|s| s == "net"))
+        .map(|e| {
+            let parent = e
+                .devpath
+                .rsplit_once("/net/")
+                .and_then(|(p, _)| entries.iter().find(|x| x.devpath == p));
+
+            SimulatedDevice {
+                name: e
+                    .properties
+                    .get("INTERFACE")
+                    .cloned()
+                    .or_else(|| last_component(&e.devpath))
+                    .unwrap_or_default(),
+                hwaddr: e
+                    .attributes
+                    .get("address")
+                    .map(|a| hwaddr_normalize(a).unwrap_or_else(|_| a.to_uppercase()))
+                    .unwrap_or_default(),
+                path: e.properties.get("ID_PATH").cloned(),
+                driver: e.properties.get("ID_NET_DRIVER").cloned(),
+                link_type: e.attributes.get("type").and_then(|t| t.parse().ok()),
+                pci_address: parent
+                    .and_then(|p| p.properties.get("PCI_SLOT_NAME").cloned())
+                    .or_else(|| e.links.get("device").and_then(|d| last_component(d))),
+                numa_node: parent
+                    .and_then(|p| p.attributes.get("numa_node"))
+                    .and_then(|n| n.parse().ok()),
+                vendor: parent
+                    .and_then(|p| p.attributes.get("vendor").cloned())
+                    .or_else(|| e.properties.get("ID_VENDOR_ID").cloned()),
+            }
+        })
+        .collect()
+}
+
+/// Parses the simple YAML description of NICs, a list of mappings with keys name, address, path,
+/// driver, type, pci_address, numa_node and vendor, e.g.
+///
+/// - name: eth0
+///   address: 52:54:00:12:34:56
+///   path: pci-0000:00:03.0
+pub fn parse_device_list(content: &str) -> Result<Vec<SimulatedDevice>, Box<dyn Error>> {
+    let mut devices: Vec<SimulatedDevice> = Vec::new();
+
+    for (n, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed == "---" {
+            continue;
+        }
+
+        let item = match trimmed.strip_prefix('-') {
+            Some(i) => {
+                devices.push(SimulatedDevice::default());
+                i.trim()
+            }
+            None => trimmed,
+        };
+        if item.is_empty() {
+            continue;
+        }
+
+        let invalid = |what: &str| format!("Line {}: {} \"{}\"", n + 1, what, trimmed);
+        let (key, value) = item
+            .split_once(':')
+            .ok_or_else(|| invalid("expected \"key: value\", got"))?;
+        let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        let device = devices
+            .last_mut()
+            .ok_or_else(|| invalid("expected list of devices, got"))?;
+        let number = |v: &str| v.parse().map_err(|_| invalid("expected number in"));
+
+        match key.trim() {
+            "name" => device.name = value.to_string(),
+            "address" => device.hwaddr = hwaddr_normalize(&value)?,
+            "path" => device.path = Some(value.to_string()),
+            "driver" => device.driver = Some(value.to_string()),
+            "type" => device.link_type = Some(number(value)?),
+            "pci_address" => device.pci_address = Some(value.to_string()),
+            "numa_node" => device.numa_node = Some(number(value)?),
+            "vendor" => device.vendor = Some(value.to_string()),
+            _ => return Err(From::from(invalid("unknown key in"))),
+        }
+    }
+
+    if let Some(i) = devices.iter().position(|d| d.name.is_empty()) {
+        return Err(From::from(format!("Device {} has no name", i + 1)));
+    }
+
+    Ok(devices)
+}
+
+/// Devices from either format, umockdev dumps start every device with "P: ".
+pub fn load_devices(path: &Path) -> Result<Vec<SimulatedDevice>, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+
+    if content.lines().any(|l| l.starts_with("P: ")) {
+        Ok(parse_umockdev(&content))
+    } else {
+        parse_device_list(&content)
+    }
+}
+
+// Policies that read the firmware tables or query the metadata service would describe the host
+fn simulated_naming_policy(
+    kind: NamingPolicyKind,
+    devices: &[SimulatedDevice],
+) -> Box<dyn NamingPolicy> {
+    match kind {
+        NamingPolicyKind::Sequential => Box::new(Sequential),
+        NamingPolicyKind::MacHash => Box::new(MacHash),
+        NamingPolicyKind::PciOrder => Box::new(PciOrder::new(
+            devices
+                .iter()
+                .filter_map(|d| d.pci_address.clone())
+                .collect(),
+        )),
+        k => {
+            warn!(
+                "Naming policy {:?} can't be simulated, naming NICs sequentially",
+                k
+            );
+            Box::new(Sequential)
+        }
+    }
+}
+
+/// Names the devices would get on a machine without any link files. Nothing on the host is read
+/// or written.
+pub fn simulate(
+    devices: &[SimulatedDevice],
+    prefix: &str,
+    settings: &Settings,
+) -> Result<Vec<PlannedLink>, Box<dyn Error>> {
+    let mut plan = Vec::new();
+
+    // NICs of the vendors listed in VendorPrefixes= are named from the index pool of their prefix
+    let mut prefixes = vec![prefix.to_string()];
+    for (_, p) in &settings.vendor_prefixes {
+        if !prefixes.contains(p) {
+            prefixes.push(p.clone());
+        }
+    }
+    let mut configs: Vec<NetSetupLinkConfig> = prefixes
+        .iter()
+        .map(|p| {
+            let mut config = NetSetupLinkConfig::new_with_prefix(p);
+
+            config.apply_settings(settings);
+            config.add_sibling_prefix(prefix);
+            config.set_offline(true);
+            config.set_naming_policy(simulated_naming_policy(settings.naming_policy, devices));
+            config
+        })
+        .collect();
+
+    let devices: Vec<&SimulatedDevice> = devices
+        .iter()
+        .filter(|d| settings.link_types.contains(&d.link_type.unwrap_or(1)))
+        .collect();
+
+    // Names the devices already have are taken before new names are allocated
+    for d in &devices {
+        for config in configs.iter_mut() {
+            if rename_needed(&d.name, config.prefix())? || !config.name_has_prefix(&d.name)? {
+                continue;
+            }
+
+            let link = match &d.path {
+                Some(p) if d.hwaddr.is_empty() => {
+                    build_link(&d.name, &LinkMatch::Path(p.clone()), "")
+                }
+                _ => build_link(&d.name, &LinkMatch::MACAddress, &d.hwaddr),
+            };
+            if let Ok(l) = link {
+                config.add_link(l);
+            }
+        }
+    }
+
+    for d in devices {
+        let matched_device = MatchedDevice {
+            hwaddr: d.hwaddr.clone(),
+            path: d.path.clone(),
+            driver: d.driver.clone(),
+            original_name: d.name.clone(),
+            ..Default::default()
+        };
+
+        let device_prefix = vendor_prefix_for(settings, prefix, &d.hwaddr, d.vendor.as_deref())
+            .unwrap_or_else(|| prefix.to_string());
+        let config = configs
+            .iter_mut()
+            .find(|c| c.prefix() == device_prefix)
+            .ok_or("No index pool for the prefix of the device")?;
+
+        let (name, source) = if !rename_needed(&d.name, prefix)?
+            || !rename_needed(&d.name, &device_prefix)?
+        {
+            (Some(d.name.clone()), PlanSource::CurrentName)
+        } else if let Some(driver) = d.driver.as_ref().filter(|d| settings.driver_skipped(d)) {
+            (
+                None,
+                PlanSource::Skipped(format!("driver {} is skipped", driver)),
+            )
+        } else {
+            match hwaddr_decision(settings, &matched_device) {
+                HwaddrDecision::Skip(reason) => (None, PlanSource::Skipped(reason)),
+                HwaddrDecision::Match(link_match, match_hwaddr) => {
+                    let policy_device = PolicyDevice {
+                        hwaddr: match_hwaddr.clone(),
+                        pci_address: d.pci_address.clone(),
+                        numa_node: d.numa_node,
+                    };
+                    let allocated = config
+                        .preferred_link_name(&policy_device)
+                        .and_then(|p| match p {
+                            Some(p) => Ok(Some(p)),
+                            None => config.pooled_link_name(&policy_device),
+                        })
+                        .and_then(|p| {
+                            let (name, source) = match p {
+                                Some((n, p)) => (n, PlanSource::NamingPolicy(p.reason)),
+                                None => (config.reserve_link_name()?, PlanSource::NewName),
+                            };
+                            let link = build_link(&name, &link_match, &match_hwaddr)?
+                                .with_driver(d.driver.clone().filter(|_| settings.match_driver));
+                            Ok((link, source))
+                        });
+
+                    match allocated {
+                        Ok((link, source)) => {
+                            config.add_link(link.clone());
+                            (Some(link.name.into()), source)
+                        }
+                        Err(e) => (None, PlanSource::Failed(e.to_string())),
+                    }
+                }
+            }
+        };
+
+        plan.push(PlannedLink {
+            hwaddr: d.hwaddr.clone(),
+            name,
+            source,
+            kernel_name: d.name.clone(),
+            path: d.path.clone(),
+            driver: d.driver.clone(),
+            speed: None,
+        });
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::parse_vendor_prefixes;
+
+    #[test]
+    fn simulate_umockdev_dump() {
+        let dump = fs::read_to_string(
+            Path::new(file!())
+                .parent()
+                .unwrap()
+                .join("../test/eth0.mockdev"),
+        )
+        .unwrap();
+        let devices = parse_umockdev(&dump);
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "eth0");
+        assert_eq!(devices[0].link_type, Some(1));
+        assert!(hwaddr_normalize(&devices[0].hwaddr).is_ok());
+    }
+
+    #[test]
+    fn simulate_device_list() {
+        let devices = parse_device_list(
+            "# sosreport of host A\n- name: eth0\n  address: 52:54:00:12:34:56\n  path: pci-0000:00:03.0\n  numa_node: 1\n- name: \"net0\"\n  address: a4:bb:6d:1c:08:b7\n  driver: e1000e\n",
+        )
+        .unwrap();
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].hwaddr, "52:54:00:12:34:56".to_uppercase());
+        assert_eq!(devices[0].numa_node, Some(1));
+        assert_eq!(devices[1].name, "net0");
+        assert_eq!(devices[1].driver.as_deref(), Some("e1000e"));
+
+        assert!(parse_device_list("name: eth0\n").is_err());
+        assert!(parse_device_list("- name: eth0\n  speed: 10\n").is_err());
+        assert!(parse_device_list("- address: 52:54:00:12:34:56\n").is_err());
+        assert!(parse_device_list("- name: eth0\n  type: ether\n").is_err());
+    }
+
+    #[test]
+    fn simulate_names() {
+        let device = |name: &str, hwaddr: &str| SimulatedDevice {
+            name: name.to_string(),
+            hwaddr: hwaddr.to_string(),
+            ..Default::default()
+        };
+        let devices = vec![
+            device("eth0", "A4:BB:6D:1C:08:B7"),
+            device("net0", "A4:BB:6D:1C:08:B8"),
+            device("eth1", "00:00:00:00:00:00"),
+            SimulatedDevice {
+                link_type: Some(772),
+                ..device("lo", "00:00:00:00:00:00")
+            },
+            device("eth2", "A4:BB:6D:1C:08:B9"),
+        ];
+
+        let plan = simulate(&devices, "net", &Settings::default()).unwrap();
+        let names: Vec<(&str, Option<&str>)> = plan
+            .iter()
+            .map(|l| (l.kernel_name.as_str(), l.name.as_deref()))
+            .collect();
+
+        assert_eq!(
+            names,
+            vec![
+                ("eth0", Some("net1")),
+                ("net0", Some("net0")),
+                ("eth1", None),
+                ("eth2", Some("net2")),
+            ]
+        );
+        assert_eq!(plan[1].source, PlanSource::CurrentName);
+    }
+
+    #[test]
+    fn simulate_vendor_prefixes() {
+        let device = |name: &str, hwaddr: &str, vendor: Option<&str>| SimulatedDevice {
+            name: name.to_string(),
+            hwaddr: hwaddr.to_string(),
+            vendor: vendor.map(|v| v.to_string()),
+            ..Default::default()
+        };
+        let devices = vec![
+            device("eth0", "E4:1D:2D:12:34:56", Some("0x15b3")),
+            device("eth1", "A4:BB:6D:1C:08:B7", None),
+            device("fab0", "E4:1D:2D:12:34:57", Some("0x15b3")),
+            device("eth2", "00:1B:21:1C:08:B8", None),
+            device("eth3", "E4:1D:2D:12:34:58", Some("15b3")),
+        ];
+        let settings = Settings {
+            vendor_prefixes: parse_vendor_prefixes("0x15b3=fab 00:1b:21=lan").unwrap(),
+            ..Default::default()
+        };
+
+        let names: Vec<Option<String>> = simulate(&devices, "net", &settings)
+            .unwrap()
+            .into_iter()
+            .map(|l| l.name)
+            .collect();
+
+        assert_eq!(
+            names,
+            ["fab1", "net0", "fab0", "lan0", "fab2"]
+                .iter()
+                .map(|n| Some(n.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+}
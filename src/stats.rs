@@ -0,0 +1,133 @@
+// SPDX-License-Identifier:  MIT
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use crate::config::PrefixedLink;
+
+/// Summary of the names allocated using one prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixStats {
+    pub prefix: String,
+    pub assigned: u64,
+    pub highest_index: Option<u64>,
+    pub gaps: Vec<(u64, u64)>,
+    pub free_names: u64,
+    pub orphaned: u64,
+    pub conflicts: u64,
+    pub last_assignment: Option<u64>,
+}
+
+/// Ranges of free indexes between the first index and the highest taken one.
+pub fn index_gaps(indexes: &[u64], first: u64) -> Vec<(u64, u64)> {
+    let taken: BTreeSet<u64> = indexes.iter().copied().filter(|i| *i >= first).collect();
+    let mut gaps = Vec::new();
+    let mut next = first;
+
+    for i in taken {
+        if i > next {
+            gaps.push((next, i - 1));
+        }
+        next = i + 1;
+    }
+
+    gaps
+}
+
+/// Gaps as a list of ranges, e.g. "2-3,7".
+pub fn format_gaps(gaps: &[(u64, u64)]) -> String {
+    gaps.iter()
+        .map(|(s, e)| match s == e {
+            true => s.to_string(),
+            false => format!("{}-{}", s, e),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Links whose [Match] section matches the same device as other link, udev applies only the one
+/// that sorts first and the others never take effect.
+pub fn match_conflicts(links: &[PrefixedLink]) -> u64 {
+    let mut matches: HashMap<(&str, &str), u64> = HashMap::new();
+
+    for l in links {
+        *matches
+            .entry((l.link_match.key(), l.match_value()))
+            .or_default() += 1;
+    }
+
+    matches.values().filter(|n| **n > 1).sum()
+}
+
+/// Modification time of the newest link file, i.e. the time of the last assignment.
+pub fn last_modified(paths: &[PathBuf]) -> Option<u64> {
+    paths
+        .iter()
+        .filter_map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+        .filter_map(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .max()
+}
+
+pub fn prefix_stats(
+    prefix: &str,
+    links: &[PrefixedLink],
+    first_index: u64,
+    free_names: u64,
+    orphaned: u64,
+) -> PrefixStats {
+    let indexes: Vec<u64> = links.iter().map(|l| l.index).collect();
+    let paths: Vec<PathBuf> = links.iter().map(|l| l.link_file_path()).collect();
+
+    PrefixStats {
+        prefix: prefix.to_string(),
+        assigned: links.len() as u64,
+        highest_index: indexes.iter().copied().max(),
+        gaps: index_gaps(&indexes, first_index),
+        free_names,
+        orphaned,
+        conflicts: match_conflicts(links),
+        last_assignment: last_modified(&paths),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_index_gaps() {
+        assert_eq!(index_gaps(&[0, 1, 4, 5, 8], 0), vec![(2, 3), (6, 7)]);
+        assert_eq!(index_gaps(&[3, 1], 0), vec![(0, 0), (2, 2)]);
+        assert_eq!(index_gaps(&[1, 2], 1), vec![]);
+        assert_eq!(index_gaps(&[0, 3], 1), vec![(1, 2)]);
+        assert!(index_gaps(&[], 0).is_empty());
+
+        assert_eq!(format_gaps(&[(2, 3), (7, 7)]), "2-3,7");
+        assert_eq!(format_gaps(&[]), "");
+    }
+
+    #[test]
+    fn stats_prefix() {
+        let links = vec![
+            PrefixedLink::new_with_hwaddr(&"net0", &"52:54:00:1c:08:b7").unwrap(),
+            PrefixedLink::new_with_hwaddr(&"net2", &"52:54:00:1c:08:b8").unwrap(),
+            PrefixedLink::new_with_hwaddr(&"net5", &"52:54:00:1c:08:b8").unwrap(),
+            PrefixedLink::new_with_path(&"net6", &"pci-0000:00:03.0").unwrap(),
+        ];
+        let stats = prefix_stats("net", &links, 0, 9993, 1);
+
+        assert_eq!(stats.assigned, 4);
+        assert_eq!(stats.highest_index, Some(6));
+        assert_eq!(stats.gaps, vec![(1, 1), (3, 4)]);
+        assert_eq!(stats.conflicts, 2);
+        assert_eq!(stats.orphaned, 1);
+        assert_eq!(stats.free_names, 9993);
+
+        let empty = prefix_stats("lan", &[], 0, 10000, 0);
+        assert_eq!(empty.highest_index, None);
+        assert_eq!(empty.last_assignment, None);
+    }
+}
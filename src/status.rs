@@ -0,0 +1,119 @@
+// SPDX-License-Identifier:  MIT
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{link_matched_device, PrefixedLink, PresentDevice};
+
+// Exit codes of "status --check" are stable, CI uses them to gate on the outcome
+pub const STATUS_APPLIED: i32 = 0;
+pub const STATUS_FAILED: i32 = 1;
+pub const STATUS_NOT_APPLIED: i32 = 2;
+
+/// Whether the name given by the link file took effect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenameState {
+    Applied,
+    /// The device still has other name, e.g. it was named by a stale initrd
+    NotApplied(String),
+    /// No present device matches the link file
+    Missing,
+}
+
+impl fmt::Display for RenameState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenameState::Applied => write!(f, "applied"),
+            RenameState::NotApplied(n) => write!(f, "not applied (device is named {})", n),
+            RenameState::Missing => write!(f, "device missing"),
+        }
+    }
+}
+
+pub fn rename_state(link: &PrefixedLink, present: &[PresentDevice]) -> RenameState {
+    match link_matched_device(link, present) {
+        Some(d) if d.name == link.name => RenameState::Applied,
+        Some(d) => RenameState::NotApplied(d.name.clone()),
+        None => RenameState::Missing,
+    }
+}
+
+/// Initrd image of the running kernel as installed by dracut.
+pub fn initrd_path() -> Option<PathBuf> {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease").ok()?;
+
+    Some(PathBuf::from(format!(
+        "/boot/initramfs-{}.img",
+        release.trim()
+    )))
+}
+
+/// Initrd built before the link file was written doesn't contain it, devices named in early boot
+/// keep the old name then.
+pub fn initrd_older_than(initrd: &Path, link_file: &Path) -> bool {
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+
+    match (modified(initrd), modified(link_file)) {
+        (Some(i), Some(l)) => i < l,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_rename_state() {
+        let present = vec![
+            PresentDevice {
+                name: "net0".to_string(),
+                hwaddrs: vec!["52:54:00:1C:08:B7".to_string()],
+                ..Default::default()
+            },
+            PresentDevice {
+                name: "eth1".to_string(),
+                hwaddrs: vec!["52:54:00:1C:08:B8".to_string()],
+                path: Some("pci-0000:00:04.0".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        let link = PrefixedLink::new_with_hwaddr(&"net0", &"52:54:00:1c:08:b7").unwrap();
+        assert_eq!(rename_state(&link, &present), RenameState::Applied);
+
+        let link = PrefixedLink::new_with_path(&"net1", &"pci-0000:00:04.0").unwrap();
+        assert_eq!(
+            rename_state(&link, &present),
+            RenameState::NotApplied("eth1".to_string())
+        );
+
+        let link = PrefixedLink::new_with_hwaddr(&"net2", &"52:54:00:1c:08:b9").unwrap();
+        assert_eq!(rename_state(&link, &present), RenameState::Missing);
+    }
+
+    #[test]
+    fn status_initrd_older() {
+        let dir = std::env::temp_dir().join(format!("prefixdevname-status-{}", std::process::id()));
+        let (initrd, link_file) = (dir.join("initramfs.img"), dir.join("net0.link"));
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&initrd, "").unwrap();
+        fs::write(&link_file, "").unwrap();
+
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(&initrd)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        assert!(initrd_older_than(&initrd, &link_file));
+        assert!(!initrd_older_than(&link_file, &initrd));
+        assert!(!initrd_older_than(&dir.join("missing"), &link_file));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,649 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+#[cfg(not(feature = "minimal"))]
+use crate::backup::restore_backup;
+use crate::backup::{backup_file, backup_link_file};
+use crate::config::set_file_attributes;
+use crate::util::{error_already_exists, temp_path_for};
+
+pub static TRANSACTION_RECORD: &str = "/var/lib/prefixdevname/last-transaction";
+
+static STAGING_DIR_PREFIX: &str = ".prefixdevname-staging-";
+static JOURNAL: &str = "journal";
+static COMMITTED: &str = "committed";
+
+// Original content of replaced files is kept in the staging directory until the commit finishes
+static ORIGINAL_SUFFIX: &str = ".orig";
+
+/// Commit failed because the file was created by someone else after it was staged, nothing was
+/// changed.
+#[derive(Debug)]
+pub struct CommitConflict {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for CommitConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Failed to write {}: file already exists, no files were changed",
+            self.path.display()
+        )
+    }
+}
+
+impl Error for CommitConflict {}
+
+/// Set of files written all at once. Files are staged in the directory next to their final
+/// location (rename and link can't cross file systems) and moved into place on commit. Files
+/// that are already in place are rolled back when any of them fails. Journal in the staging
+/// directory lets the next transaction roll back the commit interrupted by a crash. Transactions
+/// must be run under the lock.
+pub struct Transaction {
+    target_dir: PathBuf,
+    staging_dir: PathBuf,
+    staged: Vec<(String, Operation)>,
+    record: Option<(PathBuf, PathBuf)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    New,
+    Replace,
+    Remove,
+}
+
+/// Change made by the committed transaction, replaced files are restored from the backup on
+/// rollback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordedChange {
+    Added(String),
+    Changed { file_name: String, backup: String },
+}
+
+#[cfg(not(feature = "minimal"))]
+impl RecordedChange {
+    fn parse(line: &str) -> Option<RecordedChange> {
+        match line.split(' ').collect::<Vec<&str>>()[..] {
+            ["added", f] => Some(RecordedChange::Added(f.to_string())),
+            ["changed", f, b] => Some(RecordedChange::Changed {
+                file_name: f.to_string(),
+                backup: b.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RecordedChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordedChange::Added(file_name) => write!(f, "added {}", file_name),
+            RecordedChange::Changed { file_name, backup } => {
+                write!(f, "changed {} {}", file_name, backup)
+            }
+        }
+    }
+}
+
+impl Transaction {
+    pub fn new<P: AsRef<Path>>(target_dir: P) -> Result<Transaction, Box<dyn Error>> {
+        let target_dir = target_dir.as_ref().to_path_buf();
+
+        recover_transactions(&target_dir)?;
+
+        let staging_dir = target_dir.join(format!("{}{}", STAGING_DIR_PREFIX, std::process::id()));
+        fs::create_dir_all(&staging_dir)?;
+
+        Ok(Transaction {
+            target_dir,
+            staging_dir,
+            staged: Vec::new(),
+            record: None,
+        })
+    }
+
+    /// Committed changes are recorded in the file, so that the transaction can be rolled back
+    /// later. Replaced files are backed up to the backup directory.
+    pub fn record_to<P: AsRef<Path>, B: AsRef<Path>>(&mut self, record: P, backup_dir: B) {
+        self.record = Some((
+            record.as_ref().to_path_buf(),
+            backup_dir.as_ref().to_path_buf(),
+        ));
+    }
+
+    // Backup is required when the transaction is recorded, replaced file couldn't be restored
+    // by the rollback otherwise
+    fn replace(&self, staged: &Path, target: &Path) -> Result<Option<String>, Box<dyn Error>> {
+        let backup = match &self.record {
+            Some((_, backup_dir)) => backup_file(backup_dir, target)?
+                .and_then(|b| b.file_name().map(|n| n.to_string_lossy().to_string())),
+            None => {
+                backup_link_file(target);
+                None
+            }
+        };
+
+        fs::rename(staged, target)?;
+        Ok(backup)
+    }
+
+    fn check_file_name(&self, file_name: &str) -> Result<(), Box<dyn Error>> {
+        if file_name.contains('/') || self.staged.iter().any(|(f, _)| f == file_name) {
+            return Err(From::from(format!("Can't stage file \"{}\"", file_name)));
+        }
+
+        Ok(())
+    }
+
+    /// Stages the file, existing file is replaced only when replace is set, otherwise the commit
+    /// fails when the file exists.
+    pub fn stage(
+        &mut self,
+        file_name: &str,
+        content: &str,
+        mode: u32,
+        replace: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        self.check_file_name(file_name)?;
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(self.staging_dir.join(file_name))?;
+
+        file.write_all(content.as_bytes())?;
+        set_file_attributes(&file, mode, 0, 0)?;
+        self.staged.push((
+            file_name.to_string(),
+            match replace {
+                true => Operation::Replace,
+                false => Operation::New,
+            },
+        ));
+
+        Ok(())
+    }
+
+    /// Stages the removal of the file, the commit fails when the file doesn't exist.
+    #[cfg(not(feature = "minimal"))]
+    pub fn stage_removal(&mut self, file_name: &str) -> Result<(), Box<dyn Error>> {
+        self.check_file_name(file_name)?;
+        self.staged.push((file_name.to_string(), Operation::Remove));
+
+        Ok(())
+    }
+
+    fn write_journal(&self) -> Result<(), Box<dyn Error>> {
+        let mut journal = String::new();
+
+        for (file_name, operation) in &self.staged {
+            let target = self.target_dir.join(file_name);
+            let original = self
+                .staging_dir
+                .join(format!("{}{}", file_name, ORIGINAL_SUFFIX));
+
+            match operation {
+                Operation::Replace if target.exists() => {
+                    fs::copy(&target, original)?;
+                    journal.push_str(&format!("replace {}\n", file_name));
+                }
+                // Moved into place by rename, not linked, the staged file is gone afterwards
+                Operation::Replace => journal.push_str(&format!("created {}\n", file_name)),
+                Operation::Remove => {
+                    fs::copy(&target, original)
+                        .map_err(|e| format!("Failed to remove {}: {}", target.display(), e))?;
+                    journal.push_str(&format!("remove {}\n", file_name));
+                }
+                _ => journal.push_str(&format!("new {}\n", file_name)),
+            }
+        }
+
+        let mut file = fs::File::create(self.staging_dir.join(JOURNAL))?;
+        file.write_all(journal.as_bytes())?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+
+    // Removed file is backed up the same way as the replaced one, the rollback restores it
+    fn remove(&self, target: &Path) -> Result<Option<String>, Box<dyn Error>> {
+        let backup = match &self.record {
+            Some((_, backup_dir)) => backup_file(backup_dir, target)?
+                .and_then(|b| b.file_name().map(|n| n.to_string_lossy().to_string())),
+            None => {
+                backup_link_file(target);
+                None
+            }
+        };
+
+        fs::remove_file(target)?;
+        Ok(backup)
+    }
+
+    /// Moves staged files into place and removes the files staged for removal, returns paths of
+    /// the written files. Nothing is changed on failure.
+    pub fn commit(self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let _span = tracing::debug_span!("write", files = self.staged.len()).entered();
+
+        self.write_journal()?;
+
+        let mut committed = Vec::new();
+        let mut changes = Vec::new();
+
+        for (file_name, operation) in &self.staged {
+            let staged = self.staging_dir.join(file_name);
+            let target = self.target_dir.join(file_name);
+
+            // Hard link never replaces existing file, not even the one created concurrently
+            let result = match operation {
+                Operation::Replace => self.replace(&staged, &target),
+                Operation::Remove => self.remove(&target),
+                Operation::New => fs::hard_link(&staged, &target)
+                    .map(|_| None)
+                    .map_err(From::from),
+            };
+            let replace = *operation != Operation::New;
+
+            match result {
+                Ok(Some(backup)) => changes.push(RecordedChange::Changed {
+                    file_name: file_name.clone(),
+                    backup,
+                }),
+                Ok(None) if *operation == Operation::Remove => {}
+                Ok(None) => changes.push(RecordedChange::Added(file_name.clone())),
+                Err(e) => {
+                    let rollback = roll_back(&self.target_dir, &self.staging_dir);
+                    if rollback.is_ok() && !replace && error_already_exists(e.as_ref()) {
+                        return Err(Box::new(CommitConflict { path: target }));
+                    }
+                    return Err(From::from(match rollback {
+                        Ok(()) => format!(
+                            "Failed to write {}: {}, no files were changed",
+                            target.display(),
+                            e
+                        ),
+                        Err(r) => format!(
+                            "Failed to write {}: {}, rollback failed: {}",
+                            target.display(),
+                            e,
+                            r
+                        ),
+                    }));
+                }
+            }
+
+            if *operation != Operation::Remove {
+                committed.push(target);
+            }
+        }
+
+        fs::File::create(self.staging_dir.join(COMMITTED))?.sync_all()?;
+
+        if let Some((record, _)) = &self.record {
+            if let Err(e) = write_record(record, &self.target_dir, &changes) {
+                warn!(
+                    "Failed to record transaction in {}: {}",
+                    record.display(),
+                    e
+                );
+            }
+        }
+
+        Ok(committed)
+    }
+}
+
+fn write_record(
+    record: &Path,
+    target_dir: &Path,
+    changes: &[RecordedChange],
+) -> Result<(), Box<dyn Error>> {
+    let mut content = format!("dir {}\n", target_dir.display());
+
+    for c in changes {
+        content.push_str(&format!("{}\n", c));
+    }
+
+    if let Some(dir) = record.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let tmp = record.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, record)?;
+
+    Ok(())
+}
+
+/// Undoes the last recorded transaction. Added files are removed (a backup is kept) and replaced
+/// files are restored from their backups. The record is removed, hence the transaction can be
+/// rolled back only once. Returns paths of the files and what was done to them.
+#[cfg(not(feature = "minimal"))]
+pub fn roll_back_recorded<P: AsRef<Path>, B: AsRef<Path>>(
+    record: P,
+    backup_dir: B,
+    mode: u32,
+) -> Result<Vec<(PathBuf, &'static str)>, Box<dyn Error>> {
+    let content = match fs::read_to_string(record.as_ref()) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(From::from("No transaction to roll back"))
+        }
+        Err(e) => return Err(From::from(e)),
+    };
+    let mut lines = content.lines();
+    let target_dir = lines
+        .next()
+        .and_then(|l| l.strip_prefix("dir "))
+        .map(PathBuf::from)
+        .ok_or("Invalid transaction record")?;
+    let changes = lines
+        .map(|l| RecordedChange::parse(l).ok_or("Invalid transaction record"))
+        .collect::<Result<Vec<RecordedChange>, &str>>()?;
+    let mut done = Vec::new();
+
+    for change in changes.iter().rev() {
+        match change {
+            RecordedChange::Added(file_name) => {
+                let path = target_dir.join(file_name);
+
+                backup_file(&backup_dir, &path)?;
+                match fs::remove_file(&path) {
+                    Ok(()) => done.push((path, "removed")),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(From::from(e)),
+                }
+            }
+            RecordedChange::Changed { backup, .. } => {
+                done.push((
+                    restore_backup(&backup_dir, backup, &target_dir, mode)?,
+                    "restored",
+                ));
+            }
+        }
+    }
+
+    fs::remove_file(record)?;
+
+    Ok(done)
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.staging_dir);
+    }
+}
+
+/// Undoes the changes recorded in the journal of the staging directory.
+fn roll_back(target_dir: &Path, staging_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let journal = match fs::read_to_string(staging_dir.join(JOURNAL)) {
+        Ok(j) => j,
+        // Crashed before the commit started, nothing was moved into place
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(From::from(e)),
+    };
+
+    for line in journal.lines() {
+        let (operation, file_name) = match line.split_once(' ') {
+            Some(l) => l,
+            None => continue,
+        };
+        let target = target_dir.join(file_name);
+        let staged = staging_dir.join(file_name);
+
+        match operation {
+            // Remove the file only if it is the staged one, not a file created by someone else
+            "new" => match (fs::metadata(&target), fs::metadata(&staged)) {
+                (Ok(t), Ok(s)) if t.dev() == s.dev() && t.ino() == s.ino() => {
+                    fs::remove_file(&target)?
+                }
+                _ => {}
+            },
+            // Staged file still in the staging directory means it wasn't moved into place
+            "created" if !staged.exists() => match fs::remove_file(&target) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(From::from(e)),
+            },
+            "replace" | "remove" => {
+                let original = staging_dir.join(format!("{}{}", file_name, ORIGINAL_SUFFIX));
+
+                // Staged file still in the staging directory means it wasn't moved into place,
+                // the file to be removed is still in place unless it was removed
+                let changed = match operation {
+                    "replace" => !staged.exists(),
+                    _ => !target.exists(),
+                };
+                if changed {
+                    let tmp = temp_path_for(&target);
+                    let restored =
+                        fs::copy(&original, &tmp).and_then(|_| fs::rename(&tmp, &target));
+
+                    if let Err(e) = restored {
+                        let _ = fs::remove_file(&tmp);
+                        return Err(From::from(e));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Rolls back transactions interrupted by a crash, must be called under the lock.
+pub fn recover_transactions<P: AsRef<Path>>(target_dir: P) -> Result<(), Box<dyn Error>> {
+    let entries = match fs::read_dir(target_dir.as_ref()) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(From::from(e)),
+    };
+
+    for entry in entries {
+        let staging_dir = entry?.path();
+        let name = staging_dir
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+
+        if !name.starts_with(STAGING_DIR_PREFIX) {
+            continue;
+        }
+
+        if !staging_dir.join(COMMITTED).exists() {
+            warn!(
+                "Rolling back interrupted transaction {}",
+                staging_dir.display()
+            );
+            roll_back(target_dir.as_ref(), &staging_dir)?;
+        }
+
+        fs::remove_dir_all(&staging_dir)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "prefixdevname-transaction-{}-{}",
+            name,
+            std::process::id()
+        ));
+
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn transaction_commit() {
+        let dir = target_dir("commit");
+        fs::write(dir.join("b.link"), "old").unwrap();
+
+        let mut t = Transaction::new(&dir).unwrap();
+        t.stage("a.link", "a", 0o644, false).unwrap();
+        t.stage("b.link", "b", 0o644, true).unwrap();
+        assert!(t.stage("a.link", "a", 0o644, false).is_err());
+        assert_eq!(t.commit().unwrap().len(), 2);
+
+        assert_eq!(fs::read_to_string(dir.join("a.link")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dir.join("b.link")).unwrap(), "b");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn transaction_rollback() {
+        let dir = target_dir("rollback");
+        fs::write(dir.join("b.link"), "old").unwrap();
+        fs::write(dir.join("c.link"), "foreign").unwrap();
+
+        let mut t = Transaction::new(&dir).unwrap();
+        t.stage("a.link", "a", 0o644, false).unwrap();
+        t.stage("b.link", "b", 0o644, true).unwrap();
+        t.stage("c.link", "c", 0o644, false).unwrap();
+        let e = t.commit().unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<CommitConflict>().map(|c| &c.path),
+            Some(&dir.join("c.link"))
+        );
+
+        assert!(!dir.join("a.link").exists());
+        assert_eq!(fs::read_to_string(dir.join("b.link")).unwrap(), "old");
+        assert_eq!(fs::read_to_string(dir.join("c.link")).unwrap(), "foreign");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // File staged as replacement of file that doesn't exist is removed too
+        let mut t = Transaction::new(&dir).unwrap();
+        t.stage("d.link", "d", 0o644, true).unwrap();
+        t.stage("c.link", "c", 0o644, false).unwrap();
+        assert!(t.commit().is_err());
+
+        assert!(!dir.join("d.link").exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn transaction_removal() {
+        let dir = target_dir("removal");
+        fs::write(dir.join("a.link"), "a").unwrap();
+        fs::write(dir.join("b.link"), "b").unwrap();
+
+        let mut t = Transaction::new(&dir).unwrap();
+        t.stage_removal("a.link").unwrap();
+        assert!(t.stage_removal("a.link").is_err());
+        t.stage("c.link", "c", 0o644, false).unwrap();
+        assert_eq!(t.commit().unwrap(), vec![dir.join("c.link")]);
+        assert!(!dir.join("a.link").exists());
+
+        // Removal is rolled back when a later file fails
+        let mut t = Transaction::new(&dir).unwrap();
+        t.stage_removal("b.link").unwrap();
+        t.stage("c.link", "c", 0o644, false).unwrap();
+        assert!(t.commit().is_err());
+        assert_eq!(fs::read_to_string(dir.join("b.link")).unwrap(), "b");
+
+        let mut t = Transaction::new(&dir).unwrap();
+        t.stage_removal("missing.link").unwrap();
+        assert!(t.commit().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn transaction_recover() {
+        let dir = target_dir("recover");
+        let staging_dir = dir.join(format!("{}1", STAGING_DIR_PREFIX));
+
+        // Crashed after a.link was linked, b.link replaced and d.link created, c.link and e.link
+        // were not moved yet
+        fs::create_dir_all(&staging_dir).unwrap();
+        fs::write(staging_dir.join("a.link"), "a").unwrap();
+        fs::hard_link(staging_dir.join("a.link"), dir.join("a.link")).unwrap();
+        fs::write(dir.join("b.link"), "b").unwrap();
+        fs::write(staging_dir.join("b.link.orig"), "old").unwrap();
+        fs::write(staging_dir.join("c.link"), "c").unwrap();
+        fs::write(dir.join("d.link"), "d").unwrap();
+        fs::write(staging_dir.join("e.link"), "e").unwrap();
+        fs::write(
+            staging_dir.join(JOURNAL),
+            "new a.link\nreplace b.link\nnew c.link\ncreated d.link\ncreated e.link\n",
+        )
+        .unwrap();
+
+        recover_transactions(&dir).unwrap();
+
+        assert!(!dir.join("a.link").exists());
+        assert_eq!(fs::read_to_string(dir.join("b.link")).unwrap(), "old");
+        assert!(!dir.join("c.link").exists());
+        assert!(!dir.join("d.link").exists());
+        assert!(!dir.join("e.link").exists());
+        assert!(!staging_dir.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn transaction_record_rollback() {
+        let dir = target_dir("record");
+        let backup_dir = dir.join("backups");
+        let record = dir.join("last-transaction");
+        let network_dir = dir.join("network");
+
+        fs::create_dir_all(&network_dir).unwrap();
+        fs::write(network_dir.join("b.link"), "old").unwrap();
+
+        let mut t = Transaction::new(&network_dir).unwrap();
+        t.record_to(&record, &backup_dir);
+        t.stage("a.link", "a", 0o644, false).unwrap();
+        t.stage("b.link", "b", 0o644, true).unwrap();
+        t.commit().unwrap();
+
+        let done = roll_back_recorded(&record, &backup_dir, 0o644).unwrap();
+        assert_eq!(
+            done,
+            vec![
+                (network_dir.join("b.link"), "restored"),
+                (network_dir.join("a.link"), "removed")
+            ]
+        );
+        assert!(!network_dir.join("a.link").exists());
+        assert_eq!(
+            fs::read_to_string(network_dir.join("b.link")).unwrap(),
+            "old"
+        );
+        assert!(roll_back_recorded(&record, &backup_dir, 0o644).is_err());
+
+        assert_eq!(
+            RecordedChange::parse("changed b.link 1709211900-b.link"),
+            Some(RecordedChange::Changed {
+                file_name: "b.link".to_string(),
+                backup: "1709211900-b.link".to_string()
+            })
+        );
+        assert_eq!(RecordedChange::parse("garbage"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
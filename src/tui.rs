@@ -0,0 +1,244 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::io;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::crossterm::ExecutableCommand;
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Borders, List, Paragraph, Row, Table, TableState};
+
+use crate::edit::EditSession;
+use crate::output::Status;
+
+#[derive(Clone, Copy)]
+enum Input {
+    Assign,
+    Replace,
+}
+
+enum Mode {
+    Browse,
+    Input(Input, String),
+    Confirm,
+}
+
+struct Editor<'a> {
+    session: &'a mut EditSession,
+    table: TableState,
+    mode: Mode,
+    message: String,
+}
+
+const HELP: &str = "a assign  p move name here  r release  u undo  w write  q quit";
+
+impl Editor<'_> {
+    fn selected_hwaddr(&self) -> Option<String> {
+        let row = self.session.rows().get(self.table.selected()?)?;
+        Some(row.hwaddr.clone())
+    }
+
+    fn select(&mut self, offset: isize) {
+        let len = self.session.rows().len();
+
+        if len == 0 {
+            return;
+        }
+
+        let current = self.table.selected().unwrap_or(0) as isize;
+        self.table
+            .select(Some((current + offset).rem_euclid(len as isize) as usize));
+    }
+
+    fn report(&mut self, result: Result<(), Box<dyn Error>>) {
+        self.message = match result {
+            Ok(()) => match self.session.pending().last() {
+                Some(a) => format!("Pending: {}", a),
+                None => String::new(),
+            },
+            Err(e) => e.to_string(),
+        };
+    }
+
+    // Returns Some(true) when the changes should be written, Some(false) on quit
+    fn handle_key(&mut self, code: KeyCode) -> Option<bool> {
+        let hwaddr = self.selected_hwaddr().unwrap_or_default();
+
+        match std::mem::replace(&mut self.mode, Mode::Browse) {
+            Mode::Browse => match code {
+                KeyCode::Up | KeyCode::Char('k') => self.select(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.select(1),
+                KeyCode::Char('a') => self.mode = Mode::Input(Input::Assign, String::new()),
+                KeyCode::Char('p') => self.mode = Mode::Input(Input::Replace, String::new()),
+                KeyCode::Char('r') => {
+                    let name = self.session.planned_name(&hwaddr).unwrap_or_default();
+                    let result = self.session.release(&name);
+                    self.report(result);
+                }
+                KeyCode::Char('u') => {
+                    self.message = match self.session.undo() {
+                        Some(a) => format!("Undone: {}", a),
+                        None => "Nothing to undo".to_string(),
+                    }
+                }
+                KeyCode::Char('w') if !self.session.pending().is_empty() => {
+                    self.mode = Mode::Confirm
+                }
+                KeyCode::Char('w') => self.message = "No changes to write".to_string(),
+                KeyCode::Char('q') | KeyCode::Esc => return Some(false),
+                _ => {}
+            },
+            Mode::Input(kind, mut input) => match code {
+                KeyCode::Char(c) => {
+                    input.push(c);
+                    self.mode = Mode::Input(kind, input);
+                }
+                KeyCode::Backspace => {
+                    input.pop();
+                    self.mode = Mode::Input(kind, input);
+                }
+                KeyCode::Enter => {
+                    let result = match kind {
+                        Input::Assign => self.session.assign(&hwaddr, &input),
+                        Input::Replace => self.session.replace(&input, &hwaddr),
+                    };
+                    self.report(result);
+                }
+                _ => {}
+            },
+            Mode::Confirm => match code {
+                KeyCode::Char('y') => return Some(true),
+                _ => self.message = "Nothing written".to_string(),
+            },
+        }
+
+        None
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let pending = self.session.pending().len() as u16;
+        let conflicts = self.session.conflicts();
+        let [table_area, pending_area, conflicts_area, status_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(pending + 2),
+            Constraint::Length(conflicts.len() as u16 + 2),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let rows: Vec<Row> = self
+            .session
+            .rows()
+            .iter()
+            .map(|r| {
+                let style = match r.source.status() {
+                    Status::Conflict => Style::new().red(),
+                    Status::Warning => Style::new().yellow(),
+                    _ => Style::new(),
+                };
+
+                Row::new(vec![
+                    r.kernel_name.clone().unwrap_or_else(|| "-".to_string()),
+                    r.hwaddr.clone(),
+                    r.name.clone().unwrap_or_else(|| "-".to_string()),
+                    self.session
+                        .planned_name(&r.hwaddr)
+                        .unwrap_or_else(|| "-".to_string()),
+                    r.source.to_string(),
+                ])
+                .style(style)
+            })
+            .collect();
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(16),
+                Constraint::Length(18),
+                Constraint::Length(16),
+                Constraint::Length(16),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(
+            Row::new(vec!["DEVICE", "MAC", "CURRENT", "PLANNED", "SOURCE"])
+                .style(Style::new().bold()),
+        )
+        .block(
+            Block::new()
+                .borders(Borders::ALL)
+                .title("prefixdevname edit"),
+        )
+        .row_highlight_style(Style::new().reversed());
+
+        frame.render_stateful_widget(table, table_area, &mut self.table);
+        frame.render_widget(
+            List::new(self.session.pending().iter().map(|a| a.to_string()))
+                .block(Block::new().borders(Borders::ALL).title("Pending changes")),
+            pending_area,
+        );
+        frame.render_widget(
+            List::new(conflicts).block(Block::new().borders(Borders::ALL).title("Conflicts")),
+            conflicts_area,
+        );
+
+        let status = match &self.mode {
+            Mode::Browse if self.message.is_empty() => HELP.to_string(),
+            Mode::Browse => self.message.clone(),
+            Mode::Input(Input::Assign, input) => {
+                format!("Name to assign (Enter to add, Esc to cancel): {}", input)
+            }
+            Mode::Input(Input::Replace, input) => {
+                format!(
+                    "Name to move to this device (Enter to add, Esc to cancel): {}",
+                    input
+                )
+            }
+            Mode::Confirm => format!(
+                "Write {} change(s) to {}? [y/N]",
+                self.session.pending().len(),
+                crate::config::NET_SETUP_LINK_CONF_DIR
+            ),
+        };
+        frame.render_widget(Paragraph::new(status), status_area);
+    }
+}
+
+/// Runs the editor in the terminal. Returns true when the user confirmed writing the pending
+/// changes, the caller applies them.
+pub fn run_editor(session: &mut EditSession) -> Result<bool, Box<dyn Error>> {
+    enable_raw_mode()?;
+    io::stdout().execute(EnterAlternateScreen)?;
+
+    let result = (|| -> Result<bool, Box<dyn Error>> {
+        let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        let mut editor = Editor {
+            session,
+            table: TableState::default().with_selected(Some(0)),
+            mode: Mode::Browse,
+            message: String::new(),
+        };
+
+        loop {
+            terminal.draw(|f| editor.draw(f))?;
+
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+
+                if let Some(write) = editor.handle_key(key.code) {
+                    return Ok(write);
+                }
+            }
+        }
+    })();
+
+    // Terminal must be restored even when the editor failed
+    disable_raw_mode()?;
+    io::stdout().execute(LeaveAlternateScreen)?;
+
+    result
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier:  MIT
+
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub static HELPER_PATH: &str = "/usr/lib/udev/prefixdevname";
+pub static UDEV_RULES_DIR: &str = "/etc/udev/rules.d";
+pub static UDEV_RULE_FILE: &str = "71-prefixdevname.rules";
+
+/// Rule running the helper. The helper expects the kernel command line prefix to be imported,
+/// path_id and hwdb to be run and the name set by earlier rules in PREFIXDEVNAME_CURRENT_NAME,
+/// it prints the new name. Remove events are passed to the helper only when the removal of
+/// devices is handled.
+pub fn udev_rule(helper: &str, handle_remove: bool) -> String {
+    let mut rule = String::from(
+        "# Do not edit this file, it will be overwritten on updates\n\
+         \n\
+         SUBSYSTEM!=\"net\", GOTO=\"net_ifnames_prefix_end\"\n",
+    );
+
+    if handle_remove {
+        rule.push_str(&format!(
+            "ACTION==\"remove\", RUN+=\"{}\", GOTO=\"net_ifnames_prefix_end\"\n",
+            helper
+        ));
+    }
+
+    rule.push_str(&format!(
+        "ACTION!=\"add\",    GOTO=\"net_ifnames_prefix_end\"\n\
+         \n\
+         IMPORT{{cmdline}}=\"net.ifnames.prefix\"\n\
+         ENV{{net.ifnames.prefix}}!=\"?*\", GOTO=\"net_ifnames_prefix_end\"\n\
+         IMPORT{{builtin}}=\"path_id\"\n\
+         IMPORT{{builtin}}=\"hwdb\"\n\
+         ENV{{PREFIXDEVNAME_CURRENT_NAME}}=\"$name\"\n\
+         PROGRAM=\"{}\", RESULT==\"?*\", NAME=\"$result\"\n\
+         \n\
+         LABEL=\"net_ifnames_prefix_end\"\n",
+        helper
+    ));
+
+    rule
+}
+
+/// Writes the rule to the directory, the file overrides the rule of the same name shipped in
+/// /usr/lib/udev/rules.d. Returns path of the rule file.
+pub fn install_udev_rule<P: AsRef<Path>>(dir: P, rule: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let path = dir.as_ref().join(UDEV_RULE_FILE);
+    let tmp_path = path.with_extension("tmp");
+
+    fs::create_dir_all(dir.as_ref())?;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(&tmp_path)?;
+
+    file.write_all(rule.as_bytes())?;
+    fs::rename(&tmp_path, &path)?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rule shipped in the package must be the one the helper expects
+    #[test]
+    fn udev_rule_shipped() {
+        assert_eq!(
+            udev_rule(HELPER_PATH, false),
+            include_str!("../rules/71-prefixdevname.rules")
+        );
+    }
+
+    #[test]
+    fn udev_rule_remove() {
+        let rule = udev_rule("/usr/local/bin/prefixdevname", true);
+        let remove = rule.find("ACTION==\"remove\"").unwrap();
+
+        assert!(remove < rule.find("ACTION!=\"add\"").unwrap());
+        assert!(rule.contains("RUN+=\"/usr/local/bin/prefixdevname\""));
+        assert!(rule.contains("PROGRAM=\"/usr/local/bin/prefixdevname\""));
+
+        let dir = std::env::temp_dir().join(format!("prefixdevname-rules-{}", std::process::id()));
+        let path = install_udev_rule(&dir, &rule).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), rule);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,33 +1,713 @@
 // SPDX-License-Identifier:  MIT
 
-use regex::Regex;
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicU64};
+use std::time::Duration;
 
 extern crate libudev;
 use libudev::Device;
 
+use crate::error_report::emit_error_report;
+use crate::patterns::{contains_prefixed_index, is_prefixed_index};
 use crate::sema::Semaphore;
 
+pub static SYSFS_CLASS_NET: &str = "/sys/class/net";
+
+// Interface flags from <linux/if.h>
+const IFF_MASTER: u32 = 0x400;
+const IFF_SLAVE: u32 = 0x800;
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
 pub fn rename_needed(ifname: &str, prefix: &str) -> Result<bool, Box<dyn Error>> {
-    let re: Regex = Regex::new(&format!("{}\\d+", prefix)).unwrap();
+    Ok(!contains_prefixed_index(ifname, prefix))
+}
 
-    Ok(!re.is_match(ifname))
+/// Removes all occurrences of the flag from the command line, tells whether it was present.
+pub fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|a| a != flag);
+    args.len() != len
+}
+
+/// Removes "OPTION VALUE" (or "OPTION=VALUE") from the command line arguments and returns the
+/// value, the last occurrence wins.
+pub fn take_option(args: &mut Vec<String>, option: &str) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(take_options(args, option)?.pop())
+}
+
+/// Removes all occurrences of the repeatable option, returns their values in order.
+pub fn take_options(args: &mut Vec<String>, option: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let prefix = format!("{}=", option);
+    let mut values = Vec::new();
+    let mut i = 0;
+
+    while i < args.len() {
+        if let Some(v) = args[i].strip_prefix(&prefix) {
+            values.push(v.to_string());
+            args.remove(i);
+        } else if args[i] == option {
+            if i + 1 >= args.len() {
+                return Err(From::from(format!(
+                    "Option {} requires an argument",
+                    option
+                )));
+            }
+            values.push(args[i + 1].clone());
+            args.drain(i..i + 2);
+        } else {
+            i += 1;
+        }
+    }
+
+    Ok(values)
+}
+
+/// DEVPATH as passed by udev, i.e. the syspath without the /sys prefix.
+pub fn devpath_from_syspath(syspath: &Path) -> Result<String, Box<dyn Error>> {
+    match syspath.strip_prefix("/sys") {
+        Ok(p) if p.as_os_str().is_empty() => Err(From::from("Syspath can't be /sys itself")),
+        Ok(p) => Ok(format!("/{}", p.display())),
+        Err(_) => Err(From::from(format!(
+            "Syspath {} doesn't point to sysfs",
+            syspath.display()
+        ))),
+    }
+}
+
+/// Syspath of the network interface, looked up via udev by its sysname.
+#[cfg(not(feature = "minimal"))]
+pub fn syspath_for_ifname(ifname: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let lookup = || -> Result<Option<PathBuf>, Box<dyn Error>> {
+        let udev = libudev::Context::new()?;
+        let mut enumerate = libudev::Enumerator::new(&udev)?;
+
+        enumerate.match_subsystem("net")?;
+        enumerate.match_sysname(ifname)?;
+
+        let syspath = enumerate
+            .scan_devices()?
+            .next()
+            .and_then(|d| d.syspath().map(|p| p.to_path_buf()));
+        Ok(syspath)
+    };
+
+    match lookup() {
+        Ok(Some(p)) => Ok(p),
+        Ok(None) => Err(From::from(format!("No such network interface {}", ifname))),
+        Err(e) => {
+            debug!(
+                "Failed to look up {} via udev ({}), reading sysfs",
+                ifname, e
+            );
+            let path = PathBuf::from(SYSFS_CLASS_NET).join(ifname);
+            if !path.exists() {
+                return Err(From::from(format!("No such network interface {}", ifname)));
+            }
+            Ok(path)
+        }
+    }
+}
+
+/// Exports properties of the device the same way udev passes them to programs run from rules,
+/// hence the event can be reproduced by hand with --syspath.
+pub fn export_device_environment(syspath: &Path) -> Result<(), Box<dyn Error>> {
+    let syspath = std::fs::canonicalize(syspath)
+        .map_err(|e| format!("Failed to resolve {}: {}", syspath.display(), e))?;
+    let devpath = devpath_from_syspath(&syspath)?;
+
+    let properties: Vec<(String, String)> =
+        match libudev::Context::new().and_then(|udev| Device::from_syspath(&udev, &syspath)) {
+            Ok(d) => d
+                .properties()
+                .map(|p| {
+                    (
+                        p.name().to_string_lossy().to_string(),
+                        p.value().to_string_lossy().to_string(),
+                    )
+                })
+                .collect(),
+            Err(e) => {
+                debug!(
+                    "Failed to open {} via udev ({}), reading uevent",
+                    devpath, e
+                );
+                parse_uevent(&std::fs::read_to_string(syspath.join("uevent"))?)
+                    .into_iter()
+                    .collect()
+            }
+        };
+
+    for (k, v) in properties {
+        env::set_var(k, v);
+    }
+
+    env::set_var("DEVPATH", &devpath);
+    if env::var_os("INTERFACE").is_none() {
+        if let Some(n) = syspath.file_name() {
+            env::set_var("INTERFACE", n);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the variables udev passes to programs run from rules, so that a broken invocation is
+/// reported right away instead of misbehaving later.
+pub fn validate_event_environment(
+    interface: Option<&str>,
+    devpath: Option<&str>,
+    sysfs: &Path,
+) -> Result<(), Box<dyn Error>> {
+    match interface {
+        None => return Err(From::from("INTERFACE isn't set")),
+        Some(i) if i.trim().is_empty() => return Err(From::from("INTERFACE is empty")),
+        Some(i) if i.contains('/') => {
+            return Err(From::from(format!(
+                "INTERFACE \"{}\" isn't valid interface name",
+                i
+            )))
+        }
+        Some(_) => {}
+    }
+
+    let devpath = match devpath {
+        None => return Err(From::from("DEVPATH isn't set")),
+        Some(d) if !d.starts_with('/') => {
+            return Err(From::from(format!("DEVPATH \"{}\" isn't absolute path", d)))
+        }
+        Some(d) => d,
+    };
+
+    let syspath = sysfs.join(devpath.trim_start_matches('/'));
+    if !syspath.exists() {
+        return Err(From::from(format!(
+            "DEVPATH \"{}\" doesn't exist in {}",
+            devpath,
+            sysfs.display()
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn event_environment_ok() -> Result<(), Box<dyn Error>> {
+    validate_event_environment(
+        env::var("INTERFACE").ok().as_deref(),
+        env::var("DEVPATH").ok().as_deref(),
+        Path::new("/sys"),
+    )
 }
 
 pub fn event_device_name() -> String {
     env::var("INTERFACE").unwrap_or_else(|_| "".to_string())
 }
 
+/// Kernel driver of the event device, ID_NET_DRIVER isn't set yet when we run, hence we look at
+/// the driver link in sysfs.
+pub fn event_device_driver() -> Option<String> {
+    let devpath = env::var("DEVPATH").ok()?;
+
+    std::fs::read_link(format!("/sys{}/device/driver", devpath))
+        .ok()?
+        .file_name()
+        .map(|d| d.to_string_lossy().to_string())
+}
+
+/// Vendor ID of the device. PCI devices report it only in sysfs, udev sets ID_VENDOR_ID (passed as
+/// property) for USB devices.
+pub fn device_vendor_id(syspath: &Path, property: Option<String>) -> Option<String> {
+    std::fs::read_to_string(syspath.join("device/vendor"))
+        .ok()
+        .map(|v| v.trim().to_string())
+        .or(property)
+}
+
+pub fn event_device_vendor_id() -> Option<String> {
+    let devpath = env::var("DEVPATH").unwrap_or_default();
+
+    device_vendor_id(
+        Path::new(&format!("/sys{}", devpath)),
+        env::var("ID_VENDOR_ID").ok(),
+    )
+}
+
+/// Name the device already got from other naming scheme. The rule passes the name assigned by
+/// earlier rules (NAME=) in PREFIXDEVNAME_CURRENT_NAME, names provided by hwdb and net_setup_link
+/// are exported as device properties. Names using our prefix don't count.
+pub fn udev_provided_name<F>(property: F, ifname: &str, prefix: &str) -> Option<(String, String)>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let properties = [
+        "ID_NET_NAME",
+        "ID_NET_NAME_FROM_DATABASE",
+        "PREFIXDEVNAME_CURRENT_NAME",
+    ];
+
+    for p in properties {
+        let name = match property(p) {
+            Some(n) if !n.is_empty() => n,
+            _ => continue,
+        };
+
+        if p == "PREFIXDEVNAME_CURRENT_NAME" && name == ifname {
+            continue;
+        }
+
+        if !rename_needed(&name, prefix).unwrap_or(true) {
+            continue;
+        }
+
+        return Some((p.to_string(), name));
+    }
+
+    None
+}
+
+pub fn event_device_udev_provided_name(prefix: &str) -> Option<(String, String)> {
+    udev_provided_name(|p| env::var(p).ok(), &event_device_name(), prefix)
+}
+
+/// Device property set by hwdb entries, OEMs pin names of specific hardware with it.
+pub static HWDB_NAME_PROPERTY: &str = "PREFIXDEVNAME_NAME";
+
+/// Time since boot from the first field of /proc/uptime.
+pub fn parse_uptime(content: &str) -> Option<Duration> {
+    let seconds: f64 = content.split_whitespace().next()?.parse().ok()?;
+
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+pub fn system_uptime() -> Option<Duration> {
+    parse_uptime(&std::fs::read_to_string("/proc/uptime").ok()?)
+}
+
+/// Index of the name pinned by hwdb. The property holds either the name using our prefix or just
+/// the index, so that the hwdb entry doesn't depend on the prefix chosen by the administrator.
+pub fn hwdb_pinned_index<F>(property: F, prefix: &str) -> Result<Option<u64>, Box<dyn Error>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let value = match property(HWDB_NAME_PROPERTY) {
+        Some(v) if !v.trim().is_empty() => v.trim().to_string(),
+        _ => return Ok(None),
+    };
+    let index = value.strip_prefix(prefix).unwrap_or(&value);
+
+    if index.is_empty() || !index.chars().all(|c| c.is_ascii_digit()) {
+        return Err(From::from(format!(
+            "{}=\"{}\" doesn't follow the naming scheme of prefix \"{}\"",
+            HWDB_NAME_PROPERTY, value, prefix
+        )));
+    }
+
+    Ok(Some(index.parse()?))
+}
+
+pub fn event_device_hwdb_pinned_index(prefix: &str) -> Result<Option<u64>, Box<dyn Error>> {
+    hwdb_pinned_index(|p| env::var(p).ok(), prefix)
+}
+
+pub fn event_device_id_path() -> Option<String> {
+    env::var("ID_PATH").ok().filter(|p| !p.is_empty())
+}
+
+/// VLAN ID as reported in /proc/net/vlan/<ifname>, e.g. "net0.100  VID: 100  REORDER_HDR: 1 ...".
+pub fn parse_vlan_id(content: &str) -> Option<u16> {
+    let mut words = content.split_whitespace();
+
+    words.find(|w| *w == "VID:")?;
+    words.next()?.parse().ok()
+}
+
+/// Name of the VLAN device derived from its parent, e.g. net2.100. Returns None if the parent isn't
+/// named using our prefix.
+pub fn vlan_name(parent: &str, vlan_id: u16, prefix: &str) -> Option<String> {
+    if !is_prefixed_index(parent, prefix) {
+        return None;
+    }
+
+    Some(format!("{}.{}", parent, vlan_id)).filter(|n| n.len() <= 15)
+}
+
+/// Parent and VLAN ID of the event device if it is a VLAN device.
+pub fn event_device_vlan() -> Option<(String, u16)> {
+    if env::var("DEVTYPE").ok()? != "vlan" {
+        return None;
+    }
+
+    let ifname = event_device_name();
+    let parent = sysfs_net_entries(&ifname)
+        .iter()
+        .find_map(|e| e.strip_prefix("lower_").map(|p| p.to_string()))?;
+    let vlan_id = std::fs::read_to_string(PathBuf::from("/proc/net/vlan").join(&ifname))
+        .ok()
+        .as_deref()
+        .and_then(parse_vlan_id)?;
+
+    Some((parent, vlan_id))
+}
+
+/// Value of the "container" variable in the environment block of the process (NUL separated).
+pub fn container_from_environ(environ: &[u8]) -> Option<String> {
+    environ
+        .split(|b| *b == 0)
+        .filter_map(|v| v.strip_prefix(b"container="))
+        .map(|v| String::from_utf8_lossy(v).to_string())
+        .find(|v| !v.is_empty())
+}
+
+/// Detects that we run inside a container, the same way systemd-detect-virt --container does.
+/// Returns the container manager, e.g. "docker" or "systemd-nspawn".
+pub fn detect_container() -> Option<String> {
+    if let Ok(c) = std::fs::read_to_string("/run/systemd/container") {
+        if !c.trim().is_empty() {
+            return Some(c.trim().to_string());
+        }
+    }
+
+    if let Some(c) = std::fs::read("/proc/1/environ")
+        .ok()
+        .and_then(|e| container_from_environ(&e))
+    {
+        return Some(c);
+    }
+
+    if Path::new("/run/.containerenv").exists() {
+        return Some("podman".to_string());
+    }
+
+    if Path::new("/.dockerenv").exists() {
+        return Some("docker".to_string());
+    }
+
+    None
+}
+
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Path of the temporary file the content is written to before it is moved to the path. Neither
+/// concurrent processes nor threads of one process share it.
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let seq = TEMP_FILES.fetch_add(1, atomic::Ordering::Relaxed);
+
+    path.with_extension(format!("{}.{}.tmp", std::process::id(), seq))
+}
+
+// Separate /var is mounted late during the boot and not at all in the initrd, records written to
+// the mount point would be hidden once it is mounted
+pub fn var_available() -> bool {
+    !Path::new("/etc/initrd-release").exists() && Path::new("/var/lib").is_dir()
+}
+
 pub fn event_device_virtual() -> bool {
     let devpath = env::var("DEVPATH").unwrap_or_else(|_| "".to_string());
 
     devpath.starts_with("/devices/virtual")
 }
 
+pub const ARPHRD_ETHER: u32 = 1;
+
+/// Interface type (ARPHRD_*) as reported in sysfs.
+pub fn device_link_type(device: &Device) -> Option<u32> {
+    device
+        .attribute_value("type")
+        .and_then(|t| t.to_str())
+        .and_then(|t| t.trim().parse().ok())
+}
+
+pub fn event_device_link_type() -> Option<u32> {
+    let devpath = env::var("DEVPATH").ok()?;
+
+    std::fs::read_to_string(format!("/sys{}/type", devpath))
+        .ok()
+        .and_then(|t| t.trim().parse().ok())
+}
+
+pub fn parse_net_flags(flags: &str) -> Option<u32> {
+    u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok()
+}
+
+fn devtype_stacked(devtype: &str) -> bool {
+    let stacked = [
+        "vlan", "bond", "bridge", "team", "macvlan", "macvtap", "ipvlan", "vxlan",
+    ];
+
+    stacked.contains(&devtype)
+}
+
+fn sysfs_net_entries(ifname: &str) -> Vec<String> {
+    sysfs_net_entries_in(Path::new(SYSFS_CLASS_NET), ifname)
+}
+
+/// Role of the network device in virtio-net failover. Failover master, standby (virtio-net) and
+/// primary (VF) devices all share the same MAC address and only the master should be named.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverRole {
+    Master,
+    Standby,
+    Primary,
+}
+
+fn sysfs_attribute_in(root: &Path, ifname: &str, attribute: &str) -> Option<String> {
+    std::fs::read_to_string(root.join(ifname).join(attribute))
+        .ok()
+        .map(|v| v.trim().to_string())
+}
+
+fn sysfs_net_entries_in(root: &Path, ifname: &str) -> Vec<String> {
+    let dir = match std::fs::read_dir(root.join(ifname)) {
+        Ok(d) => d,
+        Err(_) => return Vec::new(),
+    };
+
+    dir.filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect()
+}
+
+// virtio-net reports "sby" as physical port name of the standby device
+fn failover_standby_in(root: &Path, ifname: &str) -> bool {
+    sysfs_attribute_in(root, ifname, "phys_port_name").is_some_and(|n| n == "sby")
+}
+
+fn failover_master_in(root: &Path, ifname: &str) -> bool {
+    sysfs_net_entries_in(root, ifname)
+        .iter()
+        .filter_map(|e| e.strip_prefix("lower_"))
+        .any(|l| failover_standby_in(root, l))
+}
+
+fn failover_role_in(root: &Path, ifname: &str) -> Option<FailoverRole> {
+    if failover_master_in(root, ifname) {
+        return Some(FailoverRole::Master);
+    }
+
+    if failover_standby_in(root, ifname) {
+        return Some(FailoverRole::Standby);
+    }
+
+    let enslaved_to_master = sysfs_net_entries_in(root, ifname)
+        .iter()
+        .filter_map(|e| e.strip_prefix("upper_"))
+        .any(|u| failover_master_in(root, u));
+
+    if enslaved_to_master {
+        return Some(FailoverRole::Primary);
+    }
+
+    // VF appears before it is enslaved to the master, but it already shares its MAC address
+    let hwaddr = sysfs_attribute_in(root, ifname, "address")?;
+    let shares_hwaddr = std::fs::read_dir(root)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|n| n != ifname)
+        .any(|n| {
+            sysfs_attribute_in(root, &n, "address").as_ref() == Some(&hwaddr)
+                && failover_master_in(root, &n)
+        });
+
+    if shares_hwaddr {
+        Some(FailoverRole::Primary)
+    } else {
+        None
+    }
+}
+
+pub fn failover_role(ifname: &str) -> Option<FailoverRole> {
+    failover_role_in(Path::new(SYSFS_CLASS_NET), ifname)
+}
+
+/// Properties of the network device needed to decide whether it consumes an index. Normally
+/// they come from udev, but when udev isn't available (minimal containers, very early boot) they
+/// are read from sysfs directly.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetDevice {
+    pub name: String,
+    pub hwaddr: Option<String>,
+    pub link_type: Option<u32>,
+    pub devtype: Option<String>,
+    pub flags: Option<u32>,
+}
+
+impl NetDevice {
+    pub fn from_udev(device: &Device) -> NetDevice {
+        let attribute = |a: &str| {
+            device
+                .attribute_value(a)
+                .and_then(|v| v.to_str())
+                .map(|v| v.trim().to_string())
+        };
+
+        NetDevice {
+            name: device
+                .sysname()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default()
+                .to_string(),
+            hwaddr: attribute("address"),
+            link_type: device_link_type(device),
+            devtype: device
+                .devtype()
+                .and_then(|t| t.to_str())
+                .map(|t| t.to_string()),
+            flags: attribute("flags").as_deref().and_then(parse_net_flags),
+        }
+    }
+
+    pub fn from_sysfs(ifname: &str) -> NetDevice {
+        let root = Path::new(SYSFS_CLASS_NET);
+        let uevent = sysfs_attribute_in(root, ifname, "uevent").unwrap_or_default();
+
+        NetDevice {
+            name: ifname.to_string(),
+            hwaddr: sysfs_attribute_in(root, ifname, "address"),
+            link_type: sysfs_attribute_in(root, ifname, "type").and_then(|t| t.parse().ok()),
+            devtype: parse_uevent(&uevent).remove("DEVTYPE"),
+            flags: sysfs_attribute_in(root, ifname, "flags")
+                .as_deref()
+                .and_then(parse_net_flags),
+        }
+    }
+}
+
+/// Parses KEY=value lines of the uevent file.
+pub fn parse_uevent(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// File name of the path as UTF-8. Warns when the name isn't valid UTF-8, callers skip such
+/// entries, none of them can be ours and they must not abort the enumeration.
+pub fn utf8_file_name(path: &Path) -> Option<&str> {
+    let name = path.file_name()?;
+
+    if name.to_str().is_none() {
+        warn!("Skipping {}, file name is not valid UTF-8", path.display());
+    }
+
+    name.to_str()
+}
+
+/// All network devices present in sysfs, used when udev isn't available.
+pub fn sysfs_net_devices() -> Result<Vec<NetDevice>, Box<dyn Error>> {
+    let mut devices = Vec::new();
+
+    for entry in std::fs::read_dir(SYSFS_CLASS_NET)? {
+        if let Some(name) = utf8_file_name(&entry?.path()) {
+            devices.push(NetDevice::from_sysfs(name));
+        }
+    }
+
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(devices)
+}
+
+/// Stacked devices (bonds, bridges, teams, VLANs, ...) are built on top of other network devices.
+/// They share MAC address with their lower devices, hence they must never consume an index. Note
+/// that team devices and older kernels don't set DEVTYPE, so we also look at sysfs. Failover
+/// master is the exception, it is the only device of the failover group that gets a name.
+pub fn net_device_stacked(device: &NetDevice) -> bool {
+    let ifname = device.name.as_str();
+
+    match failover_role(ifname) {
+        Some(FailoverRole::Master) => return false,
+        Some(_) => return true,
+        None => {}
+    }
+
+    if device.devtype.as_deref().is_some_and(devtype_stacked) {
+        return true;
+    }
+
+    if device.flags.is_some_and(|f| f & IFF_MASTER != 0) {
+        return true;
+    }
+
+    if ifname.is_empty() {
+        return false;
+    }
+
+    if let Ok(p) = std::fs::canonicalize(PathBuf::from(SYSFS_CLASS_NET).join(ifname)) {
+        if p.starts_with("/sys/devices/virtual") {
+            return true;
+        }
+    }
+
+    sysfs_net_entries(ifname).iter().any(|e| {
+        e == "bonding" || e == "bridge" || e.starts_with("slave_") || e.starts_with("lower_")
+    })
+}
+
+pub fn device_stacked(device: &Device) -> bool {
+    net_device_stacked(&NetDevice::from_udev(device))
+}
+
+/// Physical NIC enslaved to a bond, bridge or team device.
+pub fn net_device_enslaved(device: &NetDevice) -> bool {
+    if device.flags.is_some_and(|f| f & IFF_SLAVE != 0) {
+        return true;
+    }
+
+    if device.name.is_empty() {
+        return false;
+    }
+
+    sysfs_net_entries(&device.name)
+        .iter()
+        .any(|e| e == "master" || e.starts_with("upper_"))
+}
+
+pub fn device_enslaved(device: &Device) -> bool {
+    net_device_enslaved(&NetDevice::from_udev(device))
+}
+
+/// Bonding may rewrite MAC addresses of its slaves, in such case we need to use address the NIC
+/// had before it was enslaved.
+pub fn hwaddr_enslaved_ifname(ifname: &str) -> Result<String, Box<dyn Error>> {
+    let perm_hwaddr_path = PathBuf::from(SYSFS_CLASS_NET)
+        .join(ifname)
+        .join("bonding_slave/perm_hwaddr");
+
+    match std::fs::read_to_string(perm_hwaddr_path) {
+        Ok(a) => hwaddr_normalize(&a.trim()),
+        Err(_) => hwaddr_permanent(ifname),
+    }
+}
+
+pub fn hwaddr_enslaved(device: &Device) -> Result<String, Box<dyn Error>> {
+    let ifname = device
+        .sysname()
+        .and_then(|n| n.to_str())
+        .ok_or("Failed to convert from ffi::OsStr to &str")?;
+
+    hwaddr_enslaved_ifname(ifname)
+}
+
 pub fn hwaddr_valid<T: ToString>(hwaddr: &T) -> bool {
     use std::num::ParseIntError;
 
@@ -71,40 +751,128 @@ pub fn hwaddr_normalize<T: ToString>(hwaddr: &T) -> Result<String, Box<dyn Error
     Ok(addr)
 }
 
+/// Some NICs report all-zero or broadcast address until their firmware is initialized. Such address
+/// isn't usable for matching the device in the link file.
+pub fn hwaddr_assigned<T: ToString>(hwaddr: &T) -> bool {
+    let addr = hwaddr.to_string().to_ascii_uppercase();
+
+    addr != "00:00:00:00:00:00" && addr != "FF:FF:FF:FF:FF:FF"
+}
+
+/// Second least significant bit of the first octet is set for locally administered addresses.
+pub fn hwaddr_locally_administered<T: ToString>(hwaddr: &T) -> bool {
+    let addr = hwaddr.to_string();
+
+    match addr.get(0..2).map(|o| u8::from_str_radix(o, 16)) {
+        Some(Ok(o)) => o & 0x02 != 0,
+        _ => false,
+    }
+}
+
+/// Permanent (burned-in) MAC address of the interface as reported by the driver via ethtool.
+pub fn hwaddr_permanent(ifname: &str) -> Result<String, Box<dyn Error>> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    const SIOCETHTOOL: u64 = 0x8946;
+    const ETHTOOL_GPERMADDR: u32 = 0x20;
+    const MAX_ADDR_LEN: usize = 32;
+
+    #[repr(C)]
+    struct EthtoolPermAddr {
+        cmd: u32,
+        size: u32,
+        data: [u8; MAX_ADDR_LEN],
+    }
+
+    #[repr(C)]
+    struct IfReq {
+        ifr_name: [libc::c_char; 16],
+        ifr_data: *mut libc::c_void,
+        _pad: [u8; 16],
+    }
+
+    if ifname.is_empty() || ifname.len() >= 16 {
+        return Err(From::from("Invalid interface name"));
+    }
+
+    let mut perm_addr = EthtoolPermAddr {
+        cmd: ETHTOOL_GPERMADDR,
+        size: MAX_ADDR_LEN as u32,
+        data: [0; MAX_ADDR_LEN],
+    };
+    let mut ifr = IfReq {
+        ifr_name: [0; 16],
+        ifr_data: &mut perm_addr as *mut EthtoolPermAddr as *mut libc::c_void,
+        _pad: [0; 16],
+    };
+
+    for (i, b) in ifname.bytes().enumerate() {
+        ifr.ifr_name[i] = b as libc::c_char;
+    }
+
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(From::from(std::io::Error::last_os_error()));
+        }
+        let _fd = OwnedFd::from_raw_fd(fd);
+
+        if libc::ioctl(fd, SIOCETHTOOL as _, &mut ifr as *mut IfReq) < 0 {
+            return Err(From::from(std::io::Error::last_os_error()));
+        }
+    }
+
+    let addr = &perm_addr.data[..(perm_addr.size as usize).min(MAX_ADDR_LEN)];
+    if addr.len() != 6 || addr.iter().all(|b| *b == 0) {
+        return Err(From::from("Device doesn't have permanent MAC address"));
+    }
+
+    hwaddr_normalize(
+        &addr
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<String>>()
+            .join(":"),
+    )
+}
+
 pub fn hwaddr_from_event_device() -> Result<String, Box<dyn Error>> {
-    let udev = libudev::Context::new()?;
     let devpath = env::var("DEVPATH")?;
     let mut syspath = "/sys".to_string();
 
     syspath.push_str(&devpath);
 
-    let attr = Device::from_syspath(&udev, &PathBuf::from(syspath))?
-        .attribute_value("address")
-        .ok_or("Failed to get MAC Address")?
-        .to_owned();
-    let addr = hwaddr_normalize(
-        &attr
-            .to_str()
-            .ok_or("Failed to convert OsStr to String")?
+    let attr = match libudev::Context::new()
+        .and_then(|udev| Device::from_syspath(&udev, &PathBuf::from(&syspath)))
+    {
+        Ok(d) => d
+            .attribute_value("address")
+            .ok_or("Failed to get MAC Address")?
+            .to_string_lossy()
             .to_string(),
-    )?;
+        Err(e) => {
+            debug!(
+                "Failed to open event device via udev ({}), reading sysfs",
+                e
+            );
+            std::fs::read_to_string(PathBuf::from(&syspath).join("address"))
+                .map_err(|e| format!("Failed to get MAC Address: {}", e))?
+                .trim()
+                .to_string()
+        }
+    };
+    let addr = hwaddr_normalize(&attr)?;
 
     Ok(addr)
 }
 
-pub fn get_prefix_from_file(path: &str) -> Result<String, Box<dyn Error>> {
-    let mut f = File::open(path)?;
-    let mut content = String::new();
-
-    f.read_to_string(&mut content)?;
-
-    let re = Regex::new(r"net.ifnames.prefix=([[:alpha:]]+)")?;
-    let prefix = match re.captures(&content) {
-        Some(c) => c[1].to_string(),
-        None => "".to_string(),
-    };
+pub fn interface_exists(ifname: &str) -> bool {
+    PathBuf::from(SYSFS_CLASS_NET).join(ifname).exists()
+}
 
-    Ok(prefix)
+pub fn error_already_exists(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::AlreadyExists)
 }
 
 pub fn prefix_ok<T: AsRef<str>>(prefix: &T) -> bool {
@@ -123,6 +891,7 @@ pub fn exit_maybe_unlock(sema: Option<&mut Semaphore>, exit_code: i32) -> ! {
         s.unlock();
     }
 
+    emit_error_report(exit_code);
     std::process::exit(exit_code)
 }
 
@@ -130,6 +899,33 @@ pub fn exit_maybe_unlock(sema: Option<&mut Semaphore>, exit_code: i32) -> ! {
 mod tests {
     use super::*;
 
+    #[test]
+    fn utf8_file_name_invalid() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let invalid = Path::new("/etc/systemd/network").join(OsStr::from_bytes(b"71-\xff.link"));
+
+        assert_eq!(
+            utf8_file_name(Path::new(
+                "/etc/systemd/network/71-net-ifnames-prefix-net0.link"
+            )),
+            Some("71-net-ifnames-prefix-net0.link")
+        );
+        assert_eq!(utf8_file_name(&invalid), None);
+        assert_eq!(utf8_file_name(Path::new("/")), None);
+    }
+
+    #[test]
+    fn uptime_parsed() {
+        assert_eq!(
+            parse_uptime("350735.47 234388.90\n"),
+            Some(Duration::from_millis(350_735_470))
+        );
+        assert_eq!(parse_uptime(""), None);
+        assert_eq!(parse_uptime("-1 0"), None);
+    }
+
     #[test]
     fn hwaddr_valid_ok() {
         assert!(hwaddr_valid(&"11:22:33:44:55:66"));
@@ -164,6 +960,19 @@ mod tests {
         assert!(hwaddr_valid(&"52:54:00:52:1f"));
     }
 
+    #[test]
+    fn net_flags_parse() {
+        assert_eq!(parse_net_flags("0x1003\n"), Some(0x1003));
+        assert_eq!(parse_net_flags("0x1c03").unwrap() & IFF_MASTER, IFF_MASTER);
+        assert_eq!(parse_net_flags("bogus"), None);
+    }
+
+    #[test]
+    fn devtype_stacked_team() {
+        assert!(devtype_stacked("team"));
+        assert!(!devtype_stacked("wlan"));
+    }
+
     #[test]
     fn hwaddr_normalize_ok() {
         assert_eq!(
@@ -189,6 +998,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn hwaddr_assigned_ok() {
+        assert!(hwaddr_assigned(&"52:54:00:52:1F:93"));
+    }
+
+    #[test]
+    fn hwaddr_assigned_zero() {
+        assert!(!hwaddr_assigned(&"00:00:00:00:00:00"));
+    }
+
+    #[test]
+    fn hwaddr_assigned_broadcast() {
+        assert!(!hwaddr_assigned(&"ff:ff:ff:ff:ff:ff"));
+    }
+
+    #[test]
+    fn hwaddr_locally_administered_ok() {
+        assert!(hwaddr_locally_administered(&"06:54:00:52:1F:93"));
+        assert!(hwaddr_locally_administered(&"52:54:00:52:1F:93"));
+    }
+
+    #[test]
+    fn hwaddr_universally_administered() {
+        assert!(!hwaddr_locally_administered(&"A4:BB:6D:52:1F:91"));
+        assert!(!hwaddr_locally_administered(&"00:1B:21:52:1F:93"));
+    }
+
     #[test]
     fn net_prefix_ok() {
         assert_eq!(true, prefix_ok(&"net"));
@@ -219,6 +1055,72 @@ mod tests {
         assert_eq!(rename_needed("", "net").unwrap(), true);
     }
 
+    #[test]
+    fn udev_provided_name_none() {
+        let name = udev_provided_name(
+            |p| match p {
+                "PREFIXDEVNAME_CURRENT_NAME" => Some("eth0".to_string()),
+                "ID_NET_NAME" => Some("net3".to_string()),
+                _ => None,
+            },
+            "eth0",
+            "net",
+        );
+
+        assert_eq!(name, None);
+    }
+
+    #[test]
+    fn udev_provided_name_from_rule() {
+        let name = udev_provided_name(
+            |p| match p {
+                "PREFIXDEVNAME_CURRENT_NAME" => Some("lan0".to_string()),
+                _ => None,
+            },
+            "eth0",
+            "net",
+        );
+
+        assert_eq!(
+            name,
+            Some(("PREFIXDEVNAME_CURRENT_NAME".to_string(), "lan0".to_string()))
+        );
+    }
+
+    #[test]
+    fn udev_provided_name_from_database() {
+        let name = udev_provided_name(
+            |p| match p {
+                "ID_NET_NAME_FROM_DATABASE" => Some("idrac".to_string()),
+                _ => None,
+            },
+            "eth0",
+            "net",
+        );
+
+        assert_eq!(
+            name,
+            Some(("ID_NET_NAME_FROM_DATABASE".to_string(), "idrac".to_string()))
+        );
+    }
+
+    #[test]
+    fn hwdb_pinned_name() {
+        let pinned = |value: &str| {
+            hwdb_pinned_index(
+                |p| Some(value.to_string()).filter(|_| p == HWDB_NAME_PROPERTY),
+                "net",
+            )
+        };
+
+        assert_eq!(pinned("net7").unwrap(), Some(7));
+        assert_eq!(pinned("12\n").unwrap(), Some(12));
+        assert_eq!(pinned("").unwrap(), None);
+        assert!(pinned("lan3").is_err());
+        assert!(pinned("net").is_err());
+        assert_eq!(hwdb_pinned_index(|_| None, "net").unwrap(), None);
+    }
+
     #[test]
     fn event_device_not_virtual() {
         env::set_var(
@@ -235,4 +1137,159 @@ mod tests {
 
         assert_eq!(event_device_virtual(), true);
     }
+
+    #[test]
+    fn failover_roles() {
+        let root =
+            std::env::temp_dir().join(format!("prefixdevname-failover-{}", std::process::id()));
+        let hwaddr = "52:54:00:1c:08:b7\n";
+
+        for (ifname, files) in [
+            ("eth0", vec![("address", hwaddr), ("lower_eth0nsby", "")]),
+            (
+                "eth0nsby",
+                vec![("address", hwaddr), ("phys_port_name", "sby\n")],
+            ),
+            ("ens4", vec![("address", hwaddr), ("upper_eth0", "")]),
+            ("ens5", vec![("address", hwaddr)]),
+            ("ens6", vec![("address", "52:54:00:1c:08:b8\n")]),
+        ] {
+            std::fs::create_dir_all(root.join(ifname)).unwrap();
+            for (name, content) in files {
+                std::fs::write(root.join(ifname).join(name), content).unwrap();
+            }
+        }
+
+        let roles: Vec<Option<FailoverRole>> = ["eth0", "eth0nsby", "ens4", "ens5", "ens6"]
+            .iter()
+            .map(|i| failover_role_in(&root, i))
+            .collect();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            roles,
+            vec![
+                Some(FailoverRole::Master),
+                Some(FailoverRole::Standby),
+                Some(FailoverRole::Primary),
+                Some(FailoverRole::Primary),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn vlan_names() {
+        assert_eq!(
+            parse_vlan_id("net2.100  VID: 100\t REORDER_HDR: 1  dev->priv_flags: 1001\n"),
+            Some(100)
+        );
+        assert_eq!(parse_vlan_id("garbage"), None);
+
+        assert_eq!(vlan_name("net2", 100, "net"), Some("net2.100".to_string()));
+        assert_eq!(vlan_name("eth0", 100, "net"), None);
+        assert_eq!(vlan_name("netmon0", 100, "net"), None);
+        assert_eq!(vlan_name("netnetnetnet12", 4094, "netnetnetnet"), None);
+    }
+
+    #[test]
+    fn already_exists() {
+        let e: Box<dyn Error> = From::from(std::io::Error::from(std::io::ErrorKind::AlreadyExists));
+        assert!(error_already_exists(e.as_ref()));
+
+        let e: Box<dyn Error> = From::from("Name taken");
+        assert!(!error_already_exists(e.as_ref()));
+    }
+
+    #[test]
+    fn uevent() {
+        let uevent = parse_uevent("DEVTYPE=bond\nINTERFACE=bond0\nIFINDEX=5\n");
+
+        assert_eq!(uevent.get("DEVTYPE").map(|s| s.as_str()), Some("bond"));
+        assert_eq!(uevent.get("IFINDEX").map(|s| s.as_str()), Some("5"));
+        assert_eq!(parse_uevent("").len(), 0);
+    }
+
+    #[test]
+    fn container_environ() {
+        assert_eq!(
+            container_from_environ(b"PATH=/usr/bin\0container=podman\0TERM=xterm\0"),
+            Some("podman".to_string())
+        );
+        assert_eq!(container_from_environ(b"container=\0HOME=/\0"), None);
+        assert_eq!(container_from_environ(b""), None);
+    }
+
+    #[test]
+    fn options() {
+        let mut args: Vec<String> = ["prefixdevname", "--syspath", "/sys/a", "--syspath=/sys/b"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+
+        assert_eq!(
+            take_option(&mut args, "--syspath").unwrap(),
+            Some("/sys/b".to_string())
+        );
+        assert_eq!(args, vec!["prefixdevname"]);
+        assert_eq!(take_option(&mut args, "--syspath").unwrap(), None);
+
+        let mut args = vec!["--syspath".to_string()];
+        assert!(take_option(&mut args, "--syspath").is_err());
+
+        let mut args: Vec<String> = ["--set", "A=1", "plan", "--set=B=2"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            take_options(&mut args, "--set").unwrap(),
+            vec!["A=1", "B=2"]
+        );
+        assert_eq!(args, vec!["plan"]);
+
+        let mut args = vec!["--strict".to_string(), "generate".to_string()];
+        assert!(take_flag(&mut args, "--strict"));
+        assert_eq!(args, vec!["generate"]);
+        assert!(!take_flag(&mut args, "--strict"));
+    }
+
+    #[test]
+    fn syspath_devpath() {
+        assert_eq!(
+            devpath_from_syspath(Path::new("/sys/devices/pci0000:00/0000:00:03.0/net/eth0"))
+                .unwrap(),
+            "/devices/pci0000:00/0000:00:03.0/net/eth0"
+        );
+        assert!(devpath_from_syspath(Path::new("/sys")).is_err());
+        assert!(devpath_from_syspath(Path::new("/proc/net")).is_err());
+    }
+
+    #[test]
+    fn event_environment() {
+        let sysfs =
+            std::env::temp_dir().join(format!("prefixdevname-sysfs-{}", std::process::id()));
+        std::fs::create_dir_all(sysfs.join("devices/virtual/net/eth0")).unwrap();
+
+        let ok =
+            validate_event_environment(Some("eth0"), Some("/devices/virtual/net/eth0"), &sysfs);
+        let no_interface = validate_event_environment(None, Some("/devices"), &sysfs);
+        let empty_interface = validate_event_environment(Some(""), Some("/devices"), &sysfs);
+        let no_devpath = validate_event_environment(Some("eth0"), None, &sysfs);
+        let relative = validate_event_environment(Some("eth0"), Some("devices"), &sysfs);
+        let missing = validate_event_environment(Some("eth0"), Some("/devices/eth1"), &sysfs);
+        std::fs::remove_dir_all(&sysfs).unwrap();
+
+        assert!(ok.is_ok());
+        assert_eq!(no_interface.unwrap_err().to_string(), "INTERFACE isn't set");
+        assert_eq!(
+            empty_interface.unwrap_err().to_string(),
+            "INTERFACE is empty"
+        );
+        assert_eq!(no_devpath.unwrap_err().to_string(), "DEVPATH isn't set");
+        assert!(relative
+            .unwrap_err()
+            .to_string()
+            .contains("isn't absolute path"));
+        assert!(missing.unwrap_err().to_string().contains("doesn't exist"));
+    }
 }
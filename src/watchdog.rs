@@ -0,0 +1,112 @@
+// SPDX-License-Identifier:  MIT
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::counters::bump_counters;
+use crate::error_report::emit_error_report;
+use crate::sema::*;
+
+static STAGE: Mutex<&str> = Mutex::new("startup");
+static STARTED: Mutex<Option<Instant>> = Mutex::new(None);
+static DEADLINE: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub static STAGE_SETTINGS: &str = "reading settings";
+pub static STAGE_LOCK: &str = "waiting for the lock";
+pub static STAGE_SETTLE: &str = "waiting for udev to settle";
+pub static STAGE_ENUMERATE: &str = "enumerating links";
+pub static STAGE_READ_LINK_FILES: &str = "reading link files";
+pub static STAGE_NAMING: &str = "naming links";
+pub static STAGE_WRITE: &str = "writing link file";
+
+// Deadline may be moved once the settings are loaded, hence it is checked periodically
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Records what we are doing right now, it is logged when the deadline passes.
+pub fn set_stage(stage: &'static str) {
+    *STAGE.lock().unwrap() = stage;
+}
+
+pub fn stage() -> &'static str {
+    *STAGE.lock().unwrap()
+}
+
+/// Sets the deadline of the whole run counted from the start of the watchdog, zero disables it.
+pub fn set_timeout(timeout: Duration) {
+    let started = STARTED.lock().unwrap().unwrap_or_else(Instant::now);
+
+    *DEADLINE.lock().unwrap() = if timeout.is_zero() {
+        None
+    } else {
+        Some(started + timeout)
+    };
+}
+
+/// Disarms the watchdog for the rest of the run. Once the name is printed, the run must not fail,
+/// udev would drop the name. Waits for the abort already in progress, which never returns.
+pub fn stop_watchdog() {
+    *DEADLINE.lock().unwrap() = None;
+}
+
+fn abort(started: Instant) -> ! {
+    error!(
+        "Timed out after {:.1}s while {}, giving up",
+        started.elapsed().as_secs_f64(),
+        stage()
+    );
+
+    if stage() == STAGE_LOCK {
+        bump_counters(|c| c.lock_timeouts += 1);
+    }
+
+    release_lock_for_exit();
+
+    emit_error_report(1);
+    std::process::exit(1)
+}
+
+/// udev kills workers that exceed its event timeout and naming silently fails. The watchdog
+/// aborts the run before that happens, releases the lock so that other invocations can proceed
+/// and logs the stage that timed out.
+pub fn start_watchdog(timeout: Duration) {
+    let started = Instant::now();
+
+    *STARTED.lock().unwrap() = Some(started);
+    set_timeout(timeout);
+
+    // Deadline stays locked during the abort, stop_watchdog() can't return while it is running
+    thread::spawn(move || loop {
+        match *DEADLINE.lock().unwrap() {
+            None => return,
+            Some(d) if Instant::now() >= d => abort(started),
+            Some(_) => {}
+        }
+
+        thread::sleep(WATCHDOG_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deadline() -> Option<Instant> {
+        *DEADLINE.lock().unwrap()
+    }
+
+    #[test]
+    fn watchdog_timeout() {
+        set_timeout(Duration::ZERO);
+        assert_eq!(deadline(), None);
+
+        set_timeout(Duration::from_secs(30));
+        assert!(deadline().unwrap() > Instant::now() + Duration::from_secs(29));
+        stop_watchdog();
+        assert_eq!(deadline(), None);
+
+        set_timeout(Duration::ZERO);
+        set_stage(STAGE_LOCK);
+        assert_eq!(stage(), "waiting for the lock");
+    }
+}